@@ -0,0 +1,120 @@
+//! Times the extraction functions on their own, over canned pages, to
+//! catch regressions in the token sinks: an index of 5000 chapter links, a
+//! 200 KB chapter in paragraphs, one in indented lines and a chapter written
+//! mostly in character references.
+//!
+//! Run with `cargo bench --bench extraction`; `ITERATIONS` changes how many
+//! times each page is parsed (default 20). Each line gives the fastest and
+//! the median run.
+//!
+//! Baseline, release profile with `ITERATIONS=50` on one core of an x86_64
+//! Linux container:
+//!
+//! ```text
+//! index, 5000 links              fastest  11.5ms  median  12.4ms
+//! chapter, 200 KB                fastest   1.5ms  median   1.6ms
+//! chapter, 200 KB of lines       fastest 361.3µs  median 376.6µs
+//! chapter, 30000 entities        fastest  13.6ms  median  14.0ms
+//! ```
+
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+
+use epub_dude::fetch::{Limits, Site, czbooksnet::CzBooksProvider};
+use html5ever::tendril::StrTendril;
+use http::Uri;
+
+const INDEX_URL: &str = "https://czbooks.net/n/bench";
+
+fn index_page(links: usize) -> String {
+    let items: String = (1..=links)
+        .map(|n| format!(r#"<li><a href="/n/bench/{n}">第{n}章 夜行</a></li>"#))
+        .collect();
+    format!(
+        r#"<html><head><title>長篇 - 小說</title></head><body><span class="title">長篇</span><span class="author"><a>作者</a></span><ul id="chapter-list">{items}</ul></body></html>"#
+    )
+}
+
+fn chapter_page(text: &str) -> String {
+    format!(
+        r#"<html><body><div class="name">第一章</div><div class="content">{text}</div></body></html>"#
+    )
+}
+
+fn long_chapter(kb: usize) -> String {
+    let paragraph = "<p>夜色漸深，城外的河水靜靜地流著，遠處傳來幾聲犬吠。</p>\n";
+    chapter_page(&paragraph.repeat(kb * 1024 / paragraph.len() + 1))
+}
+
+/// Lines broken with newlines and indented with em spaces, as most sites
+/// write chapters.
+fn line_chapter(kb: usize) -> String {
+    let line = "\u{2003}\u{2003}夜色漸深，城外的河水靜靜地流著，遠處傳來幾聲犬吠。\n";
+    chapter_page(&line.repeat(kb * 1024 / line.len() + 1))
+}
+
+fn entity_chapter(entities: usize) -> String {
+    let paragraph =
+        "<p>&#22812;&#33394;&nbsp;&ldquo;&#x6F38;&#x6DF1;&rdquo;&amp;&lt;&gt;&hellip;</p>\n";
+    let per_paragraph = paragraph.matches('&').count();
+    chapter_page(&paragraph.repeat(entities / per_paragraph + 1))
+}
+
+/// Runs `parse` `iterations` times after a warm-up, printing the fastest
+/// and the median run.
+fn bench(name: &str, iterations: usize, mut parse: impl FnMut()) {
+    parse();
+    let mut runs: Vec<Duration> = (0..iterations)
+        .map(|_| {
+            let start = Instant::now();
+            parse();
+            start.elapsed()
+        })
+        .collect();
+    runs.sort();
+    println!(
+        "{name:<30} fastest {:>7.1?}  median {:>7.1?}",
+        runs[0],
+        runs[runs.len() / 2]
+    );
+}
+
+fn main() {
+    let iterations = env::var("ITERATIONS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&n: &usize| n > 0)
+        .unwrap_or(20);
+    let site = Site::of::<CzBooksProvider>();
+    let limits = Limits::default();
+    let url: Uri = INDEX_URL.parse().unwrap();
+
+    let index = StrTendril::from(index_page(5000));
+    bench("index, 5000 links", iterations, || {
+        let info = site.index(&url, &index, &limits).unwrap();
+        assert_eq!(info.links.len(), 5000);
+    });
+
+    let chapter_url: Uri = format!("{INDEX_URL}/1").parse().unwrap();
+    let long = StrTendril::from(long_chapter(200));
+    bench("chapter, 200 KB", iterations, || {
+        let chapter = site.chapter(&chapter_url, &long, None, &limits).unwrap();
+        assert!(!chapter.text.is_empty());
+    });
+
+    let lines = StrTendril::from(line_chapter(200));
+    bench("chapter, 200 KB of lines", iterations, || {
+        let chapter = site.chapter(&chapter_url, &lines, None, &limits).unwrap();
+        assert!(chapter.text.contains("<br />"));
+    });
+
+    let entities = StrTendril::from(entity_chapter(30_000));
+    bench("chapter, 30000 entities", iterations, || {
+        let chapter = site
+            .chapter(&chapter_url, &entities, None, &limits)
+            .unwrap();
+        assert!(chapter.text.contains('夜'));
+    });
+}
@@ -0,0 +1,84 @@
+//! Builds a large book from canned pages and reports wall time and peak
+//! memory, to catch regressions in the chapter loop's allocations.
+//!
+//! Run with `cargo bench --bench large_book`; `CHAPTERS` and `CHAPTER_KB`
+//! change the book's size (default 3000 chapters of 20 KB).
+
+use std::{env, time::Instant};
+
+use epub_dude::{BookSource, BuildOptions, MemoryFetcher, build_epub};
+
+const INDEX_URL: &str = "https://czbooks.net/n/bench";
+
+fn var(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn book(chapters: usize, chapter_kb: usize) -> MemoryFetcher {
+    let items: String = (1..=chapters)
+        .map(|n| format!(r#"<li><a href="/n/bench/{n}">第{n}章</a></li>"#))
+        .collect();
+    let mut fetcher = MemoryFetcher::new().page(
+        INDEX_URL,
+        format!(
+            r#"<span class="title">長篇</span><span class="author"><a>作者</a></span><ul id="chapter-list">{items}</ul>"#
+        ),
+    );
+
+    let paragraph = "<p>夜色漸深，城外的河水靜靜地流著，遠處傳來幾聲犬吠。</p>\n";
+    let text = paragraph.repeat(chapter_kb * 1024 / paragraph.len() + 1);
+    for n in 1..=chapters {
+        fetcher = fetcher.page(
+            &format!("https://czbooks.net/n/bench/{n}"),
+            format!(
+                r#"<html><body><div class="name">第{n}章</div><div class="content">{text}</div></body></html>"#
+            ),
+        );
+    }
+    fetcher
+}
+
+/// Peak resident set size in KiB, where the OS reports it.
+fn peak_rss() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn main() {
+    let chapters = var("CHAPTERS", 3000);
+    let chapter_kb = var("CHAPTER_KB", 20);
+    let fetcher = book(chapters, chapter_kb);
+    let before = peak_rss();
+
+    let dir = env::temp_dir().join(format!("epub-dude-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("book.epub");
+    let options = BuildOptions {
+        output: path.to_str().unwrap().parse().unwrap(),
+        work_dir: dir.join("work"),
+        ..BuildOptions::default()
+    };
+    let source = BookSource::new(INDEX_URL.parse().unwrap()).unwrap();
+
+    let start = Instant::now();
+    let summary = build_epub(&source, &fetcher, &options, &()).unwrap();
+    let elapsed = start.elapsed();
+
+    println!(
+        "{} chapters of {chapter_kb} KB in {elapsed:.2?}",
+        summary.chapters
+    );
+    match (before, peak_rss()) {
+        (Some(before), Some(after)) => println!(
+            "peak RSS {} MiB ({} MiB with the canned pages alone)",
+            after / 1024,
+            before / 1024
+        ),
+        _ => println!("peak RSS not available on this platform"),
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,1950 @@
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{BufReader, BufWriter, Cursor, Seek},
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use chrono::Local;
+use epub_builder::{
+    EpubBuilder, EpubContent, EpubVersion, MetadataOpf, MetadataOpfV3, PageDirection,
+    ReferenceType, TocElement, ZipCommandOrLibrary,
+};
+use http::Uri;
+use regex::Regex;
+
+use crate::{
+    BookSource, BuildOptions, Chapter, ChapterLink, Error, Progress, Result, SortOrder, Summary,
+    check, checkpoint, fallback, fetch,
+    fetcher::{Fetcher, Metered, Prefetcher, fetch_page},
+    footnotes, generated, headings, html, images, kepub, ladder, lock, manifest, metadata,
+    numbering, output, package, parts, plain, provenance, rebuild, revisions, scripts, selection,
+    softwrap, split, staging, state, stats, template,
+    timing::{Phase, Timings},
+    validate, workdir, xhtml,
+};
+
+/// Builds `source` into a book as configured by `options`, returning what
+/// happened along the way.
+///
+/// With `options.validate` the finished epub is checked, and structural
+/// problems are returned as [`Error::Validation`].
+pub fn build_epub(
+    source: &BookSource,
+    fetcher: &impl Fetcher,
+    options: &BuildOptions,
+    progress: &impl Progress,
+) -> Result<Summary> {
+    let built = build(
+        std::slice::from_ref(source),
+        false,
+        fetcher,
+        options,
+        progress,
+    )?;
+    validated(built, options)
+}
+
+/// Builds several `sources` into one book, as with `--merge`: each source's
+/// chapters are nested under its title in the table of contents, and the
+/// book is titled by `options.title` and credited to the first authors
+/// found. Per-book state is kept under the first source.
+pub fn build_anthology(
+    sources: &[BookSource],
+    fetcher: &impl Fetcher,
+    options: &BuildOptions,
+    progress: &impl Progress,
+) -> Result<Summary> {
+    if sources.is_empty() {
+        return Err(Error::Usage(
+            "an anthology needs at least one source".to_string(),
+        ));
+    }
+    if options.fallback.is_some() {
+        return Err(Error::Usage(
+            "--fallback-url can't be combined with --merge".to_string(),
+        ));
+    }
+    let built = build(sources, true, fetcher, options, progress)?;
+    validated(built, options)
+}
+
+fn validated((summary, epubs): (Summary, Vec<PathBuf>), options: &BuildOptions) -> Result<Summary> {
+    if options.validate {
+        for path in epubs {
+            let problems = validate::validate(&path)?;
+            if !problems.is_empty() {
+                return Err(Error::Validation { path, problems });
+            }
+            log::info!("Validated {}", path.display());
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Returns the summary and, for epubs, the written files. With `anthology`
+/// every source starts a new top-level entry in the table of contents.
+fn build(
+    sources: &[BookSource],
+    anthology: bool,
+    fetcher: &impl Fetcher,
+    options: &BuildOptions,
+    progress: &impl Progress,
+) -> Result<(Summary, Vec<PathBuf>)> {
+    let uri = &sources[0].uri;
+    let work_dir = workdir::book_dir(&options.work_dir, uri);
+    log::debug!("work directory {}", work_dir.display());
+    let _lock = lock::BookLock::acquire(&work_dir, options.wait_lock)?;
+    if options.rebuild && (anthology || options.fallback.is_some() || options.update) {
+        return Err(Error::Usage(
+            "--rebuild can't be combined with --merge, --fallback-url or --update".to_string(),
+        ));
+    }
+    // Every request goes through here, so downloads can be counted and
+    // capped, or with --rebuild refused.
+    let metered = if options.rebuild {
+        Metered::offline(fetcher)
+    } else {
+        Metered::new(fetcher)
+    };
+    let fetcher = &metered;
+
+    let fallback = match &options.fallback {
+        Some(fallback_uri) => {
+            let Some(fallback_site) = fetch::Site::for_uri(fallback_uri) else {
+                return Err(Error::UnsupportedSite(fallback_uri.clone()));
+            };
+            let fallback_page = fetch_page(fetcher, fallback_uri)?;
+            Some((
+                fallback_site,
+                fallback_site.index(fallback_uri, &fallback_page, &options.limits)?,
+            ))
+        }
+        None => None,
+    };
+
+    let mut indexes = Vec::with_capacity(sources.len());
+    if options.rebuild {
+        let stored = workdir::BookRecord::load(&work_dir)
+            .filter(|record| record.is_for(uri))
+            .and_then(|record| record.index);
+        let Some(stored) = stored else {
+            return Err(Error::Usage(format!(
+                "--rebuild needs a complete build of {uri} first; {} has none",
+                work_dir.display()
+            )));
+        };
+        indexes.push(stored.info());
+    }
+    let mut index_timings = Timings::default();
+    let mut count_warnings = Vec::new();
+    for source in sources.iter().filter(|_| !options.rebuild) {
+        let page = index_timings.time(Phase::Fetch, || fetch_page(fetcher, &source.uri))?;
+        let parsed = index_timings
+            .time(Phase::Extract, || {
+                source.site.index_with(
+                    &source.uri,
+                    &page,
+                    &options.limits,
+                    options.chapter_dates.as_ref(),
+                )
+            })
+            .and_then(|mut info| {
+                if options.prefer_og {
+                    info.use_page_meta(true);
+                }
+                let missing = source.site.missing_metadata(
+                    &info,
+                    options.title.is_none(),
+                    options.authors.is_empty() && options.require_author,
+                );
+                if missing.is_empty() {
+                    Ok(info)
+                } else {
+                    Err(Error::EmptyIndex {
+                        url: source.uri.to_string(),
+                        missing,
+                        saved: None,
+                    })
+                }
+            });
+        let info = parsed.map_err(|e| keep_page(e, &work_dir, &page))?;
+        if let Some(count_check) = &options.count_check {
+            count_warnings.extend(check::check_count(&page, info.links.len(), count_check)?);
+        }
+        indexes.push(info);
+    }
+    let info = &indexes[0];
+    let mut summary = Summary {
+        timings: index_timings,
+        warnings: count_warnings,
+        ..Summary::new(uri.to_string())
+    };
+    // Kept in book.json once the book is complete, for --rebuild.
+    let stored_index = (!anthology).then(|| rebuild::StoredIndex::from(info));
+
+    let generator = provenance::Generator::new(
+        (!options.no_provenance).then(|| uri.to_string()),
+        &provenance::config(options),
+    );
+
+    let title = match &options.title {
+        Some(title) => title.clone(),
+        None => {
+            log::debug!("raw title: {:?}", info.title);
+            let title = options.title_cleanup.apply(&info.title);
+            if title.trim().is_empty() {
+                return Err(Error::EmptyIndex {
+                    url: uri.to_string(),
+                    missing: vec![format!("title (left after cleaning up {:?})", info.title)],
+                    saved: None,
+                });
+            }
+            if anthology {
+                summary.warn("anthology titled after its first source (use --title)");
+            }
+            title
+        }
+    };
+    let authors = if options.authors.is_empty() {
+        let mut authors = Vec::new();
+        let mut credited = None;
+        for (source, info) in sources.iter().zip(&indexes) {
+            let raw: Vec<String> = info
+                .authors
+                .iter()
+                .map(|author| options.author_cleanup.apply(author))
+                .collect();
+            let found = metadata::split_authors(&raw);
+            match credited {
+                _ if found.is_empty() => {}
+                None => {
+                    authors = found;
+                    credited = Some(&source.uri);
+                }
+                Some(first) if found != authors => summary.warn(format!(
+                    "{} credits {}; keeping {} from {first}",
+                    source.uri,
+                    found.join(", "),
+                    authors.join(", ")
+                )),
+                Some(_) => {}
+            }
+        }
+        if authors.is_empty() {
+            let searched = sources[0]
+                .site
+                .missing_metadata(info, false, true)
+                .join(", ");
+            summary.warn(format!(
+                "found no {searched} on the index page, crediting \"{UNKNOWN_AUTHOR}\" (use --author, or --require-author to fail instead)"
+            ));
+            authors.push(UNKNOWN_AUTHOR.to_string());
+        }
+        authors
+    } else {
+        options.authors.clone()
+    };
+
+    let mut record = workdir::BookRecord::new(&work_dir, uri, &title);
+    record
+        .save(&work_dir)
+        .map_err(|e| Error::output(&work_dir, e))?;
+
+    let length_unit = stats::Unit::for_language(&options.language);
+    let linked_data = match &info.page.linked_data {
+        Some(linked) if !anthology => linked.clone(),
+        _ => fetch::jsonld::LinkedBook::default(),
+    };
+    let (description, cover_url) = if anthology {
+        (None, None)
+    } else {
+        (
+            info.description.clone(),
+            info.page.cover(options.prefer_og).cloned(),
+        )
+    };
+    // Rebuilds keep the identifier the book was first built with.
+    let identity = sources
+        .iter()
+        .zip(&indexes)
+        .map(|(source, info)| match &info.page.canonical {
+            Some(canonical) if options.prefer_og => canonical.to_string(),
+            _ => source.uri.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    let arc_titles: Vec<String> = indexes
+        .iter()
+        .map(|info| options.title_cleanup.apply(&info.title))
+        .collect();
+    // Which source each chapter came from, by URL; a plain book has one.
+    let mut arc_of = HashMap::new();
+    let mut links = Vec::new();
+    let credited = (indexes[0].authors.clone(), indexes[0].author_pages.clone());
+    for (arc, info) in indexes.into_iter().enumerate() {
+        let mut arc_links = info.links;
+        if options.sort == SortOrder::TitleNumber {
+            numbering::sort_by_number(&mut arc_links);
+        }
+        if anthology {
+            arc_of.extend(arc_links.iter().map(|link| (link.uri.to_string(), arc)));
+        }
+        links.extend(arc_links);
+    }
+
+    let indexed: Vec<String> = links.iter().map(|link| link.uri.to_string()).collect();
+    let mut state = state::BookState::load(&work_dir);
+    if options.update
+        && !record.chapters.is_empty()
+        && indexed.iter().all(|link| record.chapters.contains(link))
+        && state.locked.is_empty()
+    {
+        return Err(Error::UpToDate {
+            chapters: indexed.len(),
+        });
+    }
+
+    let mut manifest = manifest::Manifest {
+        authors,
+        contributors: options.contributors.clone(),
+        title: title.clone(),
+        source: uri.to_string(),
+        generator,
+        subjects: linked_data.genres,
+        published: linked_data.published,
+        linked_data: linked_data.extra,
+        ..Default::default()
+    };
+
+    if let Some(selected) = &options.chapters {
+        links = selection::apply(links, selected).map_err(|e| Error::Usage(format!("{e:#}")))?;
+    }
+    if options.title_filter.is_enabled() {
+        let (kept, excluded): (Vec<_>, Vec<_>) = links
+            .into_iter()
+            .partition(|link| options.title_filter.keeps(&link.title));
+        links = kept;
+        summary
+            .excluded
+            .extend(excluded.into_iter().map(|link| link.title));
+    }
+    if let Some(since) = options.since {
+        let (kept, excluded): (Vec<_>, Vec<_>) = links
+            .into_iter()
+            .partition(|link| link.date.is_none_or(|date| date >= since));
+        links = kept;
+        if links.iter().any(|link| link.date.is_none()) {
+            summary.warn(format!(
+                "kept {} chapters without a date despite --since",
+                links.iter().filter(|link| link.date.is_none()).count()
+            ));
+        }
+        summary
+            .excluded
+            .extend(excluded.into_iter().map(|link| link.title));
+    }
+    if let Some(pick) = options.pick {
+        let picked = pick(&title, &links).map_err(|e| Error::Usage(format!("{e:#}")))?;
+        let mut remaining: Vec<Option<ChapterLink>> = links.into_iter().map(Some).collect();
+        links = picked
+            .into_iter()
+            .filter_map(|i| remaining.get_mut(i).and_then(Option::take))
+            .collect();
+    }
+
+    if links.is_empty() {
+        return Err(Error::NothingToBuild {
+            excluded: summary.excluded.len(),
+        });
+    }
+
+    let (fallback_site, plan) = match fallback {
+        Some((fallback_site, fallback_info)) => (
+            Some(fallback_site),
+            fallback::merge(links, &fallback_info.links),
+        ),
+        None => (
+            None,
+            links.into_iter().map(fallback::Planned::primary).collect(),
+        ),
+    };
+
+    // Every chapter complete builds have had, which this one adds to and
+    // --rebuild takes every chapter from.
+    let mut cache = if anthology {
+        checkpoint::Cache::new(uri)
+    } else {
+        checkpoint::Cache::load(&work_dir, uri)
+    };
+    if options.rebuild {
+        let skipped = |link: &str| !options.retry_permanent && state.missing(link).is_some();
+        let uncached = rebuild::uncached(&plan, &cache, skipped);
+        if !uncached.is_empty() {
+            return Err(Error::Usage(format!(
+                "--rebuild: {} of {} chapters aren't cached in {}: {} (build without --rebuild to download them)",
+                uncached.len(),
+                plan.len(),
+                work_dir.display(),
+                uncached.join(", ")
+            )));
+        }
+        let unlisted: Vec<&str> = cache
+            .links()
+            .filter(|link| !indexed.iter().any(|listed| listed == link))
+            .collect();
+        if !unlisted.is_empty() {
+            summary.warn(format!(
+                "--rebuild: {} cached chapters aren't on the stored index, leaving them out: {}",
+                unlisted.len(),
+                unlisted.join(", ")
+            ));
+        }
+    }
+
+    let sequence = numbering::analyze(plan.iter().map(|p| p.link.title.as_str()));
+    if !sequence.is_clean() {
+        let problems = sequence.problems();
+        if options.strict_sequence {
+            return Err(Error::Inconsistent(format!(
+                "chapter sequence check failed: {}",
+                problems.join("; ")
+            )));
+        }
+        for p in problems {
+            summary.warn(p);
+        }
+    }
+    manifest.sequence = sequence;
+
+    let title_page = (!options.no_title_page).then(|| {
+        xhtml::title_page(&xhtml::TitlePage {
+            title: &title,
+            authors: &manifest.authors,
+            contributors: &manifest.contributors,
+            source: (!options.no_provenance).then_some(manifest.source.as_str()),
+            description: description.as_deref(),
+            description_limit: options.description_limit,
+        })
+    });
+    let cover = cover_url
+        .filter(|_| options.format.is_epub() && !options.rebuild)
+        .and_then(|url| fetch_cover(fetcher, &url, &mut summary));
+    let front = Front {
+        title_page: title_page.as_deref(),
+        cover: cover.as_ref(),
+        identity: &identity,
+    };
+    // Without a cover of its own, the book can take one from its first
+    // chapter; later parts of a split book repeat it.
+    let cover_from_content = front.cover.is_none()
+        && options.format.is_epub()
+        && options.images.cover_from_content.is_some();
+    let mut content_cover = None;
+
+    let mut embedder = images::ImageEmbedder::new(&options.images);
+    if options.format == output::Format::Html {
+        embedder = embedder.inline();
+    }
+    let mut plain_chapters = Vec::new();
+
+    let last = LastChapter::of(&plan, &indexed);
+    // Named after the normalized metadata; the book keeps it as scraped.
+    let normalize = |text: &str| metadata::normalize(text, options.fold_width);
+    let output_path = options.output.render(&output::OutputFields {
+        title: &normalize(&title),
+        author: &normalize(&manifest.authors.join(", ")),
+        date: &Local::now().format("%Y-%m-%d").to_string(),
+        host: uri.host().unwrap_or_default(),
+        last_chapter: &last.number,
+        last_chapter_title: &normalize(&last.title),
+    });
+    if let Some(parent) = output_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(|e| Error::output(parent, e))?;
+    }
+
+    let mut resumed = match options.checkpoint_every {
+        Some(_) => checkpoint::Checkpoint::load(&work_dir, uri),
+        None => checkpoint::Checkpoint::new(uri),
+    };
+    if !resumed.chapters.is_empty() {
+        log::info!(
+            "resuming {} chapters from the checkpoint in {}",
+            resumed.chapters.len(),
+            work_dir.display()
+        );
+    }
+    let mut saved = checkpoint::Checkpoint::new(uri);
+    let mut texts = options
+        .check_revisions
+        .then(|| revisions::Texts::load(&work_dir));
+    // Pages the last build generated, and those this one does for the next.
+    let previous_pages = if options.format.is_epub() {
+        generated::Generated::load(&work_dir)
+    } else {
+        generated::Generated::default()
+    };
+    let shaping = generated::fingerprint(options, length_unit);
+    let mut generated_pages = generated::Generated::new(&work_dir, shaping);
+    let mut reused = 0;
+    // Chapter files added so far, with the title of those starting a chapter.
+    let mut written: Vec<(String, String, Option<String>)> = Vec::new();
+    // The file the book, or with `--split-every` its current part, goes to.
+    let mut book_path = match options.split_every {
+        Some(_) => parts::path(&output_path, options.format, 1),
+        None => output_path.clone(),
+    };
+    let partial_path_for = |path: &Path| options.format.insert_before_extension(path, ".partial");
+    let mut partial_path = partial_path_for(&book_path);
+    if options.format.is_epub() {
+        let staging = staging::dir(options);
+        staging::check(&staging, &book_path).map_err(|e| Error::output(&staging, e))?;
+    }
+    let mut book = new_book(options, &manifest, &front, options.split_every.map(|_| 1))?;
+    let mut epubs = Vec::new();
+    // Chapters in the current part, and images embedded and those with alt
+    // text in earlier ones.
+    let mut in_part = 0;
+    let mut embedded_before = 0;
+    let mut described_before = 0;
+    // The source whose chapters are being added, and arc pages so far.
+    let mut current_arc = None;
+    let mut arc_pages = 0;
+    // Chapters in the book if the download budget or the deadline stopped
+    // it early, and whether it was the deadline.
+    let mut stopped = None;
+    let mut out_of_time = false;
+    let deadline_passed = || options.deadline.is_some_and(|at| Instant::now() >= at);
+
+    let progress = &ProgressLog::new(progress);
+    progress.start(plan.len(), &title);
+
+    // Reused for every chapter document instead of allocating one each.
+    let mut chapter_page = String::new();
+    // With --chapter-template or --chapter-nav, the chapter waiting for the
+    // name of the page after it, the last page in the current part's spine
+    // and the first page of the chapter or arc heading that page.
+    let links = options.chapter_template.is_some() || options.chapter_nav;
+    let mut held: Option<Held> = None;
+    let mut last_file: Option<String> = None;
+    let mut last_start: Option<String> = None;
+    let mut toc_titles = options.dedupe_toc_titles.then(TocTitles::default);
+
+    // Chapters are fetched `jobs` at once and assembled in order, as many
+    // as the host's politeness profile allows.
+    let jobs = fetcher.jobs(&uri.to_string(), options.jobs).max(1);
+    if jobs < options.jobs {
+        log::info!(
+            "fetching {jobs} chapters at once, as {} allows",
+            uri.host().unwrap_or_default()
+        );
+    }
+    let prefetcher = Prefetcher::new(fetcher, jobs);
+    let ahead: Vec<(usize, String)> = match options.rebuild {
+        true => Vec::new(),
+        false => plan
+            .iter()
+            .map(|p| p.link.uri.to_string())
+            .enumerate()
+            .filter(|(_, url)| resumed.get(url).is_none())
+            .filter(|(_, url)| options.retry_permanent || state.missing(url).is_none())
+            .collect(),
+    };
+
+    thread::scope(|scope| -> Result<()> {
+        let _running = prefetcher.start(scope, ahead);
+        let fetcher = &prefetcher;
+
+        for (i, item) in plan.iter().enumerate() {
+            fetcher.at(i);
+            let timed = summary.timings.clone();
+            let link = item.link.uri.to_string();
+            log::debug!("chapter {}/{}: {link}", i + 1, plan.len());
+            if !options.retry_permanent
+                && let Some(missing) = state.missing(&link)
+            {
+                summary.missing.push(format!(
+                    "chapter {} \"{}\": HTTP {} since {} (skipped)",
+                    i + 1,
+                    item.link.title,
+                    missing.status,
+                    missing.since
+                ));
+                manifest
+                    .missing
+                    .push(manifest::MissingChapter::new(i, &item.link.title, missing));
+                progress.chapter_done();
+                continue;
+            }
+            // With --update, a chapter the last build had is spliced in as it
+            // was, without downloading it again, unless it was locked.
+            let kept = previous_pages
+                .reusable(&link, i, shaping)
+                .filter(|_| options.update && state.locked(&link).is_none() && !links)
+                .and_then(|pages| previous_pages.read(pages));
+            let (mut content, url, provenance) = match &kept {
+                Some(pages) => (
+                    Chapter {
+                        title: pages.title.clone(),
+                        text: String::new(),
+                        images: Vec::new(),
+                        notes: Vec::new(),
+                    },
+                    pages.url.parse().unwrap_or_else(|_| item.link.uri.clone()),
+                    pages.provenance,
+                ),
+                None => match resumed
+                    .take(&link)
+                    .or_else(|| options.rebuild.then(|| cache.get(&link)).flatten())
+                {
+                    Some(chapter) => (
+                        chapter.chapter,
+                        chapter
+                            .url
+                            .parse()
+                            .unwrap_or_else(|_| item.link.uri.clone()),
+                        chapter.provenance,
+                    ),
+                    None => {
+                        let site = sources[arc_of.get(&link).copied().unwrap_or(0)].site;
+                        let (fetched, url, provenance) = fetch_planned(
+                            fetcher,
+                            item,
+                            i,
+                            site,
+                            fallback_site,
+                            options,
+                            &mut summary,
+                        );
+                        summary.results.count(fetched.result);
+                        let content = match fetched.content {
+                            // Cut off by the deadline rather than failed, so the
+                            // book so far is kept.
+                            Err(e) if deadline_passed() => {
+                                log::debug!("chapter {}: {e}", i + 1);
+                                if options.checkpoint_every.is_some() && !saved.chapters.is_empty()
+                                {
+                                    saved
+                                        .save(&work_dir)
+                                        .map_err(|e| Error::output(&work_dir, e))?;
+                                }
+                                stopped = Some(i);
+                                out_of_time = true;
+                                break;
+                            }
+                            Err(e) if let Some(status) = e.permanent_status() => {
+                                let missing = state::Missing {
+                                    url: link.clone(),
+                                    status,
+                                    since: Local::now().format("%Y-%m-%d").to_string(),
+                                };
+                                summary.missing.push(format!(
+                                    "chapter {} \"{}\": HTTP {status} ({link})",
+                                    i + 1,
+                                    item.link.title
+                                ));
+                                manifest.missing.push(manifest::MissingChapter::new(
+                                    i,
+                                    &item.link.title,
+                                    &missing,
+                                ));
+                                state.set_missing(missing);
+                                state
+                                    .save(&work_dir)
+                                    .map_err(|e| Error::output(&work_dir, e))?;
+                                progress.chapter_done();
+                                continue;
+                            }
+                            // Left out, with the page kept for a look.
+                            Err(e) if fetched.result == ladder::ChapterResult::ParseError => {
+                                let saved = fetched
+                                    .unparsed
+                                    .and_then(|page| keep_failed(&work_dir, i, &page));
+                                summary.failed.push(format!(
+                                    "chapter {} \"{}\": {e}{}",
+                                    i + 1,
+                                    item.link.title,
+                                    saved
+                                        .as_ref()
+                                        .map(|p| format!(" (page saved in {})", p.display()))
+                                        .unwrap_or_default()
+                                ));
+                                manifest.failed.push(manifest::FailedChapter {
+                                    index: i,
+                                    title: item.link.title.clone(),
+                                    url: link.clone(),
+                                    error: e.to_string(),
+                                    saved,
+                                });
+                                progress.chapter_done();
+                                continue;
+                            }
+                            content => content.with_context(|| {
+                                during(i, &item.link.title, &url, fetched.failed_in)
+                            })?,
+                        };
+                        if state.found(&link) {
+                            state
+                                .save(&work_dir)
+                                .map_err(|e| Error::output(&work_dir, e))?;
+                        }
+                        (content, url, provenance)
+                    }
+                },
+            };
+            let locked = kept.is_none()
+                && options
+                    .locked
+                    .as_ref()
+                    .is_some_and(|pattern| pattern.is_match(&revisions::normalize(&content.text)));
+            if locked {
+                summary.placeholders.push(format!(
+                    "chapter {} \"{}\": locked on the site ({link})",
+                    i + 1,
+                    content.title
+                ));
+                if state.set_locked(&link, Local::now().format("%Y-%m-%d").to_string()) {
+                    state
+                        .save(&work_dir)
+                        .map_err(|e| Error::output(&work_dir, e))?;
+                }
+                content.text = LOCKED_PAGE.to_string();
+                content.images.clear();
+                content.notes.clear();
+            } else if kept.is_none() && state.unlocked(&link) {
+                log::info!("chapter {} is no longer locked", i + 1);
+                summary.filled += 1;
+                state
+                    .save(&work_dir)
+                    .map_err(|e| Error::output(&work_dir, e))?;
+            }
+            let url = &url;
+            let chapter_title = &content.title;
+            let toc_title = match &mut toc_titles {
+                Some(titles) => titles.entry(chapter_title),
+                None => chapter_title.clone(),
+            };
+            if kept.is_some() {
+                summary.chapters += 1;
+            } else {
+                summary.chapter_fetched();
+            }
+            if let Some(texts) = &mut texts
+                && kept.is_none()
+                && !locked
+                && let Some((previous, current)) = texts.update(&link, &content.text)
+            {
+                summary
+                    .revised
+                    .push(format!("chapter {} \"{chapter_title}\" ({link})", i + 1));
+                if let Some(dir) = &options.revision_diff {
+                    match revisions::write_diff(dir, i, &link, &previous, &current) {
+                        Ok(path) => log::info!("diff of chapter {} in {}", i + 1, path.display()),
+                        Err(e) => summary.warn(format!("{e:#}")),
+                    }
+                }
+                manifest.revised.push(manifest::RevisedChapter {
+                    index: i,
+                    title: chapter_title.clone(),
+                    url: link.clone(),
+                    previous_hash: previous.hash,
+                    hash: current.hash,
+                });
+            }
+
+            if let Some(every) = options.split_every
+                && in_part == every
+            {
+                summary.timings.time(Phase::Add, || {
+                    release(
+                        &mut held,
+                        None,
+                        &mut book,
+                        options.partial_epub.then_some(&mut written),
+                        options,
+                        plan.len(),
+                        anthology,
+                    )
+                })?;
+                last_file = None;
+                last_start = None;
+                let next = epubs.len() + 2;
+                let done =
+                    std::mem::replace(&mut book, new_book(options, &manifest, &front, Some(next))?);
+                if let Some(cover) = &content_cover {
+                    add_cover(&mut book, cover)?;
+                }
+                summary.timings.time(Phase::Generate, || {
+                    write_book(
+                        done,
+                        options,
+                        embedder.embedded() - embedded_before,
+                        embedder.described - described_before,
+                        &book_path,
+                    )
+                })?;
+                log::debug!("wrote part {} to {}", next - 1, book_path.display());
+                // The finished part supersedes its partial copy.
+                let _ = fs::remove_file(&partial_path);
+                epubs.push(std::mem::replace(
+                    &mut book_path,
+                    parts::path(&output_path, options.format, next),
+                ));
+                partial_path = partial_path_for(&book_path);
+                written.clear();
+                in_part = 0;
+                embedded_before = embedder.embedded();
+                described_before = embedder.described;
+                embedder.next_part();
+                // The new part repeats the arc heading its first chapters.
+                current_arc = None;
+            }
+            in_part += 1;
+
+            let arc = arc_of.get(&link).copied().unwrap_or(0);
+            if anthology && current_arc != Some(arc) {
+                current_arc = Some(arc);
+                let arc_title = &arc_titles[arc];
+                if options.format.is_epub() {
+                    arc_pages += 1;
+                    let name = format!("arc-{arc_pages}.xhtml");
+                    let page = xhtml::chapter(arc_title, "", None);
+                    release(
+                        &mut held,
+                        Some(&name),
+                        &mut book,
+                        options.partial_epub.then_some(&mut written),
+                        options,
+                        plan.len(),
+                        anthology,
+                    )?;
+                    last_file = Some(name.clone());
+                    last_start = Some(name.clone());
+                    book.add_content(
+                        EpubContent::new(&name, page.as_bytes())
+                            .title(arc_title.clone())
+                            .reftype(ReferenceType::Text),
+                    )?;
+                    if options.partial_epub {
+                        written.push((name, page, Some(arc_title.clone())));
+                    }
+                } else {
+                    plain_chapters.push(plain::PlainChapter {
+                        title: arc_title.clone(),
+                        markup: String::new(),
+                    });
+                }
+            }
+
+            let footer = options.chapter_footer.as_deref().map(|template| {
+                xhtml::footer(
+                    template,
+                    &xhtml::FooterFields {
+                        url: &url.to_string(),
+                        date: &Local::now().format("%Y-%m-%d").to_string(),
+                        index: i + 1,
+                    },
+                )
+            });
+
+            let key = generated::key(shaping, i, &content, footer.as_deref());
+            let cached = match &kept {
+                Some(pages) => Some(Cow::Borrowed(pages)),
+                None => previous_pages
+                    .get(&link, &key)
+                    .filter(|_| options.format.is_epub() && content.images.is_empty() && !links)
+                    .and_then(|pages| previous_pages.read(pages))
+                    .map(Cow::Owned),
+            };
+            let length = if let Some(pages) = cached {
+                log::debug!("chapter {}: unchanged, reusing its pages", i + 1);
+                reused += 1;
+                summary.timings.time(Phase::Add, || {
+                    add_pages(
+                        &mut book,
+                        &pages,
+                        &toc_title,
+                        anthology,
+                        options.partial_epub.then_some(&mut written),
+                    )
+                })?;
+                last_file = pages.files.last().map(|(name, _)| name.clone());
+                last_start = pages.files.first().map(|(name, _)| name.clone());
+                let length = pages.length;
+                summary.length += length;
+                generated_pages
+                    .insert(link.clone(), pages.into_owned())
+                    .map_err(|e| Error::output(&work_dir, e))?;
+                length
+            } else {
+                // Rendering, less the downloads and additions timed within.
+                let rendering = Instant::now();
+                let timed_before = summary.timings.total();
+                let text = if options.merge_softwrap {
+                    softwrap::merge(&content.text, &options.language)
+                } else {
+                    Cow::Borrowed(content.text.as_str())
+                };
+                let text = if options.per_paragraph_lang {
+                    Cow::Owned(
+                        scripts::tag_paragraphs(
+                            &text,
+                            &options.language,
+                            options.epub2 && options.format.is_epub(),
+                        )
+                        .into_owned(),
+                    )
+                } else {
+                    text
+                };
+                embedder.search_cover(i == 0 && cover_from_content);
+                let mut image_fetch = Duration::ZERO;
+                let body = embedder
+                    .render(
+                        &mut book,
+                        &text,
+                        &content.images,
+                        url,
+                        |image_url| {
+                            let started = Instant::now();
+                            let fetched = fetcher
+                                .get(&image_url.to_string())
+                                .map(|response| response.body)
+                                .map_err(anyhow::Error::from);
+                            image_fetch += started.elapsed();
+                            fetched
+                        },
+                        &mut summary,
+                    )
+                    .with_context(|| during(i, chapter_title, url, Phase::Xhtml))?;
+                summary.timings.add(Phase::Fetch, image_fetch);
+                if let Some((bytes, format)) = embedder.cover.take() {
+                    let found = Cover {
+                        bytes,
+                        mime: format.mime(),
+                        extension: format.extension(),
+                    };
+                    add_cover(&mut book, &found)
+                        .with_context(|| during(i, chapter_title, url, Phase::Add))?;
+                    content_cover = Some(found);
+                } else if i == 0 && cover_from_content {
+                    log::info!("chapter 1 has no image big enough for a cover");
+                }
+                // Measured before footnote numbering and footers are added.
+                let length = stats::count(&body, length_unit)
+                    + content
+                        .notes
+                        .iter()
+                        .map(|n| stats::count(n, length_unit))
+                        .sum::<usize>();
+                summary.length += length;
+                log::debug!(
+                    "chapter {} {chapter_title:?}: {length} {}",
+                    i + 1,
+                    length_unit.as_str()
+                );
+                let epub3 = options.format.is_epub() && !options.epub2;
+                let body = footnotes::render(&body, &content.notes, i, epub3);
+                let (body, sections) = headings::promote(&body, &options.headings, i);
+
+                if options.format.is_epub() {
+                    let overhead = match &options.chapter_template {
+                        Some(template) => template
+                            .render(&template::ChapterFields {
+                                title: chapter_title,
+                                body: footer.as_deref().unwrap_or_default(),
+                                index: i + 1,
+                                total: plan.len(),
+                                prev_href: last_file.as_deref(),
+                                next_href: None,
+                                lang: &options.language,
+                            })
+                            .with_context(|| during(i, chapter_title, url, Phase::Xhtml))?
+                            .len(),
+                        None => xhtml::chapter(chapter_title, "", footer.as_deref()).len(),
+                    } + if options.chapter_nav {
+                        // At its longest, with both links and two-letter parts.
+                        xhtml::chapter_nav(
+                            Some(&format!("{i}-zz.xhtml")),
+                            Some(&format!("{}-zz.xhtml", i + 1)),
+                        )
+                        .len()
+                            + 1
+                    } else {
+                        0
+                    };
+                    let parts =
+                        split::split(&body, options.max_chapter_size.saturating_sub(overhead));
+                    let names = split::part_names(i, parts.len());
+                    let last = parts.len() - 1;
+
+                    // Each section is listed under the chapter, pointing into the
+                    // part holding it.
+                    let sections = sections
+                        .iter()
+                        .map(|section| {
+                            let id = format!(r#"id="{}""#, section.id);
+                            let file = parts
+                                .iter()
+                                .position(|part| part.contains(&id))
+                                .unwrap_or(0);
+                            (
+                                format!("{}#{}", names[file], section.id),
+                                section.title.clone(),
+                            )
+                        })
+                        .collect();
+                    let mut pages = generated::Pages {
+                        key,
+                        index: i,
+                        title: chapter_title.clone(),
+                        url: url.to_string(),
+                        provenance,
+                        length,
+                        files: Vec::with_capacity(parts.len()),
+                        sections,
+                    };
+                    let mut held_page = None;
+                    for (p, part) in split::relink(&parts, &names).into_iter().enumerate() {
+                        let part_footer = footer.as_deref().filter(|_| p == last);
+                        match &options.chapter_template {
+                            // Rendered once the next page is named.
+                            _ if p == last && links => {
+                                let body = match part_footer {
+                                    Some(footer) => format!("{part}\n{footer}"),
+                                    None => part.into_owned(),
+                                };
+                                let prev = match p {
+                                    0 => last_file.clone(),
+                                    _ => Some(names[p - 1].clone()),
+                                };
+                                held_page = Some(HeldPage {
+                                    body,
+                                    prev,
+                                    prev_chapter: last_start.clone(),
+                                });
+                                pages.files.push((names[p].clone(), String::new()));
+                                continue;
+                            }
+                            Some(template) => {
+                                chapter_page = template
+                                    .render(&template::ChapterFields {
+                                        title: chapter_title,
+                                        body: &part,
+                                        index: i + 1,
+                                        total: plan.len(),
+                                        prev_href: match p {
+                                            0 => last_file.as_deref(),
+                                            _ => Some(names[p - 1].as_str()),
+                                        },
+                                        next_href: Some(&names[p + 1]),
+                                        lang: &options.language,
+                                    })
+                                    .with_context(|| during(i, chapter_title, url, Phase::Xhtml))?;
+                            }
+                            None => xhtml::chapter_into(
+                                &mut chapter_page,
+                                chapter_title,
+                                &part,
+                                part_footer,
+                            ),
+                        }
+                        if options.format == output::Format::Kepub {
+                            chapter_page = kepub::spans(&chapter_page);
+                        }
+                        pages.files.push((names[p].clone(), chapter_page.clone()));
+                    }
+                    summary.timings.time(Phase::Add, || {
+                        release(
+                            &mut held,
+                            Some(&names[0]),
+                            &mut book,
+                            options.partial_epub.then_some(&mut written),
+                            options,
+                            plan.len(),
+                            anthology,
+                        )
+                    })?;
+                    last_file = Some(names[last].clone());
+                    last_start = Some(names[0].clone());
+                    if let Some(last) = held_page {
+                        held = Some(Held {
+                            pages,
+                            toc_title,
+                            last,
+                        });
+                    } else {
+                        summary.timings.time(Phase::Add, || {
+                            add_pages(
+                                &mut book,
+                                &pages,
+                                &toc_title,
+                                anthology,
+                                options.partial_epub.then_some(&mut written),
+                            )
+                        })?;
+                        // Pages with images can't be reused, as the images are
+                        // added to the book as they're rendered, and locked ones
+                        // are to be replaced.
+                        if content.images.is_empty() && !locked {
+                            generated_pages
+                                .insert(link.clone(), pages)
+                                .map_err(|e| Error::output(&work_dir, e))?;
+                        }
+                    }
+                } else {
+                    plain_chapters.push(plain::PlainChapter {
+                        title: chapter_title.clone(),
+                        markup: match footer {
+                            Some(footer) => format!("{body}\n{footer}"),
+                            None => body.into_owned(),
+                        },
+                    });
+                }
+                let elsewhere = summary.timings.total().saturating_sub(timed_before);
+                summary
+                    .timings
+                    .add(Phase::Xhtml, rendering.elapsed().saturating_sub(elsewhere));
+                length
+            };
+            if let Some(date) = item.link.date {
+                summary.chapter_dated(date);
+            }
+            manifest.chapters.push(manifest::ManifestChapter {
+                index: i,
+                title: chapter_title.clone(),
+                url: url.to_string(),
+                published: item.link.date.map(|date| date.to_string()),
+                provenance,
+                length,
+                status: if locked {
+                    manifest::ChapterStatus::Locked
+                } else {
+                    manifest::ChapterStatus::Complete
+                },
+            });
+            // Kept whole, now that nothing else needs it, for the checkpoint
+            // and the cache; kept and locked chapters are only placeholders.
+            if kept.is_none() && !locked {
+                let chapter = checkpoint::Saved {
+                    link,
+                    url: url.to_string(),
+                    provenance,
+                    chapter: content,
+                };
+                if !anthology {
+                    cache
+                        .put(&chapter)
+                        .map_err(|e| Error::output(&work_dir, e))?;
+                }
+                if options.checkpoint_every.is_some() {
+                    saved.chapters.push(chapter);
+                }
+            }
+            log::trace!("chapter {} took {}", i + 1, summary.timings.since(&timed));
+            progress.chapter_done();
+            progress.downloaded(metered.total());
+
+            // Checked between chapters, so the one that crosses the budget is
+            // still complete.
+            let over_budget = options
+                .max_total_bytes
+                .is_some_and(|limit| metered.total() > limit)
+                && i + 1 < plan.len();
+            out_of_time = deadline_passed() && i + 1 < plan.len();
+            let stopping = over_budget || out_of_time;
+
+            if let Some(every) = options.checkpoint_every
+                && ((i + 1) % every.max(1) == 0 || stopping)
+                && i + 1 < plan.len()
+            {
+                saved
+                    .save(&work_dir)
+                    .map_err(|e| Error::output(&work_dir, e))?;
+                if options.partial_epub && !stopping && options.format.is_epub() {
+                    write_partial(&partial_path, &title, options, &written)
+                        .map_err(|e| Error::output(&partial_path, e))?;
+                }
+                log::debug!("checkpointed {} chapters", i + 1);
+            }
+
+            if stopping {
+                stopped = Some(i + 1);
+                break;
+            }
+        }
+        Ok(())
+    })?;
+
+    release(
+        &mut held,
+        None,
+        &mut book,
+        options.partial_epub.then_some(&mut written),
+        options,
+        plan.len(),
+        anthology,
+    )?;
+    progress.finish();
+    summary.downloaded = metered.total();
+    summary.transferred = metered.wire_total();
+    let length = stats::Length::new(
+        length_unit,
+        summary.length,
+        options
+            .reading_speed
+            .unwrap_or_else(|| length_unit.default_rate()),
+    );
+    if options.length_meta {
+        length.add_to(&mut book);
+    }
+    last.add_to(&mut book);
+    manifest.length = Some(length.clone());
+    manifest.results = summary.results.clone();
+    summary.estimate = Some(length);
+
+    if let Some(selector) = &options.author_page
+        && let Some(body) =
+            about_author(fetcher, &credited, selector, &options.limits, &mut summary)
+    {
+        // EPUB 2's guide has no type for an author's bio; acknowledgements
+        // is the back matter closest to it.
+        let title = metadata::about_author_title(&options.language);
+        let page = xhtml::chapter(title, &body, None);
+        book.add_content(
+            EpubContent::new("about-author.xhtml", page.as_bytes())
+                .title(title)
+                .reftype(ReferenceType::Acknowledgements),
+        )?;
+    }
+
+    if options.colophon {
+        book.add_content(
+            EpubContent::new(
+                "colophon.xhtml",
+                Cursor::new(xhtml::colophon(
+                    &summary,
+                    &manifest.generator.version,
+                    manifest.generator.source.as_deref(),
+                )),
+            )
+            .title("Colophon")
+            .reftype(ReferenceType::Colophon),
+        )?;
+    }
+
+    if let Some(texts) = &texts {
+        texts
+            .save(&work_dir)
+            .map_err(|e| Error::output(&work_dir, e))?;
+    }
+    if options.format.is_epub() {
+        if reused > 0 {
+            log::info!("reused the pages of {reused} unchanged chapters");
+        }
+        generated_pages
+            .save(&work_dir)
+            .map_err(|e| Error::output(&work_dir, e))?;
+    }
+
+    let output_path = match stopped {
+        Some(_) => partial_path.clone(),
+        None => book_path,
+    };
+    if options.format.is_epub() {
+        summary.timings.time(Phase::Generate, || {
+            write_book(
+                book,
+                options,
+                embedder.embedded() - embedded_before,
+                embedder.described - described_before,
+                &output_path,
+            )
+        })?;
+        epubs.push(output_path);
+        if options.split_every.is_some() {
+            summary.parts = epubs.clone();
+        }
+        summary.files = epubs.clone();
+    } else {
+        let generating = Instant::now();
+        let written = if options.format == output::Format::Html {
+            html::write(
+                &output_path,
+                &html::Page {
+                    title: &title,
+                    authors: &manifest.authors,
+                    language: &options.language,
+                    writing_mode: options.writing_mode,
+                    theme: options.theme,
+                    typography: &options.typography,
+                    chapters: &plain_chapters,
+                    description: description
+                        .as_deref()
+                        .filter(|_| options.toc_description)
+                        .map(|d| xhtml::description_paragraphs(d, options.description_limit))
+                        .filter(|d| !d.is_empty()),
+                },
+            )
+        } else {
+            plain::write(
+                &output_path,
+                options.format,
+                &title,
+                &manifest.authors,
+                &plain_chapters,
+            )
+        };
+        written.map_err(|e| Error::output(&output_path, e))?;
+        summary.timings.add(Phase::Generate, generating.elapsed());
+        summary.files = vec![output_path];
+    }
+    summary.written = summary
+        .files
+        .iter()
+        .filter_map(|file| fs::metadata(file).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    summary.title = title;
+    summary.authors = manifest.authors.clone();
+
+    if let Some(path) = &options.manifest {
+        manifest.write(path).map_err(|e| Error::output(path, e))?;
+    }
+
+    if let Some(chapters) = stopped {
+        return Err(match options.max_total_bytes {
+            Some(limit) if !out_of_time => Error::Budget {
+                limit,
+                chapters,
+                of: plan.len(),
+                partial: partial_path,
+            },
+            _ => Error::Deadline {
+                chapters,
+                of: plan.len(),
+                partial: partial_path,
+            },
+        });
+    }
+
+    if !anthology {
+        let listed: HashSet<&str> = indexed.iter().map(String::as_str).collect();
+        cache.retain(|link| listed.contains(link));
+        cache
+            .save(&work_dir)
+            .map_err(|e| Error::output(&work_dir, e))?;
+    }
+    record.chapters = indexed;
+    record.index = stored_index;
+    record
+        .save(&work_dir)
+        .map_err(|e| Error::output(&work_dir, e))?;
+
+    // The book is complete, so there's nothing left to resume.
+    if options.checkpoint_every.is_some() {
+        checkpoint::Checkpoint::remove(&work_dir);
+        let _ = fs::remove_file(&partial_path);
+    }
+
+    Ok((summary, epubs))
+}
+
+/// The bio `selector` finds on each author page the index links to, given
+/// with the authors credited, as page markup; anything that goes wrong is a
+/// warning, and `None` leaves the page out.
+fn about_author(
+    fetcher: &impl Fetcher,
+    (authors, pages): &(Vec<String>, Vec<Uri>),
+    selector: &fetch::selector::Selector,
+    limits: &fetch::Limits,
+    summary: &mut Summary,
+) -> Option<String> {
+    if pages.is_empty() {
+        summary.warn("no author page linked from the index page (--author-page)");
+        return None;
+    }
+    let mut body = String::new();
+    for (i, url) in pages.iter().enumerate() {
+        let page = match fetch_page(fetcher, url) {
+            Ok(page) => page,
+            Err(e) => {
+                summary.warn(format!("failed to download author page {url}: {e}"));
+                continue;
+            }
+        };
+        let paragraphs = fetch::parse_within(
+            &page,
+            fetch::text::TextSink::from(selector.clone()),
+            limits.parse_time,
+        )
+        .and_then(fetch::text::TextSink::into_paragraphs);
+        let Some(paragraphs) = paragraphs else {
+            summary.warn(format!("no author bio ({selector}) on {url}"));
+            continue;
+        };
+        if pages.len() > 1
+            && let Some(name) = authors.get(i)
+        {
+            body.push_str(&format!("<h3>{}</h3>\n", xhtml::escape(name.trim())));
+        }
+        for paragraph in paragraphs {
+            body.push_str(&format!("<p>{}</p>\n", xhtml::escape(&paragraph)));
+        }
+    }
+    (!body.is_empty()).then(|| format!("<h2>About the Author</h2>\n{body}"))
+}
+
+/// A chapter's pages held back until the page after them is named, as
+/// their last links to it with `--chapter-template` or `--chapter-nav`.
+struct Held {
+    pages: generated::Pages,
+    toc_title: String,
+    /// The last of `pages.files` is empty until it is rendered.
+    last: HeldPage,
+}
+
+struct HeldPage {
+    /// Footer included.
+    body: String,
+    /// The page before it.
+    prev: Option<String>,
+    /// The first page of the chapter or arc heading before it.
+    prev_chapter: Option<String>,
+}
+
+/// Renders the last page of the `held` chapter, if any, linking to `next`,
+/// and adds the chapter to the book.
+fn release(
+    held: &mut Option<Held>,
+    next: Option<&str>,
+    book: &mut EpubBuilder<ZipCommandOrLibrary>,
+    written: Option<&mut Vec<(String, String, Option<String>)>>,
+    options: &BuildOptions,
+    total: usize,
+    anthology: bool,
+) -> Result<()> {
+    let Some(Held {
+        mut pages,
+        toc_title,
+        last: HeldPage {
+            mut body,
+            prev,
+            prev_chapter,
+        },
+    }) = held.take()
+    else {
+        return Ok(());
+    };
+    if options.chapter_nav {
+        body.push('\n');
+        body.push_str(&xhtml::chapter_nav(prev_chapter.as_deref(), next));
+    }
+    let mut page = match &options.chapter_template {
+        Some(template) => template
+            .render(&template::ChapterFields {
+                title: &pages.title,
+                body: &body,
+                index: pages.index + 1,
+                total,
+                prev_href: prev.as_deref(),
+                next_href: next,
+                lang: &options.language,
+            })
+            .with_context(|| during(pages.index, &pages.title, &pages.url, Phase::Xhtml))?,
+        None => xhtml::chapter(&pages.title, &body, None),
+    };
+    if options.format == output::Format::Kepub {
+        page = kepub::spans(&page);
+    }
+    if let Some((_, last)) = pages.files.last_mut() {
+        *last = page;
+    }
+    add_pages(book, &pages, &toc_title, anthology, written)
+}
+
+/// `--dedupe-toc-titles`: the second and later chapters with the same title
+/// are listed in the table of contents as "Title (2)", "Title (3)" and so
+/// on, counting in book order, so an update that adds chapters at the end
+/// leaves the earlier entries as they were.
+#[derive(Default)]
+struct TocTitles {
+    seen: HashMap<String, usize>,
+}
+
+impl TocTitles {
+    fn entry(&mut self, title: &str) -> String {
+        let count = self.seen.entry(title.trim().to_string()).or_default();
+        *count += 1;
+        match *count {
+            1 => title.to_string(),
+            n => format!("{title} ({n})"),
+        }
+    }
+}
+
+/// How often [`ProgressLog`] logs, in chapters and in time.
+const PROGRESS_EVERY: usize = 100;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Passes progress on and logs how far the chapter loop is, every
+/// [`PROGRESS_EVERY`] chapters or [`PROGRESS_INTERVAL`], whichever comes
+/// first, for runs nobody watches a progress bar of.
+struct ProgressLog<'a, P> {
+    inner: &'a P,
+    total: Cell<usize>,
+    done: Cell<usize>,
+    started: Cell<Instant>,
+    /// Chapters done when progress was last logged, and when.
+    logged: Cell<(usize, Instant)>,
+}
+
+impl<'a, P: Progress> ProgressLog<'a, P> {
+    fn new(inner: &'a P) -> Self {
+        ProgressLog {
+            inner,
+            total: Cell::new(0),
+            done: Cell::new(0),
+            started: Cell::new(Instant::now()),
+            logged: Cell::new((0, Instant::now())),
+        }
+    }
+}
+
+impl<P: Progress> Progress for ProgressLog<'_, P> {
+    fn start(&self, chapters: usize, title: &str) {
+        let now = Instant::now();
+        self.total.set(chapters);
+        self.done.set(0);
+        self.started.set(now);
+        self.logged.set((0, now));
+        self.inner.start(chapters, title);
+    }
+
+    fn chapter_done(&self) {
+        self.inner.chapter_done();
+        let (done, total) = (self.done.get() + 1, self.total.get());
+        self.done.set(done);
+        let now = Instant::now();
+        let (last, at) = self.logged.get();
+        if done >= total || (done - last < PROGRESS_EVERY && now - at < PROGRESS_INTERVAL) {
+            return;
+        }
+        self.logged.set((done, now));
+        let elapsed = now - self.started.get();
+        let left = elapsed.mul_f64((total - done) as f64 / done as f64);
+        log::info!(
+            "chapter {done}/{total}, {} elapsed, about {} left",
+            clock(elapsed),
+            clock(left)
+        );
+    }
+
+    fn downloaded(&self, bytes: usize) {
+        self.inner.downloaded(bytes);
+    }
+
+    fn finish(&self) {
+        self.inner.finish();
+    }
+}
+
+/// `took` as hours, minutes and seconds: "00:04:05".
+fn clock(took: Duration) -> String {
+    let secs = took.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Credited when the index names no author.
+const UNKNOWN_AUTHOR: &str = "Unknown";
+
+/// Stands in for a chapter that is locked on the site.
+const LOCKED_PAGE: &str = "<p>This chapter is locked on the site. Build the book again with --update once it unlocks to fill it in.</p>";
+
+/// The book's last chapter, which ongoing serials are named and tagged
+/// after so snapshots can sit side by side.
+struct LastChapter {
+    /// From its title, or else its place on the index; zero-padded.
+    number: String,
+    title: String,
+    date: Option<chrono::NaiveDate>,
+}
+
+impl LastChapter {
+    fn of(plan: &[fallback::Planned], indexed: &[String]) -> Self {
+        let link = &plan.last().expect("a plan with chapters").link;
+        let number = numbering::chapter_number(&link.title).unwrap_or_else(|| {
+            let url = link.uri.to_string();
+            indexed
+                .iter()
+                .position(|u| *u == url)
+                .unwrap_or(plan.len() - 1) as u64
+                + 1
+        });
+        LastChapter {
+            number: format!("{number:04}"),
+            title: link.title.clone(),
+            date: link.date,
+        }
+    }
+
+    fn add_to(&self, book: &mut EpubBuilder<ZipCommandOrLibrary>) {
+        let mut meta = vec![
+            ("epub-dude:last-chapter", self.number.clone()),
+            ("epub-dude:last-chapter-title", xhtml::escape(&self.title)),
+        ];
+        if let Some(date) = self.date {
+            meta.push(("epub-dude:last-chapter-date", date.to_string()));
+        }
+        for (property, content) in meta {
+            book.add_metadata_opf(Box::new(MetadataOpfV3::new(property.to_string(), content)));
+        }
+    }
+}
+
+/// Adds a chapter's `pages` to `book`, the first listed in the table of
+/// contents as `toc_title` with the chapter's sections under it, and each
+/// to `written` for `--partial-epub`.
+fn add_pages(
+    book: &mut EpubBuilder<ZipCommandOrLibrary>,
+    pages: &generated::Pages,
+    toc_title: &str,
+    anthology: bool,
+    mut written: Option<&mut Vec<(String, String, Option<String>)>>,
+) -> Result<()> {
+    for (p, (name, page)) in pages.files.iter().enumerate() {
+        if let Some(written) = written.as_mut() {
+            written.push((
+                name.clone(),
+                page.clone(),
+                (p == 0).then(|| toc_title.to_string()),
+            ));
+        }
+        let content = EpubContent::new(name, page.as_bytes());
+        book.add_content(if p == 0 {
+            let mut content = content.title(toc_title).reftype(ReferenceType::Text);
+            if anthology {
+                content = content.level(2);
+            }
+            for (target, section) in &pages.sections {
+                content = content.child(TocElement::new(target.clone(), section.clone()));
+            }
+            content
+        } else {
+            content
+        })
+        .with_context(|| during(pages.index, &pages.title, &pages.url, Phase::Add))?;
+    }
+    Ok(())
+}
+
+/// Where a chapter's build failed, for its error: `chapter 3 "Title"
+/// (https://...), rendering`.
+fn during(index: usize, title: &str, url: impl std::fmt::Display, phase: Phase) -> String {
+    format!(
+        "chapter {} \"{title}\" ({url}), {}",
+        index + 1,
+        phase.doing()
+    )
+}
+
+/// A cover image, downloaded from the index page's `og:image` or, with
+/// `--cover-from-content`, taken from the first chapter.
+struct Cover {
+    bytes: Vec<u8>,
+    mime: &'static str,
+    extension: &'static str,
+}
+
+fn fetch_cover(fetcher: &impl Fetcher, url: &Uri, summary: &mut Summary) -> Option<Cover> {
+    let bytes = match fetcher.get(&url.to_string()) {
+        Ok(response) => response.body,
+        Err(e) => {
+            summary.warn(format!("failed to download cover {url}: {e}"));
+            return None;
+        }
+    };
+    match images::sniff(&bytes) {
+        Some((mime, extension)) => Some(Cover {
+            bytes,
+            mime,
+            extension,
+        }),
+        None => {
+            summary.warn(format!("skipped cover {url} in an unknown image format"));
+            None
+        }
+    }
+}
+
+fn add_cover(book: &mut EpubBuilder<ZipCommandOrLibrary>, cover: &Cover) -> Result<()> {
+    book.add_cover_image(
+        format!("cover.{}", cover.extension),
+        cover.bytes.as_slice(),
+        cover.mime,
+    )?;
+    Ok(())
+}
+
+/// What every part of a book opens with, and the URL its identifier is
+/// derived from.
+struct Front<'a> {
+    title_page: Option<&'a str>,
+    cover: Option<&'a Cover>,
+    identity: &'a str,
+}
+
+/// A builder set up with the book's metadata, stylesheet and front matter,
+/// for the whole book or, with `part`, one of its `--split-every` parts.
+fn new_book(
+    options: &BuildOptions,
+    manifest: &manifest::Manifest,
+    front: &Front,
+    part: Option<usize>,
+) -> Result<EpubBuilder<ZipCommandOrLibrary>> {
+    let mut book = EpubBuilder::new(staging::zip(options)?)?;
+
+    book.epub_version(if options.epub2 {
+        EpubVersion::V20
+    } else {
+        EpubVersion::V33
+    });
+    book.stylesheet(
+        xhtml::stylesheet(options.writing_mode, options.theme, &options.typography).as_bytes(),
+    )?;
+    if options.writing_mode == xhtml::WritingMode::VerticalRl {
+        // `epub_direction` doesn't reach the spine; the metadata key does.
+        book.metadata("direction", PageDirection::Rtl.to_string())?;
+        book.add_metadata_opf(Box::new(MetadataOpf {
+            name: "primary-writing-mode".to_string(),
+            content: options.writing_mode.as_str().to_string(),
+        }));
+    }
+    manifest.generator.add_to(&mut book);
+
+    for author in &manifest.authors {
+        book.add_author(author.as_str());
+    }
+    // Refined contributor roles and accessibility properties are EPUB 3 only.
+    if !options.epub2 {
+        metadata::add_contributors(&mut book, &manifest.contributors);
+    }
+    match part {
+        Some(n) => {
+            book.set_title(parts::title(&manifest.title, n));
+            parts::add_to(&mut book, &manifest.title, front.identity, n, options.epub2);
+        }
+        None => {
+            book.set_title(manifest.title.clone());
+            book.set_uuid(parts::identifier(front.identity, 0));
+        }
+    }
+    book.set_languages(vec![options.language.clone()]);
+    book.set_toc_name(
+        options
+            .toc_title
+            .as_deref()
+            .unwrap_or_else(|| metadata::toc_title(&options.language)),
+    );
+    for subject in &manifest.subjects {
+        book.add_subject(subject.as_str());
+    }
+    if let Some(published) = manifest.published.as_deref().and_then(metadata::parse_date) {
+        book.set_publication_date(published);
+    }
+
+    if let Some(cover) = front.cover {
+        add_cover(&mut book, cover)?;
+        let page = xhtml::cover_page(&manifest.title, &format!("cover.{}", cover.extension));
+        book.add_content(
+            EpubContent::new("cover.xhtml", page.as_bytes())
+                .title(manifest.title.clone())
+                .reftype(ReferenceType::Cover),
+        )?;
+    }
+    if let Some(page) = front.title_page {
+        book.add_content(
+            EpubContent::new("title.xhtml", page.as_bytes())
+                .title(manifest.title.clone())
+                .reftype(ReferenceType::TitlePage),
+        )?;
+    }
+    Ok(book)
+}
+
+/// Adds the table of contents and accessibility metadata and writes `book`.
+fn write_book(
+    mut book: EpubBuilder<ZipCommandOrLibrary>,
+    options: &BuildOptions,
+    images: usize,
+    described: usize,
+    path: &Path,
+) -> Result<()> {
+    book.inline_toc();
+    if !options.epub2 {
+        metadata::add_accessibility(&mut book, images > 0, described == images);
+    }
+    generate(book, path, package::BACK_MATTER, options).map_err(|e| Error::output(path, e))?;
+    Ok(())
+}
+
+/// Generates `book` into a file in the [`staging`] directory and packages
+/// that into `path`, so that neither archive is held in memory.
+fn generate(
+    book: EpubBuilder<ZipCommandOrLibrary>,
+    path: &Path,
+    nonlinear: &[&str],
+    options: &BuildOptions,
+) -> anyhow::Result<()> {
+    let unpackaged = staging::archive(options, path);
+    let packaged = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&unpackaged)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut epub| {
+            book.generate(&mut epub)?;
+            epub.rewind()?;
+            let to = File::create(path)?;
+            package::finish(
+                BufReader::new(epub),
+                BufWriter::new(to),
+                nonlinear,
+                &options.language,
+                options.compression_level,
+                staging::deflated_at(options),
+            )
+        });
+    let _ = fs::remove_file(&unpackaged);
+    packaged
+}
+
+/// Images, which a partial epub leaves out.
+static IMAGE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<img\b[^>]*>").expect("valid image tag regex"));
+
+/// Writes the chapters added so far as a text-only epub, since the builder
+/// can only generate once.
+fn write_partial(
+    path: &Path,
+    title: &str,
+    options: &BuildOptions,
+    written: &[(String, String, Option<String>)],
+) -> anyhow::Result<()> {
+    let mut book = EpubBuilder::new(staging::zip(options)?)?;
+    book.metadata("title", format!("{title} (partial)"))?;
+    book.set_languages(vec![options.language.clone()]);
+    book.stylesheet(
+        xhtml::stylesheet(options.writing_mode, options.theme, &options.typography).as_bytes(),
+    )?;
+    for (name, page, chapter_title) in written {
+        let content = EpubContent::new(name, Cursor::new(IMAGE.replace_all(page, "").into_owned()));
+        book.add_content(match chapter_title {
+            Some(chapter_title) => content.title(chapter_title.clone()),
+            None => content,
+        })?;
+    }
+    generate(book, path, &[], options)
+}
+
+/// Saves the index `page` to the work directory for an
+/// [`Error::EmptyIndex`], so the error can say where to look.
+fn keep_page(e: Error, work_dir: &Path, page: &str) -> Error {
+    let Error::EmptyIndex { url, missing, .. } = e else {
+        return e;
+    };
+    let path = work_dir.join("index.html");
+    let saved = match checkpoint::write_atomic(&path, page.as_bytes()) {
+        Ok(()) => Some(path),
+        Err(e) => {
+            log::warn!("failed to save the index page: {e:#}");
+            None
+        }
+    };
+    Error::EmptyIndex {
+        url,
+        missing,
+        saved,
+    }
+}
+
+/// Saves the page of chapter `i` that failed to parse in the work
+/// directory's `failed/`, returning where.
+fn keep_failed(work_dir: &Path, i: usize, page: &[u8]) -> Option<PathBuf> {
+    let path = work_dir.join("failed").join(format!("{}.html", i + 1));
+    let saved = fs::create_dir_all(work_dir.join("failed"))
+        .map_err(anyhow::Error::from)
+        .and_then(|()| checkpoint::write_atomic(&path, page));
+    match saved {
+        Ok(()) => Some(path),
+        Err(e) => {
+            log::warn!("failed to save the page of chapter {}: {e:#}", i + 1);
+            None
+        }
+    }
+}
+
+/// Fetches a planned chapter, falling back to its alternate when the
+/// primary copy fails or is empty. Returns where the content came from.
+fn fetch_planned(
+    fetcher: &impl Fetcher,
+    item: &fallback::Planned,
+    i: usize,
+    site: fetch::Site,
+    fallback_site: Option<fetch::Site>,
+    options: &BuildOptions,
+    summary: &mut Summary,
+) -> (ladder::Fetched, Uri, fallback::Provenance) {
+    let item_site = match item.provenance {
+        fallback::Provenance::Primary => site,
+        fallback::Provenance::Fallback => fallback_site.unwrap_or(site),
+    };
+    let fetched = ladder::fetch(fetcher, &item.link.uri, &item_site, options);
+    summary.timings.merge(&fetched.timings);
+    if fetched.readable {
+        summary.warn(format!(
+            "chapter {} \"{}\": no text where the site keeps it, took the page's largest block of text",
+            i + 1,
+            item.link.title
+        ));
+    }
+
+    let unusable = fetched
+        .content
+        .as_ref()
+        .map_or(true, |c| c.text.trim().is_empty());
+    if unusable && let (Some(alternate), Some(fallback_site)) = (&item.alternate, &fallback_site) {
+        let again = ladder::fetch(fetcher, &alternate.uri, fallback_site, options);
+        summary.timings.merge(&again.timings);
+        if let Ok(c) = again.content
+            && !c.text.trim().is_empty()
+        {
+            summary.warn(format!(
+                "chapter {} \"{}\" taken from fallback {}",
+                i + 1,
+                item.link.title,
+                alternate.uri
+            ));
+            let fetched = ladder::Fetched {
+                content: Ok(c),
+                unparsed: None,
+                ..fetched
+            };
+            return (
+                fetched,
+                alternate.uri.clone(),
+                fallback::Provenance::Fallback,
+            );
+        }
+    }
+    (fetched, item.link.uri.clone(), item.provenance)
+}
@@ -0,0 +1,330 @@
+//! `catalog`: an OPDS 1.2 acquisition feed for a directory of epubs, so
+//! reader apps can browse books served from a static web server.
+//!
+//! Entries come from each book's package document: its title, authors,
+//! language and unique identifier, which also keys the entry's id and the
+//! file names of its cover and thumbnail under `covers/`.
+
+use std::{
+    fs::{self, File},
+    io::{Cursor, Read},
+    path::{self, Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use image::codecs::jpeg::JpegEncoder;
+use roxmltree::Document;
+use zip::ZipArchive;
+
+use crate::{validate, workdir, xhtml};
+
+pub const DEFAULT_FEED: &str = "catalog.xml";
+pub const DEFAULT_TITLE: &str = "epub-dude library";
+/// The longer side of a cover thumbnail, in pixels.
+pub const THUMBNAIL_SIZE: u32 = 256;
+const COVERS: &str = "covers";
+const FEED_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+
+/// One epub found under the catalog directory.
+#[derive(Debug)]
+pub struct Book {
+    /// Where the book is, relative to the catalog directory.
+    pub path: PathBuf,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub language: Option<String>,
+    pub identifier: String,
+    pub updated: DateTime<Utc>,
+    /// The cover image and its media type.
+    pub cover: Option<(Vec<u8>, String)>,
+}
+
+impl Book {
+    /// The entry id, stable as long as the book's identifier is.
+    pub fn id(&self) -> String {
+        match uuid::Uuid::parse_str(&self.identifier) {
+            Ok(uuid) => uuid.urn().to_string(),
+            Err(_) => self.identifier.clone(),
+        }
+    }
+
+    fn cover_stem(&self) -> String {
+        format!("{:016x}", workdir::fnv1a(self.identifier.as_bytes()))
+    }
+}
+
+/// Reads every epub under `dir`, newest first. Files that can't be read as
+/// an epub are skipped with a warning.
+pub fn scan(dir: &Path) -> Result<Vec<Book>> {
+    let mut files = Vec::new();
+    find_epubs(dir, &mut files)?;
+    let mut books = Vec::new();
+    for file in files {
+        match read(dir, &file) {
+            Ok(book) => books.push(book),
+            Err(e) => log::warn!("skipping {}: {e:#}", file.display()),
+        }
+    }
+    books.sort_by(|a, b| b.updated.cmp(&a.updated).then_with(|| a.path.cmp(&b.path)));
+    Ok(books)
+}
+
+fn find_epubs(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            if path.file_name() != Some(COVERS.as_ref()) {
+                find_epubs(&path, files)?;
+            }
+        } else if path.extension().is_some_and(|e| e == "epub") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the package document of the epub at `path`.
+pub fn read(dir: &Path, path: &Path) -> Result<Book> {
+    let updated = fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map(DateTime::<Utc>::from)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut zip = ZipArchive::new(File::open(path)?).context("not a zip archive")?;
+
+    let container = entry(&mut zip, "META-INF/container.xml")?;
+    let container = Document::parse(&container).context("unreadable container.xml")?;
+    let opf_path = container
+        .descendants()
+        .find(|n| n.has_tag_name("rootfile"))
+        .and_then(|n| n.attribute("full-path"))
+        .context("no package document in container.xml")?
+        .to_string();
+    let opf = entry(&mut zip, &opf_path)?;
+    let opf = Document::parse(&opf).with_context(|| format!("unreadable {opf_path}"))?;
+
+    let text = |name: &str| {
+        opf.descendants()
+            .filter(|n| n.has_tag_name(name))
+            .filter_map(|n| n.text())
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect::<Vec<_>>()
+    };
+    let unique = opf.root_element().attribute("unique-identifier");
+    let identifier = opf
+        .descendants()
+        .filter(|n| n.has_tag_name("identifier"))
+        .find(|n| unique.is_none() || n.attribute("id") == unique)
+        .and_then(|n| n.text())
+        .map(|t| t.trim().to_string())
+        .unwrap_or_else(|| path.display().to_string());
+
+    let cover = cover_item(&opf).and_then(|(href, media_type)| {
+        let href = validate::join(&opf_path, &href);
+        let mut bytes = Vec::new();
+        zip.by_name(&href).ok()?.read_to_end(&mut bytes).ok()?;
+        Some((bytes, media_type))
+    });
+
+    Ok(Book {
+        path: path.strip_prefix(dir).unwrap_or(path).to_path_buf(),
+        title: text("title").into_iter().next().unwrap_or_else(|| {
+            path.file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        }),
+        authors: text("creator"),
+        language: text("language").into_iter().next(),
+        identifier,
+        updated,
+        cover,
+    })
+}
+
+fn entry(zip: &mut ZipArchive<File>, name: &str) -> Result<String> {
+    let mut text = String::new();
+    zip.by_name(name)
+        .with_context(|| format!("no {name}"))?
+        .read_to_string(&mut text)
+        .with_context(|| format!("unreadable {name}"))?;
+    Ok(text)
+}
+
+/// The manifest item EPUB 3 marks as `cover-image`, or the one EPUB 2's
+/// `<meta name="cover">` points at.
+fn cover_item(opf: &Document) -> Option<(String, String)> {
+    let items = || opf.descendants().filter(|n| n.has_tag_name("item"));
+    let cover_id = opf
+        .descendants()
+        .find(|n| n.has_tag_name("meta") && n.attribute("name") == Some("cover"))
+        .and_then(|n| n.attribute("content"));
+    let item = items()
+        .find(|n| {
+            n.attribute("properties")
+                .is_some_and(|p| p.split_whitespace().any(|p| p == "cover-image"))
+        })
+        .or_else(|| items().find(|n| cover_id.is_some() && n.attribute("id") == cover_id))?;
+    Some((
+        item.attribute("href")?.to_string(),
+        item.attribute("media-type").unwrap_or_default().to_string(),
+    ))
+}
+
+/// Writes the feed for `books` to `feed`, and their covers and thumbnails
+/// under `covers/` in `dir`. Links are relative to the feed, wherever it is.
+pub fn write(dir: &Path, feed: &Path, title: &str, books: &[Book]) -> Result<()> {
+    let covers = dir.join(COVERS);
+    let feed_dir = feed.parent().unwrap_or(Path::new(""));
+    let base = relative(feed_dir, dir)?;
+    let updated = books
+        .iter()
+        .map(|b| b.updated)
+        .max()
+        .unwrap_or_else(Utc::now);
+    let feed_name = feed.file_name().unwrap_or_default().to_string_lossy();
+
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\" xmlns:dc=\"http://purl.org/dc/terms/\" xmlns:opds=\"http://opds-spec.org/2010/catalog\">\n",
+    );
+    out.push_str(&format!(
+        "  <id>urn:epub-dude:catalog:{:016x}</id>\n  <title>{}</title>\n  <updated>{}</updated>\n  <author><name>epub-dude</name></author>\n",
+        workdir::fnv1a(title.as_bytes()),
+        xhtml::escape(title),
+        timestamp(updated)
+    ));
+    for rel in ["self", "start"] {
+        out.push_str(&format!(
+            "  <link rel=\"{rel}\" href=\"{}\" type=\"{FEED_TYPE}\"/>\n",
+            href(Path::new(feed_name.as_ref()))
+        ));
+    }
+
+    for book in books {
+        out.push_str("  <entry>\n");
+        out.push_str(&format!(
+            "    <title>{}</title>\n    <id>{}</id>\n    <updated>{}</updated>\n",
+            xhtml::escape(&book.title),
+            xhtml::escape(&book.id()),
+            timestamp(book.updated)
+        ));
+        for author in &book.authors {
+            out.push_str(&format!(
+                "    <author><name>{}</name></author>\n",
+                xhtml::escape(author)
+            ));
+        }
+        if let Some(language) = &book.language {
+            out.push_str(&format!(
+                "    <dc:language>{}</dc:language>\n",
+                xhtml::escape(language)
+            ));
+        }
+        out.push_str(&format!(
+            "    <link rel=\"http://opds-spec.org/acquisition\" href=\"{}\" type=\"application/epub+zip\"/>\n",
+            href(&base.join(&book.path))
+        ));
+        if let Some((bytes, media_type)) = &book.cover {
+            fs::create_dir_all(&covers)
+                .with_context(|| format!("Failed to create {}", covers.display()))?;
+            let stem = book.cover_stem();
+            let extension = match media_type.as_str() {
+                "image/png" => "png",
+                "image/gif" => "gif",
+                "image/webp" => "webp",
+                "image/svg+xml" => "svg",
+                _ => "jpg",
+            };
+            let image = Path::new(COVERS).join(format!("{stem}.{extension}"));
+            fs::write(dir.join(&image), bytes)
+                .with_context(|| format!("Failed to write {}", image.display()))?;
+            out.push_str(&format!(
+                "    <link rel=\"http://opds-spec.org/image\" href=\"{}\" type=\"{}\"/>\n",
+                href(&base.join(&image)),
+                xhtml::escape(media_type)
+            ));
+            match thumbnail(bytes) {
+                Some(jpeg) => {
+                    let thumb = Path::new(COVERS).join(format!("{stem}-thumb.jpg"));
+                    fs::write(dir.join(&thumb), jpeg)
+                        .with_context(|| format!("Failed to write {}", thumb.display()))?;
+                    out.push_str(&format!(
+                        "    <link rel=\"http://opds-spec.org/image/thumbnail\" href=\"{}\" type=\"image/jpeg\"/>\n",
+                        href(&base.join(&thumb))
+                    ));
+                }
+                None => log::warn!("no thumbnail for the cover of {}", book.path.display()),
+            }
+        }
+        out.push_str("  </entry>\n");
+    }
+    out.push_str("</feed>\n");
+
+    fs::write(feed, out).with_context(|| format!("Failed to write {}", feed.display()))
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// `bytes` scaled down to [`THUMBNAIL_SIZE`], as JPEG.
+fn thumbnail(bytes: &[u8]) -> Option<Vec<u8>> {
+    let image = image::load_from_memory(bytes).ok()?;
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+    let mut out = Cursor::new(Vec::new());
+    thumbnail
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut out, 80))
+        .ok()?;
+    Some(out.into_inner())
+}
+
+/// The path from the directory `from` to `to`, `..` where it leaves `from`.
+fn relative(from: &Path, to: &Path) -> Result<PathBuf> {
+    let (from, to) = (resolved(from)?, resolved(to)?);
+    let (from, to): (Vec<_>, Vec<_>) = (from.components().collect(), to.components().collect());
+    if from.first() != to.first() {
+        anyhow::bail!("the feed must be on the same drive as the books");
+    }
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut path: PathBuf =
+        std::iter::repeat_n(Component::ParentDir, from.len() - common).collect();
+    path.extend(&to[common..]);
+    Ok(path)
+}
+
+/// `path` made absolute, without `.` and `..`.
+fn resolved(path: &Path) -> Result<PathBuf> {
+    let absolute =
+        path::absolute(path).with_context(|| format!("Failed to resolve {}", path.display()))?;
+    let mut resolved = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            other => resolved.push(other),
+        }
+    }
+    Ok(resolved)
+}
+
+/// A relative URL for `path`, percent-encoding all but unreserved characters.
+fn href(path: &Path) -> String {
+    let mut out = String::new();
+    for (i, component) in path.components().enumerate() {
+        if i > 0 {
+            out.push('/');
+        }
+        for b in component.as_os_str().to_string_lossy().bytes() {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                out.push(b as char);
+            } else {
+                out.push_str(&format!("%{b:02X}"));
+            }
+        }
+    }
+    out
+}
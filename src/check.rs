@@ -0,0 +1,46 @@
+use regex::Regex;
+
+use crate::{Error, Result};
+
+#[derive(Debug)]
+pub struct CountCheck {
+    pub pattern: Regex,
+    pub tolerance: usize,
+    pub strict: bool,
+}
+
+/// Extracts the chapter total an index page claims, e.g. "共 1523 章".
+///
+/// The first capture group of `pattern` (or the whole match when it has no
+/// groups) must be the number; separators like "1,523" are tolerated.
+pub fn claimed_count(page: &str, pattern: &Regex) -> Option<usize> {
+    let caps = pattern.captures(page)?;
+    let m = caps.get(1).or_else(|| caps.get(0))?;
+    let digits: String = m.as_str().chars().filter(char::is_ascii_digit).collect();
+    digits.parse().ok()
+}
+
+/// Compares the chapter total the index `page` claims with the `scraped`
+/// links, returning the warning to give when they differ by more than the
+/// tolerance, or failing with [`Error::Inconsistent`] with `check.strict`.
+pub fn check_count(page: &str, scraped: usize, check: &CountCheck) -> Result<Option<String>> {
+    let Some(claimed) = claimed_count(page, &check.pattern) else {
+        return Ok(Some(format!(
+            "claimed chapter count pattern `{}` did not match the index page",
+            check.pattern
+        )));
+    };
+
+    if claimed.abs_diff(scraped) <= check.tolerance {
+        return Ok(None);
+    }
+
+    let msg = format!(
+        "index page claims {claimed} chapters but {scraped} links were found (tolerance {})",
+        check.tolerance
+    );
+    if check.strict {
+        return Err(Error::Inconsistent(msg));
+    }
+    Ok(Some(msg))
+}
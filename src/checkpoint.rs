@@ -0,0 +1,278 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use http::Uri;
+use serde::{Deserialize, Serialize};
+
+use crate::{fallback::Provenance, fetch::Chapter, workdir};
+
+/// Bumped whenever the stored format changes; checkpoints written with
+/// another version are discarded.
+pub const VERSION: u32 = 1;
+const FILE: &str = "checkpoint.json";
+/// Bumped whenever the format of [`Cache`] changes. Caches of version 1
+/// were checkpoints and are moved over.
+pub const CACHE_VERSION: u32 = 2;
+/// The chapters of the last complete builds, for `--rebuild`.
+const CACHE: &str = "chapters.json";
+const CACHED: &str = "chapters";
+
+/// The chapters of a book parsed so far, so a crashed build can be redone
+/// without fetching them again.
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    version: u32,
+    /// The index page the chapters belong to.
+    source: String,
+    pub chapters: Vec<Saved>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Saved {
+    /// The planned link, which later runs look the chapter up by.
+    pub link: String,
+    /// Where the content was actually taken from.
+    pub url: String,
+    pub provenance: Provenance,
+    pub chapter: Chapter,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+    source: String,
+}
+
+impl Checkpoint {
+    pub fn new(source: &Uri) -> Self {
+        Checkpoint {
+            version: VERSION,
+            source: source.to_string(),
+            chapters: Vec::new(),
+        }
+    }
+
+    /// Reads the checkpoint in `dir`, or starts an empty one if there is
+    /// none usable for `source`.
+    pub fn load(dir: &Path, source: &Uri) -> Self {
+        Checkpoint::load_file(&dir.join(FILE), source, "checkpoint")
+    }
+
+    fn load_file(path: &Path, source: &Uri, what: &str) -> Self {
+        let Ok(json) = fs::read_to_string(path) else {
+            return Checkpoint::new(source);
+        };
+        let discard = |reason: String| {
+            log::warn!("discarding {what} {}: {reason}", path.display());
+            let _ = fs::remove_file(path);
+            Checkpoint::new(source)
+        };
+
+        match serde_json::from_str::<Header>(&json) {
+            Ok(header) if header.version != VERSION => discard(format!(
+                "written by format version {}, expected {VERSION}",
+                header.version
+            )),
+            Ok(header) if header.source != source.to_string() => {
+                discard(format!("belongs to {}", header.source))
+            }
+            Ok(_) => match serde_json::from_str::<Checkpoint>(&json) {
+                Ok(checkpoint) => checkpoint,
+                Err(e) => discard(e.to_string()),
+            },
+            Err(e) => discard(e.to_string()),
+        }
+    }
+
+    pub fn get(&self, link: &str) -> Option<&Saved> {
+        self.chapters.iter().find(|saved| saved.link == link)
+    }
+
+    /// Removes and returns the chapter saved for `link`.
+    pub fn take(&mut self, link: &str) -> Option<Saved> {
+        let i = self.chapters.iter().position(|saved| saved.link == link)?;
+        Some(self.chapters.swap_remove(i))
+    }
+
+    /// Adds `saved`, replacing what was saved for the same link.
+    pub fn put(&mut self, saved: Saved) {
+        match self.chapters.iter_mut().find(|old| old.link == saved.link) {
+            Some(old) => *old = saved,
+            None => self.chapters.push(saved),
+        }
+    }
+
+    /// Drops the chapters whose links `keep` turns down.
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.chapters.retain(|saved| keep(&saved.link));
+    }
+
+    /// Replaces the checkpoint in `dir`.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        write_atomic(&dir.join(FILE), &serde_json::to_vec(self)?)
+    }
+
+    pub fn remove(dir: &Path) {
+        let _ = fs::remove_file(dir.join(FILE));
+    }
+}
+
+/// Every chapter complete builds have had, so the book can be rebuilt
+/// without fetching any, see [`crate::rebuild`]. `chapters.json` lists
+/// them; each is kept in a file of its own under `chapters/`, named after
+/// a hash of its content, and read when it's needed, so a build doesn't
+/// hold them all.
+pub struct Cache {
+    /// Where the chapters are kept; none for a cache that isn't.
+    dir: Option<PathBuf>,
+    listed: Listed,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Listed {
+    version: u32,
+    /// The index page the chapters belong to.
+    source: String,
+    /// The file each chapter is kept in, by planned link.
+    chapters: BTreeMap<String, String>,
+}
+
+impl Cache {
+    /// An empty cache that is never kept, for books that don't have one.
+    pub fn new(source: &Uri) -> Self {
+        Cache {
+            dir: None,
+            listed: Listed {
+                version: CACHE_VERSION,
+                source: source.to_string(),
+                chapters: BTreeMap::new(),
+            },
+        }
+    }
+
+    /// Reads the list of chapters kept in `dir`, or starts an empty cache
+    /// if there is none usable for `source`.
+    pub fn load(dir: &Path, source: &Uri) -> Self {
+        let mut cache = Cache {
+            dir: Some(dir.join(CACHED)),
+            ..Cache::new(source)
+        };
+        let path = dir.join(CACHE);
+        let Ok(json) = fs::read_to_string(&path) else {
+            return cache;
+        };
+        let discard = |cache: Cache, reason: String| {
+            log::warn!("discarding chapter cache {}: {reason}", path.display());
+            let _ = fs::remove_file(&path);
+            cache
+        };
+
+        match serde_json::from_str::<Header>(&json) {
+            Ok(header) if header.source != source.to_string() => {
+                discard(cache, format!("belongs to {}", header.source))
+            }
+            Ok(header) if header.version == VERSION => {
+                log::info!("moving the chapter cache to {}", dir.join(CACHED).display());
+                let moved = serde_json::from_str::<Checkpoint>(&json)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|old| old.chapters.iter().try_for_each(|saved| cache.put(saved)));
+                match moved {
+                    Ok(()) => cache,
+                    Err(e) => discard(Cache::new(source), format!("{e:#}")),
+                }
+            }
+            Ok(header) if header.version != CACHE_VERSION => discard(
+                cache,
+                format!(
+                    "written by format version {}, expected {CACHE_VERSION}",
+                    header.version
+                ),
+            ),
+            Ok(_) => match serde_json::from_str::<Listed>(&json) {
+                Ok(listed) => Cache { listed, ..cache },
+                Err(e) => discard(cache, e.to_string()),
+            },
+            Err(e) => discard(cache, e.to_string()),
+        }
+    }
+
+    pub fn contains(&self, link: &str) -> bool {
+        self.listed.chapters.contains_key(link)
+    }
+
+    /// The links of every chapter kept.
+    pub fn links(&self) -> impl Iterator<Item = &str> {
+        self.listed.chapters.keys().map(String::as_str)
+    }
+
+    /// The chapter kept for `link`, read from its file.
+    pub fn get(&self, link: &str) -> Option<Saved> {
+        let path = self.dir.as_ref()?.join(self.listed.chapters.get(link)?);
+        let read = fs::read(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))
+            .and_then(|json| Ok(serde_json::from_slice(&json)?));
+        match read {
+            Ok(saved) => Some(saved),
+            Err(e) => {
+                log::warn!("cached chapter {link}: {e:#}");
+                None
+            }
+        }
+    }
+
+    /// Keeps `saved`, in place of what was kept for the same link once the
+    /// cache is saved.
+    pub fn put(&mut self, saved: &Saved) -> Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let json = serde_json::to_vec(saved)?;
+        let name = format!("{:016x}.json", workdir::fnv1a(&json));
+        let path = dir.join(&name);
+        if !path.exists() {
+            write_atomic(&path, &json)?;
+        }
+        self.listed.chapters.insert(saved.link.clone(), name);
+        Ok(())
+    }
+
+    /// Drops the chapters whose links `keep` turns down.
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.listed.chapters.retain(|link, _| keep(link));
+    }
+
+    /// Replaces the list in `dir`, removing the chapters no longer on it.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        write_atomic(&dir.join(CACHE), &serde_json::to_vec(&self.listed)?)?;
+        let Ok(entries) = fs::read_dir(dir.join(CACHED)) else {
+            return Ok(());
+        };
+        let kept: HashSet<&str> = self.listed.chapters.values().map(String::as_str).collect();
+        for entry in entries.flatten() {
+            if !entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| kept.contains(name))
+            {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replaces `path` without ever leaving a torn file, creating its directory.
+pub(crate) fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create work directory {}", dir.display()))?;
+    }
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, content).with_context(|| format!("Failed to write {}", temp.display()))?;
+    fs::rename(&temp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
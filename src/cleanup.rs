@@ -0,0 +1,98 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+
+/// Site names after an underscore or pipe ("书名_某某小说网") and the usual
+/// marketing suffixes such as "最新章节".
+const DEFAULT_TITLE_RULES: &[&str] = &[
+    r"\s*[_|｜].*$",
+    r"\s*[\(（【\[]?(最新章节|全文阅读|全文免费阅读|免费阅读|在线阅读|无弹窗|txt下载|TXT下载)[\)）】\]]?\s*$",
+];
+
+/// Labels some sites put before the name, such as "作者：" or "Author:".
+const DEFAULT_AUTHOR_RULES: &[&str] = &[r"(?i)^\s*(作者|著者|author|writer)\s*[:：]\s*"];
+
+/// What a [`Cleanup`] is applied to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Title,
+    Author,
+}
+
+impl Field {
+    fn default_rules(self) -> &'static [&'static str] {
+        match self {
+            Field::Title => DEFAULT_TITLE_RULES,
+            Field::Author => DEFAULT_AUTHOR_RULES,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Field::Title => "title",
+            Field::Author => "author",
+        }
+    }
+}
+
+/// Regexes whose matches are removed from a scraped title or author.
+#[derive(Debug)]
+pub struct Cleanup {
+    field: Field,
+    rules: Vec<Regex>,
+}
+
+impl Cleanup {
+    /// The built-in rules for `field`.
+    pub fn defaults(field: Field) -> Self {
+        Cleanup {
+            field,
+            rules: field
+                .default_rules()
+                .iter()
+                .map(|r| Regex::new(r).expect("valid default cleanup rule"))
+                .collect(),
+        }
+    }
+
+    pub fn new(field: Field, use_defaults: bool, extra: &[String]) -> Result<Self> {
+        let mut cleanup = if use_defaults {
+            Cleanup::defaults(field)
+        } else {
+            Cleanup {
+                field,
+                rules: Vec::new(),
+            }
+        };
+
+        for rule in extra {
+            cleanup
+                .rules
+                .push(Regex::new(rule).with_context(|| {
+                    format!("Invalid --{}-strip regex: {rule}", field.as_str())
+                })?);
+        }
+
+        Ok(cleanup)
+    }
+
+    /// Applies every rule in order. A rule that would erase a whole title is
+    /// skipped so a too-greedy pattern can't leave the book untitled; an
+    /// author that is nothing but a label is dropped.
+    pub fn apply(&self, raw: &str) -> String {
+        let mut cleaned = raw.trim().to_string();
+
+        for rule in &self.rules {
+            let stripped = rule.replace_all(&cleaned, "");
+            let stripped = stripped.trim();
+            if !stripped.is_empty() || self.field == Field::Author {
+                cleaned = stripped.to_string();
+            }
+        }
+
+        if cleaned != raw {
+            log::debug!("cleaned {} {raw:?} -> {cleaned:?}", self.field.as_str());
+        }
+
+        cleaned
+    }
+}
@@ -0,0 +1,83 @@
+//! Chapter dates as index pages print them, for `--since`.
+
+use std::sync::LazyLock;
+
+use chrono::{DateTime, Datelike, Days, Duration, Local, Months, NaiveDate};
+use regex::Regex;
+
+static FULL_DATE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(\d{4})\s*[-/.年]\s*(\d{1,2})\s*[-/.月]\s*(\d{1,2})").expect("valid date regex")
+});
+
+static MONTH_DAY: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?:^|\D)(\d{1,2})\s*[-/月]\s*(\d{1,2})(?:\D|$)").expect("valid month-day regex")
+});
+
+static AGO: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)(\d+)\s*(?:(秒|分鐘|分钟|分|小時|小时|時|时|天|日|週|周|星期|個月|个月|月|年)\s*前|(second|minute|min|hour|day|week|month|year)s?\s+ago)",
+    )
+    .expect("valid relative date regex")
+});
+
+/// Reads a date such as "2024-01-15", "2024/1/15", "2024年1月15日" or,
+/// taken as the latest such day not after `now`, "01-15". Relative ones
+/// ("今天", "昨天", "前天", "3天前", "2 hours ago") count back from `now`.
+pub fn parse(text: &str, now: DateTime<Local>) -> Option<NaiveDate> {
+    let text = text.trim();
+    let today = now.date_naive();
+
+    if let Some(caps) = FULL_DATE.captures(text) {
+        let number = |i: usize| caps[i].parse::<u32>().ok();
+        return NaiveDate::from_ymd_opt(number(1)? as i32, number(2)?, number(3)?);
+    }
+
+    let lower = text.to_lowercase();
+    for (words, days) in [
+        (
+            &["今天", "今日", "剛剛", "刚刚", "today", "just now"][..],
+            0,
+        ),
+        (&["昨天", "昨日", "yesterday"][..], 1),
+        (&["前天"][..], 2),
+    ] {
+        if words.iter().any(|w| lower.contains(w)) {
+            return today.checked_sub_days(Days::new(days));
+        }
+    }
+
+    if let Some(caps) = AGO.captures(text) {
+        let n: u32 = caps[1].parse().ok()?;
+        let unit = caps.get(2).or_else(|| caps.get(3))?.as_str().to_lowercase();
+        let back = |d: Duration| now.checked_sub_signed(d).map(|t| t.date_naive());
+        return match unit.as_str() {
+            "秒" | "second" => back(Duration::seconds(n.into())),
+            "分鐘" | "分钟" | "分" | "minute" | "min" => back(Duration::minutes(n.into())),
+            "小時" | "小时" | "時" | "时" | "hour" => back(Duration::hours(n.into())),
+            "天" | "日" | "day" => today.checked_sub_days(Days::new(n.into())),
+            "週" | "周" | "星期" | "week" => {
+                today.checked_sub_days(Days::new(7 * u64::from(n)))
+            }
+            "個月" | "个月" | "月" | "month" => today.checked_sub_months(Months::new(n)),
+            _ => today.checked_sub_months(Months::new(12 * n)),
+        };
+    }
+
+    if let Some(caps) = MONTH_DAY.captures(text) {
+        let (month, day) = (caps[1].parse().ok()?, caps[2].parse().ok()?);
+        let date = NaiveDate::from_ymd_opt(today.year(), month, day)?;
+        return if date > today {
+            NaiveDate::from_ymd_opt(today.year() - 1, month, day)
+        } else {
+            Some(date)
+        };
+    }
+    None
+}
+
+/// Reads a `--since` date, which has to be a full one.
+pub fn parse_since(text: &str) -> anyhow::Result<NaiveDate> {
+    NaiveDate::parse_from_str(text.trim(), "%Y-%m-%d").map_err(|_| {
+        anyhow::anyhow!("Invalid --since: {text} (expected a date such as 2024-01-31)")
+    })
+}
@@ -0,0 +1,189 @@
+use std::path::PathBuf;
+
+use http::Uri;
+
+use crate::validate::Problem;
+
+/// Process exit codes, one per [`Error`] category and one per way a build
+/// can succeed short of complete. Scripts rely on these, so they don't
+/// change; new outcomes get new codes.
+pub mod exit_code {
+    /// Every chapter is in the book.
+    pub const SUCCESS: i32 = 0;
+    pub const FAILURE: i32 = 1;
+    pub const USAGE: i32 = 2;
+    pub const NETWORK: i32 = 3;
+    pub const PARSE: i32 = 4;
+    pub const OUTPUT: i32 = 5;
+    pub const LOCKED: i32 = 6;
+    /// `--deadline` passed before the book was done.
+    pub const DEADLINE: i32 = 7;
+    /// The book was built, but chapters the site no longer has, or whose
+    /// pages couldn't be parsed, were left out, see
+    /// [`crate::Summary::exit_code`].
+    pub const SKIPPED: i32 = 8;
+    /// Every chapter was filtered out, e.g. by `--since`, so nothing was
+    /// built.
+    pub const UP_TO_DATE: i32 = 9;
+    /// `check` found chapters the last build didn't have.
+    pub const UPDATES: i32 = 10;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A bad argument or option value.
+    #[error("{0}")]
+    Usage(String),
+    #[error("unsupported domain: {0}")]
+    UnsupportedSite(Uri),
+    #[error("failed to fetch {url}: {reason}")]
+    Fetch {
+        url: String,
+        /// The last HTTP status, if the server answered at all.
+        status: Option<u16>,
+        reason: String,
+    },
+    /// A page didn't contain what a provider looks for; `what` names the
+    /// selector, e.g. "chapter links (ul#chapter-list a)".
+    #[error("found no {what} on {url}")]
+    Parse { url: String, what: String },
+    /// An index page on which some of what every book has matched nothing,
+    /// named in `missing` like "title (.title)". The page is kept in
+    /// `saved` for a look at what the site sent.
+    #[error(
+        "found no {} on {url}{}{}",
+        .missing.join(", "),
+        overrides(.missing),
+        .saved.as_ref().map(|p| format!("; the page is saved in {}", p.display())).unwrap_or_default()
+    )]
+    EmptyIndex {
+        url: String,
+        missing: Vec<String>,
+        saved: Option<PathBuf>,
+    },
+    /// The index failed a `--strict-sequence` or `--strict-count` check,
+    /// e.g. "chapter sequence check failed: missing chapter numbers: 4-5".
+    #[error("{0}")]
+    Inconsistent(String),
+    /// A page that took longer than [`crate::fetch::Limits::parse_time`] to parse.
+    #[error("gave up parsing {url} after {limit:?}")]
+    ParseTimeout {
+        url: String,
+        limit: std::time::Duration,
+    },
+    #[error("failed to write {}", path.display())]
+    Output {
+        path: PathBuf,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// The finished epub failed the `validate` checks.
+    #[error("{} structural problems in {}", .problems.len(), .path.display())]
+    Validation {
+        path: PathBuf,
+        problems: Vec<Problem>,
+    },
+    /// `max_total_bytes` ran out; the chapters so far were still written.
+    #[error(
+        "download budget of {limit} bytes exceeded after {chapters} of {of} chapters; the book so far is in {}",
+        .partial.display()
+    )]
+    Budget {
+        limit: usize,
+        chapters: usize,
+        of: usize,
+        partial: PathBuf,
+    },
+    /// The deadline passed; the chapters so far were still written.
+    #[error(
+        "deadline passed after {chapters} of {of} chapters; the book so far is in {}",
+        .partial.display()
+    )]
+    Deadline {
+        chapters: usize,
+        of: usize,
+        partial: PathBuf,
+    },
+    /// The filters left no chapters to build.
+    #[error("no chapters left to build ({excluded} filtered out)")]
+    NothingToBuild { excluded: usize },
+    /// `--update` found only chapters the last complete build had.
+    #[error("no new chapters since the last build ({chapters} on the index)")]
+    UpToDate { chapters: usize },
+    /// Another run holds the book's work directory.
+    #[error("{} is held by process {pid} (use --wait-lock to wait for it)", .path.display())]
+    Locked { path: PathBuf, pid: u32 },
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) | Error::UnsupportedSite(_) => exit_code::USAGE,
+            Error::Fetch { .. } | Error::Budget { .. } => exit_code::NETWORK,
+            Error::Parse { .. }
+            | Error::EmptyIndex { .. }
+            | Error::ParseTimeout { .. }
+            | Error::Inconsistent(_) => exit_code::PARSE,
+            Error::Output { .. } | Error::Validation { .. } => exit_code::OUTPUT,
+            Error::Locked { .. } => exit_code::LOCKED,
+            Error::Deadline { .. } => exit_code::DEADLINE,
+            Error::NothingToBuild { .. } | Error::UpToDate { .. } => exit_code::UP_TO_DATE,
+            // A typed error wrapped in context (e.g. which chapter failed)
+            // keeps its category.
+            Error::Other(e) => e
+                .chain()
+                .find_map(|cause| cause.downcast_ref::<Error>())
+                .map_or(exit_code::FAILURE, Error::exit_code),
+        }
+    }
+
+    /// The status of a fetch that can't succeed on a retry: the page is
+    /// gone (404) or deleted for good (410).
+    pub fn permanent_status(&self) -> Option<u16> {
+        match self {
+            Error::Fetch {
+                status: Some(status @ (404 | 410)),
+                ..
+            } => Some(*status),
+            Error::Other(e) => e
+                .chain()
+                .find_map(|cause| cause.downcast_ref::<Error>())
+                .and_then(Error::permanent_status),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn output(path: impl Into<PathBuf>, source: impl Into<anyhow::Error>) -> Self {
+        Error::Output {
+            path: path.into(),
+            source: source.into(),
+        }
+    }
+}
+
+/// The flags that set what an [`Error::EmptyIndex`] found missing.
+fn overrides(missing: &[String]) -> String {
+    let flags: Vec<&str> = missing
+        .iter()
+        .filter_map(|what| match what.split(' ').next() {
+            Some("title") => Some("--title"),
+            Some("author") => Some("--author"),
+            _ => None,
+        })
+        .collect();
+    if flags.is_empty() {
+        String::new()
+    } else {
+        format!(" (set with {})", flags.join(" and "))
+    }
+}
+
+impl From<epub_builder::Error> for Error {
+    fn from(e: epub_builder::Error) -> Self {
+        Error::Other(e.into())
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,100 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{fetch::ChapterLink, numbering::chapter_number};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Provenance {
+    Primary,
+    Fallback,
+}
+
+/// A chapter scheduled for download, with the entry to try on the fallback
+/// mirror when the primary copy fails or comes back empty.
+pub struct Planned {
+    pub link: ChapterLink,
+    pub provenance: Provenance,
+    pub alternate: Option<ChapterLink>,
+}
+
+impl Planned {
+    pub fn primary(link: ChapterLink) -> Self {
+        Planned {
+            link,
+            provenance: Provenance::Primary,
+            alternate: None,
+        }
+    }
+}
+
+fn normalize_title(title: &str) -> String {
+    title
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Pairs every primary chapter with its fallback counterpart (by chapter
+/// number, then by title) and inserts fallback chapters whose numbers are
+/// absent from the primary list at their numeric position.
+pub fn merge(primary: Vec<ChapterLink>, fallback: &[ChapterLink]) -> Vec<Planned> {
+    let mut by_number: HashMap<u64, &ChapterLink> = HashMap::new();
+    let mut by_title: HashMap<String, &ChapterLink> = HashMap::new();
+    for link in fallback {
+        if let Some(n) = chapter_number(&link.title) {
+            by_number.entry(n).or_insert(link);
+        }
+        by_title.entry(normalize_title(&link.title)).or_insert(link);
+    }
+
+    let primary_numbers: HashSet<u64> = primary
+        .iter()
+        .filter_map(|l| chapter_number(&l.title))
+        .collect();
+
+    let mut plan: Vec<Planned> = primary
+        .into_iter()
+        .map(|link| {
+            let alternate = chapter_number(&link.title)
+                .and_then(|n| by_number.get(&n))
+                .or_else(|| by_title.get(&normalize_title(&link.title)))
+                .map(|l| (*l).clone());
+            Planned {
+                alternate,
+                ..Planned::primary(link)
+            }
+        })
+        .collect();
+
+    // Without any numbered primary chapters there is nothing to compare against.
+    if primary_numbers.is_empty() {
+        return plan;
+    }
+
+    let mut missing: Vec<(u64, &ChapterLink)> = by_number
+        .iter()
+        .filter(|&(n, _)| !primary_numbers.contains(n))
+        .map(|(n, l)| (*n, *l))
+        .collect();
+    missing.sort_by_key(|(n, _)| *n);
+
+    for (n, link) in missing {
+        let position = plan
+            .iter()
+            .rposition(|p| chapter_number(&p.link.title).is_some_and(|m| m < n))
+            .map_or(0, |i| i + 1);
+        plan.insert(
+            position,
+            Planned {
+                link: link.clone(),
+                provenance: Provenance::Fallback,
+                alternate: None,
+            },
+        );
+    }
+
+    plan
+}
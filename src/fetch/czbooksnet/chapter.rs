@@ -0,0 +1,62 @@
+use std::cell::{Cell, RefCell};
+
+use html5ever::tokenizer::{TagKind, Token, TokenSink, TokenSinkResult};
+
+use crate::fetch::{Chapter, ContentWriter, selector::has_class};
+
+#[derive(Default)]
+pub struct ChapterSink {
+    found_name: Cell<bool>,
+    title: RefCell<String>,
+    content: RefCell<ContentWriter>,
+}
+
+impl From<ContentWriter> for ChapterSink {
+    fn from(writer: ContentWriter) -> Self {
+        ChapterSink {
+            content: RefCell::new(writer),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<ChapterSink> for Chapter {
+    fn from(val: ChapterSink) -> Self {
+        let (text, images, notes) = val.content.into_inner().finish();
+        Chapter {
+            title: val.title.into_inner(),
+            text,
+            images,
+            notes,
+        }
+    }
+}
+
+impl TokenSink for ChapterSink {
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        let mut content = self.content.borrow_mut();
+
+        match token {
+            Token::TagToken(tag) => match tag.kind {
+                TagKind::StartTag if content.is_open() => content.start_tag(&tag),
+                TagKind::StartTag if content.opens_notes(&tag) => content.open(&tag),
+                TagKind::StartTag if has_class(&tag, "name") => self.found_name.set(true),
+                TagKind::StartTag if has_class(&tag, "content") => content.open(&tag),
+                TagKind::StartTag => {}
+                TagKind::EndTag if content.is_open() => content.end_tag(&tag),
+                TagKind::EndTag => self.found_name.set(false),
+            },
+            Token::CharacterTokens(text) => {
+                if content.is_open() {
+                    content.text(&text);
+                } else if self.found_name.get() {
+                    self.title.borrow_mut().push_str(text.as_ref());
+                }
+            }
+            _ => {}
+        }
+        TokenSinkResult::Continue
+    }
+}
@@ -0,0 +1,207 @@
+use std::cell::{Cell, RefCell};
+
+use html5ever::tokenizer::{TagKind, Token, TokenSink, TokenSinkResult};
+use http::Uri;
+
+use chrono::{Local, NaiveDate};
+
+use crate::{
+    dates,
+    fetch::{
+        self, BookInfo, ChapterLink, IndexContext,
+        selector::{Selector, has_class, has_id},
+    },
+    metadata,
+};
+
+#[derive(Default)]
+pub struct LinksSink {
+    base: Uri,
+    dates: Option<Selector>,
+    /// The text of the date element being read, and its tag.
+    date_text: RefCell<Option<(String, String)>>,
+    /// A date read before any link that could take it, for lists that put
+    /// the date first.
+    pending_date: Cell<Option<NaiveDate>>,
+    links: RefCell<Vec<ChapterLink>>,
+    authors: RefCell<Vec<String>>,
+    author_pages: RefCell<Vec<Uri>>,
+    title: Cell<String>,
+    found_author_tag: Cell<bool>,
+    found_author: Cell<bool>,
+    found_title: Cell<bool>,
+    /// How many `<ul>`s deep the chapter list being read is, counting its
+    /// own. Sites split the list into one `ul#chapter-list` per volume, and
+    /// some nest lists inside them.
+    list_depth: Cell<usize>,
+    found_link_text: Cell<bool>,
+}
+
+impl From<IndexContext> for LinksSink {
+    fn from(context: IndexContext) -> Self {
+        LinksSink {
+            base: context.base,
+            dates: context.dates,
+            ..Default::default()
+        }
+    }
+}
+
+impl LinksSink {
+    fn in_list(&self) -> bool {
+        self.list_depth.get() > 0
+    }
+
+    /// Gives the date in `text` to the last link if it has none yet, or
+    /// else to the next one.
+    fn date_read(&self, text: &str) {
+        let Some(date) = dates::parse(text, Local::now()) else {
+            log::debug!("no chapter date in {text:?}");
+            return;
+        };
+        let mut links = self.links.borrow_mut();
+        match links.last_mut() {
+            Some(link) if link.date.is_none() => link.date = Some(date),
+            _ => self.pending_date.set(Some(date)),
+        }
+    }
+}
+
+impl From<LinksSink> for BookInfo {
+    fn from(val: LinksSink) -> Self {
+        BookInfo {
+            authors: val.authors.into_inner(),
+            author_pages: val.author_pages.into_inner(),
+            title: val.title.into_inner(),
+            description: None,
+            links: val.links.into_inner(),
+            page: Default::default(),
+        }
+    }
+}
+
+impl TokenSink for LinksSink {
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        match token {
+            Token::TagToken(tag) if self.date_text.borrow().is_some() => {
+                let done = tag.kind == TagKind::EndTag
+                    && self
+                        .date_text
+                        .borrow()
+                        .as_ref()
+                        .is_some_and(|(_, name)| name == tag.name.as_ref());
+                if done && let Some((text, _)) = self.date_text.take() {
+                    self.date_read(&text);
+                }
+            }
+            Token::TagToken(tag)
+                if tag.kind == TagKind::StartTag
+                    && self.in_list()
+                    && self.dates.as_ref().is_some_and(|d| d.matches(&tag)) =>
+            {
+                self.date_text
+                    .replace(Some((String::new(), tag.name.to_string())));
+            }
+            Token::TagToken(tag) => match tag.kind {
+                TagKind::StartTag => match tag.name.as_ref() {
+                    "span" => {
+                        if has_class(&tag, "author") {
+                            self.found_author_tag.set(true);
+                        }
+                        if has_class(&tag, "title") {
+                            self.found_title.set(true);
+                        }
+                    }
+                    "a" => match (self.found_author_tag.get(), self.in_list()) {
+                        (true, false) => {
+                            self.found_author.set(true);
+                            self.authors.borrow_mut().push(String::new());
+                            let href = tag
+                                .attrs
+                                .iter()
+                                .find(|attr| attr.name.local.as_ref() == "href")
+                                .and_then(|attr| fetch::resolve(&self.base, attr.value.as_ref()));
+                            if let Some(uri) = href {
+                                self.author_pages.borrow_mut().push(uri);
+                            }
+                        }
+                        (false, true) => {
+                            for attr in &tag.attrs {
+                                if attr.name.local.as_ref() == "href" {
+                                    let href = attr.value.as_ref();
+                                    let Some(uri) = fetch::resolve(&self.base, href) else {
+                                        log::warn!("skipping chapter link with bad href {href:?}");
+                                        continue;
+                                    };
+                                    self.links.borrow_mut().push(ChapterLink {
+                                        uri,
+                                        title: String::new(),
+                                        date: self.pending_date.take(),
+                                    });
+                                    self.found_link_text.set(true);
+                                }
+                            }
+                        }
+                        (_, _) => {}
+                    },
+                    "ul" if self.in_list() => self.list_depth.set(self.list_depth.get() + 1),
+                    "ul" if has_id(&tag, "chapter-list") => self.list_depth.set(1),
+                    _ => {}
+                },
+                TagKind::EndTag => match (
+                    self.found_author.get(),
+                    self.found_title.get(),
+                    self.in_list(),
+                ) {
+                    (true, false, false) => self.found_author.set(false),
+                    (false, false, false) => {
+                        // The author span may hold several links, one per co-author.
+                        if tag.name.as_ref() == "span" {
+                            self.found_author_tag.set(false);
+                        }
+                    }
+                    (false, true, false) => self.found_title.set(false),
+                    (false, false, true) => match tag.name.as_ref() {
+                        "ul" => self.list_depth.set(self.list_depth.get() - 1),
+                        "a" => {
+                            self.found_link_text.set(false);
+                            if let Some(link) = self.links.borrow_mut().last_mut() {
+                                link.title = metadata::normalize(&link.title, false);
+                            }
+                        }
+                        _ => {}
+                    },
+                    (_, _, _) => {}
+                },
+            },
+            Token::CharacterTokens(text) if self.date_text.borrow().is_some() => {
+                if let Some((date, _)) = self.date_text.borrow_mut().as_mut() {
+                    date.push_str(&text);
+                }
+            }
+            Token::CharacterTokens(text) => {
+                match (self.found_author.get(), self.found_title.get()) {
+                    (true, false) => {
+                        if let Some(author) = self.authors.borrow_mut().last_mut() {
+                            author.push_str(&text);
+                        }
+                    }
+                    (false, true) => {
+                        self.title.set(text.to_string());
+                    }
+                    (_, _) => {
+                        if self.found_link_text.get()
+                            && let Some(link) = self.links.borrow_mut().last_mut()
+                        {
+                            link.title.push_str(&text);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        TokenSinkResult::Continue
+    }
+}
@@ -0,0 +1,17 @@
+mod chapter;
+mod links;
+
+pub use chapter::ChapterSink;
+pub use links::LinksSink;
+
+use super::Provider;
+
+pub struct CzBooksProvider;
+
+impl Provider for CzBooksProvider {
+    const LINKS: &'static str = "ul#chapter-list a";
+    const TITLE: &'static str = ".title";
+    const AUTHORS: &'static str = ".author a";
+    type Link = LinksSink;
+    type Chapter = ChapterSink;
+}
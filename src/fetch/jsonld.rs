@@ -0,0 +1,115 @@
+//! schema.org `Book` descriptions from `<script type="application/ld+json">`
+//! blocks.
+//!
+//! A block may hold one object, an array of them or an `@graph`; the first
+//! object typed `Book` wins. Blocks that aren't valid JSON are skipped, as
+//! are values of unexpected shapes, since sites fill these in by hand.
+
+use std::collections::BTreeMap;
+
+use http::Uri;
+use serde_json::Value;
+
+use crate::fetch;
+
+/// The fields of a schema.org `Book` the book is built from.
+#[derive(Debug, Default, Clone)]
+pub struct LinkedBook {
+    pub name: Option<String>,
+    pub authors: Vec<String>,
+    pub description: Option<String>,
+    /// `genre`, which becomes the book's subjects.
+    pub genres: Vec<String>,
+    /// `datePublished`, as the page gives it.
+    pub published: Option<String>,
+    pub image: Option<Uri>,
+    /// The remaining fields, for the manifest; chapter lists (`hasPart`) and
+    /// JSON-LD keywords such as `@context` are left out.
+    pub extra: BTreeMap<String, Value>,
+}
+
+/// The first `Book` described in `scripts`, with its URLs resolved against
+/// the page at `base`.
+pub fn book(scripts: &[String], base: &Uri) -> Option<LinkedBook> {
+    scripts.iter().find_map(|script| {
+        let value: Value = match serde_json::from_str(script) {
+            Ok(value) => value,
+            Err(e) => {
+                log::debug!("{base}: skipping malformed JSON-LD: {e}");
+                return None;
+            }
+        };
+        find_book(&value).map(|book| read(book, base))
+    })
+}
+
+fn find_book(value: &Value) -> Option<&serde_json::Map<String, Value>> {
+    match value {
+        Value::Array(values) => values.iter().find_map(find_book),
+        Value::Object(object) => {
+            let is_book = match object.get("@type") {
+                Some(Value::String(t)) => is_book_type(t),
+                Some(Value::Array(types)) => {
+                    types.iter().any(|t| t.as_str().is_some_and(is_book_type))
+                }
+                _ => false,
+            };
+            if is_book {
+                Some(object)
+            } else {
+                object.get("@graph").and_then(find_book)
+            }
+        }
+        _ => None,
+    }
+}
+
+fn is_book_type(t: &str) -> bool {
+    matches!(
+        t,
+        "Book" | "schema:Book" | "http://schema.org/Book" | "https://schema.org/Book"
+    )
+}
+
+fn read(object: &serde_json::Map<String, Value>, base: &Uri) -> LinkedBook {
+    let mut book = LinkedBook::default();
+    for (key, value) in object {
+        match key.as_str() {
+            "name" => book.name = text(value),
+            "author" => book.authors = list(value).filter_map(text).collect(),
+            "description" => book.description = text(value),
+            "genre" => book.genres = list(value).filter_map(text).collect(),
+            "datePublished" => book.published = text(value),
+            "image" => {
+                book.image = list(value)
+                    .filter_map(|v| text(v.get("url").unwrap_or(v)))
+                    .find_map(|url| fetch::resolve(base, &url))
+            }
+            "hasPart" => {}
+            _ if key.starts_with('@') => {}
+            _ => {
+                book.extra.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    book
+}
+
+/// `value` itself, or each element of an array.
+fn list(value: &Value) -> impl Iterator<Item = &Value> {
+    match value {
+        Value::Array(values) => values.iter(),
+        _ => std::slice::from_ref(value).iter(),
+    }
+}
+
+/// A string, a number, or a thing's `name`, trimmed; `None` when empty.
+fn text(value: &Value) -> Option<String> {
+    let text = match value {
+        Value::String(s) => s.trim().to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Object(object) => return object.get("name").and_then(text),
+        _ => return None,
+    };
+    (!text.is_empty()).then_some(text)
+}
@@ -1,20 +1,770 @@
-use html5ever::tokenizer::TokenSink;
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDate;
+use html5ever::{
+    tendril::StrTendril,
+    tokenizer::{BufferQueue, Tag, TokenSink, Tokenizer, TokenizerOpts},
+};
 use http::Uri;
+use serde::{Deserialize, Serialize};
 
 pub mod czbooksnet;
+pub mod jsonld;
+pub mod og;
+pub mod readable;
+pub mod selector;
+pub mod text;
+
+use selector::Selector;
 
 pub trait Provider {
-    type Link: Default + TokenSink<Handle = ()> + Into<BookInfo>;
-    type Chapter: Default + TokenSink<Handle = ()> + Into<ChapterInfo>;
+    /// Where the index page lists chapters, names the book and credits its
+    /// authors, named in "found no ..." errors.
+    const LINKS: &'static str;
+    const TITLE: &'static str;
+    const AUTHORS: &'static str;
+    /// Built from the index page's URL, which chapter links resolve against,
+    /// and the user's index options.
+    type Link: From<IndexContext> + TokenSink<Handle = ()> + Into<BookInfo>;
+    /// Built around a [`ContentWriter`] carrying the user's content options.
+    type Chapter: From<ContentWriter> + TokenSink<Handle = ()> + Into<Chapter>;
+}
+
+/// What an index page's sink starts from.
+#[derive(Default)]
+pub struct IndexContext {
+    pub base: Uri,
+    /// Where the chapter list gives each chapter's date, from
+    /// `--chapter-date-selector`.
+    pub dates: Option<Selector>,
 }
 
 pub struct BookInfo {
-    pub author: String,
+    pub authors: Vec<String>,
+    /// Where the author credits link to, e.g. each author's page on the site.
+    pub author_pages: Vec<Uri>,
+    pub title: String,
+    pub description: Option<String>,
+    pub links: ChapterList,
+    /// The page's canonical URL, Open Graph tags and JSON-LD, whatever the
+    /// site.
+    pub page: og::PageMeta,
+}
+
+impl BookInfo {
+    /// Fills in the title, description and authors from [`BookInfo::page`].
+    ///
+    /// What the provider scraped comes first, then the page's JSON-LD, then
+    /// its Open Graph tags; `prefer_og` puts the Open Graph tags first.
+    /// Last come the page's `<title>` and `<meta name="description">`. The
+    /// command line's `--title` and `--author` override all of them.
+    pub fn use_page_meta(&mut self, prefer_og: bool) {
+        let linked = self.page.linked_data.as_ref();
+        let og = &self.page;
+        let pick = |scraped: Option<&String>,
+                    linked: Option<&String>,
+                    og: Option<&String>,
+                    document: Option<&String>| {
+            let scraped = scraped.filter(|s| !s.trim().is_empty());
+            let found = if prefer_og {
+                og.or(scraped).or(linked)
+            } else {
+                scraped.or(linked).or(og)
+            };
+            found.or(document).cloned()
+        };
+        let title = pick(
+            Some(&self.title),
+            linked.and_then(|b| b.name.as_ref()),
+            og.title.as_ref(),
+            og.document_title.as_ref(),
+        );
+        let description = pick(
+            self.description.as_ref(),
+            linked.and_then(|b| b.description.as_ref()),
+            og.description.as_ref(),
+            og.meta_description.as_ref(),
+        );
+        if self.authors.iter().all(|a| a.trim().is_empty())
+            && let Some(linked) = linked
+        {
+            self.authors = linked.authors.clone();
+        }
+        self.title = title.unwrap_or_default();
+        self.description = description;
+    }
+}
+
+/// A book's chapters in the order the index page lists them.
+pub type ChapterList = Vec<ChapterLink>;
+
+#[derive(Clone)]
+pub struct ChapterLink {
+    pub uri: Uri,
     pub title: String,
-    pub links: Vec<Uri>,
+    /// When the index says the chapter was published, see [`crate::dates`].
+    pub date: Option<NaiveDate>,
 }
 
-pub struct ChapterInfo {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chapter {
     pub title: String,
+    /// Chapter markup; images appear as [`image_marker`]s indexing `images`.
     pub text: String,
+    pub images: Vec<ChapterImage>,
+    /// Footnote bodies in page order; the text references them with
+    /// [`note_marker`]s.
+    pub notes: Vec<String>,
+}
+
+/// An `<img>` found in chapter content, with all of its attributes so the
+/// real source can be chosen among `src`, `data-src`, `srcset`, ...
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChapterImage {
+    pub attrs: Vec<(String, String)>,
+}
+
+impl ChapterImage {
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub const IMAGE_MARKER_START: char = '\u{E000}';
+pub const IMAGE_MARKER_END: char = '\u{E001}';
+
+/// Placeholder left in chapter text where the `index`th image appeared, using
+/// private-use characters that can't occur in scraped text.
+pub fn image_marker(index: usize) -> String {
+    format!("{IMAGE_MARKER_START}{index}{IMAGE_MARKER_END}")
+}
+
+pub const NOTE_MARKER_START: char = '\u{E002}';
+pub const NOTE_MARKER_END: char = '\u{E003}';
+
+/// Placeholder left where the `index`th footnote marker appeared.
+pub fn note_marker(index: usize) -> String {
+    format!("{NOTE_MARKER_START}{index}{NOTE_MARKER_END}")
+}
+
+/// Where translator notes live on a page: the superscript markers in the
+/// text and the block at the bottom holding one note per child element.
+#[derive(Debug, Clone)]
+pub struct NoteSelectors {
+    pub marker: Selector,
+    pub container: Selector,
+}
+
+/// Bounds on what one page may cost, so pathological markup (say, a
+/// multi-megabyte unterminated attribute) fails its page instead of
+/// stalling the run.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Parsing gives up on a page after this long.
+    pub parse_time: Duration,
+    /// Chapter text and notes beyond this many bytes are dropped.
+    pub max_text: usize,
+    /// Chapter links on an index page beyond this many are dropped.
+    pub max_links: usize,
+}
+
+pub const DEFAULT_MAX_TEXT: usize = 8 * 1024 * 1024;
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            parse_time: Duration::from_secs(30),
+            max_text: DEFAULT_MAX_TEXT,
+            max_links: 50_000,
+        }
+    }
+}
+
+/// Elements inside a chapter's content container that survive into the XHTML.
+pub const PRESERVED_ELEMENTS: &[&str] = &[
+    "ruby",
+    "rb",
+    "rt",
+    "rtc",
+    "rp",
+    "blockquote",
+    "pre",
+    "table",
+    "caption",
+    "thead",
+    "tbody",
+    "tfoot",
+    "tr",
+    "th",
+    "td",
+];
+
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Elements whose text is code, not chapter content (inline ad scripts).
+const SCRIPT_ELEMENTS: &[&str] = &["script", "style", "noscript"];
+
+/// Ruby parts whose end tag HTML lets authors omit.
+const RUBY_PARTS: &[&str] = &["rb", "rt", "rp"];
+
+/// Table parts that are only valid inside a `<table>`.
+const TABLE_PARTS: &[&str] = &["caption", "thead", "tbody", "tfoot", "tr", "th", "td"];
+const TABLE_SECTIONS: &[&str] = &["table", "thead", "tbody", "tfoot"];
+
+struct OpenElement {
+    name: String,
+    /// Whether the start tag was written, so the end tag must be too.
+    emitted: bool,
+}
+
+/// Turns the tokens inside a chapter's content container into XHTML.
+///
+/// Text is escaped, elements in [`PRESERVED_ELEMENTS`] are re-emitted (closing
+/// any end tags the source omitted, so the output is well-formed), and every
+/// other element only counts towards finding the container's end tag. Text
+/// inside scripts and styles is dropped.
+///
+/// Tables are repaired on the way: cells outside a row get one, text between
+/// cells gets a cell, and table parts outside any table degrade to text with
+/// rows on separate lines.
+///
+/// With [`NoteSelectors`], footnote markers are replaced by [`note_marker`]s
+/// (their own text, usually a number, is dropped) and each child of the note
+/// container becomes one entry of the chapter's notes.
+///
+/// Once [`Limits::max_text`] bytes of text are written, further text is
+/// dropped.
+#[derive(Default)]
+pub struct ContentWriter {
+    text: String,
+    images: Vec<ChapterImage>,
+    /// Open elements, the container itself first.
+    stack: Vec<OpenElement>,
+    note_selectors: Option<NoteSelectors>,
+    notes: Vec<String>,
+    markers: usize,
+    /// Stack depth of the open footnote marker / note container element.
+    marker_depth: Option<usize>,
+    notes_depth: Option<usize>,
+    /// Text bytes left before the rest is dropped; unbounded when `None`.
+    text_budget: Option<usize>,
+}
+
+impl ContentWriter {
+    pub fn new(note_selectors: Option<NoteSelectors>) -> Self {
+        ContentWriter {
+            note_selectors,
+            ..Default::default()
+        }
+    }
+
+    pub fn max_text(mut self, bytes: usize) -> Self {
+        self.text_budget = Some(bytes);
+        self
+    }
+
+    /// Reserves room for a chapter from a page of `bytes`, most of which is
+    /// usually its text, up to [`ContentWriter::max_text`].
+    pub fn page_size(mut self, bytes: usize) -> Self {
+        self.text
+            .reserve(bytes.min(self.text_budget.unwrap_or(usize::MAX)));
+        self
+    }
+
+    /// Whether the text budget is spent; markup stops being written too,
+    /// except end tags the output still needs.
+    fn exhausted(&self) -> bool {
+        self.text_budget == Some(0)
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.stack.is_empty()
+    }
+
+    /// Whether `tag` starts the note container, which sinks should open even
+    /// when it sits outside the chapter's content element.
+    pub fn opens_notes(&self, tag: &Tag) -> bool {
+        self.notes_depth.is_none()
+            && self
+                .note_selectors
+                .as_ref()
+                .is_some_and(|s| s.container.matches(tag))
+    }
+
+    pub fn open(&mut self, tag: &Tag) {
+        if tag.self_closing || VOID_ELEMENTS.contains(&tag.name.as_ref()) {
+            return;
+        }
+        if self.opens_notes(tag) {
+            self.notes_depth = Some(self.stack.len() + 1);
+        }
+        self.push(tag.name.as_ref(), false);
+    }
+
+    /// The buffer currently written to: the open note, or the chapter text.
+    fn out(&mut self) -> &mut String {
+        match self.notes_depth {
+            Some(_) => {
+                if self.notes.is_empty() {
+                    self.notes.push(String::new());
+                }
+                self.notes.last_mut().expect("just pushed")
+            }
+            None => &mut self.text,
+        }
+    }
+
+    fn push(&mut self, name: &str, emitted: bool) {
+        self.stack.push(OpenElement {
+            name: name.to_string(),
+            emitted,
+        });
+    }
+
+    fn top(&self) -> Option<&str> {
+        self.stack.last().map(|open| open.name.as_str())
+    }
+
+    /// Whether the innermost written element is a table, section or row,
+    /// where text and line breaks aren't allowed.
+    fn between_cells(&self) -> bool {
+        self.stack
+            .iter()
+            .rev()
+            .find(|open| open.emitted)
+            .is_some_and(|open| open.name == "tr" || TABLE_SECTIONS.contains(&open.name.as_str()))
+    }
+
+    fn inside(&self, name: &str) -> bool {
+        self.stack
+            .iter()
+            .any(|open| open.emitted && open.name == name)
+    }
+
+    pub fn start_tag(&mut self, tag: &Tag) {
+        let name = tag.name.as_ref();
+
+        if name == "img" && self.marker_depth.is_none() && !self.exhausted() {
+            let marker = image_marker(self.images.len());
+            self.out().push_str(&marker);
+            self.images.push(ChapterImage {
+                attrs: tag
+                    .attrs
+                    .iter()
+                    .map(|a| (a.name.local.to_string(), a.value.to_string()))
+                    .collect(),
+            });
+        }
+
+        if tag.self_closing || VOID_ELEMENTS.contains(&name) {
+            return;
+        }
+
+        if RUBY_PARTS.contains(&name) || name == "rtc" {
+            while self.top().is_some_and(|top| {
+                RUBY_PARTS.contains(&top) || (top == "rtc" && matches!(name, "rb" | "rtc"))
+            }) {
+                self.pop();
+            }
+        }
+
+        if self.opens_notes(tag) {
+            self.notes_depth = Some(self.stack.len() + 1);
+            self.push(name, false);
+            return;
+        }
+        if self.notes_depth == Some(self.stack.len()) {
+            self.notes.push(String::new());
+        }
+        if self.marker_depth.is_none()
+            && self.notes_depth.is_none()
+            && self
+                .note_selectors
+                .as_ref()
+                .is_some_and(|s| s.marker.matches(tag))
+        {
+            self.text.push_str(&note_marker(self.markers));
+            self.markers += 1;
+            self.marker_depth = Some(self.stack.len() + 1);
+            self.push(name, false);
+            return;
+        }
+
+        if TABLE_PARTS.contains(&name) {
+            if !self.inside("table") {
+                self.push(name, false);
+                return;
+            }
+            self.close_table_parts(name);
+        }
+
+        let emitted =
+            PRESERVED_ELEMENTS.contains(&name) && self.marker_depth.is_none() && !self.exhausted();
+        if emitted {
+            let mut start = format!("<{name}");
+            if matches!(name, "td" | "th") {
+                for attr in &tag.attrs {
+                    let attr_name = attr.name.local.as_ref();
+                    if matches!(attr_name, "colspan" | "rowspan")
+                        && attr.value.parse::<u16>().is_ok_and(|n| n > 0)
+                    {
+                        start.push_str(&format!(r#" {attr_name}="{}""#, attr.value));
+                    }
+                }
+            }
+            start.push('>');
+            self.out().push_str(&start);
+        }
+        self.push(name, emitted);
+    }
+
+    /// Closes whatever the table part `name` implicitly ends, and opens the
+    /// row a stray cell needs.
+    fn close_table_parts(&mut self, name: &str) {
+        let stop: &[&str] = match name {
+            "td" | "th" => &["table", "thead", "tbody", "tfoot", "tr"],
+            "tr" => TABLE_SECTIONS,
+            _ => &["table"],
+        };
+        while self.top().is_some_and(|top| !stop.contains(&top)) {
+            self.pop();
+        }
+        if matches!(name, "td" | "th") && self.top() != Some("tr") {
+            self.out().push_str("<tr>");
+            self.push("tr", true);
+        }
+    }
+
+    pub fn end_tag(&mut self, tag: &Tag) {
+        let name = tag.name.as_ref();
+        // Stray end tags for elements that aren't open are ignored.
+        if !self.stack.iter().any(|open| open.name == name) {
+            return;
+        }
+        while let Some(open) = self.pop() {
+            if open == name {
+                break;
+            }
+        }
+
+        if name == "p"
+            && self.is_open()
+            && self.marker_depth.is_none()
+            && !self.exhausted()
+            && !self.inside("pre")
+            && !self.between_cells()
+        {
+            self.out().push_str("<br />");
+        }
+    }
+
+    pub fn text(&mut self, text: &str) {
+        if text.is_empty()
+            || self.marker_depth.is_some()
+            || self
+                .stack
+                .iter()
+                .any(|open| SCRIPT_ELEMENTS.contains(&open.name.as_str()))
+        {
+            return;
+        }
+        // Only cells may hold text in a table; whitespace between them is
+        // formatting and anything else gets a cell of its own.
+        if self.between_cells() {
+            if text.trim().is_empty() {
+                return;
+            }
+            self.close_table_parts("td");
+            self.out().push_str("<td>");
+            self.push("td", true);
+        }
+
+        if self.exhausted() {
+            return;
+        }
+        if let Some(budget) = &mut self.text_budget {
+            *budget = budget.saturating_sub(text.len());
+        }
+
+        // Preformatted text keeps its own line breaks and indentation.
+        if self.inside("pre") {
+            crate::xhtml::escape_into(self.out(), text);
+        } else {
+            push_lines(self.out(), text);
+        }
+    }
+
+    fn pop(&mut self) -> Option<String> {
+        let open = self.stack.pop()?;
+        let depth = self.stack.len() + 1;
+        if self.marker_depth == Some(depth) {
+            self.marker_depth = None;
+        } else if self.notes_depth == Some(depth) {
+            self.notes_depth = None;
+        } else if open.emitted {
+            self.out().push_str(&format!("</{}>", open.name));
+        } else if self.marker_depth.is_none() {
+            // Table parts outside a table: keep cells apart and rows on
+            // their own lines.
+            match open.name.as_str() {
+                "td" | "th" => self.out().push(' '),
+                "tr" => self.out().push_str("<br />"),
+                _ => {}
+            }
+        }
+        Some(open.name)
+    }
+
+    pub fn finish(mut self) -> (String, Vec<ChapterImage>, Vec<String>) {
+        while self.pop().is_some() {}
+        let notes = self
+            .notes
+            .iter()
+            .map(|n| trim_breaks(n).to_string())
+            .filter(|n| !n.is_empty())
+            .collect();
+        (self.text, self.images, notes)
+    }
+}
+
+/// Strips the whitespace and line breaks left around a note by its markup.
+fn trim_breaks(mut s: &str) -> &str {
+    loop {
+        let trimmed = s
+            .trim()
+            .trim_start_matches("<br />")
+            .trim_end_matches("<br />");
+        if trimmed.len() == s.len() {
+            return s;
+        }
+        s = trimmed;
+    }
+}
+
+/// Resolves a possibly relative `href` found on the page at `base`.
+pub fn resolve(base: &Uri, href: &str) -> Option<Uri> {
+    let href = href.trim();
+    if href.starts_with("http://") || href.starts_with("https://") {
+        return href.parse().ok();
+    }
+
+    let scheme = base.scheme_str().unwrap_or("https");
+    if let Some(rest) = href.strip_prefix("//") {
+        return format!("{scheme}://{rest}").parse().ok();
+    }
+
+    let authority = base.authority()?;
+    let path = if href.starts_with('/') {
+        href.to_string()
+    } else {
+        let dir = base.path().rsplit_once('/').map_or("", |(dir, _)| dir);
+        format!("{dir}/{href}")
+    };
+
+    format!("{scheme}://{authority}{path}").parse().ok()
+}
+
+/// [`escape`](crate::xhtml::escape)s `text` onto the end of `out`, line
+/// breaks becoming `<br />` and the em spaces sites indent with dropped, in
+/// one pass that copies the text in runs between them.
+fn push_lines(out: &mut String, text: &str) {
+    const EM_SPACE: &[u8] = "\u{2003}".as_bytes();
+    let bytes = text.as_bytes();
+    let mut run = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let (len, with) = match bytes[i] {
+            b'\n' => (1, "<br />"),
+            _ if bytes[i..].starts_with(EM_SPACE) => (EM_SPACE.len(), ""),
+            b => match crate::xhtml::entity(b) {
+                Some(entity) => (1, entity),
+                None => {
+                    i += 1;
+                    continue;
+                }
+            },
+        };
+        out.push_str(&text[run..i]);
+        out.push_str(with);
+        i += len;
+        run = i;
+    }
+    out.push_str(&text[run..]);
+}
+
+/// A provider resolved at runtime, so books can mix sites (e.g. a fallback mirror).
+#[derive(Clone, Copy)]
+pub struct Site {
+    links: &'static str,
+    title: &'static str,
+    authors: &'static str,
+    index: fn(&Uri, &StrTendril, Option<&Selector>, Duration) -> Option<BookInfo>,
+    chapter: fn(&StrTendril, ContentWriter, Duration) -> Option<Chapter>,
+}
+
+impl Site {
+    pub fn of<P: Provider>() -> Self {
+        Site {
+            links: P::LINKS,
+            title: P::TITLE,
+            authors: P::AUTHORS,
+            index: |url, page, dates, limit| {
+                let context = IndexContext {
+                    base: url.clone(),
+                    dates: dates.cloned(),
+                };
+                parse_within(page, P::Link::from(context), limit).map(Into::into)
+            },
+            chapter: |page, writer, limit| {
+                parse_within(page, P::Chapter::from(writer), limit).map(Into::into)
+            },
+        }
+    }
+
+    pub fn for_uri(uri: &Uri) -> Option<Self> {
+        match uri.host() {
+            Some("czbooks.net") => Some(Site::of::<czbooksnet::CzBooksProvider>()),
+            _ => None,
+        }
+    }
+
+    /// Parses the index page fetched from `url`, filling in what the
+    /// provider missed from its [`og`] tags; an index without chapter links
+    /// means the site's markup has changed under us, and is an
+    /// [`Error::EmptyIndex`](crate::Error::EmptyIndex).
+    pub fn index(&self, url: &Uri, page: &StrTendril, limits: &Limits) -> crate::Result<BookInfo> {
+        self.index_with(url, page, limits, None)
+    }
+
+    /// Like [`Site::index`], also reading each chapter's date from the
+    /// elements `dates` matches in the chapter list.
+    pub fn index_with(
+        &self,
+        url: &Uri,
+        page: &StrTendril,
+        limits: &Limits,
+        dates: Option<&Selector>,
+    ) -> crate::Result<BookInfo> {
+        let Some(mut info) = (self.index)(url, page, dates, limits.parse_time) else {
+            return Err(timed_out(url, limits));
+        };
+        let Some(meta) = parse_within(page, og::PageMetaSink::from(url.clone()), limits.parse_time)
+        else {
+            return Err(timed_out(url, limits));
+        };
+        info.page = meta.into();
+        info.use_page_meta(false);
+        if info.links.is_empty() {
+            let mut missing = vec![format!("chapter links ({})", self.links)];
+            missing.extend(self.missing_metadata(&info, true, true));
+            return Err(crate::Error::EmptyIndex {
+                url: url.to_string(),
+                missing,
+                saved: None,
+            });
+        }
+        if info.links.len() > limits.max_links {
+            log::warn!(
+                "{url}: the index lists {} chapter links, keeping the first {}",
+                info.links.len(),
+                limits.max_links
+            );
+            info.links.truncate(limits.max_links);
+        }
+        Ok(info)
+    }
+
+    /// The title and author selectors that found nothing in `info`, of
+    /// those asked about.
+    pub fn missing_metadata(&self, info: &BookInfo, title: bool, authors: bool) -> Vec<String> {
+        let mut missing = Vec::new();
+        if title && info.title.trim().is_empty() {
+            missing.push(format!("title ({})", self.title));
+        }
+        if authors && info.authors.iter().all(|a| a.trim().is_empty()) {
+            missing.push(format!("author ({})", self.authors));
+        }
+        missing
+    }
+
+    /// Parses the chapter page fetched from `url`.
+    pub fn chapter(
+        &self,
+        url: &Uri,
+        page: &StrTendril,
+        notes: Option<&NoteSelectors>,
+        limits: &Limits,
+    ) -> crate::Result<Chapter> {
+        let writer = ContentWriter::new(notes.cloned())
+            .max_text(limits.max_text)
+            .page_size(page.len());
+        let chapter = (self.chapter)(page, writer, limits.parse_time)
+            .ok_or_else(|| timed_out(url, limits))?;
+        let extracted = chapter.text.len() + chapter.notes.iter().map(String::len).sum::<usize>();
+        if extracted >= limits.max_text {
+            log::warn!(
+                "{url}: chapter text exceeds {} bytes, dropping the rest",
+                limits.max_text
+            );
+        }
+        Ok(chapter)
+    }
+}
+
+fn timed_out(url: &Uri, limits: &Limits) -> crate::Error {
+    log::warn!(
+        "{url}: still parsing after {:?}, giving up",
+        limits.parse_time
+    );
+    crate::Error::ParseTimeout {
+        url: url.to_string(),
+        limit: limits.parse_time,
+    }
+}
+
+/// Pages reach the tokenizer in pieces this big, so the time limit is
+/// checked even in the middle of one huge tag.
+const CHUNK: usize = 64 * 1024;
+
+pub fn parse<T: Default + TokenSink<Handle = ()>>(page: &StrTendril) -> T {
+    parse_with(page, T::default())
+}
+
+pub fn parse_with<T: TokenSink<Handle = ()>>(page: &StrTendril, sinker: T) -> T {
+    parse_within(page, sinker, Duration::MAX).expect("no time limit")
+}
+
+/// Like [`parse_with`], giving up with `None` once parsing takes longer
+/// than `limit`.
+pub fn parse_within<T: TokenSink<Handle = ()>>(
+    page: &StrTendril,
+    sinker: T,
+    limit: Duration,
+) -> Option<T> {
+    let input = BufferQueue::default();
+    let tok = Tokenizer::new(sinker, TokenizerOpts::default());
+    let start = Instant::now();
+
+    let mut offset = 0;
+    while offset < page.len() {
+        let mut end = (offset + CHUNK).min(page.len());
+        while !page.is_char_boundary(end) {
+            end += 1;
+        }
+        input.push_back(page.subtendril(offset as u32, (end - offset) as u32));
+        let _ = tok.feed(&input);
+        offset = end;
+        if start.elapsed() > limit {
+            return None;
+        }
+    }
+    tok.end();
+
+    Some(tok.sink)
 }
@@ -0,0 +1,205 @@
+//! What an index page says about itself for machines: `<link
+//! rel="canonical">`, the Open Graph `<meta property="og:...">` tags link
+//! previews use and the schema.org [`jsonld`](super::jsonld) blocks search
+//! engines read. Being meant for machines, they tend to outlast the class
+//! names the providers scrape.
+
+use std::cell::{Cell, RefCell};
+
+use html5ever::tokenizer::{TagKind, Token, TokenSink, TokenSinkResult, states::RawKind};
+use http::Uri;
+
+use crate::fetch::{self, jsonld};
+
+#[derive(Debug, Default, Clone)]
+pub struct PageMeta {
+    /// `<link rel="canonical">`, or failing that `og:url`.
+    pub canonical: Option<Uri>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// `og:image`, the book's cover on novel sites.
+    pub image: Option<Uri>,
+    /// The first schema.org `Book` in the page's JSON-LD.
+    pub linked_data: Option<jsonld::LinkedBook>,
+    /// `<title>`, with `og:site_name` taken off its end.
+    pub document_title: Option<String>,
+    /// `<meta name="description">`.
+    pub meta_description: Option<String>,
+}
+
+impl PageMeta {
+    /// The cover image: the JSON-LD one, then `og:image`, or the other way
+    /// round with `prefer_og`.
+    pub fn cover(&self, prefer_og: bool) -> Option<&Uri> {
+        let linked = self.linked_data.as_ref().and_then(|b| b.image.as_ref());
+        if prefer_og {
+            self.image.as_ref().or(linked)
+        } else {
+            linked.or(self.image.as_ref())
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct PageMetaSink {
+    base: Uri,
+    meta: RefCell<PageMeta>,
+    og_url: RefCell<Option<Uri>>,
+    /// The text of each `application/ld+json` script.
+    scripts: RefCell<Vec<String>>,
+    in_script: Cell<bool>,
+    site_name: RefCell<Option<String>>,
+    /// The text of the first `<title>`, once it has started.
+    title: RefCell<Option<String>>,
+    in_title: Cell<bool>,
+}
+
+impl From<Uri> for PageMetaSink {
+    fn from(base: Uri) -> Self {
+        PageMetaSink {
+            base,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<PageMetaSink> for PageMeta {
+    fn from(val: PageMetaSink) -> Self {
+        let mut meta = val.meta.into_inner();
+        if meta.canonical.is_none() {
+            meta.canonical = val.og_url.into_inner();
+        }
+        meta.linked_data = jsonld::book(&val.scripts.into_inner(), &val.base);
+        let title = val.title.into_inner().unwrap_or_default();
+        let title = without_site_name(&title, val.site_name.into_inner().as_deref());
+        meta.document_title = Some(title).filter(|t| !t.is_empty());
+        meta
+    }
+}
+
+/// `title` without a trailing `site_name` and the dash, bar or the like
+/// before it: "Book - Site" is "Book". A title that is only the site's
+/// name is kept.
+pub fn without_site_name(title: &str, site_name: Option<&str>) -> String {
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    let Some(site_name) = site_name.map(str::trim).filter(|s| !s.is_empty()) else {
+        return title;
+    };
+    match title.strip_suffix(site_name) {
+        Some(rest) => {
+            let rest =
+                rest.trim_end_matches(|c: char| c.is_whitespace() || "-–—|｜_·:：".contains(c));
+            if rest.is_empty() {
+                title
+            } else {
+                rest.to_string()
+            }
+        }
+        None => title,
+    }
+}
+
+impl TokenSink for PageMetaSink {
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        let tag = match token {
+            Token::TagToken(tag) => tag,
+            Token::CharacterTokens(text) => {
+                if self.in_title.get()
+                    && let Some(title) = self.title.borrow_mut().as_mut()
+                {
+                    title.push_str(&text);
+                }
+                if self.in_script.get()
+                    && let Some(script) = self.scripts.borrow_mut().last_mut()
+                {
+                    script.push_str(&text);
+                }
+                return TokenSinkResult::Continue;
+            }
+            _ => return TokenSinkResult::Continue,
+        };
+        if tag.kind == TagKind::EndTag {
+            match tag.name.as_ref() {
+                "script" => self.in_script.set(false),
+                "title" => self.in_title.set(false),
+                _ => {}
+            }
+            return TokenSinkResult::Continue;
+        }
+        let attr = |name: &str| {
+            tag.attrs
+                .iter()
+                .find(|a| a.name.local.as_ref() == name)
+                .map(|a| a.value.trim())
+                .filter(|v| !v.is_empty())
+        };
+        let mut meta = self.meta.borrow_mut();
+        match tag.name.as_ref() {
+            "link" => {
+                let canonical = attr("rel").is_some_and(|rel| {
+                    rel.split_whitespace()
+                        .any(|r| r.eq_ignore_ascii_case("canonical"))
+                });
+                if canonical && meta.canonical.is_none() {
+                    meta.canonical = attr("href").and_then(|href| fetch::resolve(&self.base, href));
+                }
+            }
+            "meta" => {
+                if attr("name").is_some_and(|name| name.eq_ignore_ascii_case("description"))
+                    && meta.meta_description.is_none()
+                {
+                    meta.meta_description = attr("content").map(str::to_string);
+                }
+                let (Some(property), Some(content)) = (attr("property"), attr("content")) else {
+                    return TokenSinkResult::Continue;
+                };
+                // The first of repeated tags wins, as it does for previews.
+                match property {
+                    "og:title" if meta.title.is_none() => meta.title = Some(content.to_string()),
+                    "og:description" if meta.description.is_none() => {
+                        meta.description = Some(content.to_string())
+                    }
+                    "og:image" | "og:image:url" if meta.image.is_none() => {
+                        meta.image = fetch::resolve(&self.base, content)
+                    }
+                    "og:site_name" => {
+                        let mut site_name = self.site_name.borrow_mut();
+                        if site_name.is_none() {
+                            *site_name = Some(content.to_string());
+                        }
+                    }
+                    "og:url" => {
+                        let mut og_url = self.og_url.borrow_mut();
+                        if og_url.is_none() {
+                            *og_url = fetch::resolve(&self.base, content);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            "script" => {
+                let linked_data =
+                    attr("type").is_some_and(|t| t.eq_ignore_ascii_case("application/ld+json"));
+                if linked_data {
+                    self.scripts.borrow_mut().push(String::new());
+                }
+                self.in_script.set(linked_data);
+                // Script text is not markup, so a "<" in it opens no tag.
+                return TokenSinkResult::RawData(RawKind::ScriptData);
+            }
+            // Only the first, the document's; an SVG's come later.
+            "title" => {
+                let mut title = self.title.borrow_mut();
+                if title.is_none() {
+                    *title = Some(String::new());
+                    self.in_title.set(true);
+                    return TokenSinkResult::RawData(RawKind::Rcdata);
+                }
+            }
+            _ => {}
+        }
+        TokenSinkResult::Continue
+    }
+}
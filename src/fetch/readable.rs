@@ -0,0 +1,161 @@
+//! The readability fallback for a chapter page on which the site's content
+//! selector found no text, e.g. after the site renamed its container: the
+//! block holding the most text outside of links and page furniture is taken
+//! as the chapter.
+//!
+//! The page is read twice, once to score every block and once to write the
+//! winner out through a [`ContentWriter`], so the markup comes out as clean
+//! as the provider's own.
+
+use std::cell::{Cell, RefCell};
+
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::{Tag, TagKind, Token, TokenSink, TokenSinkResult};
+
+use crate::fetch::{Chapter, ContentWriter, Limits, VOID_ELEMENTS, parse_within};
+
+/// Elements that can hold a chapter.
+const BLOCKS: &[&str] = &["div", "article", "section", "main", "td"];
+/// Elements whose text doesn't count towards their block's score.
+const FURNITURE: &[&str] = &[
+    "a", "script", "style", "noscript", "nav", "header", "footer", "aside", "form", "button",
+    "select",
+];
+/// The least text, in characters, a block needs to pass for a chapter
+/// rather than a notice or a menu.
+const MIN_CHARS: usize = 100;
+
+/// The page's chapter text by the fallback, titled `title`, or `None` if
+/// no block holds enough text.
+pub fn chapter(page: &StrTendril, title: &str, limits: &Limits) -> Option<Chapter> {
+    let scores = parse_within(page, ScoreSink::default(), limits.parse_time)?;
+    let (target, score) = scores.best.get()?;
+    if score < MIN_CHARS {
+        return None;
+    }
+    let writer = ContentWriter::new(None).max_text(limits.max_text);
+    let sink = parse_within(page, BlockSink::new(target, writer), limits.parse_time)?;
+    let (text, images, notes) = sink.content.into_inner().finish();
+    Some(Chapter {
+        title: title.to_string(),
+        text,
+        images,
+        notes,
+    })
+}
+
+/// Counts the text directly in each block, by the order blocks start in.
+#[derive(Default)]
+struct ScoreSink {
+    /// Open blocks: which block, its name and its text so far.
+    open: RefCell<Vec<(usize, String, usize)>>,
+    blocks: Cell<usize>,
+    /// Open [`FURNITURE`] elements.
+    furniture: Cell<usize>,
+    /// The block with the most text and how much.
+    best: Cell<Option<(usize, usize)>>,
+}
+
+impl ScoreSink {
+    fn close(&self, name: &str) {
+        let mut open = self.open.borrow_mut();
+        // Stray end tags for blocks that aren't open are ignored.
+        if !open.iter().any(|(_, open, _)| open == name) {
+            return;
+        }
+        while let Some((block, open_name, score)) = open.pop() {
+            if self.best.get().is_none_or(|(_, best)| score > best) {
+                self.best.set(Some((block, score)));
+            }
+            if open_name == name {
+                break;
+            }
+        }
+    }
+}
+
+impl TokenSink for ScoreSink {
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        match token {
+            Token::TagToken(tag) => {
+                let name = tag.name.as_ref();
+                let void = tag.self_closing || VOID_ELEMENTS.contains(&name);
+                match tag.kind {
+                    TagKind::StartTag if BLOCKS.contains(&name) && !void => {
+                        let block = self.blocks.get();
+                        self.blocks.set(block + 1);
+                        self.open.borrow_mut().push((block, name.to_string(), 0));
+                    }
+                    TagKind::StartTag if FURNITURE.contains(&name) && !void => {
+                        self.furniture.set(self.furniture.get() + 1);
+                    }
+                    TagKind::EndTag if BLOCKS.contains(&name) => self.close(name),
+                    TagKind::EndTag if FURNITURE.contains(&name) => {
+                        self.furniture.set(self.furniture.get().saturating_sub(1));
+                    }
+                    _ => {}
+                }
+            }
+            Token::CharacterTokens(text) if self.furniture.get() == 0 => {
+                if let Some((_, _, score)) = self.open.borrow_mut().last_mut() {
+                    *score += text.trim().chars().count();
+                }
+            }
+            Token::EOFToken => {
+                while let Some(name) = self.open.borrow().last().map(|(_, name, _)| name.clone()) {
+                    self.close(&name);
+                }
+            }
+            _ => {}
+        }
+        TokenSinkResult::Continue
+    }
+}
+
+/// Writes out the `target`th block.
+struct BlockSink {
+    target: usize,
+    blocks: Cell<usize>,
+    content: RefCell<ContentWriter>,
+}
+
+impl BlockSink {
+    fn new(target: usize, writer: ContentWriter) -> Self {
+        BlockSink {
+            target,
+            blocks: Cell::new(0),
+            content: RefCell::new(writer),
+        }
+    }
+
+    fn is_target(&self, tag: &Tag) -> bool {
+        let name = tag.name.as_ref();
+        if !BLOCKS.contains(&name) || tag.self_closing {
+            return false;
+        }
+        let block = self.blocks.get();
+        self.blocks.set(block + 1);
+        block == self.target
+    }
+}
+
+impl TokenSink for BlockSink {
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        let mut content = self.content.borrow_mut();
+        match token {
+            Token::TagToken(tag) => match tag.kind {
+                TagKind::StartTag if content.is_open() => content.start_tag(&tag),
+                TagKind::StartTag if self.is_target(&tag) => content.open(&tag),
+                TagKind::EndTag if content.is_open() => content.end_tag(&tag),
+                _ => {}
+            },
+            Token::CharacterTokens(text) if content.is_open() => content.text(&text),
+            _ => {}
+        }
+        TokenSinkResult::Continue
+    }
+}
@@ -0,0 +1,98 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{Result, bail};
+use html5ever::tokenizer::Tag;
+
+/// A minimal CSS-like selector for command-line options: `tag`, `.class`,
+/// `#id` or a combination such as `sup.note` or `div#notes`.
+#[derive(Debug, Clone)]
+pub struct Selector {
+    tag: Option<String>,
+    class: Option<String>,
+    id: Option<String>,
+}
+
+impl Selector {
+    pub fn matches(&self, tag: &Tag) -> bool {
+        self.tag.as_deref().is_none_or(|t| t == tag.name.as_ref())
+            && self.class.as_deref().is_none_or(|c| has_class(tag, c))
+            && self.id.as_deref().is_none_or(|id| has_id(tag, id))
+    }
+}
+
+/// Whether `class` is one of the classes in `tag`'s `class` attribute,
+/// ignoring ASCII case.
+pub fn has_class(tag: &Tag, class: &str) -> bool {
+    attr(tag, "class").is_some_and(|v| {
+        v.split_ascii_whitespace()
+            .any(|v| v.eq_ignore_ascii_case(class))
+    })
+}
+
+/// Whether `tag`'s `id` is `id`, ignoring ASCII case and stray whitespace.
+pub fn has_id(tag: &Tag, id: &str) -> bool {
+    attr(tag, "id").is_some_and(|v| v.trim_ascii().eq_ignore_ascii_case(id))
+}
+
+fn attr<'a>(tag: &'a Tag, name: &str) -> Option<&'a str> {
+    tag.attrs
+        .iter()
+        .find(|a| a.name.local.as_ref() == name)
+        .map(|a| a.value.as_ref())
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(tag) = &self.tag {
+            write!(f, "{tag}")?;
+        }
+        if let Some(class) = &self.class {
+            write!(f, ".{class}")?;
+        }
+        if let Some(id) = &self.id {
+            write!(f, "#{id}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Selector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let split = s.find(['.', '#']).unwrap_or(s.len());
+        let mut selector = Selector {
+            tag: (split > 0).then(|| s[..split].to_ascii_lowercase()),
+            class: None,
+            id: None,
+        };
+
+        let mut rest = &s[split..];
+        while let Some(kind) = rest.chars().next() {
+            rest = &rest[1..];
+            let end = rest.find(['.', '#']).unwrap_or(rest.len());
+            let (name, tail) = rest.split_at(end);
+            let slot = if kind == '.' {
+                &mut selector.class
+            } else {
+                &mut selector.id
+            };
+            if name.is_empty() || slot.is_some() {
+                bail!("Invalid selector {s:?} (expected tag, .class, #id or e.g. sup.note)");
+            }
+            *slot = Some(name.to_string());
+            rest = tail;
+        }
+
+        let bad_tag = selector
+            .tag
+            .as_deref()
+            .is_some_and(|t| !t.chars().all(|c| c.is_ascii_alphanumeric()));
+        if bad_tag || (selector.tag.is_none() && selector.class.is_none() && selector.id.is_none())
+        {
+            bail!("Invalid selector {s:?} (expected tag, .class, #id or e.g. sup.note)");
+        }
+        Ok(selector)
+    }
+}
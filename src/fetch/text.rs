@@ -0,0 +1,97 @@
+//! The plain text of one element picked by a [`Selector`], e.g. an author's
+//! bio on their page for `--author-page`.
+
+use std::cell::{Cell, RefCell};
+
+use html5ever::tokenizer::{TagKind, Token, TokenSink, TokenSinkResult};
+
+use crate::fetch::selector::Selector;
+
+/// Tags whose start or end begins a new paragraph.
+const BREAKS: &[&str] = &["p", "br", "div", "li", "h1", "h2", "h3", "h4", "h5", "h6"];
+
+/// Collects the text of the first element `selector` matches, one string
+/// per paragraph.
+pub struct TextSink {
+    selector: Selector,
+    /// The matched element's tag and how deeply it is nested in itself, while
+    /// inside it.
+    open: RefCell<Option<(String, usize)>>,
+    done: Cell<bool>,
+    paragraphs: RefCell<Vec<String>>,
+}
+
+impl From<Selector> for TextSink {
+    fn from(selector: Selector) -> Self {
+        TextSink {
+            selector,
+            open: RefCell::new(None),
+            done: Cell::new(false),
+            paragraphs: RefCell::new(vec![String::new()]),
+        }
+    }
+}
+
+impl TextSink {
+    /// The paragraphs found, or `None` if nothing matched or the element
+    /// held no text.
+    pub fn into_paragraphs(self) -> Option<Vec<String>> {
+        let paragraphs: Vec<String> = self
+            .paragraphs
+            .into_inner()
+            .into_iter()
+            .map(|p| p.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|p| !p.is_empty())
+            .collect();
+        (!paragraphs.is_empty()).then_some(paragraphs)
+    }
+
+    fn new_paragraph(&self) {
+        let mut paragraphs = self.paragraphs.borrow_mut();
+        if paragraphs.last().is_some_and(|p| !p.trim().is_empty()) {
+            paragraphs.push(String::new());
+        }
+    }
+}
+
+impl TokenSink for TextSink {
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        if self.done.get() {
+            return TokenSinkResult::Continue;
+        }
+        match token {
+            Token::TagToken(tag) => {
+                let mut open = self.open.borrow_mut();
+                let Some((name, depth)) = open.as_mut() else {
+                    if tag.kind == TagKind::StartTag && self.selector.matches(&tag) {
+                        *open = Some((tag.name.to_string(), 0));
+                    }
+                    return TokenSinkResult::Continue;
+                };
+                if tag.name.as_ref() == name.as_str() {
+                    match tag.kind {
+                        TagKind::StartTag if !tag.self_closing => *depth += 1,
+                        TagKind::EndTag if *depth == 0 => {
+                            self.done.set(true);
+                            return TokenSinkResult::Continue;
+                        }
+                        TagKind::EndTag => *depth -= 1,
+                        _ => {}
+                    }
+                }
+                if BREAKS.contains(&tag.name.as_ref()) {
+                    self.new_paragraph();
+                }
+            }
+            Token::CharacterTokens(text) if self.open.borrow().is_some() => {
+                if let Some(paragraph) = self.paragraphs.borrow_mut().last_mut() {
+                    paragraph.push_str(&text);
+                }
+            }
+            _ => {}
+        }
+        TokenSinkResult::Continue
+    }
+}
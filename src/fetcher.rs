@@ -0,0 +1,884 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    io::Read,
+    path::PathBuf,
+    sync::{
+        Condvar, Mutex, MutexGuard,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use html5ever::tendril::StrTendril;
+use http::Uri;
+use ureq::Agent;
+
+use crate::{
+    Error, Result,
+    politeness::Profiles,
+    pool,
+    session::{Exchange, Session},
+    tls,
+};
+
+/// A successfully fetched page or image.
+pub struct Response {
+    /// The body, decompressed.
+    pub body: Vec<u8>,
+    pub content_type: Option<String>,
+    /// The size of the body as transferred, before decompression.
+    pub wire_bytes: usize,
+}
+
+/// Downloads pages and images, so the pipeline can run against something
+/// other than the network. Fetchers are shared between `--jobs` workers.
+pub trait Fetcher: Sync {
+    fn get(&self, url: &str) -> Result<Response>;
+
+    /// How many of `wanted` requests to `url`'s host may be in flight at
+    /// once.
+    fn jobs(&self, _url: &str, wanted: usize) -> usize {
+        wanted
+    }
+}
+
+/// Pauses between requests, so sites aren't hammered.
+#[derive(Debug, Clone, Copy)]
+pub struct Delays {
+    /// Waited after every successful request.
+    pub after_request: Duration,
+    /// Waited before the first retry of a 4xx; doubles on each further one.
+    pub backoff: Duration,
+}
+
+impl Default for Delays {
+    fn default() -> Self {
+        Delays {
+            after_request: Duration::from_millis(900),
+            backoff: Duration::from_millis(3000),
+        }
+    }
+}
+
+/// Bounds on how long fetching may go on, which retries check rather than
+/// sleeping through them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimeLimits {
+    /// When the run stops fetching altogether, e.g. from `--deadline`.
+    pub deadline: Option<Instant>,
+    /// The most one page may take, retries included.
+    pub per_page: Option<Duration>,
+}
+
+impl TimeLimits {
+    /// When a fetch starting now has to give up, and the reason it gives.
+    fn cutoff(&self) -> Option<(Instant, String)> {
+        let page = self
+            .per_page
+            .map(|limit| (Instant::now() + limit, format!("gave up after {limit:?}")));
+        let run = self
+            .deadline
+            .map(|at| (at, "the run's deadline passed".to_string()));
+        match (page, run) {
+            (Some(page), Some(run)) => Some(if page.0 < run.0 { page } else { run }),
+            (page, run) => page.or(run),
+        }
+    }
+}
+
+/// Browser user agents rotated through on 403s with `--rotate-user-agent`.
+pub const DEFAULT_USER_AGENTS: &[&str] = &[
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:125.0) Gecko/20100101 Firefox/125.0",
+    "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+];
+
+/// Fetches over HTTP, retrying 4xx responses other than 404 and 410 (sites
+/// rate-limit with 403/429) with exponential backoff and pausing after
+/// every request to stay polite.
+pub struct HttpFetcher {
+    agent: Agent,
+    delays: Delays,
+    time_limits: TimeLimits,
+    user_agents: Vec<String>,
+    /// The pool entry in use once a 403 rotated away from the default.
+    current_agent: Mutex<Option<usize>>,
+    dump: Option<Dump>,
+    recording: Option<Mutex<Vec<Exchange>>>,
+    politeness: Option<Politeness>,
+}
+
+/// The per-host profiles an [`HttpFetcher`] paces itself by.
+struct Politeness {
+    path: PathBuf,
+    profiles: Mutex<Profiles>,
+    adaptive: bool,
+    in_flight: AtomicUsize,
+}
+
+impl HttpFetcher {
+    pub fn new(agent: Agent) -> Self {
+        HttpFetcher {
+            agent,
+            delays: Delays::default(),
+            time_limits: TimeLimits::default(),
+            user_agents: Vec::new(),
+            current_agent: Mutex::new(None),
+            dump: None,
+            recording: None,
+            politeness: None,
+        }
+    }
+
+    pub fn delays(mut self, delays: Delays) -> Self {
+        self.delays = delays;
+        self
+    }
+
+    /// Stop retrying, and cut off requests in progress, at these limits.
+    pub fn time_limits(mut self, limits: TimeLimits) -> Self {
+        self.time_limits = limits;
+        self
+    }
+
+    /// Pace each host by its profile in `path`, see [`crate::politeness`]; with
+    /// `adaptive`, also learn from its responses and save what changes.
+    pub fn politeness(mut self, path: PathBuf, adaptive: bool) -> Self {
+        self.politeness = Some(Politeness {
+            profiles: Mutex::new(Profiles::load(&path)),
+            path,
+            adaptive,
+            in_flight: AtomicUsize::new(0),
+        });
+        self
+    }
+
+    /// Writes the learned profiles back, e.g. at the end of a run.
+    pub fn save_politeness(&self) -> anyhow::Result<()> {
+        match &self.politeness {
+            Some(p) if p.adaptive => p.profiles.lock().unwrap().save(&p.path),
+            _ => Ok(()),
+        }
+    }
+
+    fn learn(&self, host: Option<&str>, status: u16) {
+        let (Some(p), Some(host)) = (&self.politeness, host) else {
+            return;
+        };
+        if !p.adaptive {
+            return;
+        }
+        let mut profiles = p.profiles.lock().unwrap();
+        let in_flight = p.in_flight.load(Ordering::Relaxed);
+        if profiles.learn(host, self.delays.after_request, status == 429, in_flight)
+            && let Err(e) = profiles.save(&p.path)
+        {
+            log::warn!("failed to save {}: {e:#}", p.path.display());
+        }
+    }
+
+    /// On a 403, retry as the next user agent in `pool`, with the site's
+    /// origin as referer; the new agent sticks for later requests.
+    pub fn rotate_user_agents(mut self, pool: Vec<String>) -> Self {
+        self.user_agents = pool;
+        self
+    }
+
+    /// Write every request and response to numbered files in `dir`.
+    pub fn dump_http(mut self, dir: PathBuf) -> Self {
+        self.dump = Some(Dump {
+            dir,
+            next: AtomicUsize::new(0),
+        });
+        self
+    }
+
+    /// Keep every exchange for [`HttpFetcher::session`].
+    pub fn record(mut self) -> Self {
+        self.recording = Some(Mutex::new(Vec::new()));
+        self
+    }
+
+    /// The exchanges recorded so far, which are handed over only once.
+    pub fn session(&self) -> Option<Session> {
+        let recording = self.recording.as_ref()?;
+        Some(Session::new(std::mem::take(
+            &mut *recording.lock().unwrap(),
+        )))
+    }
+
+    fn rotate(&self, url: &str) {
+        if self.user_agents.is_empty() {
+            return;
+        }
+        let mut current = self.current_agent.lock().unwrap();
+        let next = current.map_or(0, |i| (i + 1) % self.user_agents.len());
+        *current = Some(next);
+        log::info!(
+            "{url}: HTTP 403, switching user agent to {:?}",
+            self.user_agents[next]
+        );
+    }
+
+    /// Sends one GET and reads the whole body, whatever the status, dumping
+    /// and recording the exchange. Gives up on the server after `timeout`.
+    fn request(&self, url: &str, timeout: Option<Duration>) -> Result<Raw> {
+        let mut headers = vec![("Accept-Encoding", "gzip".to_string())];
+        if let Some(i) = *self.current_agent.lock().unwrap() {
+            headers.push(("User-Agent", self.user_agents[i].clone()));
+            if let Ok(uri) = url.parse::<Uri>()
+                && let (Some(scheme), Some(authority)) = (uri.scheme_str(), uri.authority())
+            {
+                headers.push(("Referer", format!("{scheme}://{authority}/")));
+            }
+        }
+        let n = self.dump.as_ref().map(|dump| dump.request(url, &headers));
+
+        let sent = self.send(url, &headers, timeout);
+
+        if let (Some(dump), Some(n), Ok((parts, body))) = (&self.dump, n, &sent) {
+            dump.response(n, parts, body);
+        }
+        if let Some(recording) = &self.recording {
+            let text = |(name, value): (&str, &[u8])| {
+                (
+                    name.to_string(),
+                    String::from_utf8_lossy(value).into_owned(),
+                )
+            };
+            let (status, response_headers, body, error) = match &sent {
+                Ok((parts, body)) => (
+                    Some(parts.status.as_u16()),
+                    parts
+                        .headers
+                        .iter()
+                        .map(|(name, value)| text((name.as_str(), value.as_bytes())))
+                        .collect(),
+                    body.clone(),
+                    None,
+                ),
+                Err(e) => (None, Vec::new(), Vec::new(), Some(e.to_string())),
+            };
+            recording.lock().unwrap().push(Exchange {
+                url: url.to_string(),
+                request_headers: headers
+                    .iter()
+                    .map(|(name, value)| text((name, value.as_bytes())))
+                    .collect(),
+                status,
+                headers: response_headers,
+                body,
+                error,
+            });
+        }
+
+        let (parts, body) = sent.map_err(|e| Error::Fetch {
+            url: url.to_string(),
+            status: None,
+            reason: url
+                .parse::<Uri>()
+                .ok()
+                .and_then(|uri| tls::rejection(uri.host()?, &e))
+                .unwrap_or_else(|| e.to_string()),
+        })?;
+        let header = |name| parts.headers.get(name).and_then(|v| v.to_str().ok());
+        Ok(Raw {
+            status: parts.status.as_u16(),
+            content_type: header(http::header::CONTENT_TYPE).map(String::from),
+            wire_bytes: body.len(),
+            body: decode(url, header(http::header::CONTENT_ENCODING), body)?,
+        })
+    }
+
+    /// Sends a GET and reads the body; a body cut off partway is finished
+    /// by [`HttpFetcher::resume`], or downloaded again from the start.
+    fn send(
+        &self,
+        url: &str,
+        headers: &[(&str, String)],
+        timeout: Option<Duration>,
+    ) -> std::result::Result<(http::response::Parts, Vec<u8>), ureq::Error> {
+        let (parts, body) = self.call(url, headers, timeout)?;
+        let mut bytes = Vec::new();
+        let Err(cut) = body.into_reader().read_to_end(&mut bytes) else {
+            return Ok((parts, bytes));
+        };
+        if bytes.is_empty() {
+            return Err(cut.into());
+        }
+        if let Some(bytes) = self.resume(url, headers, timeout, &parts, bytes, cut)? {
+            return Ok((parts, bytes));
+        }
+        let (parts, body) = self.call(url, headers, timeout)?;
+        let mut bytes = Vec::new();
+        body.into_reader().read_to_end(&mut bytes)?;
+        Ok((parts, bytes))
+    }
+
+    fn call(
+        &self,
+        url: &str,
+        headers: &[(&str, String)],
+        timeout: Option<Duration>,
+    ) -> std::result::Result<(http::response::Parts, ureq::Body), ureq::Error> {
+        let mut request = self.agent.get(url);
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        pool::last_connection();
+        let response = request
+            .config()
+            .http_status_as_error(false)
+            .timeout_global(timeout)
+            .build()
+            .call()?;
+        match pool::last_connection() {
+            Some((id, 1)) => log::debug!("{url}: over new connection {id}"),
+            Some((id, n)) => log::debug!("{url}: reusing connection {id}, request {n} on it"),
+            None => {}
+        }
+        Ok(response.into_parts())
+    }
+
+    /// The rest of a body the transfer of `parts` cut off after `bytes`,
+    /// asked for with `Range` as long as the server takes byte ranges and
+    /// sent a `Content-Length` to check the whole against. `None` when it
+    /// has to be downloaded again from the start: the server doesn't take
+    /// ranges, sends the whole body anyway or its `ETag` has changed.
+    fn resume(
+        &self,
+        url: &str,
+        headers: &[(&str, String)],
+        timeout: Option<Duration>,
+        parts: &http::response::Parts,
+        mut bytes: Vec<u8>,
+        mut cut: std::io::Error,
+    ) -> std::result::Result<Option<Vec<u8>>, ureq::Error> {
+        let ranges = header(parts, http::header::ACCEPT_RANGES)
+            .is_some_and(|r| r.split(',').any(|unit| unit.trim() == "bytes"));
+        let length =
+            header(parts, http::header::CONTENT_LENGTH).and_then(|l| l.parse::<usize>().ok());
+        let etag = header(parts, http::header::ETAG);
+        let (true, true, Some(length)) = (parts.status.is_success(), ranges, length) else {
+            log::info!(
+                "{url}: cut off after {} bytes ({cut}), downloading it again",
+                bytes.len()
+            );
+            return Ok(None);
+        };
+
+        for _ in 0..RESUMES {
+            log::info!(
+                "{url}: cut off after {} of {length} bytes ({cut}), resuming",
+                bytes.len()
+            );
+            let mut ranged = headers.to_vec();
+            ranged.push(("Range", format!("bytes={}-", bytes.len())));
+            // A weak ETag can't vouch for the bytes.
+            if let Some(etag) = etag.filter(|e| !e.starts_with("W/")) {
+                ranged.push(("If-Range", etag.to_string()));
+            }
+            let (more, body) = self.call(url, &ranged, timeout)?;
+            let starts = header(&more, http::header::CONTENT_RANGE)
+                .and_then(|r| r.strip_prefix("bytes "))
+                .and_then(|r| r.split_once('-'))
+                .and_then(|(start, _)| start.parse::<usize>().ok());
+            if more.status != 206
+                || starts != Some(bytes.len())
+                || header(&more, http::header::ETAG) != etag
+            {
+                log::info!(
+                    "{url}: HTTP {} instead of the rest of the body, downloading it again",
+                    more.status.as_u16()
+                );
+                return Ok(None);
+            }
+            match body.into_reader().read_to_end(&mut bytes) {
+                Ok(_) if bytes.len() == length => return Ok(Some(bytes)),
+                Ok(_) => {
+                    log::info!(
+                        "{url}: resumed body is {} bytes, not {length}, downloading it again",
+                        bytes.len()
+                    );
+                    return Ok(None);
+                }
+                Err(e) => cut = e,
+            }
+        }
+        Err(cut.into())
+    }
+}
+
+impl Fetcher for HttpFetcher {
+    fn get(&self, url: &str) -> Result<Response> {
+        let host = host_of(url);
+        let mut delays = self.delays;
+        if let (Some(p), Some(host)) = (&self.politeness, &host) {
+            delays.after_request = p.profiles.lock().unwrap().delay(host, delays.after_request);
+            p.in_flight.fetch_add(1, Ordering::Relaxed);
+        }
+        let cutoff = self.time_limits.cutoff();
+        let until = cutoff.as_ref().map(|(at, _)| *at);
+        let response = retrying(
+            url,
+            delays,
+            cutoff.clone(),
+            || {
+                let raw = self.request(
+                    url,
+                    until.map(|at| at.saturating_duration_since(Instant::now())),
+                )?;
+                self.learn(host.as_deref(), raw.status);
+                Ok(raw)
+            },
+            || self.rotate(url),
+        );
+        if let (Some(p), Some(_)) = (&self.politeness, &host) {
+            p.in_flight.fetch_sub(1, Ordering::Relaxed);
+        }
+        response
+    }
+
+    fn jobs(&self, url: &str, wanted: usize) -> usize {
+        match (&self.politeness, host_of(url)) {
+            (Some(p), Some(host)) => p.profiles.lock().unwrap().jobs(&host, wanted),
+            _ => wanted,
+        }
+    }
+}
+
+fn header(parts: &http::response::Parts, name: http::header::HeaderName) -> Option<&str> {
+    parts
+        .headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+}
+
+fn host_of(url: &str) -> Option<String> {
+    Some(url.parse::<Uri>().ok()?.host()?.to_ascii_lowercase())
+}
+
+/// A response as received, before its status is acted on.
+pub(crate) struct Raw {
+    pub(crate) status: u16,
+    pub(crate) content_type: Option<String>,
+    /// Decompressed by [`decode`].
+    pub(crate) body: Vec<u8>,
+    pub(crate) wire_bytes: usize,
+}
+
+/// How many times in a row a cut-off body is resumed before giving up.
+const RESUMES: usize = 3;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Decompresses a body sent with `Content-Encoding: encoding`. Servers
+/// don't always tell the truth, so the gzip magic decides: a body claimed to
+/// be gzip that doesn't start with it is taken as is, and one that does is
+/// decompressed whatever the header says.
+pub(crate) fn decode(url: &str, encoding: Option<&str>, body: Vec<u8>) -> Result<Vec<u8>> {
+    let encoding = encoding.map(|e| e.trim().to_ascii_lowercase());
+    let gzipped = body.starts_with(GZIP_MAGIC);
+    match encoding.as_deref() {
+        None | Some("" | "identity") if !gzipped => return Ok(body),
+        None | Some("" | "identity" | "gzip" | "x-gzip") => {}
+        Some(other) => {
+            return Err(Error::Fetch {
+                url: url.to_string(),
+                status: None,
+                reason: format!("unsupported Content-Encoding {other}"),
+            });
+        }
+    }
+    if !gzipped {
+        log::debug!("{url}: sent as gzip but isn't, reading it as is");
+        return Ok(body);
+    }
+    let mut decoded = Vec::with_capacity(body.len() * 4);
+    flate2::read::MultiGzDecoder::new(body.as_slice())
+        .read_to_end(&mut decoded)
+        .map_err(|e| Error::Fetch {
+            url: url.to_string(),
+            status: None,
+            reason: format!("corrupt gzip body: {e}"),
+        })?;
+    Ok(decoded)
+}
+
+/// Repeats `send` until it gets a 2xx: 404 and 410 fail at once, other 4xx
+/// are retried with backoff (calling `on_403` before retrying a 403) and
+/// anything else fails. Past `cutoff`, it fails with the reason given
+/// instead of sending again, and it never sleeps beyond it.
+pub(crate) fn retrying(
+    url: &str,
+    delays: Delays,
+    cutoff: Option<(Instant, String)>,
+    mut send: impl FnMut() -> Result<Raw>,
+    mut on_403: impl FnMut(),
+) -> Result<Response> {
+    let mut retries = 3;
+    let mut delay = delays.backoff;
+    let fetch_error = |status, reason: String| Error::Fetch {
+        url: url.to_string(),
+        status: Some(status),
+        reason,
+    };
+
+    let mut last_status = None;
+    loop {
+        if let Some((at, reason)) = &cutoff
+            && Instant::now() >= *at
+        {
+            return Err(Error::Fetch {
+                url: url.to_string(),
+                status: last_status,
+                reason: reason.clone(),
+            });
+        }
+        let raw = send()?;
+        last_status = Some(raw.status);
+        match raw.status {
+            200..=299 => {
+                thread::sleep(delays.after_request);
+                return Ok(Response {
+                    body: raw.body,
+                    content_type: raw.content_type,
+                    wire_bytes: raw.wire_bytes,
+                });
+            }
+            // Gone pages won't come back on a retry.
+            code @ (404 | 410) => return Err(fetch_error(code, format!("HTTP {code}"))),
+            code @ 400..=499 => {
+                retries -= 1;
+                if retries == 0 {
+                    return Err(fetch_error(
+                        code,
+                        format!("HTTP {code}, giving up after 3 attempts"),
+                    ));
+                }
+                log::warn!("{url}: HTTP {code}, retrying in {delay:?}");
+                if code == 403 {
+                    on_403();
+                }
+                let left = cutoff.as_ref().map_or(delay, |(at, _)| {
+                    at.saturating_duration_since(Instant::now())
+                });
+                thread::sleep(delay.min(left));
+                delay *= 2;
+            }
+            code => return Err(fetch_error(code, format!("HTTP {code}"))),
+        }
+    }
+}
+
+/// Writes each exchange to `NNNN-request.txt` and `NNNN-response.txt` in a
+/// directory. Response bodies are the bytes as received, before any charset
+/// decoding, so encoding problems show up in the dump.
+struct Dump {
+    dir: PathBuf,
+    next: AtomicUsize,
+}
+
+impl Dump {
+    /// Dumps the request line and the headers set for it, returning its
+    /// number. The agent's own defaults (user agent, cookies) aren't listed
+    /// unless overridden.
+    fn request(&self, url: &str, headers: &[(&str, String)]) -> usize {
+        let n = self.next.fetch_add(1, Ordering::Relaxed) + 1;
+        let uri = url.parse::<Uri>().ok();
+        let target = uri
+            .as_ref()
+            .and_then(|u| u.path_and_query())
+            .map_or("/", |p| p.as_str());
+        let mut text = format!("GET {target} HTTP/1.1\r\n");
+        if let Some(authority) = uri.as_ref().and_then(|u| u.authority()) {
+            text.push_str(&format!("Host: {authority}\r\n"));
+        }
+        for (name, value) in headers {
+            text.push_str(&format!("{name}: {value}\r\n"));
+        }
+
+        let path = self.dir.join(format!("{n:04}-request.txt"));
+        self.write(&path, text.as_bytes());
+        log::info!("{n:04}: GET {url}");
+        n
+    }
+
+    fn response(&self, n: usize, parts: &http::response::Parts, body: &[u8]) {
+        let mut bytes = format!("{:?} {}\r\n", parts.version, parts.status).into_bytes();
+        for (name, value) in &parts.headers {
+            bytes.extend_from_slice(name.as_str().as_bytes());
+            bytes.extend_from_slice(b": ");
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.extend_from_slice(b"\r\n");
+        }
+        bytes.extend_from_slice(b"\r\n");
+        bytes.extend_from_slice(body);
+        self.write(&self.dir.join(format!("{n:04}-response.txt")), &bytes);
+    }
+
+    /// A failed dump is only worth a warning; the fetch itself went fine.
+    fn write(&self, path: &std::path::Path, bytes: &[u8]) {
+        if let Err(e) = fs::create_dir_all(&self.dir).and_then(|()| fs::write(path, bytes)) {
+            log::warn!("Failed to write {}: {e}", path.display());
+        }
+    }
+}
+
+/// Serves canned bodies or error statuses by exact URL and answers
+/// everything else with a 404, recording each request. URLs are matched as
+/// [`Uri`] displays them, so a bare host needs its trailing slash.
+#[derive(Default)]
+pub struct MemoryFetcher {
+    pages: HashMap<String, std::result::Result<Vec<u8>, u16>>,
+    requests: Mutex<Vec<String>>,
+}
+
+impl MemoryFetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn page(mut self, url: &str, body: impl Into<Vec<u8>>) -> Self {
+        self.pages.insert(url.to_string(), Ok(body.into()));
+        self
+    }
+
+    /// Fails every request for `url` with HTTP `status`.
+    pub fn status(mut self, url: &str, status: u16) -> Self {
+        self.pages.insert(url.to_string(), Err(status));
+        self
+    }
+
+    /// Every URL requested so far, in order.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Fetcher for MemoryFetcher {
+    fn get(&self, url: &str) -> Result<Response> {
+        self.requests.lock().unwrap().push(url.to_string());
+        match self.pages.get(url).cloned().unwrap_or(Err(404)) {
+            Ok(body) => Ok(Response {
+                wire_bytes: body.len(),
+                body,
+                content_type: None,
+            }),
+            Err(status) => Err(Error::Fetch {
+                url: url.to_string(),
+                status: Some(status),
+                reason: format!("HTTP {status}"),
+            }),
+        }
+    }
+}
+
+/// Counts the body bytes of every response fetched through it.
+pub(crate) struct Metered<'a, F> {
+    inner: &'a F,
+    bytes: AtomicUsize,
+    wire_bytes: AtomicUsize,
+    /// With `--rebuild`, every request fails without being sent.
+    offline: bool,
+}
+
+impl<'a, F: Fetcher> Metered<'a, F> {
+    pub(crate) fn new(inner: &'a F) -> Self {
+        Metered {
+            inner,
+            bytes: AtomicUsize::new(0),
+            wire_bytes: AtomicUsize::new(0),
+            offline: false,
+        }
+    }
+
+    /// A meter that sends nothing, failing every request instead.
+    pub(crate) fn offline(inner: &'a F) -> Self {
+        Metered {
+            offline: true,
+            ..Metered::new(inner)
+        }
+    }
+
+    /// Body bytes received, decompressed.
+    pub(crate) fn total(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Body bytes as transferred.
+    pub(crate) fn wire_total(&self) -> usize {
+        self.wire_bytes.load(Ordering::Relaxed)
+    }
+}
+
+impl<F: Fetcher> Fetcher for Metered<'_, F> {
+    fn get(&self, url: &str) -> Result<Response> {
+        if self.offline {
+            return Err(Error::Fetch {
+                url: url.to_string(),
+                status: None,
+                reason: "not downloaded with --rebuild".to_string(),
+            });
+        }
+        let response = self.inner.get(url)?;
+        self.bytes.fetch_add(response.body.len(), Ordering::Relaxed);
+        self.wire_bytes
+            .fetch_add(response.wire_bytes, Ordering::Relaxed);
+        Ok(response)
+    }
+
+    fn jobs(&self, url: &str, wanted: usize) -> usize {
+        self.inner.jobs(url, wanted)
+    }
+}
+
+/// How many chapters per job [`Prefetcher`] fetches past the one being
+/// assembled, so a slow page doesn't leave the other workers idle, and a
+/// long book isn't held in memory.
+const AHEAD_PER_JOB: usize = 4;
+
+/// Serves pages fetched ahead of time, each once, and fetches anything else
+/// (images, fallback chapters) through the inner fetcher as it's asked for.
+///
+/// [`start`](Prefetcher::start) sets `jobs` workers going through the
+/// chapters, each taking the next as soon as it's done with one, up to
+/// [`AHEAD_PER_JOB`] per job past where [`at`](Prefetcher::at) says
+/// assembly is. A slow page holds up only its own chapter.
+pub(crate) struct Prefetcher<'a, F> {
+    inner: &'a F,
+    jobs: usize,
+    queue: Mutex<Queue>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+    /// Chapters not yet taken by a worker, by place in the book.
+    pending: VecDeque<(usize, String)>,
+    fetching: HashSet<String>,
+    ready: HashMap<String, Result<Response>>,
+    /// The place of the chapter being assembled.
+    at: usize,
+}
+
+/// Stops [`Prefetcher::start`]'s workers taking more chapters when dropped.
+pub(crate) struct Running<'p> {
+    queue: &'p Mutex<Queue>,
+    changed: &'p Condvar,
+}
+
+impl<'a, F: Fetcher> Prefetcher<'a, F> {
+    pub(crate) fn new(inner: &'a F, jobs: usize) -> Self {
+        Prefetcher {
+            inner,
+            jobs: jobs.max(1),
+            queue: Mutex::new(Queue::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Starts fetching `chapters`, pairs of place and URL in order, on
+    /// `scope`. With one job, they're fetched as they're asked for instead.
+    pub(crate) fn start<'scope>(
+        &'scope self,
+        scope: &'scope thread::Scope<'scope, '_>,
+        chapters: Vec<(usize, String)>,
+    ) -> Running<'scope> {
+        if self.jobs > 1 {
+            let workers = self.jobs.min(chapters.len());
+            self.lock().pending = chapters.into();
+            for _ in 0..workers {
+                scope.spawn(|| self.work());
+            }
+        }
+        Running {
+            queue: &self.queue,
+            changed: &self.changed,
+        }
+    }
+
+    /// Tells the workers assembly has got to place `at`.
+    pub(crate) fn at(&self, at: usize) {
+        self.lock().at = at;
+        self.changed.notify_all();
+    }
+
+    fn work(&self) {
+        loop {
+            let mut queue = self.lock();
+            let url = loop {
+                let at = queue.at;
+                match queue.pending.front() {
+                    None => return,
+                    // Passed without being asked for, e.g. kept from the
+                    // last build.
+                    Some((place, _)) if *place < at => {
+                        queue.pending.pop_front();
+                    }
+                    Some((place, url)) if *place < at + self.jobs * AHEAD_PER_JOB => {
+                        let url = url.clone();
+                        queue.pending.pop_front();
+                        break url;
+                    }
+                    Some(_) => {
+                        queue = self.changed.wait(queue).unwrap_or_else(|e| e.into_inner());
+                    }
+                }
+            };
+            queue.fetching.insert(url.clone());
+            drop(queue);
+
+            let response = self.inner.get(&url);
+            let mut queue = self.lock();
+            queue.fetching.remove(&url);
+            queue.ready.insert(url, response);
+            self.changed.notify_all();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.pending.clear();
+        self.changed.notify_all();
+    }
+}
+
+impl<F: Fetcher> Fetcher for Prefetcher<'_, F> {
+    fn get(&self, url: &str) -> Result<Response> {
+        let mut queue = self.lock();
+        loop {
+            if let Some(response) = queue.ready.remove(url) {
+                return response;
+            }
+            if !queue.fetching.contains(url) {
+                break;
+            }
+            queue = self.changed.wait(queue).unwrap_or_else(|e| e.into_inner());
+        }
+        // Not taken by a worker yet, so fetched here instead.
+        queue.pending.retain(|(_, pending)| pending != url);
+        drop(queue);
+        self.inner.get(url)
+    }
+}
+
+pub fn fetch_page(fetcher: &impl Fetcher, url: &Uri) -> Result<StrTendril> {
+    let url = url.to_string();
+    let response = fetcher.get(&url)?;
+    let text = String::from_utf8(response.body).map_err(|_| Error::Parse {
+        url,
+        what: "UTF-8 text".to_string(),
+    })?;
+    Ok(StrTendril::from(text))
+}
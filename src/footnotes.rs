@@ -0,0 +1,69 @@
+use std::borrow::Cow;
+
+use crate::fetch::{NOTE_MARKER_END, NOTE_MARKER_START};
+
+/// Replaces footnote markers in chapter `index`'s markup with links to its
+/// notes and appends the notes themselves.
+///
+/// EPUB 3 gets `noteref` anchors and `footnote` asides, which most readers
+/// show as pop-ups; EPUB 2 gets plain in-page links with back-references.
+/// Ids carry the chapter index so they're unique across the book.
+pub fn render<'a>(text: &'a str, notes: &[String], index: usize, epub3: bool) -> Cow<'a, str> {
+    if notes.is_empty() && !text.contains(NOTE_MARKER_START) {
+        return Cow::Borrowed(text);
+    }
+
+    let note_id = |n: usize| format!("note-{index}-{n}");
+    let ref_id = |n: usize| format!("noteref-{index}-{n}");
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(NOTE_MARKER_START) {
+        out.push_str(&rest[..start]);
+        let tail = &rest[start + NOTE_MARKER_START.len_utf8()..];
+        let Some(end) = tail.find(NOTE_MARKER_END) else {
+            rest = tail;
+            break;
+        };
+        let marker: Option<usize> = tail[..end].parse().ok();
+        rest = &tail[end + NOTE_MARKER_END.len_utf8()..];
+
+        // Markers without a matching note are dropped.
+        let Some(n) = marker.filter(|m| *m < notes.len()).map(|m| m + 1) else {
+            continue;
+        };
+        let noteref = if epub3 { r#" epub:type="noteref""# } else { "" };
+        out.push_str(&format!(
+            r##"<a{noteref} id="{}" href="#{}"><sup>{n}</sup></a>"##,
+            ref_id(n),
+            note_id(n)
+        ));
+    }
+    out.push_str(rest);
+
+    if notes.is_empty() {
+        return Cow::Owned(out);
+    }
+
+    if epub3 {
+        for (i, note) in notes.iter().enumerate() {
+            let n = i + 1;
+            out.push_str(&format!(
+                r#"<aside epub:type="footnote" class="footnote" id="{}"><p>{n}. {note}</p></aside>"#,
+                note_id(n)
+            ));
+        }
+    } else {
+        out.push_str(r#"<div class="footnotes">"#);
+        for (i, note) in notes.iter().enumerate() {
+            let n = i + 1;
+            out.push_str(&format!(
+                r##"<p class="footnote" id="{}"><a href="#{}">{n}</a>. {note}</p>"##,
+                note_id(n),
+                ref_id(n)
+            ));
+        }
+        out.push_str("</div>");
+    }
+    Cow::Owned(out)
+}
@@ -0,0 +1,265 @@
+//! Chapter pages generated by earlier builds, so a rebuild from cached
+//! chapters can skip rendering those that haven't changed.
+//!
+//! Each build keeps the XHTML of every chapter page it generated in the
+//! book's work directory, keyed by a hash of the chapter's cleaned text and
+//! every option that shapes the page: the format, the footer, heading rules,
+//! the page size limit, the stylesheet's writing mode and so on. A chapter
+//! whose hash matches is added to the book as it was last time; changing
+//! any of those options changes every hash. Chapters with images are always
+//! rendered, since their images have to be added to the book anew.
+//!
+//! `generated.json` lists the pages; the XHTML itself is written to
+//! `pages/`, a directory per hash, as each chapter is generated, and read
+//! back one chapter at a time, so a build never holds more than the
+//! chapter it is on.
+//!
+//! `--update` goes further: a chapter the last build had is taken from here
+//! without being downloaded at all, as long as the options and its place in
+//! the book are the same.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{BuildOptions, checkpoint, fallback::Provenance, fetch::Chapter, stats, workdir};
+
+/// Bumped whenever the stored format changes; pages written with another
+/// version are discarded.
+pub const VERSION: u32 = 2;
+const FILE: &str = "generated.json";
+const PAGES: &str = "pages";
+
+/// The pages of each chapter, by chapter URL.
+#[derive(Serialize, Deserialize)]
+pub struct Generated {
+    version: u32,
+    /// The [`fingerprint`] of the build that generated the pages.
+    #[serde(default)]
+    shaping: u64,
+    chapters: BTreeMap<String, Pages>,
+    /// Where the XHTML is kept; none for pages that are only listed.
+    #[serde(skip)]
+    dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Pages {
+    /// See [`key`].
+    pub key: String,
+    /// The chapter's place in the book, which its file names and footnote
+    /// ids follow.
+    pub index: usize,
+    pub title: String,
+    /// Where the chapter was downloaded from, the fallback mirror's URL for
+    /// a fallback chapter.
+    pub url: String,
+    pub provenance: Provenance,
+    /// The chapter's length, in its [`stats::Unit`].
+    pub length: usize,
+    /// Each file's name and XHTML, in spine order. Only the names are
+    /// listed in `generated.json`; the XHTML is in `pages/<key>/`.
+    #[serde(with = "names")]
+    pub files: Vec<(String, String)>,
+    /// The table of contents entries under the chapter: target and title.
+    pub sections: Vec<(String, String)>,
+}
+
+impl Default for Generated {
+    fn default() -> Self {
+        Generated {
+            version: VERSION,
+            shaping: 0,
+            chapters: BTreeMap::new(),
+            dir: None,
+        }
+    }
+}
+
+impl Generated {
+    /// Pages to be generated by a build with the given [`fingerprint`],
+    /// kept in the work directory `dir`.
+    pub fn new(dir: &Path, shaping: u64) -> Self {
+        Generated {
+            shaping,
+            dir: Some(dir.join(PAGES)),
+            ..Generated::default()
+        }
+    }
+
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(FILE);
+        let Ok(json) = fs::read_to_string(&path) else {
+            return Generated::default();
+        };
+        match serde_json::from_str::<Header>(&json) {
+            Ok(header) if header.version != VERSION => {
+                log::warn!(
+                    "discarding {}: written by format version {}, expected {VERSION}",
+                    path.display(),
+                    header.version
+                );
+                return Generated::default();
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::warn!("discarding {}: {e}", path.display());
+                return Generated::default();
+            }
+        }
+        match serde_json::from_str::<Generated>(&json) {
+            Ok(generated) => Generated {
+                dir: Some(dir.join(PAGES)),
+                ..generated
+            },
+            Err(e) => {
+                log::warn!("discarding {}: {e}", path.display());
+                Generated::default()
+            }
+        }
+    }
+
+    /// Replaces the list in `dir`, removing the XHTML of pages no longer
+    /// on it.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        checkpoint::write_atomic(&dir.join(FILE), &serde_json::to_vec(self)?)?;
+        let Ok(entries) = fs::read_dir(dir.join(PAGES)) else {
+            return Ok(());
+        };
+        let keys: HashSet<&str> = self
+            .chapters
+            .values()
+            .map(|pages| pages.key.as_str())
+            .collect();
+        for entry in entries.flatten() {
+            if !entry
+                .file_name()
+                .to_str()
+                .is_some_and(|key| keys.contains(key))
+            {
+                let _ = fs::remove_dir_all(entry.path());
+            }
+        }
+        Ok(())
+    }
+
+    /// The pages generated for the chapter at `url`, if they were generated
+    /// from what `key` was; [`read`](Generated::read) has their XHTML.
+    pub fn get(&self, url: &str, key: &str) -> Option<&Pages> {
+        self.chapters.get(url).filter(|pages| pages.key == key)
+    }
+
+    /// The pages generated for the chapter at `url` when it was chapter
+    /// `index` of a build with the same [`fingerprint`], whatever its text
+    /// now is.
+    pub fn reusable(&self, url: &str, index: usize, shaping: u64) -> Option<&Pages> {
+        self.chapters
+            .get(url)
+            .filter(|pages| self.shaping == shaping && pages.index == index)
+    }
+
+    /// Lists `pages` for the chapter at `url`, writing out their XHTML
+    /// unless pages with the same key already were.
+    pub fn insert(&mut self, url: String, mut pages: Pages) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            let dir = dir.join(&pages.key);
+            for (name, page) in &mut pages.files {
+                let path = dir.join(&*name);
+                if !path.exists() {
+                    checkpoint::write_atomic(&path, page.as_bytes())?;
+                }
+                *page = String::new();
+            }
+        }
+        self.chapters.insert(url, pages);
+        Ok(())
+    }
+
+    /// `pages` with their XHTML read back in, if every file is still there.
+    pub fn read(&self, pages: &Pages) -> Option<Pages> {
+        let dir = self.dir.as_ref()?.join(&pages.key);
+        let mut read = pages.clone();
+        for (name, page) in &mut read.files {
+            let path = dir.join(&*name);
+            match fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))
+            {
+                Ok(xhtml) => *page = xhtml,
+                Err(e) => {
+                    log::warn!("not reusing the pages of {}: {e:#}", pages.url);
+                    return None;
+                }
+            }
+        }
+        Some(read)
+    }
+}
+
+/// [`Pages::files`] as only their names.
+mod names {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        files: &[(String, String)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(files.iter().map(|(name, _)| name))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(String, String)>, D::Error> {
+        let names = Vec::<String>::deserialize(deserializer)?;
+        Ok(names
+            .into_iter()
+            .map(|name| (name, String::new()))
+            .collect())
+    }
+}
+
+/// A hash of the options that shape every chapter page, for [`key`].
+pub fn fingerprint(options: &BuildOptions, unit: stats::Unit) -> u64 {
+    let shaping = format!(
+        "{} {:?} {} {:?} {} {:?} {} {:?} {:?} {:?} {} {}",
+        env!("CARGO_PKG_VERSION"),
+        options.format,
+        options.epub2,
+        options.headings,
+        options.max_chapter_size,
+        options.writing_mode,
+        options.language,
+        options.images,
+        unit,
+        options.chapter_footer,
+        options.merge_softwrap,
+        options.per_paragraph_lang,
+    );
+    workdir::fnv1a(shaping.as_bytes())
+}
+
+/// What chapter `index`'s pages are generated from: its content, its
+/// footer as rendered and the build's [`fingerprint`].
+pub fn key(fingerprint: u64, index: usize, chapter: &Chapter, footer: Option<&str>) -> String {
+    let mut hashed = format!("{fingerprint:016x} {index}");
+    for piece in [chapter.title.as_str(), &chapter.text]
+        .into_iter()
+        .chain(chapter.notes.iter().map(String::as_str))
+        .chain(footer)
+    {
+        // Length-prefixed, so moving text from one piece to the next
+        // changes the hash.
+        hashed.push_str(&format!("\n{}:", piece.len()));
+        hashed.push_str(piece);
+    }
+    format!("{:016x}", workdir::fnv1a(hashed.as_bytes()))
+}
@@ -0,0 +1,129 @@
+use std::borrow::Cow;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use crate::xhtml;
+
+/// Section markers such as "（一）", "Part 2" and lines in 【brackets】.
+const DEFAULT_HEADING_RULES: &[&str] = &[
+    r"^[（(][一二三四五六七八九十百〇零两\d０-９]{1,4}[）)]$",
+    r"^(?i:part|section)\s*[\d０-９]+\b.{0,40}$",
+    r"^【[^】]{1,30}】$",
+];
+
+pub const DEFAULT_MAX_SUBHEADINGS: usize = 30;
+
+/// Regexes for lines inside chapter text that start a sub-section.
+#[derive(Debug)]
+pub struct HeadingRules {
+    rules: Vec<Regex>,
+    /// Cap on sub-entries per chapter, so a too-broad pattern can't flood
+    /// the table of contents.
+    pub max: usize,
+}
+
+impl Default for HeadingRules {
+    fn default() -> Self {
+        HeadingRules {
+            rules: Vec::new(),
+            max: DEFAULT_MAX_SUBHEADINGS,
+        }
+    }
+}
+
+impl HeadingRules {
+    pub fn new(use_defaults: bool, extra: &[String], max: usize) -> Result<Self> {
+        let mut rules = Vec::new();
+        if use_defaults {
+            rules.extend(
+                DEFAULT_HEADING_RULES
+                    .iter()
+                    .map(|r| Regex::new(r).expect("valid default heading rule")),
+            );
+        }
+        for rule in extra {
+            rules.push(
+                Regex::new(rule).with_context(|| format!("Invalid --heading-pattern: {rule}"))?,
+            );
+        }
+        Ok(HeadingRules { rules, max })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// The patterns of the rules, in the order they are tried.
+    pub fn patterns(&self) -> impl Iterator<Item = &str> {
+        self.rules.iter().map(Regex::as_str)
+    }
+}
+
+pub struct Heading {
+    pub id: String,
+    pub title: String,
+}
+
+/// Promotes matching top-level lines of chapter `index`'s markup to
+/// `<h2 class="section">`, returning the new markup and the headings found
+/// in order. At most `rules.max` lines are promoted.
+pub fn promote<'a>(
+    body: &'a str,
+    rules: &HeadingRules,
+    index: usize,
+) -> (Cow<'a, str>, Vec<Heading>) {
+    let mut headings = Vec::new();
+    if !rules.is_enabled() {
+        return (Cow::Borrowed(body), headings);
+    }
+
+    let mut out = String::with_capacity(body.len());
+    let mut depth = 0usize;
+    let mut lines = body.split("<br />").peekable();
+
+    while let Some(line) = lines.next() {
+        let text = line.trim();
+        let is_heading = depth == 0
+            && headings.len() < rules.max
+            && !text.is_empty()
+            && !text.contains('<')
+            && rules.rules.iter().any(|r| r.is_match(text));
+        depth = nesting_after(depth, line);
+
+        if is_heading {
+            let id = format!("section-{index}-{}", headings.len() + 1);
+            out.push_str(&format!(r#"<h2 class="section" id="{id}">{text}</h2>"#));
+            headings.push(Heading {
+                id,
+                title: xhtml::unescape(text),
+            });
+            continue;
+        }
+
+        out.push_str(line);
+        if lines.peek().is_some() {
+            out.push_str("<br />");
+        }
+    }
+
+    (Cow::Owned(out), headings)
+}
+
+/// Element depth after `markup`, starting at `depth`.
+pub(crate) fn nesting_after(mut depth: usize, markup: &str) -> usize {
+    let mut rest = markup;
+    while let Some(start) = rest.find('<') {
+        let Some(end) = rest[start..].find('>') else {
+            break;
+        };
+        let tag = &rest[start..start + end + 1];
+        if tag.starts_with("</") {
+            depth = depth.saturating_sub(1);
+        } else if !tag.ends_with("/>") {
+            depth += 1;
+        }
+        rest = &rest[start + end + 1..];
+    }
+    depth
+}
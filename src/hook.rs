@@ -0,0 +1,105 @@
+//! `--post-hook`: a shell command run on every file a build writes.
+
+use std::{
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+};
+
+use anyhow::{Context, Result};
+use epub_dude::Summary;
+
+pub struct Hook {
+    pub command: String,
+    /// Report a failing hook without failing the run.
+    pub ignore_failure: bool,
+}
+
+impl Hook {
+    /// Runs the hook once per file in `summary`, streaming its output through
+    /// the log. Returns the exit status to fail the run with, if any.
+    pub fn run(&self, summary: &Summary) -> Option<i32> {
+        let level = if self.ignore_failure {
+            log::Level::Warn
+        } else {
+            log::Level::Error
+        };
+        for (i, file) in summary.files.iter().enumerate() {
+            let part = (summary.files.len() > 1).then_some(i + 1);
+            let status = match self.run_on(summary, file, part) {
+                Ok(0) => continue,
+                Ok(code) => {
+                    log::log!(
+                        level,
+                        "post hook exited with status {code} for {}",
+                        file.display()
+                    );
+                    code
+                }
+                Err(e) => {
+                    log::log!(level, "post hook failed for {}: {e:#}", file.display());
+                    epub_dude::exit_code::FAILURE
+                }
+            };
+            if !self.ignore_failure {
+                return Some(status);
+            }
+        }
+        None
+    }
+
+    fn run_on(&self, summary: &Summary, file: &Path, part: Option<usize>) -> Result<i32> {
+        let mut command = shell(&self.command);
+        command
+            .env("EPUB_DUDE_OUTPUT", file)
+            .env("EPUB_DUDE_TITLE", &summary.title)
+            .env("EPUB_DUDE_AUTHOR", summary.authors.join(", "))
+            .env("EPUB_DUDE_SOURCE", &summary.source)
+            .env("EPUB_DUDE_CHAPTERS", summary.chapters.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(part) = part {
+            command.env("EPUB_DUDE_PART", part.to_string());
+        }
+        log::debug!("running post hook for {}", file.display());
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("Failed to start {:?}", self.command))?;
+
+        let stdout = child.stdout.take().expect("piped stdout");
+        let stderr = child.stderr.take().expect("piped stderr");
+        thread::scope(|scope| {
+            scope.spawn(|| forward(stdout, log::Level::Info));
+            scope.spawn(|| forward(stderr, log::Level::Warn));
+        });
+
+        let status = child.wait().context("Failed to wait for the post hook")?;
+        // Killed by a signal, there is no code to pass on.
+        Ok(status.code().unwrap_or(epub_dude::exit_code::FAILURE))
+    }
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.args(["/C", command]);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.args(["-c", command]);
+    shell
+}
+
+/// Logs each line of the hook's output as it comes.
+fn forward(output: impl Read, level: log::Level) {
+    for line in BufReader::new(output).split(b'\n') {
+        let Ok(line) = line else { break };
+        let line = String::from_utf8_lossy(&line);
+        log::log!(level, "[hook] {}", line.trim_end_matches('\r'));
+    }
+}
@@ -0,0 +1,73 @@
+//! `--format html`: the whole book as one self-contained HTML5 page.
+//!
+//! The stylesheet is inlined and images, with `--images`, are data URIs, so
+//! the page opens offline without a single request.
+
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+
+use crate::{plain::PlainChapter, xhtml};
+
+/// Keeps the contents list compact and chapters apart.
+const EXTRA_STYLE: &str = "nav.toc ol {\n  padding-left: 1.5em;\n}\nsection.chapter {\n  margin-top: 3em;\n}\n\
+    header p.description {\n  font-size: 0.9em;\n}\n";
+
+pub struct Page<'a> {
+    pub title: &'a str,
+    pub authors: &'a [String],
+    pub language: &'a str,
+    pub writing_mode: xhtml::WritingMode,
+    pub theme: xhtml::Theme,
+    pub typography: &'a xhtml::Typography,
+    pub chapters: &'a [PlainChapter],
+    /// With `--toc-description`, the description's paragraphs, shown above
+    /// the table of contents.
+    pub description: Option<String>,
+}
+
+/// Writes `page` to `path`: a heading, the description if given, a linked
+/// table of contents, then every chapter under its own anchored heading.
+pub fn write(path: &Path, page: &Page) -> Result<()> {
+    let title = xhtml::escape(page.title);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html lang=\"{}\">\n<head>\n<meta charset=\"utf-8\" />\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\" />\n\
+         <title>{title}</title>\n<style>\n{}{EXTRA_STYLE}</style>\n</head>\n<body>\n",
+        xhtml::escape(page.language),
+        xhtml::stylesheet(page.writing_mode, page.theme, page.typography),
+    );
+
+    out.push_str(&format!("<header>\n<h1>{title}</h1>\n"));
+    if !page.authors.is_empty() {
+        out.push_str(&format!(
+            "<p class=\"author\">{}</p>\n",
+            xhtml::escape(&page.authors.join(", "))
+        ));
+    }
+    if let Some(description) = &page.description {
+        out.push_str(description);
+        out.push('\n');
+    }
+    out.push_str("</header>\n<nav class=\"toc\">\n<ol>\n");
+    for (i, chapter) in page.chapters.iter().enumerate() {
+        out.push_str(&format!(
+            "<li><a href=\"#chapter-{}\">{}</a></li>\n",
+            i + 1,
+            xhtml::escape(&chapter.title)
+        ));
+    }
+    out.push_str("</ol>\n</nav>\n");
+
+    for (i, chapter) in page.chapters.iter().enumerate() {
+        out.push_str(&format!(
+            "<section class=\"chapter\" id=\"chapter-{}\">\n<h2>{}</h2>\n{}\n</section>\n",
+            i + 1,
+            xhtml::escape(&chapter.title),
+            chapter.markup
+        ));
+    }
+    out.push_str("</body>\n</html>\n");
+
+    fs::write(path, out).with_context(|| format!("Failed to write {}", path.display()))
+}
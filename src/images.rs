@@ -0,0 +1,520 @@
+use std::{collections::HashMap, fmt, io::Cursor, str::FromStr};
+
+use anyhow::Result;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use epub_builder::{EpubBuilder, ZipCommandOrLibrary};
+use http::Uri;
+use image::{ImageFormat, codecs::jpeg::JpegEncoder, imageops::FilterType};
+use regex::Regex;
+
+use crate::{
+    fetch::{self, ChapterImage, IMAGE_MARKER_END, IMAGE_MARKER_START},
+    summary::Summary,
+    svg, workdir, xhtml,
+};
+
+pub const DEFAULT_ALT_TEMPLATE: &str = "Illustration {n}";
+pub const DEFAULT_QUALITY: u8 = 85;
+/// The shorter side an image needs for `--cover-from-content` to take it.
+pub const DEFAULT_COVER_MIN_DIMENSION: u32 = 300;
+/// Lazy-loading attributes checked before falling back to `src`.
+pub const DEFAULT_SOURCE_ATTRS: &[&str] = &["data-src", "data-original", "srcset", "src"];
+
+#[derive(Debug)]
+pub struct ImageOptions {
+    pub embed: bool,
+    /// Alt text for images without one; `{n}` is the image number in the book.
+    pub alt_template: String,
+    /// Mark images without alt text as decorative instead of using the template.
+    pub decorative: bool,
+    /// Downscale images whose width or height exceeds this many pixels.
+    pub max_dimension: Option<u32>,
+    /// JPEG quality used when recompressing; enables recompression on its own.
+    pub quality: Option<u8>,
+    /// Attributes holding the image URL, in priority order.
+    pub source_attrs: Vec<String>,
+    /// Images whose URL matches are left out without being downloaded,
+    /// e.g. a site's banner or watermark.
+    pub skip: Option<Regex>,
+    /// The formats the reader can show; images in others are converted,
+    /// or left out for SVGs. Every format when `None`.
+    pub formats: Option<Vec<Format>>,
+    /// With `--cover-from-content`, the shorter side the first chapter's
+    /// image needs to be taken as the cover, so icons and spacers don't.
+    pub cover_from_content: Option<u32>,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        ImageOptions {
+            embed: false,
+            alt_template: DEFAULT_ALT_TEMPLATE.to_string(),
+            decorative: false,
+            max_dimension: None,
+            quality: None,
+            source_attrs: DEFAULT_SOURCE_ATTRS.iter().map(|a| a.to_string()).collect(),
+            skip: None,
+            formats: None,
+            cover_from_content: None,
+        }
+    }
+}
+
+/// Downloads chapter images into the package and replaces their markers with
+/// `<img>` elements, numbering images across the whole book.
+///
+/// An image is stored once however many chapters show it: the same bytes
+/// under another URL, say with a cache-busting query string, point at the
+/// copy already in the package.
+pub struct ImageEmbedder<'a> {
+    options: &'a ImageOptions,
+    count: usize,
+    /// Where each image stored so far went, by a hash and the length of its
+    /// bytes as downloaded.
+    stored: HashMap<(u64, usize), String>,
+    /// Whether the images being embedded are looked at for a cover.
+    searching_cover: bool,
+    /// With `--cover-from-content`, the first image found big enough while
+    /// searching, also left where it is in its chapter.
+    pub cover: Option<(Vec<u8>, Format)>,
+    /// Put images into the markup as data URIs rather than into the package.
+    inline: bool,
+    /// Images embedded with meaningful alt text.
+    pub described: usize,
+}
+
+impl<'a> ImageEmbedder<'a> {
+    pub fn new(options: &'a ImageOptions) -> Self {
+        ImageEmbedder {
+            options,
+            count: 0,
+            stored: HashMap::new(),
+            searching_cover: false,
+            cover: None,
+            inline: false,
+            described: 0,
+        }
+    }
+
+    /// Embeds images as `data:` URIs, for single-file HTML.
+    pub fn inline(mut self) -> Self {
+        self.inline = true;
+        self
+    }
+
+    /// Images embedded so far, counting each place an image is shown.
+    pub fn embedded(&self) -> usize {
+        self.count
+    }
+
+    /// Forgets the images stored so far, for the next part of a split book,
+    /// whose package has none of them.
+    pub fn next_part(&mut self) {
+        self.stored.clear();
+    }
+
+    /// With `--cover-from-content`, looks at the images embedded from now
+    /// on for a cover until one is found or `search` is false.
+    pub fn search_cover(&mut self, search: bool) {
+        self.searching_cover = search && self.options.cover_from_content.is_some();
+    }
+
+    pub fn render(
+        &mut self,
+        book: &mut EpubBuilder<ZipCommandOrLibrary>,
+        text: &str,
+        images: &[ChapterImage],
+        base: &Uri,
+        mut fetch_bytes: impl FnMut(&Uri) -> Result<Vec<u8>>,
+        summary: &mut Summary,
+    ) -> Result<String> {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+
+        while let Some(start) = rest.find(IMAGE_MARKER_START) {
+            out.push_str(&rest[..start]);
+            let tail = &rest[start + IMAGE_MARKER_START.len_utf8()..];
+            let Some(end) = tail.find(IMAGE_MARKER_END) else {
+                rest = tail;
+                break;
+            };
+            let image = tail[..end].parse().ok().and_then(|i: usize| images.get(i));
+            rest = &tail[end + IMAGE_MARKER_END.len_utf8()..];
+
+            if let (true, Some(image)) = (self.options.embed, image) {
+                out.push_str(&self.embed(book, image, base, &mut fetch_bytes, summary)?);
+            }
+        }
+
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    fn embed(
+        &mut self,
+        book: &mut EpubBuilder<ZipCommandOrLibrary>,
+        image: &ChapterImage,
+        base: &Uri,
+        fetch_bytes: &mut impl FnMut(&Uri) -> Result<Vec<u8>>,
+        summary: &mut Summary,
+    ) -> Result<String> {
+        let source_alt = image.attr("alt").map(str::trim).filter(|a| !a.is_empty());
+
+        let fallback = || source_alt.map(xhtml::escape).unwrap_or_default();
+        let Some(src) = pick_source(
+            image,
+            &self.options.source_attrs,
+            self.options.max_dimension,
+        ) else {
+            summary.warn("skipped image without a usable source");
+            return Ok(fallback());
+        };
+        let Some(url) = fetch::resolve(base, src) else {
+            summary.warn(format!("skipped image with unusable src {src:?}"));
+            return Ok(fallback());
+        };
+        if let Some(skip) = &self.options.skip
+            && skip.is_match(&url.to_string())
+        {
+            log::debug!("skipped image {url} matching --skip-image-pattern");
+            return Ok(String::new());
+        }
+        let bytes = match fetch_bytes(&url) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                summary.warn(format!("failed to download image {url}: {e}"));
+                return Ok(fallback());
+            }
+        };
+        let Some(format) = Format::detect(&bytes) else {
+            summary.warn(format!("skipped image {url} with unrecognized format"));
+            return Ok(fallback());
+        };
+
+        let content = (workdir::fnv1a(&bytes), bytes.len());
+        let path = match self.stored.get(&content) {
+            Some(path) => path.clone(),
+            None => {
+                let before = bytes.len();
+                let (bytes, format) = match self.prepare(bytes, format) {
+                    Ok(prepared) => prepared,
+                    Err(reason) => {
+                        summary.warn(format!("skipped image {url}: {reason}"));
+                        return Ok(fallback());
+                    }
+                };
+                summary.image_bytes_before += before;
+                summary.image_bytes_after += bytes.len();
+                self.consider_cover(&url, &bytes, format);
+
+                let (mime, ext) = (format.mime(), format.extension());
+                let path = if self.inline {
+                    format!("data:{mime};base64,{}", STANDARD.encode(&bytes))
+                } else {
+                    let path = format!("images/{:04}.{ext}", self.stored.len() + 1);
+                    book.add_resource(&path, Cursor::new(bytes), mime)?;
+                    path
+                };
+                self.stored.insert(content, path.clone());
+                path
+            }
+        };
+        self.count += 1;
+
+        let alt = match source_alt {
+            Some(alt) => Some(alt.to_string()),
+            None if self.options.decorative => None,
+            None => Some(
+                self.options
+                    .alt_template
+                    .replace("{n}", &self.count.to_string()),
+            ),
+        };
+
+        Ok(match alt {
+            Some(alt) => {
+                self.described += 1;
+                format!(r#"<img src="{path}" alt="{}" />"#, xhtml::escape(&alt))
+            }
+            None => format!(r#"<img src="{path}" alt="" role="presentation" />"#),
+        })
+    }
+}
+
+impl ImageEmbedder<'_> {
+    /// Takes the image at `url` as the cover if a cover is being searched
+    /// for and it is big enough, logging the decision either way.
+    fn consider_cover(&mut self, url: &Uri, bytes: &[u8], format: Format) {
+        let Some(min) = self.options.cover_from_content else {
+            return;
+        };
+        if !self.searching_cover || self.cover.is_some() || format.raster().is_none() {
+            return;
+        }
+        let size = image::ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok());
+        match size {
+            Some((width, height)) if width.min(height) >= min => {
+                log::info!("taking {url} ({width}x{height}) as the cover");
+                self.cover = Some((bytes.to_vec(), format));
+            }
+            Some((width, height)) => {
+                log::info!("not taking {url} as the cover: {width}x{height} is under {min}px");
+            }
+            None => log::info!("not taking {url} as the cover: its size can't be read"),
+        }
+    }
+
+    fn allows(&self, format: Format) -> bool {
+        self.options
+            .formats
+            .as_ref()
+            .is_none_or(|formats| formats.contains(&format))
+    }
+
+    /// The image to embed for `bytes` in `format`: sanitized if it's an
+    /// SVG, converted if the reader can't show its format, downscaled and
+    /// re-encoded if that was asked for and makes it smaller. The error
+    /// says why it can't be embedded at all.
+    fn prepare(
+        &self,
+        bytes: Vec<u8>,
+        format: Format,
+    ) -> std::result::Result<(Vec<u8>, Format), String> {
+        if format == Format::Svg {
+            if !self.allows(Format::Svg) {
+                return Err("an SVG, which isn't among --image-formats".to_string());
+            }
+            return svg::sanitize(&bytes)
+                .map(|clean| (clean.into_bytes(), Format::Svg))
+                .ok_or_else(|| "not a well-formed SVG".to_string());
+        }
+
+        let Some(img) = format
+            .raster()
+            .and_then(|raster| image::load_from_memory_with_format(&bytes, raster).ok())
+        else {
+            return Err(format!("corrupt or unsupported {format} image"));
+        };
+        // Images within `--max-image-dimension` are only re-encoded when
+        // asked for an `--image-quality`, or to leave a disallowed format.
+        let allowed = self.allows(format);
+        let oversized = self
+            .options
+            .max_dimension
+            .is_some_and(|max| img.width() > max || img.height() > max);
+        if allowed && !oversized && self.options.quality.is_none() {
+            return Ok((bytes, format));
+        }
+        match self.encode(img) {
+            Some((out, to)) if !allowed || oversized || out.len() < bytes.len() => Ok((out, to)),
+            Some(_) => Ok((bytes, format)),
+            None if allowed && !oversized => Ok((bytes, format)),
+            None => Err(format!("{format} couldn't be converted")),
+        }
+    }
+
+    /// Downscales and re-encodes an image: as PNG if it has an alpha
+    /// channel, as JPEG otherwise, unless `--image-formats` leaves only the
+    /// other one.
+    fn encode(&self, mut img: image::DynamicImage) -> Option<(Vec<u8>, Format)> {
+        if let Some(max) = self.options.max_dimension
+            && (img.width() > max || img.height() > max)
+        {
+            img = img.resize(max, max, FilterType::Lanczos3);
+        }
+
+        let png = if img.color().has_alpha() {
+            self.allows(Format::Png) || !self.allows(Format::Jpeg)
+        } else {
+            !self.allows(Format::Jpeg)
+        };
+        let mut out = Vec::new();
+        if png {
+            img.write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+                .ok()?;
+            Some((out, Format::Png))
+        } else {
+            let quality = self.options.quality.unwrap_or(DEFAULT_QUALITY);
+            img.to_rgb8()
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut out, quality))
+                .ok()?;
+            Some((out, Format::Jpeg))
+        }
+    }
+}
+
+/// Picks the image URL from the first attribute in `order` with a real value,
+/// skipping inline `data:` placeholders such as lazy-loading 1×1 GIFs.
+fn pick_source<'a>(
+    image: &'a ChapterImage,
+    order: &[String],
+    max_dimension: Option<u32>,
+) -> Option<&'a str> {
+    order.iter().find_map(|name| {
+        let value = image.attr(name)?.trim();
+        let url = if name == "srcset" {
+            best_srcset_candidate(value, max_dimension)?
+        } else {
+            value
+        };
+        (!url.is_empty() && !url.starts_with("data:")).then_some(url)
+    })
+}
+
+/// Chooses the widest `srcset` candidate not exceeding `max_dimension`
+/// (or the narrowest one if all exceed it). Density descriptors (`2x`) rank
+/// by density since they carry no pixel size.
+fn best_srcset_candidate(srcset: &str, max_dimension: Option<u32>) -> Option<&str> {
+    let candidates: Vec<(&str, Option<u32>, f32)> = srcset_candidates(srcset)
+        .into_iter()
+        .filter_map(|(url, descriptor)| {
+            let descriptor = descriptor.unwrap_or("1x");
+            if let Some(w) = descriptor.strip_suffix('w') {
+                Some((url, w.parse().ok(), 0.0))
+            } else {
+                let density = descriptor.strip_suffix('x')?.parse().ok()?;
+                Some((url, None, density))
+            }
+        })
+        .filter(|(url, _, _)| !url.starts_with("data:"))
+        .collect();
+
+    let fits = |w: &Option<u32>| match (w, max_dimension) {
+        (Some(w), Some(max)) => *w <= max,
+        _ => true,
+    };
+
+    candidates
+        .iter()
+        .filter(|(_, w, _)| fits(w))
+        .max_by(|a, b| {
+            (a.1, a.2)
+                .partial_cmp(&(b.1, b.2))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .or_else(|| candidates.iter().min_by_key(|(_, w, _)| *w))
+        .map(|(url, _, _)| *url)
+}
+
+/// Splits a `srcset` into its URLs and their first descriptors. A URL runs
+/// to the next whitespace, so the commas of a `data:` URL stay in it; only
+/// commas ending a URL or its descriptors separate candidates.
+fn srcset_candidates(srcset: &str) -> Vec<(&str, Option<&str>)> {
+    let mut candidates = Vec::new();
+    let mut rest = srcset;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() {
+            return candidates;
+        }
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let (url, after) = rest.split_at(end);
+        if url.ends_with(',') {
+            candidates.push((url.trim_end_matches(','), None));
+            rest = after;
+            continue;
+        }
+        let (descriptors, after) = after.split_once(',').unwrap_or((after, ""));
+        candidates.push((url, descriptors.split_whitespace().next()));
+        rest = after;
+    }
+}
+
+/// An image format, as detected from the image's bytes rather than its URL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+    Svg,
+}
+
+impl Format {
+    /// Detects the image format from its magic bytes.
+    pub fn detect(bytes: &[u8]) -> Option<Format> {
+        if bytes.starts_with(b"\x89PNG") {
+            Some(Format::Png)
+        } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+            Some(Format::Jpeg)
+        } else if bytes.starts_with(b"GIF8") {
+            Some(Format::Gif)
+        } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+            Some(Format::Webp)
+        } else if svg::is_svg(bytes) {
+            Some(Format::Svg)
+        } else {
+            None
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Format::Jpeg => "image/jpeg",
+            Format::Png => "image/png",
+            Format::Gif => "image/gif",
+            Format::Webp => "image/webp",
+            Format::Svg => "image/svg+xml",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpg",
+            Format::Png => "png",
+            Format::Gif => "gif",
+            Format::Webp => "webp",
+            Format::Svg => "svg",
+        }
+    }
+
+    fn raster(self) -> Option<ImageFormat> {
+        match self {
+            Format::Jpeg => Some(ImageFormat::Jpeg),
+            Format::Png => Some(ImageFormat::Png),
+            Format::Gif => Some(ImageFormat::Gif),
+            Format::Webp => Some(ImageFormat::WebP),
+            Format::Svg => None,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Jpeg => "jpeg",
+            Format::Png => "png",
+            Format::Gif => "gif",
+            Format::Webp => "webp",
+            Format::Svg => "svg",
+        })
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(Format::Jpeg),
+            "png" => Ok(Format::Png),
+            "gif" => Ok(Format::Gif),
+            "webp" => Ok(Format::Webp),
+            "svg" => Ok(Format::Svg),
+            _ => {
+                anyhow::bail!("Invalid --image-formats: {s} (expected jpeg, png, gif, webp or svg)")
+            }
+        }
+    }
+}
+
+/// Detects a raster image's format from its magic bytes, as a media type
+/// and file extension.
+pub fn sniff(bytes: &[u8]) -> Option<(&'static str, &'static str)> {
+    Format::detect(bytes)
+        .filter(|format| format.raster().is_some())
+        .map(|format| (format.mime(), format.extension()))
+}
@@ -0,0 +1,134 @@
+//! `--format kepub`: Kobo's markup on top of a chapter document.
+//!
+//! Kobo readers track progress and turn pages by sentence, so every
+//! sentence goes into `<span class="koboSpan" id="kobo.P.S">`, for sentence
+//! S of paragraph P, and the body into the `book-columns` and `book-inner`
+//! divs Kobo lays pages out with. Sentences end at sentence-ending
+//! punctuation, with any closing quotes or brackets after it.
+
+/// Ends a sentence wherever it appears.
+const FULL_STOPS: &[char] = &['。', '！', '？', '…'];
+/// Ends a sentence only before whitespace, so "3.5" and "a.b" hold together.
+const ASCII_STOPS: &[char] = &['.', '!', '?'];
+/// Kept with the sentence they follow.
+const CLOSERS: &[char] = &['」', '』', '”', '’', '"', '\'', '）', ')', '】', '》'];
+
+/// Tags that start a new paragraph for numbering.
+const BLOCKS: &[&str] = &[
+    "p",
+    "div",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "li",
+    "blockquote",
+    "aside",
+    "section",
+    "br",
+];
+
+/// Rewrites a generated chapter document with Kobo's spans and divs.
+/// Anything outside `<body>` is left as it is.
+pub fn spans(document: &str) -> String {
+    let (Some(start), Some(end)) = (document.find("<body>"), document.rfind("</body>")) else {
+        return document.to_string();
+    };
+    let start = start + "<body>".len();
+    let mut out = Spans {
+        out: String::with_capacity(document.len() + document.len() / 2),
+        paragraph: 0,
+        sentence: 0,
+    };
+    out.out.push_str(&document[..start]);
+    out.out
+        .push_str(r#"<div id="book-columns"><div id="book-inner">"#);
+    out.body(&document[start..end]);
+    out.out.push_str("</div></div>");
+    out.out.push_str(&document[end..]);
+    out.out
+}
+
+struct Spans {
+    out: String,
+    paragraph: usize,
+    sentence: usize,
+}
+
+impl Spans {
+    fn body(&mut self, mut rest: &str) {
+        while !rest.is_empty() {
+            if rest.starts_with('<') {
+                let end = rest.find('>').map_or(rest.len(), |e| e + 1);
+                let tag = &rest[..end];
+                if starts_paragraph(tag) {
+                    self.paragraph += 1;
+                    self.sentence = 0;
+                }
+                self.out.push_str(tag);
+                rest = &rest[end..];
+            } else {
+                let end = rest.find('<').unwrap_or(rest.len());
+                self.text(&rest[..end]);
+                rest = &rest[end..];
+            }
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        for sentence in sentences(text) {
+            // Line breaks and indentation between sentences stay outside.
+            let trimmed = sentence.trim_start();
+            self.out
+                .push_str(&sentence[..sentence.len() - trimmed.len()]);
+            if trimmed.is_empty() {
+                continue;
+            }
+            let sentence = trimmed;
+            self.sentence += 1;
+            self.out.push_str(&format!(
+                r#"<span class="koboSpan" id="kobo.{}.{}">{sentence}</span>"#,
+                self.paragraph, self.sentence
+            ));
+        }
+    }
+}
+
+fn starts_paragraph(tag: &str) -> bool {
+    if tag.starts_with("</") {
+        return false;
+    }
+    let name = tag[1..]
+        .split(|c: char| c.is_whitespace() || c == '/' || c == '>')
+        .next()
+        .unwrap_or_default();
+    BLOCKS.contains(&name)
+}
+
+/// Splits escaped text after each sentence's punctuation and closers.
+fn sentences(text: &str) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, c)| c);
+        let ends = FULL_STOPS.contains(&c)
+            || (ASCII_STOPS.contains(&c) && next.is_none_or(char::is_whitespace));
+        // Runs like "……" or "?!" end the sentence once, at their last mark.
+        if !ends || next.is_some_and(|n| FULL_STOPS.contains(&n) || ASCII_STOPS.contains(&n)) {
+            continue;
+        }
+        while chars.peek().is_some_and(|&(_, c)| CLOSERS.contains(&c)) {
+            chars.next();
+        }
+        let end = chars.peek().map_or(text.len(), |&(i, _)| i);
+        out.push(&text[start..end]);
+        start = end;
+    }
+    if start < text.len() {
+        out.push(&text[start..]);
+    }
+    out
+}
@@ -0,0 +1,175 @@
+//! The retry ladder a chapter goes down when its download or extraction
+//! goes wrong, and the tally of how chapters came through it, so a flaky
+//! network can be told from selectors the site has broken.
+//!
+//! HTTP failures are retried with backoff by the [`Fetcher`] itself. A page
+//! that comes back without chapter text is downloaded once more, then read
+//! with the [`readable`] fallback. A page that can't be parsed at all is
+//! saved in the work directory's `failed/` for a look, and the chapter is
+//! left out of the book.
+
+use html5ever::tendril::StrTendril;
+use http::Uri;
+use serde::Serialize;
+
+use crate::{
+    BuildOptions, Chapter, Error,
+    fetch::{Site, readable},
+    fetcher::Fetcher,
+    timing::{Phase, Timings},
+};
+
+/// How a chapter's first download went.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChapterResult {
+    FetchedOk,
+    /// No page, after the fetcher's retries.
+    HttpFailed,
+    /// A page without chapter text where the site keeps it.
+    ParsedEmpty,
+    /// A page that couldn't be parsed, e.g. not UTF-8 or too slow to parse.
+    ParseError,
+}
+
+/// How many chapters' first downloads went each way.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct ResultCounts {
+    pub fetched_ok: usize,
+    pub http_failed: usize,
+    pub parsed_empty: usize,
+    pub parse_error: usize,
+}
+
+impl ResultCounts {
+    pub fn count(&mut self, result: ChapterResult) {
+        *match result {
+            ChapterResult::FetchedOk => &mut self.fetched_ok,
+            ChapterResult::HttpFailed => &mut self.http_failed,
+            ChapterResult::ParsedEmpty => &mut self.parsed_empty,
+            ChapterResult::ParseError => &mut self.parse_error,
+        } += 1;
+    }
+
+    /// Whether any chapter's first download went wrong.
+    pub fn troubled(&self) -> bool {
+        self.http_failed + self.parsed_empty + self.parse_error > 0
+    }
+}
+
+/// A chapter as it came off the ladder.
+pub struct Fetched {
+    pub content: crate::Result<Chapter>,
+    /// How the first download went, whatever a later rung made of it.
+    pub result: ChapterResult,
+    /// Whether the text is the [`readable`] fallback's.
+    pub readable: bool,
+    /// The page as downloaded, with a [`ChapterResult::ParseError`].
+    pub unparsed: Option<Vec<u8>>,
+    /// How long each rung's download, decoding and extraction took.
+    pub timings: Timings,
+    /// Where the first download went wrong, with an error.
+    pub failed_in: Phase,
+}
+
+/// Downloads and parses the chapter at `url`, going down the ladder as far
+/// as it takes.
+pub fn fetch(fetcher: &impl Fetcher, url: &Uri, site: &Site, options: &BuildOptions) -> Fetched {
+    let mut timings = Timings::default();
+    let (chapter, page) = match attempt(fetcher, url, site, options, &mut timings) {
+        Attempt::Parsed(chapter, _) if !chapter.text.trim().is_empty() => {
+            return Fetched::new(Ok(chapter), ChapterResult::FetchedOk, timings);
+        }
+        Attempt::Parsed(chapter, page) => (chapter, page),
+        Attempt::HttpFailed(e) => {
+            return Fetched {
+                failed_in: Phase::Fetch,
+                ..Fetched::new(Err(e), ChapterResult::HttpFailed, timings)
+            };
+        }
+        Attempt::Unparsable(e, body, phase) => {
+            return Fetched {
+                unparsed: Some(body),
+                failed_in: phase,
+                ..Fetched::new(Err(e), ChapterResult::ParseError, timings)
+            };
+        }
+    };
+
+    log::info!("{url}: no chapter text, downloading it again");
+    let (chapter, page) = match attempt(fetcher, url, site, options, &mut timings) {
+        Attempt::Parsed(again, _) if !again.text.trim().is_empty() => {
+            return Fetched::new(Ok(again), ChapterResult::ParsedEmpty, timings);
+        }
+        Attempt::Parsed(again, page) => (again, page),
+        // The first copy parsed, so it is read with the fallback instead.
+        _ => (chapter, page),
+    };
+
+    let found = timings.time(Phase::Extract, || {
+        readable::chapter(&page, &chapter.title, &options.limits)
+    });
+    match found {
+        Some(found) if !found.text.trim().is_empty() => {
+            log::warn!(
+                "{url}: no chapter text where the site keeps it, taking the page's largest block of text"
+            );
+            Fetched {
+                readable: true,
+                ..Fetched::new(Ok(found), ChapterResult::ParsedEmpty, timings)
+            }
+        }
+        _ => Fetched::new(Ok(chapter), ChapterResult::ParsedEmpty, timings),
+    }
+}
+
+impl Fetched {
+    fn new(content: crate::Result<Chapter>, result: ChapterResult, timings: Timings) -> Self {
+        Fetched {
+            content,
+            result,
+            readable: false,
+            unparsed: None,
+            timings,
+            failed_in: Phase::Extract,
+        }
+    }
+}
+
+enum Attempt {
+    Parsed(Chapter, StrTendril),
+    HttpFailed(Error),
+    /// The error, the page's bytes and whether decoding or extraction
+    /// failed.
+    Unparsable(Error, Vec<u8>, Phase),
+}
+
+fn attempt(
+    fetcher: &impl Fetcher,
+    url: &Uri,
+    site: &Site,
+    options: &BuildOptions,
+    timings: &mut Timings,
+) -> Attempt {
+    let body = match timings.time(Phase::Fetch, || fetcher.get(&url.to_string())) {
+        Ok(response) => response.body,
+        Err(e) => return Attempt::HttpFailed(e),
+    };
+    let page = match timings.time(Phase::Decode, || String::from_utf8(body)) {
+        Ok(text) => StrTendril::from(text),
+        Err(e) => {
+            let error = Error::Parse {
+                url: url.to_string(),
+                what: "UTF-8 text".to_string(),
+            };
+            return Attempt::Unparsable(error, e.into_bytes(), Phase::Decode);
+        }
+    };
+    let chapter = timings.time(Phase::Extract, || {
+        site.chapter(url, &page, options.notes.as_ref(), &options.limits)
+    });
+    match chapter {
+        Ok(chapter) => Attempt::Parsed(chapter, page),
+        Err(e) => Attempt::Unparsable(e, page.as_bytes().to_vec(), Phase::Extract),
+    }
+}
@@ -0,0 +1,315 @@
+//! Scrapes web novels from supported sites and builds them into epubs.
+//!
+//! ```no_run
+//! use epub_dude::{BookSource, BuildOptions, HttpFetcher, build_epub};
+//!
+//! let source = BookSource::new("https://czbooks.net/n/abc123".parse()?)?;
+//! let fetcher = HttpFetcher::new(ureq::Agent::new_with_defaults());
+//! let summary = build_epub(&source, &fetcher, &BuildOptions::default(), &())?;
+//! println!("{} chapters", summary.chapters);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use http::Uri;
+
+mod book;
+pub mod catalog;
+pub mod check;
+pub mod checkpoint;
+pub mod cleanup;
+pub mod dates;
+mod error;
+pub mod fallback;
+pub mod fetch;
+pub mod fetcher;
+mod footnotes;
+pub mod generated;
+pub mod headings;
+mod html;
+pub mod images;
+mod kepub;
+pub mod ladder;
+mod lock;
+pub mod manifest;
+pub mod metadata;
+mod ncx;
+pub mod numbering;
+pub mod outcome;
+pub mod output;
+mod package;
+pub mod parts;
+pub mod plain;
+pub mod politeness;
+mod pool;
+pub mod provenance;
+pub mod rebuild;
+pub mod resolve;
+pub mod revisions;
+pub mod scripts;
+pub mod selection;
+pub mod session;
+pub mod softwrap;
+pub mod split;
+mod staging;
+pub mod state;
+pub mod stats;
+pub mod summary;
+mod svg;
+pub mod template;
+pub mod timing;
+pub mod tls;
+pub mod updates;
+pub mod validate;
+pub mod workdir;
+pub mod xhtml;
+
+pub use book::{build_anthology, build_epub};
+pub use error::{Error, Result, exit_code};
+pub use fetch::{BookInfo, Chapter, ChapterLink, ChapterList};
+pub use fetcher::{DEFAULT_USER_AGENTS, Delays, Fetcher, HttpFetcher, MemoryFetcher, TimeLimits};
+pub use summary::Summary;
+
+pub const DEFAULT_DESCRIPTION_LIMIT: usize = 500;
+pub const DEFAULT_LANGUAGE: &str = "zh";
+
+/// A book's index page on a supported site.
+#[derive(Clone)]
+pub struct BookSource {
+    pub uri: Uri,
+    site: fetch::Site,
+}
+
+impl BookSource {
+    pub fn new(uri: Uri) -> Result<Self> {
+        match fetch::Site::for_uri(&uri) {
+            Some(site) => Ok(BookSource { uri, site }),
+            None => Err(Error::UnsupportedSite(uri)),
+        }
+    }
+
+    /// Reads `uri` with `site`'s parser whatever its host, e.g. a mirror.
+    pub fn with_site(uri: Uri, site: fetch::Site) -> Self {
+        BookSource { uri, site }
+    }
+
+    /// Fetches the index page and extracts the book's details and chapters.
+    pub fn info(&self, fetcher: &impl Fetcher) -> Result<BookInfo> {
+        let page = fetcher::fetch_page(fetcher, &self.uri)?;
+        self.site.index(&self.uri, &page, &fetch::Limits::default())
+    }
+
+    pub fn chapter(&self, fetcher: &impl Fetcher, link: &ChapterLink) -> Result<Chapter> {
+        let page = fetcher::fetch_page(fetcher, &link.uri)?;
+        self.site
+            .chapter(&link.uri, &page, None, &fetch::Limits::default())
+    }
+}
+
+/// Reports progress through the chapter loop; `()` reports nothing.
+pub trait Progress {
+    fn start(&self, _chapters: usize, _title: &str) {}
+    fn chapter_done(&self) {}
+    /// The bytes downloaded so far, after each chapter.
+    fn downloaded(&self, _bytes: usize) {}
+    fn finish(&self) {}
+}
+
+impl Progress for () {}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    #[default]
+    Document,
+    TitleNumber,
+}
+
+/// Everything that shapes a built book; the defaults match the command line's.
+#[derive(Debug)]
+pub struct BuildOptions {
+    pub count_check: Option<check::CountCheck>,
+    pub sort: SortOrder,
+    /// Build only these chapters, in this order, e.g. from `--chapters-file`.
+    pub chapters: Option<Vec<Uri>>,
+    /// Chapters to skip by title, before anything is downloaded.
+    pub title_filter: selection::TitleFilter,
+    /// Where the index gives each chapter's date.
+    pub chapter_dates: Option<fetch::selector::Selector>,
+    /// Skip chapters dated before this; undated ones are kept.
+    pub since: Option<chrono::NaiveDate>,
+    /// Build only if the index lists chapters the last complete build
+    /// didn't have, see [`Error::UpToDate`].
+    pub update: bool,
+    /// Build from what the last complete build kept in the work directory,
+    /// without downloading anything, see [`rebuild`].
+    pub rebuild: bool,
+    /// Chapters whose text matches this are locked on the site: they get a
+    /// placeholder page, and `--update` runs retry them.
+    pub locked: Option<regex::Regex>,
+    /// Asked to narrow the chapters down further once the index is parsed,
+    /// e.g. by `--interactive`.
+    pub pick: Option<selection::Picker>,
+    pub strict_sequence: bool,
+    pub manifest: Option<std::path::PathBuf>,
+    pub fallback: Option<Uri>,
+    pub chapter_footer: Option<String>,
+    /// Lays out chapter pages in place of the built-in skeleton.
+    pub chapter_template: Option<template::ChapterTemplate>,
+    /// Ends each chapter with links to its neighbours and the table of
+    /// contents.
+    pub chapter_nav: bool,
+    /// Lists repeated chapter titles in the table of contents as "Title (2)"
+    /// and so on; the chapters' own headings are left as they are.
+    pub dedupe_toc_titles: bool,
+    pub no_provenance: bool,
+    pub output: output::OutputTemplate,
+    pub format: output::Format,
+    pub title: Option<String>,
+    pub authors: Vec<String>,
+    /// Take the title, description and identifier from the index page's
+    /// canonical URL and Open Graph tags over those the site's provider
+    /// finds, rather than only filling in what it missed.
+    pub prefer_og: bool,
+    /// Fail if the index gives no author, rather than crediting "Unknown".
+    /// An index without a title always fails.
+    pub require_author: bool,
+    pub title_cleanup: cleanup::Cleanup,
+    pub author_cleanup: cleanup::Cleanup,
+    /// Map full-width ASCII in the title and author to half-width where they
+    /// name the output file.
+    pub fold_width: bool,
+    /// Join lines of prose the site hard-wrapped back into paragraphs.
+    pub merge_softwrap: bool,
+    /// Tag paragraphs in another script than the book's with their own
+    /// language.
+    pub per_paragraph_lang: bool,
+    pub contributors: Vec<metadata::Contributor>,
+    pub no_title_page: bool,
+    pub colophon: bool,
+    /// With `--author-page`, where the author's page keeps their bio, for
+    /// an "About the Author" page after the last chapter.
+    pub author_page: Option<fetch::selector::Selector>,
+    pub images: images::ImageOptions,
+    pub writing_mode: xhtml::WritingMode,
+    pub theme: xhtml::Theme,
+    pub typography: xhtml::Typography,
+    pub description_limit: usize,
+    pub max_chapter_size: usize,
+    pub headings: headings::HeadingRules,
+    pub language: String,
+    /// Titles the table of contents over the one for `language`.
+    pub toc_title: Option<String>,
+    /// Show the description above the table of contents of an HTML book.
+    pub toc_description: bool,
+    pub reading_speed: Option<usize>,
+    pub length_meta: bool,
+    pub validate: bool,
+    pub epub2: bool,
+    /// The deflate level of the epub's entries, 0 to store them. Images,
+    /// fonts and the `mimetype` are stored whatever it is.
+    pub compression_level: u8,
+    pub notes: Option<fetch::NoteSelectors>,
+    /// Caps on parse time, chapter text and index links per page.
+    pub limits: fetch::Limits,
+    /// Chapters fetched at once.
+    pub jobs: usize,
+    /// The root of the per-book directories described in [`workdir`].
+    pub work_dir: std::path::PathBuf,
+    /// Where intermediate files are staged, the system's temp directory
+    /// if none.
+    pub temp_dir: Option<std::path::PathBuf>,
+    /// The external zip command epubs are built with, `zip` but on
+    /// Windows; without one, or when it can't be run, the built-in zip
+    /// library is used.
+    pub zip_command: Option<String>,
+    /// Write the book as numbered parts of this many chapters each, see
+    /// [`parts`].
+    pub split_every: Option<usize>,
+    /// Stop, writing the chapters so far to a partial book, once downloads
+    /// exceed this many bytes.
+    pub max_total_bytes: Option<usize>,
+    /// Stop the same way once this passes; the fetcher should be given it
+    /// too, see [`TimeLimits`].
+    pub deadline: Option<std::time::Instant>,
+    /// Fetch chapters earlier runs found permanently missing again.
+    pub retry_permanent: bool,
+    /// Compare chapters with the text an earlier run kept, see [`revisions`].
+    pub check_revisions: bool,
+    /// Write a diff of each revised chapter here.
+    pub revision_diff: Option<std::path::PathBuf>,
+    /// How long to wait for another run building the same book.
+    pub wait_lock: std::time::Duration,
+    /// Save the parsed chapters every this many, to resume after a crash.
+    pub checkpoint_every: Option<usize>,
+    /// Also write the book so far to `<output>.partial.epub` at each checkpoint.
+    pub partial_epub: bool,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            count_check: None,
+            sort: SortOrder::default(),
+            chapters: None,
+            title_filter: selection::TitleFilter::default(),
+            chapter_dates: None,
+            since: None,
+            update: false,
+            rebuild: false,
+            locked: None,
+            pick: None,
+            strict_sequence: false,
+            manifest: None,
+            fallback: None,
+            chapter_footer: None,
+            chapter_template: None,
+            chapter_nav: false,
+            dedupe_toc_titles: false,
+            no_provenance: false,
+            output: output::OutputTemplate::default(),
+            format: output::Format::default(),
+            title: None,
+            authors: Vec::new(),
+            prefer_og: false,
+            require_author: false,
+            title_cleanup: cleanup::Cleanup::defaults(cleanup::Field::Title),
+            author_cleanup: cleanup::Cleanup::defaults(cleanup::Field::Author),
+            fold_width: false,
+            merge_softwrap: false,
+            per_paragraph_lang: false,
+            contributors: Vec::new(),
+            no_title_page: false,
+            colophon: false,
+            author_page: None,
+            images: images::ImageOptions::default(),
+            writing_mode: xhtml::WritingMode::default(),
+            theme: xhtml::Theme::default(),
+            typography: xhtml::Typography::default(),
+            description_limit: DEFAULT_DESCRIPTION_LIMIT,
+            max_chapter_size: split::DEFAULT_MAX_CHAPTER_SIZE,
+            headings: headings::HeadingRules::default(),
+            language: DEFAULT_LANGUAGE.to_string(),
+            toc_title: None,
+            toc_description: false,
+            reading_speed: None,
+            length_meta: false,
+            validate: false,
+            epub2: false,
+            compression_level: package::DEFAULT_LEVEL,
+            notes: None,
+            limits: fetch::Limits::default(),
+            jobs: 1,
+            work_dir: workdir::default_root(),
+            temp_dir: None,
+            zip_command: staging::default_command(),
+            split_every: None,
+            max_total_bytes: None,
+            deadline: None,
+            retry_permanent: false,
+            check_revisions: false,
+            revision_diff: None,
+            wait_lock: std::time::Duration::ZERO,
+            checkpoint_every: None,
+            partial_epub: false,
+        }
+    }
+}
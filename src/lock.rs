@@ -0,0 +1,123 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{Error, Result};
+
+pub(crate) const FILE: &str = "lock";
+/// Locks older than this are reclaimed even if their process can't be
+/// checked.
+const STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long a lock file that can't be read yet is taken as held, for the
+/// moment between another run creating it and writing who holds it.
+const UNREADABLE_GRACE: Duration = Duration::from_secs(10);
+const POLL: Duration = Duration::from_millis(500);
+
+/// Holds a book's work directory against other runs until dropped.
+pub struct BookLock {
+    path: PathBuf,
+}
+
+struct Holder {
+    pid: u32,
+    /// Seconds since the epoch when the lock was taken.
+    since: u64,
+}
+
+impl BookLock {
+    /// Takes the lock in `dir`, waiting up to `wait` for another run to
+    /// release it. Locks left by crashed processes are reclaimed.
+    pub fn acquire(dir: &Path, wait: Duration) -> Result<Self> {
+        fs::create_dir_all(dir).map_err(|e| Error::output(dir, e))?;
+        let path = dir.join(FILE);
+        let deadline = Instant::now() + wait;
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    let holder = format!("{}\n{}\n", std::process::id(), now());
+                    file.write_all(holder.as_bytes())
+                        .map_err(|e| Error::output(&path, e))?;
+                    return Ok(BookLock { path });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(Error::output(&path, e)),
+            }
+
+            let holder = read(&path);
+            let stale = match &holder {
+                Some(holder) => holder.is_stale(),
+                None => match age(&path) {
+                    Some(age) => age > UNREADABLE_GRACE,
+                    // Released since, so try again.
+                    None => continue,
+                },
+            };
+            if stale {
+                log::warn!("reclaiming stale lock {}", path.display());
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Locked {
+                    path,
+                    pid: holder.map_or(0, |h| h.pid),
+                });
+            }
+            thread::sleep(POLL);
+        }
+    }
+}
+
+impl Drop for BookLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        // Don't leave empty work directories behind.
+        if let Some(dir) = self.path.parent() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+impl Holder {
+    fn is_stale(&self) -> bool {
+        now().saturating_sub(self.since) > STALE_AFTER.as_secs() || !is_running(self.pid)
+    }
+}
+
+/// `None` for a lock file that's unreadable, half-written or gone.
+fn read(path: &Path) -> Option<Holder> {
+    let content = fs::read_to_string(path).ok()?;
+    let mut lines = content.lines();
+    Some(Holder {
+        pid: lines.next()?.parse().ok()?,
+        since: lines.next()?.parse().ok()?,
+    })
+}
+
+/// How long ago the file at `path` was last written, `None` if it's gone.
+fn age(path: &Path) -> Option<Duration> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.elapsed().unwrap_or_default())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(target_os = "linux")]
+fn is_running(pid: u32) -> bool {
+    Path::new(&format!("/proc/{pid}")).exists()
+}
+
+/// Without a portable process check, only the timestamp decides.
+#[cfg(not(target_os = "linux"))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
@@ -0,0 +1,53 @@
+use std::sync::Mutex;
+
+use indicatif::ProgressBar;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Minimal stderr logger; `-v` enables debug and `-vv` trace output.
+struct StderrLogger;
+
+static LOGGER: StderrLogger = StderrLogger;
+/// The progress bar on stderr, which lines are printed above.
+static BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = match record.level() {
+            Level::Info => record.args().to_string(),
+            level => format!("[{}] {}", level.as_str().to_lowercase(), record.args()),
+        };
+        // Printed with the bar cleared and drawn again below, so a line
+        // never runs into it.
+        match &*BAR.lock().unwrap() {
+            Some(bar) => bar.suspend(|| eprintln!("{line}")),
+            None => eprintln!("{line}"),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init(verbosity: usize) {
+    let level = match verbosity {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Prints lines above `bar` from now on.
+pub fn above(bar: &ProgressBar) {
+    *BAR.lock().unwrap() = Some(bar.clone());
+}
@@ -1,17 +1,65 @@
-use std::{env, fs::File, io::Cursor, str::FromStr, thread};
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
 
 use anyhow::{Context, Result};
-use core::time;
-use epub_builder::{EpubBuilder, EpubContent, EpubVersion, ReferenceType, TocElement, ZipCommand};
-use html5ever::{
-    tendril::{ByteTendril, ReadExt},
-    tokenizer::{BufferQueue, TokenSink, Tokenizer, TokenizerOpts},
+use epub_dude::{
+    BookSource, BuildOptions, DEFAULT_DESCRIPTION_LIMIT, DEFAULT_LANGUAGE, DEFAULT_USER_AGENTS,
+    Delays, Error, Fetcher, HttpFetcher, Progress, SortOrder, Summary, TimeLimits, build_anthology,
+    build_epub, catalog, check, cleanup, dates, exit_code, fetch, headings, images, metadata,
+    outcome, output, politeness, resolve, selection,
+    session::{Replay, Session},
+    split, template, tls, updates, workdir, xhtml,
 };
 use http::Uri;
-use indicatif::{ProgressBar, ProgressStyle};
-use ureq::{Agent, BodyReader, unversioned::multipart::Form};
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use regex::Regex;
+use ureq::{Agent, config::IpFamily, unversioned::multipart::Form};
 
-mod fetch;
+mod hook;
+mod logger;
+mod picker;
+
+const DEFAULT_FOOTER: &str = "Source: {url}, fetched {date}";
+
+/// Draws the chapter loop's progress on stderr.
+struct Bar(ProgressBar);
+
+impl Bar {
+    /// A bar hidden until the chapter loop starts, with log lines printed
+    /// above it.
+    fn new() -> Self {
+        let bar = ProgressBar::hidden();
+        logger::above(&bar);
+        Bar(bar)
+    }
+}
+
+impl Progress for Bar {
+    fn start(&self, chapters: usize, title: &str) {
+        self.0.set_draw_target(ProgressDrawTarget::stderr());
+        self.0.set_length(chapters as u64);
+        self.0.set_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {prefix} {msg}",
+                )
+                .expect("valid progress template")
+                .progress_chars("#>-"),
+        );
+        self.0.set_message(format!("Processing {title}"));
+    }
+
+    fn chapter_done(&self) {
+        self.0.inc(1);
+    }
+
+    fn downloaded(&self, bytes: usize) {
+        self.0.set_prefix(HumanBytes(bytes as u64).to_string());
+    }
+
+    fn finish(&self) {
+        self.0.finish_with_message("Done");
+    }
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -19,7 +67,7 @@ fn main() {
 
     if args.len() < 2 {
         print_usage(&args[0]);
-        return;
+        std::process::exit(exit_code::USAGE);
     }
 
     let command = &args[1];
@@ -28,13 +76,537 @@ fn main() {
         "fetch" => {
             let mut opts = getopts::Options::new();
             opts.optflag("h", "help", "print this help menu");
+            opts.optflagmulti("v", "verbose", "log more details; repeat for trace output");
+            opts.optopt(
+                "",
+                "claimed-count",
+                "regex locating the chapter total claimed on the index page, e.g. '共\\s*(\\d+)\\s*章'",
+                "REGEX",
+            );
+            opts.optopt(
+                "",
+                "count-tolerance",
+                "allowed difference between claimed and scraped chapter counts (default 0)",
+                "N",
+            );
+            opts.optflag(
+                "",
+                "strict-count",
+                "fail instead of warning when the chapter counts differ",
+            );
+            opts.optopt(
+                "",
+                "sort",
+                "chapter order: document (default) or title-number",
+                "ORDER",
+            );
+            opts.optflag(
+                "",
+                "strict-sequence",
+                "fail when chapter numbers have gaps, duplicates or are out of order",
+            );
+            opts.optopt(
+                "",
+                "manifest",
+                "write a JSON manifest of the build to FILE",
+                "FILE",
+            );
+            opts.optopt(
+                "",
+                "fallback-url",
+                "index page of a mirror used for chapters missing or empty on the primary",
+                "URL",
+            );
+            opts.optflagopt(
+                "",
+                "chapter-footer",
+                "append a source line to each chapter; TEMPLATE may use {url}, {date} and {index}",
+                "TEMPLATE",
+            );
+            opts.optopt(
+                "",
+                "chapter-template",
+                "lay out chapter pages with the Jinja-style template in FILE, given title, body, index, total, prev_href, next_href and lang",
+                "FILE",
+            );
+            opts.optflag(
+                "",
+                "chapter-nav",
+                "end each chapter with links to the previous and next chapters and the table of contents",
+            );
+            opts.optflag(
+                "",
+                "dedupe-toc-titles",
+                "number repeated chapter titles in the table of contents, e.g. 无题 (2)",
+            );
+            opts.optopt(
+                "f",
+                "format",
+                "output format: epub (default), kepub (Kobo), txt, md or html (a single page)",
+                "FORMAT",
+            );
+            opts.optmulti(
+                "",
+                "exclude-title",
+                "skip chapters whose title matches REGEX; repeatable",
+                "REGEX",
+            );
+            opts.optmulti(
+                "",
+                "include-title",
+                "build only chapters whose title matches REGEX, even if excluded; repeatable",
+                "REGEX",
+            );
+            opts.optopt(
+                "",
+                "chapter-date-selector",
+                "element next to each chapter link holding its date, e.g. span.time",
+                "SELECTOR",
+            );
+            opts.optopt(
+                "",
+                "since",
+                "build only chapters dated DATE (YYYY-MM-DD) or later; needs --chapter-date-selector",
+                "DATE",
+            );
+            opts.optflag(
+                "",
+                "update",
+                "write nothing unless the index lists chapters the last complete build didn't have, or chapters are still locked; reuses the pages of chapters already built",
+            );
+            opts.optflag(
+                "",
+                "rebuild",
+                "build the book again from the index and chapters its last complete build kept, without downloading anything",
+            );
+            opts.optopt(
+                "",
+                "locked-pattern",
+                "treat chapters whose text matches REGEX as locked on the site: build a placeholder and retry them on --update runs",
+                "REGEX",
+            );
+            opts.optopt(
+                "",
+                "post-hook",
+                "run COMMAND in the shell on every file written, with EPUB_DUDE_OUTPUT, EPUB_DUDE_TITLE, EPUB_DUDE_AUTHOR, EPUB_DUDE_SOURCE and EPUB_DUDE_CHAPTERS (and EPUB_DUDE_PART for parts) set; its failure fails the run",
+                "COMMAND",
+            );
+            opts.optflag(
+                "",
+                "ignore-hook-failure",
+                "only report a --post-hook that fails",
+            );
+            opts.optflag(
+                "",
+                "merge",
+                "build all the URLs into one book, each under its own title in the contents (use with --title)",
+            );
+            opts.optflag(
+                "",
+                "interactive",
+                "pick the chapters to build from the index in the terminal",
+            );
+            opts.optopt(
+                "",
+                "chapters-file",
+                "build only the chapters listed in FILE (as printed by `list`), in its order",
+                "FILE",
+            );
+            opts.optopt("", "title", "override the scraped book title", "TITLE");
+            opts.optmulti(
+                "",
+                "author",
+                "override the scraped author; repeat for several authors",
+                "AUTHOR",
+            );
+            opts.optflag(
+                "",
+                "prefer-og",
+                "take the title, description and identifier from the page's Open Graph tags and canonical URL over the scraped ones",
+            );
+            opts.optflag(
+                "",
+                "require-author",
+                "fail when the index page gives no author, instead of crediting \"Unknown\"",
+            );
+            opts.optmulti("", "translator", "add a translator; repeatable", "NAME");
+            opts.optmulti("", "illustrator", "add an illustrator; repeatable", "NAME");
+            opts.optmulti(
+                "",
+                "title-strip",
+                "regex removed from the scraped title; repeatable",
+                "REGEX",
+            );
+            opts.optflag(
+                "",
+                "no-default-title-strip",
+                "do not apply the built-in site-name suffix rules to the title",
+            );
+            opts.optmulti(
+                "",
+                "author-strip",
+                "regex removed from the scraped author; repeatable",
+                "REGEX",
+            );
+            opts.optflag(
+                "",
+                "no-default-author-strip",
+                "do not strip the built-in label prefixes such as 作者： from the author",
+            );
+            opts.optflag(
+                "",
+                "fold-width",
+                "map full-width letters, digits and punctuation to half-width in output file names",
+            );
+            opts.optflag(
+                "",
+                "merge-softwrap",
+                "join lines of prose the site hard-wrapped at a fixed width back into paragraphs",
+            );
+            opts.optflag(
+                "",
+                "per-paragraph-lang",
+                "tag paragraphs written mostly in another script than the book's language with a language of their own",
+            );
+            opts.optflag(
+                "",
+                "images",
+                "download and embed chapter images (as data URIs with --format html)",
+            );
+            opts.optopt(
+                "",
+                "alt-template",
+                "alt text for images without one; {n} is the image number (default \"Illustration {n}\")",
+                "TEMPLATE",
+            );
+            opts.optflag(
+                "",
+                "decorative-images",
+                "mark images without alt text as decorative instead",
+            );
+            opts.optopt(
+                "",
+                "max-image-dimension",
+                "downscale embedded images larger than PX on either side",
+                "PX",
+            );
+            opts.optopt(
+                "",
+                "image-quality",
+                "JPEG quality 1-100 when recompressing images (default 85)",
+                "Q",
+            );
+            opts.optopt(
+                "",
+                "image-attrs",
+                "comma-separated img attributes holding the URL, in priority order (default data-src,data-original,srcset,src)",
+                "ATTRS",
+            );
+            opts.optopt(
+                "",
+                "skip-image-pattern",
+                "leave out images whose URL matches REGEX, such as a site's banner or watermark",
+                "REGEX",
+            );
+            opts.optopt(
+                "",
+                "image-formats",
+                "comma-separated image formats the reader can show, from jpeg, png, gif, webp and svg; images in others are converted to JPEG or PNG (default all)",
+                "FORMATS",
+            );
+            opts.optflag(
+                "",
+                "cover-from-content",
+                "without a cover on the index page, take the first chapter's first image that is big enough (needs --images)",
+            );
+            opts.optopt(
+                "",
+                "cover-min-dimension",
+                "how many pixels the shorter side of an image needs for --cover-from-content to take it (default 300)",
+                "PX",
+            );
+            opts.optopt(
+                "",
+                "writing-mode",
+                "horizontal-tb (default) or vertical-rl for right-to-left vertical text",
+                "MODE",
+            );
+            opts.optopt(
+                "",
+                "theme",
+                "built-in stylesheet: plain (default, the reader's own typography), classic, modern or night-safe",
+                "THEME",
+            );
+            opts.optflag("", "justify", "justify the text, over the theme");
+            opts.optopt(
+                "",
+                "font-size",
+                "text size as a percentage of the reader's, over the theme",
+                "PERCENT",
+            );
+            opts.optopt(
+                "",
+                "line-height",
+                "line height, e.g. 1.6, over the theme",
+                "FLOAT",
+            );
+            opts.optopt(
+                "",
+                "paragraph-spacing",
+                "space after each paragraph in em, e.g. 0.5, over the theme",
+                "EM",
+            );
+            opts.optflag(
+                "",
+                "print-css",
+                "print the stylesheet the book would embed, with the theme and typography options, and exit",
+            );
+            opts.optopt(
+                "",
+                "epub-version",
+                "3 (default) or 2 for older readers",
+                "VERSION",
+            );
+            opts.optopt(
+                "",
+                "compression-level",
+                "deflate level of the epub's pages, 0 (stored) to 9 (default); images and fonts are always stored",
+                "N",
+            );
+            opts.optopt(
+                "",
+                "footnote-marker",
+                "selector of footnote markers in the text, e.g. sup.note",
+                "SELECTOR",
+            );
+            opts.optopt(
+                "",
+                "footnote-container",
+                "selector of the block holding the notes, one per child, e.g. div.notes",
+                "SELECTOR",
+            );
+            opts.optflag("", "no-title-page", "do not generate a title page");
+            opts.optflag(
+                "",
+                "headings",
+                "turn section markers such as （一）, Part 2 or 【...】 lines into sub-TOC entries",
+            );
+            opts.optmulti(
+                "",
+                "heading-pattern",
+                "regex for section heading lines; without --headings only these are used",
+                "REGEX",
+            );
+            opts.optopt(
+                "",
+                "max-subheadings",
+                "cap on section entries per chapter (default 30)",
+                "N",
+            );
+            opts.optopt(
+                "",
+                "max-chapter-size",
+                "split chapters whose XHTML exceeds N KB at paragraph boundaries (default 300)",
+                "N",
+            );
+            opts.optopt(
+                "",
+                "split-every",
+                "write the book as \"<title> - Part N.epub\" files of N chapters each, as a calibre series",
+                "N",
+            );
+            opts.optflag(
+                "",
+                "colophon",
+                "append a page recording the source, fetch dates and tool version",
+            );
+            opts.optflag(
+                "",
+                "author-page",
+                "follow the author's link on the index page and append their bio as an \"About the Author\" page",
+            );
+            opts.optopt(
+                "",
+                "author-bio-selector",
+                "where the author's page keeps the bio, for --author-page (default .description)",
+                "SELECTOR",
+            );
+            opts.optopt(
+                "",
+                "description-limit",
+                "truncate the title page description after N characters (default 500)",
+                "N",
+            );
+            opts.optflag(
+                "",
+                "toc-description",
+                "show the description above the table of contents with --format html (an epub's is on its title page)",
+            );
+            opts.optopt(
+                "",
+                "language",
+                "book language as a BCP 47 tag (default zh)",
+                "LANG",
+            );
+            opts.optopt(
+                "",
+                "toc-title",
+                "title of the table of contents (default from --language, e.g. 目录 for zh)",
+                "TITLE",
+            );
+            opts.optopt(
+                "",
+                "reading-speed",
+                "characters (zh/ja) or words per minute for the reading time estimate",
+                "N",
+            );
+            opts.optflag(
+                "",
+                "length-meta",
+                "record the book length and reading time in the epub metadata",
+            );
+            opts.optopt("j", "jobs", "chapters to fetch at once (default 1)", "N");
+            opts.optopt(
+                "",
+                "delay",
+                "wait MS milliseconds after each request (default 900); hosts that answered 429 are remembered and given longer",
+                "MS",
+            );
+            opts.optflag(
+                "",
+                "no-adaptive",
+                "keep each host's remembered delay and concurrency as they are instead of adjusting them",
+            );
+            opts.optopt(
+                "",
+                "checkpoint-every",
+                "save parsed chapters every N chapters so a crashed build can resume",
+                "N",
+            );
+            opts.optflag(
+                "",
+                "partial-epub",
+                "with --checkpoint-every, also write the book so far to <output>.partial.epub",
+            );
+            opts.optopt(
+                "",
+                "work-dir",
+                "root of the per-book state directories (default ~/.local/share/epub-dude)",
+                "DIR",
+            );
+            opts.optopt(
+                "",
+                "temp-dir",
+                "where to stage the files of an epub while it is built (default: the system's temp directory)",
+                "DIR",
+            );
+            opts.optopt(
+                "",
+                "zip-command",
+                "external zip command to build epubs with (default zip; the built-in zip library on Windows or when it can't be run)",
+                "CMD",
+            );
+            network_opts(&mut opts);
+            opts.optopt(
+                "",
+                "max-total-bytes",
+                "stop once downloads exceed BYTES (K/M/G suffixes allowed), writing the book so far to <output>.partial.<ext>",
+                "BYTES",
+            );
+            opts.optflag(
+                "",
+                "result-json",
+                "print how the run went as one JSON object on stdout at the end",
+            );
+            opts.optopt(
+                "",
+                "deadline",
+                "stop fetching after DURATION (e.g. 90s, 30m or 6h), writing the book so far to <output>.partial.<ext>",
+                "DURATION",
+            );
+            opts.optopt(
+                "",
+                "chapter-timeout",
+                "give up on a chapter page after DURATION, retries included",
+                "DURATION",
+            );
+            opts.optopt(
+                "",
+                "max-chapter-text",
+                "drop chapter text beyond BYTES (K/M/G suffixes allowed, default 8M)",
+                "BYTES",
+            );
+            opts.optflag(
+                "",
+                "rotate-user-agent",
+                "on HTTP 403, retry with another browser user agent and a referer (off by default)",
+            );
+            opts.optopt(
+                "",
+                "user-agent-file",
+                "extra user agents for --rotate-user-agent, one per line",
+                "FILE",
+            );
+            opts.optopt(
+                "",
+                "dump-http",
+                "write every request and raw response to numbered files in DIR",
+                "DIR",
+            );
+            opts.optopt(
+                "",
+                "record",
+                "save every request and response, in order, to FILE for --replay",
+                "FILE",
+            );
+            opts.optopt(
+                "",
+                "replay",
+                "serve the responses recorded in FILE instead of the network",
+                "FILE",
+            );
+            opts.optflag(
+                "",
+                "retry-permanent",
+                "fetch chapters that earlier runs found gone (404/410) again",
+            );
+            opts.optflag(
+                "",
+                "check-revisions",
+                "report chapters whose text changed since the last run with this flag",
+            );
+            opts.optopt(
+                "",
+                "revision-diff",
+                "write a diff of each revised chapter to DIR (implies --check-revisions)",
+                "DIR",
+            );
+            opts.optopt(
+                "",
+                "wait-lock",
+                "wait up to SECS for another run building the same book (default: fail at once)",
+                "SECS",
+            );
+            opts.optflag(
+                "",
+                "validate",
+                "check the finished epub's structure and fail if anything is broken",
+            );
+            opts.optflag(
+                "",
+                "no-provenance",
+                "do not embed the source URL in the epub metadata",
+            );
+            opts.optopt(
+                "o",
+                "output-template",
+                "output path, with {title}, {author}, {date}, {host}, {last_chapter} and {last_chapter_title} placeholders (default \"{title}.epub\")",
+                "TEMPLATE",
+            );
 
             let matches = match opts.parse(&args[2..]) {
                 Ok(m) => m,
-                Err(f) => {
-                    eprintln!("{f}");
-                    std::process::exit(1);
-                }
+                Err(f) => usage_error(&f.to_string()),
             };
 
             if matches.opt_present("h") {
@@ -43,16 +615,142 @@ fn main() {
                 return;
             }
 
+            if matches.opt_present("print-css") {
+                match fetch_options(&matches) {
+                    Ok(options) => print!(
+                        "{}",
+                        xhtml::stylesheet(options.writing_mode, options.theme, &options.typography)
+                    ),
+                    Err(e) => usage_error(&format!("{e:#}")),
+                }
+                return;
+            }
+
             if matches.free.is_empty() {
-                eprintln!("Error: Missing URL for fetch command");
-                std::process::exit(1);
+                usage_error("Missing URL for fetch command");
             }
 
-            for u in &matches.free {
-                let url = Uri::from_str(u).expect("Parse Uri failed");
-                if let Err(e) = process_book(&url, &agent) {
-                    eprintln!("Failed to process {url}: {e}");
+            let verbose = matches.opt_count("v") > 0;
+            logger::init(matches.opt_count("v"));
+
+            let options = match fetch_options(&matches) {
+                Ok(o) => o,
+                Err(e) => usage_error(&format!("{e:#}")),
+            };
+
+            let merge = matches.opt_present("merge");
+
+            let hook = matches.opt_str("post-hook").map(|command| hook::Hook {
+                command,
+                ignore_failure: matches.opt_present("ignore-hook-failure"),
+            });
+            let record = matches.opt_str("record").map(PathBuf::from);
+            let started = std::time::Instant::now();
+            let mut outcome = outcome::RunResult::default();
+            let status = if let Some(path) = matches.opt_str("replay") {
+                if record.is_some() || matches.opt_present("dump-http") {
+                    usage_error("--replay can't be combined with --record or --dump-http");
+                }
+                let replay = match Session::load(std::path::Path::new(&path)) {
+                    Ok(session) => Replay::new(session),
+                    Err(e) => usage_error(&format!("{e:#}")),
+                };
+                let mut status = fetch_books(
+                    &matches.free,
+                    &replay,
+                    &options,
+                    merge,
+                    hook.as_ref(),
+                    verbose,
+                    &mut outcome,
+                );
+                // Even a skipped image means the replay didn't match the recording.
+                let misses = replay.misses();
+                if !misses.is_empty() {
+                    eprintln!("Requested but not in {path}:");
+                    for url in &misses {
+                        eprintln!("  - {url}");
+                    }
+                    if status == 0 {
+                        status = exit_code::FAILURE;
+                    }
+                }
+                status
+            } else {
+                let agent = match network_agent(&matches) {
+                    Ok(agent) => agent,
+                    Err(e) => usage_error(&format!("{e:#}")),
+                };
+                let mut fetcher = match user_agents(&matches) {
+                    Ok(pool) => HttpFetcher::new(agent).rotate_user_agents(pool),
+                    Err(e) => usage_error(&format!("{e:#}")),
+                };
+                match time_limits(&matches, &options) {
+                    Ok(limits) => fetcher = fetcher.time_limits(limits),
+                    Err(e) => usage_error(&format!("{e:#}")),
+                }
+                if let Some(ms) = matches.opt_str("delay") {
+                    match ms.parse() {
+                        Ok(ms) => {
+                            fetcher = fetcher.delays(Delays {
+                                after_request: Duration::from_millis(ms),
+                                ..Delays::default()
+                            })
+                        }
+                        Err(_) => {
+                            usage_error(&format!("Invalid --delay: {ms} (expected milliseconds)"))
+                        }
+                    }
+                }
+                fetcher = fetcher.politeness(
+                    politeness::default_path(),
+                    !matches.opt_present("no-adaptive"),
+                );
+                if let Some(dir) = matches.opt_str("dump-http") {
+                    fetcher = fetcher.dump_http(dir.into());
+                }
+                if record.is_some() {
+                    fetcher = fetcher.record();
+                }
+                let mut status = fetch_books(
+                    &matches.free,
+                    &fetcher,
+                    &options,
+                    merge,
+                    hook.as_ref(),
+                    verbose,
+                    &mut outcome,
+                );
+                if let Err(e) = fetcher.save_politeness() {
+                    log::warn!("Failed to save the host profiles: {e:#}");
                 }
+                // Failed runs are the ones worth recording, so always save.
+                if let (Some(path), Some(session)) = (&record, fetcher.session()) {
+                    match session.save(path) {
+                        Ok(()) => log::info!(
+                            "Recorded {} requests to {}",
+                            session.exchanges.len(),
+                            path.display()
+                        ),
+                        Err(e) => {
+                            eprintln!("Failed to save the recording: {e:#}");
+                            if status == 0 {
+                                status = exit_code::OUTPUT;
+                            }
+                        }
+                    }
+                }
+                status
+            };
+            if matches.opt_present("result-json") {
+                outcome.finish(status, started.elapsed());
+                match serde_json::to_string(&outcome) {
+                    Ok(json) => println!("{json}"),
+                    Err(e) => eprintln!("Failed to write the result: {e}"),
+                }
+            }
+            if status != 0 {
+                std::process::exit(status);
             }
         }
         "send" => {
@@ -68,10 +766,7 @@ fn main() {
 
             let matches = match opts.parse(&args[2..]) {
                 Ok(m) => m,
-                Err(f) => {
-                    eprintln!("{f}");
-                    std::process::exit(1);
-                }
+                Err(f) => usage_error(&f.to_string()),
             };
 
             if matches.opt_present("h") {
@@ -81,30 +776,371 @@ fn main() {
             }
 
             let Some(key) = matches.opt_str("k") else {
-                eprintln!("Error: -k/--key is required for the send command");
-                std::process::exit(1);
+                usage_error("-k/--key is required for the send command");
             };
 
             if matches.free.is_empty() {
-                eprintln!("Error: Missing file path for send command");
-                std::process::exit(1);
+                usage_error("Missing file path for send command");
             }
 
             let kepubify = !matches.opt_present("no-kepubify");
             let kindlegen = !matches.opt_present("no-kindlegen");
 
+            let mut status = 0;
             for file in &matches.free {
                 if let Err(e) = send_to_djazz(&agent, file, &key, kepubify, kindlegen) {
-                    eprintln!("Failed to send {file}: {e}");
+                    eprintln!("Failed to send {file}: {e:#}");
+                    status = exit_code::NETWORK;
                 }
             }
+            if status != 0 {
+                std::process::exit(status);
+            }
+        }
+        "list" => {
+            let mut opts = getopts::Options::new();
+            opts.optflag("h", "help", "print this help menu");
+            opts.optopt(
+                "",
+                "format",
+                "output format: tsv (default) or json",
+                "FORMAT",
+            );
+            network_opts(&mut opts);
+
+            let matches = match opts.parse(&args[2..]) {
+                Ok(m) => m,
+                Err(f) => usage_error(&f.to_string()),
+            };
+
+            if matches.opt_present("h") {
+                let brief = format!("Usage: {} list [options] <URL>", args[0]);
+                print!("{}", opts.usage(&brief));
+                return;
+            }
+
+            let [url] = matches.free.as_slice() else {
+                usage_error("The list command takes exactly one URL");
+            };
+            let format: selection::ListFormat = match matches.opt_str("format") {
+                Some(format) => match format.parse() {
+                    Ok(format) => format,
+                    Err(e) => usage_error(&format!("{e:#}")),
+                },
+                None => selection::ListFormat::default(),
+            };
+            let url = match Uri::from_str(url) {
+                Ok(url) => url,
+                Err(e) => usage_error(&format!("Invalid URL {url}: {e}")),
+            };
+
+            let agent = match network_agent(&matches) {
+                Ok(agent) => agent,
+                Err(e) => usage_error(&format!("{e:#}")),
+            };
+            let fetcher = HttpFetcher::new(agent);
+            let listing = BookSource::new(url.clone())
+                .and_then(|source| source.info(&fetcher))
+                .map(|info| selection::ChapterListing::new(&url, &info));
+            let written = listing.and_then(|listing| {
+                listing
+                    .write(format, &mut std::io::stdout().lock())
+                    .map_err(Error::from)
+            });
+            if let Err(e) = written {
+                report(&format!("Failed to list {url}"), &e, false);
+                std::process::exit(e.exit_code());
+            }
+        }
+        "clean" => {
+            let mut opts = getopts::Options::new();
+            opts.optflag("h", "help", "print this help menu");
+            opts.optflag("", "list", "show the known books instead of removing any");
+            opts.optopt(
+                "",
+                "older-than",
+                "remove every book last built more than DAYS ago",
+                "DAYS",
+            );
+            opts.optopt(
+                "",
+                "work-dir",
+                "root of the per-book state directories (default ~/.local/share/epub-dude)",
+                "DIR",
+            );
+
+            let matches = match opts.parse(&args[2..]) {
+                Ok(m) => m,
+                Err(f) => usage_error(&f.to_string()),
+            };
+
+            if matches.opt_present("h") {
+                let brief = format!(
+                    "Usage: {} clean [options] [<URL>...]\n\nRemoves the saved state of the given books, or of those not built in --older-than days.",
+                    args[0]
+                );
+                print!("{}", opts.usage(&brief));
+                return;
+            }
+
+            let root = matches
+                .opt_str("work-dir")
+                .map_or_else(workdir::default_root, PathBuf::from);
+            let older_than = matches
+                .opt_str("older-than")
+                .map(|days| match days.parse::<i64>() {
+                    Ok(days) if days >= 0 => chrono::Duration::days(days),
+                    _ => usage_error(&format!(
+                        "Invalid --older-than: {days} (expected a number of days)"
+                    )),
+                });
+            let list = matches.opt_present("list");
+            if !list && older_than.is_none() && matches.free.is_empty() {
+                usage_error("Give the URLs of books to clean, --older-than or --list");
+            }
+
+            if let Err(e) = clean(&root, &matches.free, older_than, list) {
+                report("Failed to clean", &e, false);
+                std::process::exit(e.exit_code());
+            }
+        }
+        "check" => {
+            let mut opts = getopts::Options::new();
+            opts.optflag("h", "help", "print this help menu");
+            opts.optopt(
+                "",
+                "work-dir",
+                "root of the per-book state directories (default ~/.local/share/epub-dude)",
+                "DIR",
+            );
+            network_opts(&mut opts);
+
+            let matches = match opts.parse(&args[2..]) {
+                Ok(m) => m,
+                Err(f) => usage_error(&f.to_string()),
+            };
+
+            if matches.opt_present("h") {
+                let brief = format!(
+                    "Usage: {} check [options] <URL>\n\nFetches only the index page and lists chapters the last complete build didn't have.\nExits 0 when up to date and {} when there are new chapters.",
+                    args[0],
+                    exit_code::UPDATES
+                );
+                print!("{}", opts.usage(&brief));
+                return;
+            }
+
+            let [url] = matches.free.as_slice() else {
+                usage_error("The check command takes exactly one URL");
+            };
+            let url = match Uri::from_str(url) {
+                Ok(url) => url,
+                Err(e) => usage_error(&format!("Invalid URL {url}: {e}")),
+            };
+            let root = matches
+                .opt_str("work-dir")
+                .map_or_else(workdir::default_root, PathBuf::from);
+
+            let agent = match network_agent(&matches) {
+                Ok(agent) => agent,
+                Err(e) => usage_error(&format!("{e:#}")),
+            };
+            let fetcher = HttpFetcher::new(agent);
+            let updates = BookSource::new(url.clone())
+                .and_then(|source| updates::check(&source, &fetcher, &root));
+            match updates {
+                Ok(updates) if updates.new.is_empty() => println!("{}: up to date", updates.title),
+                Ok(updates) => {
+                    if updates.built {
+                        println!("{}: {} new chapters", updates.title, updates.new.len());
+                    } else {
+                        println!(
+                            "{}: never built, {} chapters",
+                            updates.title,
+                            updates.new.len()
+                        );
+                    }
+                    for link in &updates.new {
+                        println!("  {}", link.title);
+                    }
+                    std::process::exit(exit_code::UPDATES);
+                }
+                Err(e) => {
+                    report(&format!("Failed to check {url}"), &e, false);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        "catalog" => {
+            let mut opts = getopts::Options::new();
+            opts.optflag("h", "help", "print this help menu");
+            opts.optopt(
+                "o",
+                "output",
+                &format!(
+                    "where to write the feed (default DIR/{})",
+                    catalog::DEFAULT_FEED
+                ),
+                "FILE",
+            );
+            opts.optopt(
+                "",
+                "title",
+                &format!("title of the feed (default \"{}\")", catalog::DEFAULT_TITLE),
+                "TITLE",
+            );
+
+            let matches = match opts.parse(&args[2..]) {
+                Ok(m) => m,
+                Err(f) => usage_error(&f.to_string()),
+            };
+
+            if matches.opt_present("h") {
+                let brief = format!(
+                    "Usage: {} catalog [options] <DIR>\n\nWrites an OPDS feed of the epubs under DIR, with their covers in DIR/covers.",
+                    args[0]
+                );
+                print!("{}", opts.usage(&brief));
+                return;
+            }
+
+            let [dir] = matches.free.as_slice() else {
+                usage_error("The catalog command takes exactly one directory");
+            };
+            let dir = PathBuf::from(dir);
+            if !dir.is_dir() {
+                usage_error(&format!("Not a directory: {}", dir.display()));
+            }
+            let feed = matches
+                .opt_str("output")
+                .map_or_else(|| dir.join(catalog::DEFAULT_FEED), PathBuf::from);
+            let title = matches
+                .opt_str("title")
+                .unwrap_or_else(|| catalog::DEFAULT_TITLE.to_string());
+
+            let written = catalog::scan(&dir).and_then(|books| {
+                catalog::write(&dir, &feed, &title, &books)?;
+                println!("{} books in {}", books.len(), feed.display());
+                Ok(())
+            });
+            if let Err(e) = written.map_err(Error::from) {
+                report(&format!("Failed to catalog {}", dir.display()), &e, false);
+                std::process::exit(e.exit_code());
+            }
         }
         _ => {
             eprintln!("Unknown command: {command}");
             print_usage(&args[0]);
-            std::process::exit(1);
+            std::process::exit(exit_code::USAGE);
+        }
+    }
+}
+
+/// Builds every book in `urls`, or with `merge` one book of them all,
+/// running `hook` on each, and returns the exit status for the run.
+fn fetch_books(
+    urls: &[String],
+    fetcher: &impl Fetcher,
+    options: &BuildOptions,
+    merge: bool,
+    hook: Option<&hook::Hook>,
+    verbose: bool,
+    outcome: &mut outcome::RunResult,
+) -> i32 {
+    if merge {
+        let sources = urls
+            .iter()
+            .map(|u| match Uri::from_str(u) {
+                Ok(url) => BookSource::new(url),
+                Err(e) => Err(Error::Usage(format!("Invalid URL {u}: {e}"))),
+            })
+            .collect::<Result<Vec<_>, _>>();
+        let bar = Bar::new();
+        let result = sources.and_then(|sources| build_anthology(&sources, fetcher, options, &bar));
+        outcome.record(&result);
+        return finished(&urls.join(", "), result, &bar, hook, verbose);
+    }
+
+    // Later books are still attempted; the first failure sets the code.
+    let mut status = 0;
+    for u in urls {
+        let url = match Uri::from_str(u) {
+            Ok(url) => url,
+            Err(e) => {
+                eprintln!("Invalid URL {u}: {e}");
+                outcome.error(format!("Invalid URL {u}: {e}"));
+                if status == 0 {
+                    status = exit_code::USAGE;
+                }
+                continue;
+            }
+        };
+        let bar = Bar::new();
+        let result = BookSource::new(url.clone())
+            .and_then(|source| build_epub(&source, fetcher, options, &bar));
+        outcome.record(&result);
+        let code = finished(&url.to_string(), result, &bar, hook, verbose);
+        if status == 0 {
+            status = code;
+        }
+    }
+    status
+}
+
+/// Prints how building `what` went and runs `hook` on a success,
+/// returning the exit status.
+fn finished(
+    what: &str,
+    result: Result<Summary, Error>,
+    bar: &Bar,
+    hook: Option<&hook::Hook>,
+    verbose: bool,
+) -> i32 {
+    match result {
+        Ok(summary) => {
+            summary.print();
+            hook.and_then(|hook| hook.run(&summary))
+                .unwrap_or_else(|| summary.exit_code())
+        }
+        Err(e) => {
+            bar.0.abandon();
+            if let Error::Validation { problems, .. } = &e {
+                eprintln!("Validation of {what} failed:");
+                for p in problems {
+                    eprintln!("  - {p}");
+                }
+            } else {
+                report(&format!("Failed to process {what}"), &e, verbose);
+            }
+            e.exit_code()
+        }
+    }
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("Error: {message}");
+    std::process::exit(exit_code::USAGE);
+}
+
+/// Prints `e` and its causes on one line, or with `verbose` a line each.
+fn report(what: &str, e: &Error, verbose: bool) {
+    let causes = std::iter::successors(std::error::Error::source(e), |c| c.source());
+    if verbose {
+        eprintln!("{what}: {e}");
+        for cause in causes {
+            eprintln!("  caused by: {cause}");
+        }
+        return;
+    }
+    // Every cause on one line, leaving out those a message already ends
+    // with.
+    let mut line = e.to_string();
+    for cause in causes.map(|c| c.to_string()) {
+        if !line.ends_with(&cause) {
+            line = format!("{line}: {cause}");
         }
     }
+    eprintln!("{what}: {line}");
 }
 
 fn print_usage(program: &str) {
@@ -115,8 +1151,670 @@ fn print_usage(program: &str) {
     println!(
         "  send [options] <FILE.epub>...  Send an existing epub to a Kobo/Kindle using send.djazz.se"
     );
+    println!("  list [options] <URL>           Print a book's chapters, for --chapters-file");
+    println!("  clean [options] [<URL>...]     Remove or list the state kept for books");
+    println!("  check [options] <URL>          Report whether a book has new chapters");
+    println!("  catalog [options] <DIR>        Write an OPDS feed of the epubs in a directory");
     println!();
     println!("Run `{program} <command> --help` for more information on a command.");
+    println!();
+    println!("Exit codes:");
+    for (code, meaning) in [
+        (exit_code::SUCCESS, "success"),
+        (exit_code::FAILURE, "other failure"),
+        (exit_code::USAGE, "bad arguments or an unsupported site"),
+        (
+            exit_code::NETWORK,
+            "a download failed, or --max-total-bytes ran out",
+        ),
+        (exit_code::PARSE, "a page didn't have what was expected"),
+        (
+            exit_code::OUTPUT,
+            "a file couldn't be written, or --validate failed",
+        ),
+        (exit_code::LOCKED, "another run is building the book"),
+        (exit_code::DEADLINE, "--deadline passed"),
+        (
+            exit_code::SKIPPED,
+            "built, leaving out chapters the site no longer has",
+        ),
+        (
+            exit_code::UP_TO_DATE,
+            "every chapter was filtered out, or --update found none new; nothing built",
+        ),
+        (exit_code::UPDATES, "check found new chapters"),
+    ] {
+        println!("  {code:<3} {meaning}");
+    }
+}
+
+/// Lists the books under `root`, or removes those in `urls` and, with
+/// `older_than`, those not built within it.
+fn clean(
+    root: &std::path::Path,
+    urls: &[String],
+    older_than: Option<chrono::Duration>,
+    list: bool,
+) -> Result<(), Error> {
+    let books = workdir::list(root)?;
+    if list {
+        for book in &books {
+            let last_run = book
+                .last_run()
+                .map_or("-".to_string(), |t| t.format("%Y-%m-%d %H:%M").to_string());
+            let (title, url) = match &book.record {
+                Some(record) => (record.title.as_str(), record.url.as_str()),
+                None => ("?", "?"),
+            };
+            println!("{last_run}\t{}\t{title}\t{url}", HumanBytes(book.size));
+        }
+        return Ok(());
+    }
+
+    let mut doomed = Vec::new();
+    for u in urls {
+        let url = Uri::from_str(u).map_err(|e| Error::Usage(format!("Invalid URL {u}: {e}")))?;
+        let dir = workdir::book_dir(root, &url);
+        if dir.is_dir() {
+            doomed.push(dir);
+        } else {
+            eprintln!("Nothing saved for {u}");
+        }
+    }
+    if let Some(age) = older_than {
+        let cutoff = chrono::Local::now() - age;
+        doomed.extend(
+            books
+                .iter()
+                .filter(|b| b.last_run().is_none_or(|t| t < cutoff))
+                .map(|b| b.dir.clone()),
+        );
+    }
+    doomed.sort();
+    doomed.dedup();
+
+    for dir in &doomed {
+        let size = books.iter().find(|b| &b.dir == dir).map_or(0, |b| b.size);
+        workdir::remove(dir)?;
+        println!("Removed {} ({})", dir.display(), HumanBytes(size));
+    }
+    Ok(())
+}
+
+/// `--ipv4-only`, `--ipv6-only`, `--resolve`, `--cacert`, `--insecure` and
+/// `--no-keepalive`, for the commands that fetch.
+fn network_opts(opts: &mut getopts::Options) {
+    opts.optflag("4", "ipv4-only", "connect over IPv4 only");
+    opts.optflag("6", "ipv6-only", "connect over IPv6 only");
+    opts.optmulti(
+        "",
+        "resolve",
+        "connect to HOST:PORT at ADDR instead of looking it up, as curl does; repeatable",
+        "HOST:PORT:ADDR",
+    );
+    opts.optopt(
+        "",
+        "cacert",
+        "trust the CA certificates in this PEM file instead of the built-in ones",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "insecure",
+        "don't verify TLS certificates at all (unsafe; for broken mirrors only)",
+    );
+    opts.optflag(
+        "",
+        "no-keepalive",
+        "open a new connection for every request, for hosts that mishandle reused ones",
+    );
+}
+
+/// The agent [`network_opts`] asked for.
+fn network_agent(matches: &getopts::Matches) -> Result<Agent> {
+    let family = match (
+        matches.opt_present("ipv4-only"),
+        matches.opt_present("ipv6-only"),
+    ) {
+        (true, true) => anyhow::bail!("--ipv4-only and --ipv6-only can't be combined"),
+        (true, false) => IpFamily::Ipv4Only,
+        (false, true) => IpFamily::Ipv6Only,
+        (false, false) => IpFamily::Any,
+    };
+    let pins = matches
+        .opt_strs("resolve")
+        .iter()
+        .map(|pin| pin.parse())
+        .collect::<Result<Vec<resolve::Pin>>>()?;
+    let insecure = matches.opt_present("insecure");
+    let cacert = matches.opt_str("cacert").map(PathBuf::from);
+    if insecure && cacert.is_some() {
+        anyhow::bail!("--cacert and --insecure can't be combined");
+    }
+    let tls = tls::config(cacert.as_deref(), insecure)?;
+    if insecure {
+        eprintln!(
+            "{}",
+            console::style(
+                "WARNING: --insecure is set, so TLS certificates are not verified and anyone on the network can change what's downloaded"
+            )
+            .red()
+            .bold()
+        );
+    }
+    Ok(resolve::agent(
+        family,
+        pins,
+        tls,
+        !matches.opt_present("no-keepalive"),
+    ))
+}
+
+/// The `--rotate-user-agent` pool: the built-in agents, then the file's.
+fn user_agents(matches: &getopts::Matches) -> Result<Vec<String>> {
+    let file = matches.opt_str("user-agent-file");
+    if !matches.opt_present("rotate-user-agent") {
+        if file.is_some() {
+            anyhow::bail!("--user-agent-file needs --rotate-user-agent");
+        }
+        return Ok(Vec::new());
+    }
+
+    let mut pool: Vec<String> = DEFAULT_USER_AGENTS
+        .iter()
+        .map(|ua| ua.to_string())
+        .collect();
+    if let Some(file) = file {
+        let content = std::fs::read_to_string(&file)
+            .with_context(|| format!("Failed to read --user-agent-file {file}"))?;
+        pool.extend(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(String::from),
+        );
+    }
+    Ok(pool)
+}
+
+/// The run's `--deadline`, from `options`, and `--chapter-timeout`.
+fn time_limits(matches: &getopts::Matches, options: &BuildOptions) -> Result<TimeLimits> {
+    let per_page = match matches.opt_str("chapter-timeout") {
+        Some(value) => Some(parse_duration(&value).with_context(|| {
+            format!("Invalid --chapter-timeout: {value} (expected e.g. 90s or 5m)")
+        })?),
+        None => None,
+    };
+    Ok(TimeLimits {
+        deadline: options.deadline,
+        per_page,
+    })
+}
+
+/// "90s", "30m", "6h" or plain seconds.
+fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&value[..i], c.to_ascii_lowercase()),
+        _ => (value, 's'),
+    };
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        _ => anyhow::bail!("unknown unit {unit}"),
+    };
+    let number: u64 = number.trim().parse()?;
+    match number.checked_mul(multiplier) {
+        Some(0) => anyhow::bail!("must be more than zero"),
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => anyhow::bail!("too large"),
+    }
+}
+
+/// "2M" or "800k"; suffixes are binary multiples.
+fn parse_bytes(value: &str) -> Result<usize> {
+    let value = value.trim();
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&value[..i], c.to_ascii_uppercase()),
+        _ => (value, 'B'),
+    };
+    let multiplier = match unit {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => anyhow::bail!("unknown unit {unit}"),
+    };
+    let number: usize = number.trim().parse()?;
+    number.checked_mul(multiplier).context("too large")
+}
+
+fn fetch_options(matches: &getopts::Matches) -> Result<BuildOptions> {
+    let mut options = BuildOptions::default();
+    // First, since what other options mean can depend on it.
+    if let Some(format) = matches.opt_str("format") {
+        options.format = format.parse()?;
+    }
+
+    if let Some(pattern) = matches.opt_str("claimed-count") {
+        let tolerance = match matches.opt_str("count-tolerance") {
+            Some(t) => t
+                .parse()
+                .with_context(|| format!("Invalid --count-tolerance: {t}"))?,
+            None => 0,
+        };
+        options.count_check = Some(check::CountCheck {
+            pattern: Regex::new(&pattern)
+                .with_context(|| format!("Invalid --claimed-count regex: {pattern}"))?,
+            tolerance,
+            strict: matches.opt_present("strict-count"),
+        });
+    }
+
+    if let Some(file) = matches.opt_str("chapters-file") {
+        let text = std::fs::read_to_string(&file)
+            .with_context(|| format!("Failed to read --chapters-file {file}"))?;
+        options.chapters = Some(
+            selection::read_selection(&text)
+                .with_context(|| format!("Invalid --chapters-file {file}"))?,
+        );
+    }
+
+    options.title_filter = selection::TitleFilter::new(
+        &matches.opt_strs("include-title"),
+        &matches.opt_strs("exclude-title"),
+    )?;
+    options.chapter_dates = match matches.opt_str("chapter-date-selector") {
+        Some(selector) => Some(
+            selector
+                .parse()
+                .context("Invalid --chapter-date-selector")?,
+        ),
+        None => None,
+    };
+    options.update = matches.opt_present("update");
+    options.rebuild = matches.opt_present("rebuild");
+    options.locked = match matches.opt_str("locked-pattern") {
+        Some(pattern) => Some(
+            Regex::new(&pattern)
+                .with_context(|| format!("Invalid --locked-pattern regex: {pattern}"))?,
+        ),
+        None => None,
+    };
+    options.since = match matches.opt_str("since") {
+        Some(_) if options.chapter_dates.is_none() => {
+            anyhow::bail!("--since needs --chapter-date-selector to find the chapters' dates")
+        }
+        Some(since) => Some(dates::parse_since(&since)?),
+        None => None,
+    };
+
+    if matches.opt_present("interactive") {
+        if !picker::available() {
+            anyhow::bail!(
+                "--interactive needs a terminal; use `list` and --chapters-file to pick chapters in a script"
+            );
+        }
+        options.pick = Some(picker::pick);
+    }
+
+    if let Some(sort) = matches.opt_str("sort") {
+        options.sort = match sort.as_str() {
+            "document" => SortOrder::Document,
+            "title-number" => SortOrder::TitleNumber,
+            _ => anyhow::bail!("Invalid --sort: {sort} (expected document or title-number)"),
+        };
+    }
+
+    options.language = matches
+        .opt_str("language")
+        .unwrap_or_else(|| DEFAULT_LANGUAGE.to_string());
+    options.toc_title = matches.opt_str("toc-title");
+    options.reading_speed = match matches.opt_str("reading-speed") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(rate) if rate > 0 => Some(rate),
+            _ => anyhow::bail!("Invalid --reading-speed: {n} (expected a positive number)"),
+        },
+        None => None,
+    };
+    if let Some(n) = matches.opt_str("jobs") {
+        options.jobs = match n.parse::<usize>() {
+            Ok(jobs) if jobs > 0 => jobs,
+            _ => anyhow::bail!("Invalid --jobs: {n} (expected a positive number)"),
+        };
+    }
+    if let Some(n) = matches.opt_str("checkpoint-every") {
+        options.checkpoint_every = match n.parse::<usize>() {
+            Ok(every) if every > 0 => Some(every),
+            _ => anyhow::bail!("Invalid --checkpoint-every: {n} (expected a positive number)"),
+        };
+    }
+    options.partial_epub = matches.opt_present("partial-epub");
+    options.retry_permanent = matches.opt_present("retry-permanent");
+    options.revision_diff = matches.opt_str("revision-diff").map(PathBuf::from);
+    options.check_revisions =
+        matches.opt_present("check-revisions") || options.revision_diff.is_some();
+    if let Some(bytes) = matches.opt_str("max-total-bytes") {
+        options.max_total_bytes = Some(parse_bytes(&bytes).with_context(|| {
+            format!("Invalid --max-total-bytes: {bytes} (expected e.g. 500000, 800K or 2M)")
+        })?);
+    }
+    if let Some(value) = matches.opt_str("deadline") {
+        let after = parse_duration(&value).with_context(|| {
+            format!("Invalid --deadline: {value} (expected e.g. 90s, 30m or 6h)")
+        })?;
+        options.deadline = Some(std::time::Instant::now() + after);
+    }
+    if let Some(bytes) = matches.opt_str("max-chapter-text") {
+        options.limits.max_text = match parse_bytes(&bytes) {
+            Ok(n) if n > 0 => n,
+            _ => anyhow::bail!("Invalid --max-chapter-text: {bytes} (expected e.g. 500K or 8M)"),
+        };
+    }
+    if let Some(secs) = matches.opt_str("wait-lock") {
+        let secs: u64 = secs
+            .parse()
+            .with_context(|| format!("Invalid --wait-lock: {secs} (expected seconds)"))?;
+        options.wait_lock = std::time::Duration::from_secs(secs);
+    }
+    if let Some(dir) = matches.opt_str("work-dir") {
+        options.work_dir = PathBuf::from(dir);
+    }
+    options.temp_dir = matches.opt_str("temp-dir").map(PathBuf::from);
+    if let Some(command) = matches.opt_str("zip-command") {
+        options.zip_command = Some(command);
+    }
+    options.length_meta = matches.opt_present("length-meta");
+    options.validate = matches.opt_present("validate");
+    options.strict_sequence = matches.opt_present("strict-sequence");
+    options.manifest = matches.opt_str("manifest").map(PathBuf::from);
+
+    options.no_provenance = matches.opt_present("no-provenance");
+    options.no_title_page = matches.opt_present("no-title-page");
+    options.colophon = matches.opt_present("colophon");
+    options.author_page = match (
+        matches.opt_present("author-page"),
+        matches.opt_str("author-bio-selector"),
+    ) {
+        (true, selector) => Some(
+            selector
+                .as_deref()
+                .unwrap_or(".description")
+                .parse()
+                .context("Invalid --author-bio-selector")?,
+        ),
+        (false, Some(_)) => anyhow::bail!("--author-bio-selector needs --author-page"),
+        (false, None) => None,
+    };
+    if let Some(mode) = matches.opt_str("writing-mode") {
+        options.writing_mode = match mode.as_str() {
+            "horizontal-tb" => xhtml::WritingMode::HorizontalTb,
+            "vertical-rl" => xhtml::WritingMode::VerticalRl,
+            _ => anyhow::bail!(
+                "Invalid --writing-mode: {mode} (expected horizontal-tb or vertical-rl)"
+            ),
+        };
+    }
+    if let Some(theme) = matches.opt_str("theme") {
+        options.theme = theme.parse()?;
+    }
+    options.typography = xhtml::Typography {
+        justify: matches.opt_present("justify"),
+        font_size: match matches.opt_str("font-size") {
+            Some(size) => Some(
+                size.trim_end_matches('%')
+                    .parse()
+                    .ok()
+                    .filter(|&percent: &u32| percent > 0)
+                    .with_context(|| {
+                        format!("Invalid --font-size: {size} (expected a positive percentage)")
+                    })?,
+            ),
+            None => None,
+        },
+        line_height: match matches.opt_str("line-height") {
+            Some(height) => Some(
+                height
+                    .parse()
+                    .ok()
+                    .filter(|&h: &f32| h.is_finite() && h > 0.0)
+                    .with_context(|| {
+                        format!("Invalid --line-height: {height} (expected a positive number)")
+                    })?,
+            ),
+            None => None,
+        },
+        paragraph_spacing: match matches.opt_str("paragraph-spacing") {
+            Some(spacing) => Some(
+                spacing
+                    .trim_end_matches("em")
+                    .parse()
+                    .ok()
+                    .filter(|&em: &f32| em.is_finite() && em >= 0.0)
+                    .with_context(|| {
+                        format!("Invalid --paragraph-spacing: {spacing} (expected ems, 0 or more)")
+                    })?,
+            ),
+            None => None,
+        },
+    };
+    if let Some(level) = matches.opt_str("compression-level") {
+        options.compression_level = level
+            .parse()
+            .ok()
+            .filter(|&level: &u8| level <= 9)
+            .with_context(|| format!("Invalid --compression-level: {level} (expected 0 to 9)"))?;
+    }
+    if let Some(version) = matches.opt_str("epub-version") {
+        options.epub2 = match version.as_str() {
+            "2" => true,
+            "3" => false,
+            _ => anyhow::bail!("Invalid --epub-version: {version} (expected 2 or 3)"),
+        };
+    }
+    options.notes = match (
+        matches.opt_str("footnote-marker"),
+        matches.opt_str("footnote-container"),
+    ) {
+        (Some(marker), Some(container)) => Some(fetch::NoteSelectors {
+            marker: marker.parse()?,
+            container: container.parse()?,
+        }),
+        (None, None) => None,
+        _ => anyhow::bail!("--footnote-marker and --footnote-container must be given together"),
+    };
+    options.images = images::ImageOptions {
+        embed: matches.opt_present("images")
+            && (options.format.is_epub() || options.format == output::Format::Html),
+        alt_template: matches
+            .opt_str("alt-template")
+            .unwrap_or_else(|| images::DEFAULT_ALT_TEMPLATE.to_string()),
+        decorative: matches.opt_present("decorative-images"),
+        max_dimension: match matches.opt_str("max-image-dimension") {
+            Some(px) => Some(
+                px.parse()
+                    .ok()
+                    .filter(|&px: &u32| px > 0)
+                    .with_context(|| format!("Invalid --max-image-dimension: {px}"))?,
+            ),
+            None => None,
+        },
+        quality: match matches.opt_str("image-quality") {
+            Some(q) => Some(
+                q.parse()
+                    .ok()
+                    .filter(|q: &u8| (1..=100).contains(q))
+                    .with_context(|| format!("Invalid --image-quality: {q} (expected 1-100)"))?,
+            ),
+            None => None,
+        },
+        source_attrs: match matches.opt_str("image-attrs") {
+            Some(attrs) => attrs.split(',').map(|a| a.trim().to_string()).collect(),
+            None => images::DEFAULT_SOURCE_ATTRS
+                .iter()
+                .map(|a| a.to_string())
+                .collect(),
+        },
+        skip: match matches.opt_str("skip-image-pattern") {
+            Some(pattern) => Some(
+                Regex::new(&pattern)
+                    .with_context(|| format!("Invalid --skip-image-pattern regex: {pattern}"))?,
+            ),
+            None => None,
+        },
+        formats: match matches.opt_str("image-formats") {
+            Some(list) => {
+                let formats = list
+                    .split(',')
+                    .map(|f| f.trim().parse())
+                    .collect::<anyhow::Result<Vec<images::Format>>>()?;
+                if !formats
+                    .iter()
+                    .any(|f| matches!(f, images::Format::Jpeg | images::Format::Png))
+                {
+                    anyhow::bail!(
+                        "--image-formats must include jpeg or png, which other formats are converted to"
+                    );
+                }
+                Some(formats)
+            }
+            None => None,
+        },
+        cover_from_content: match matches.opt_str("cover-min-dimension") {
+            Some(px) => Some(
+                px.parse()
+                    .ok()
+                    .filter(|&px: &u32| px > 0)
+                    .with_context(|| format!("Invalid --cover-min-dimension: {px}"))?,
+            ),
+            None => Some(images::DEFAULT_COVER_MIN_DIMENSION),
+        }
+        .filter(|_| matches.opt_present("cover-from-content")),
+    };
+    options.description_limit = match matches.opt_str("description-limit") {
+        Some(n) => n
+            .parse()
+            .with_context(|| format!("Invalid --description-limit: {n}"))?,
+        None => DEFAULT_DESCRIPTION_LIMIT,
+    };
+    options.toc_description = matches.opt_present("toc-description");
+    let max_subheadings = match matches.opt_str("max-subheadings") {
+        Some(n) => n
+            .parse()
+            .with_context(|| format!("Invalid --max-subheadings: {n}"))?,
+        None => headings::DEFAULT_MAX_SUBHEADINGS,
+    };
+    options.headings = headings::HeadingRules::new(
+        matches.opt_present("headings"),
+        &matches.opt_strs("heading-pattern"),
+        max_subheadings,
+    )?;
+    options.max_chapter_size = match matches.opt_str("max-chapter-size") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(kb) if kb > 0 => kb * 1024,
+            _ => {
+                anyhow::bail!("Invalid --max-chapter-size: {n} (expected a positive number of KB)")
+            }
+        },
+        None => split::DEFAULT_MAX_CHAPTER_SIZE,
+    };
+    options.title = matches.opt_str("title");
+    if options
+        .title
+        .as_deref()
+        .is_some_and(|t| t.trim().is_empty())
+    {
+        anyhow::bail!("Invalid --title: it can't be empty, as it also names the output file");
+    }
+    options.authors = matches.opt_strs("author");
+    options.prefer_og = matches.opt_present("prefer-og");
+    options.require_author = matches.opt_present("require-author");
+    for (opt, role) in [
+        ("translator", metadata::Role::Translator),
+        ("illustrator", metadata::Role::Illustrator),
+    ] {
+        options.contributors.extend(
+            matches
+                .opt_strs(opt)
+                .into_iter()
+                .map(|name| metadata::Contributor { name, role }),
+        );
+    }
+    options.title_cleanup = cleanup::Cleanup::new(
+        cleanup::Field::Title,
+        !matches.opt_present("no-default-title-strip"),
+        &matches.opt_strs("title-strip"),
+    )?;
+    options.author_cleanup = cleanup::Cleanup::new(
+        cleanup::Field::Author,
+        !matches.opt_present("no-default-author-strip"),
+        &matches.opt_strs("author-strip"),
+    )?;
+    options.fold_width = matches.opt_present("fold-width");
+    options.merge_softwrap = matches.opt_present("merge-softwrap");
+    options.per_paragraph_lang = matches.opt_present("per-paragraph-lang");
+
+    options.output = match matches.opt_str("output-template") {
+        Some(template) => template.parse()?,
+        None => output::OutputTemplate::for_format(options.format),
+    };
+    if let Some(n) = matches.opt_str("split-every") {
+        options.split_every = match n.parse::<usize>() {
+            Ok(n) if n > 0 => Some(n),
+            _ => {
+                anyhow::bail!("Invalid --split-every: {n} (expected a positive number of chapters)")
+            }
+        };
+        if !options.format.is_epub() {
+            anyhow::bail!("--split-every only applies to --format epub or kepub");
+        }
+    }
+
+    if matches.opt_present("cover-from-content") && !options.images.embed {
+        anyhow::bail!(
+            "--cover-from-content takes the cover from embedded images, so it needs --images"
+        );
+    }
+    if matches.opt_present("cover-min-dimension") && !matches.opt_present("cover-from-content") {
+        anyhow::bail!("--cover-min-dimension needs --cover-from-content");
+    }
+
+    if options.toc_description {
+        if options.format.is_epub() && options.no_title_page {
+            anyhow::bail!(
+                "--toc-description puts the description on an epub's title page, which --no-title-page leaves out"
+            );
+        }
+        if !options.format.is_epub() && options.format != output::Format::Html {
+            anyhow::bail!("--toc-description only applies to --format html, epub or kepub");
+        }
+    }
+
+    if matches.opt_present("chapter-footer") {
+        options.chapter_footer = Some(
+            matches
+                .opt_str("chapter-footer")
+                .unwrap_or_else(|| DEFAULT_FOOTER.to_string()),
+        );
+    }
+
+    options.chapter_template = match matches.opt_str("chapter-template") {
+        Some(path) => Some(template::ChapterTemplate::load(&PathBuf::from(path))?),
+        None => None,
+    };
+    options.chapter_nav = matches.opt_present("chapter-nav");
+    options.dedupe_toc_titles = matches.opt_present("dedupe-toc-titles");
+
+    if let Some(fallback) = matches.opt_str("fallback-url") {
+        options.fallback = Some(
+            Uri::from_str(&fallback)
+                .with_context(|| format!("Invalid --fallback-url: {fallback}"))?,
+        );
+    }
+
+    Ok(options)
 }
 
 fn send_to_djazz(
@@ -171,118 +1869,3 @@ fn send_to_djazz(
 
     Ok(())
 }
-
-fn process_book(uri: &Uri, agent: &Agent) -> Result<()> {
-    match uri.host() {
-        Some("czbooks.net") => {
-            process_book_with_provider::<fetch::czbooksnet::CzBooksProvider>(uri, agent)
-        }
-        _ => anyhow::bail!("Unsupported domain: {uri}"),
-    }
-}
-
-fn process_book_with_provider<P: fetch::Provider>(uri: &Uri, agent: &Agent) -> Result<()> {
-    let mut book = EpubBuilder::new(ZipCommand::new()?)?;
-
-    book.epub_version(EpubVersion::V33);
-
-    let info: fetch::BookInfo = process::<P::Link>(agent, uri)?.into();
-
-    book.add_author(info.author);
-    let title = info.title;
-    book.set_title(title.clone());
-    let links = info.links;
-
-    let bar = ProgressBar::new(links.len() as u64);
-    bar.set_style(
-        ProgressStyle::default_bar()
-            .template(
-                "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}",
-            )?
-            .progress_chars("#>-"),
-    );
-    bar.set_message(format!("Processing {title}"));
-
-    for (i, item) in links.iter().enumerate() {
-        let content_result = process::<P::Chapter>(agent, item);
-
-        if let Err(e) = content_result {
-            bar.abandon_with_message(format!("Failed to process chapter: {e}"));
-            return Err(e);
-        }
-
-        let content_sink = content_result?;
-        let content: fetch::ChapterInfo = content_sink.into();
-        let chapter_title = content.title;
-
-        book.add_content(
-            EpubContent::new(
-                format!("{i}.xhtml"),
-                Cursor::new(format!(
-                    r#"<?xml version="1.0" encoding="UTF-8"?>
-        <html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
-        <body>
-        {}
-        </body>
-        </html>"#,
-                    content.text
-                )),
-            )
-            .title(chapter_title.clone())
-            .reftype(ReferenceType::Text)
-            .child(TocElement::new(format!("{i}.xhtml#1"), chapter_title)),
-        )?;
-        bar.inc(1);
-    }
-
-    bar.finish_with_message("Done");
-    book.inline_toc();
-
-    let mut output_file = File::create(format!("{title}.epub"))?;
-    book.generate(&mut output_file)?;
-
-    Ok(())
-}
-
-fn process<T: Default + TokenSink<Handle = ()>>(agent: &Agent, path: &Uri) -> Result<T> {
-    let mut resp = fetch_with_backoff(agent, path)?;
-    let mut chunk = ByteTendril::new();
-
-    resp.read_to_tendril(&mut chunk)?;
-
-    let input = BufferQueue::default();
-    input.push_back(
-        chunk
-            .try_reinterpret()
-            .map_err(|e| anyhow::Error::msg(format!("try_reinterpret failed on:{e:?}")))?,
-    );
-
-    let sinker = T::default();
-    let tok = Tokenizer::new(sinker, TokenizerOpts::default());
-    let _ = tok.feed(&input);
-    tok.end();
-
-    Ok(tok.sink)
-}
-
-fn fetch_with_backoff(agent: &Agent, path: &Uri) -> Result<BodyReader<'static>> {
-    let mut retries = 3;
-    let mut delay = time::Duration::from_millis(3000);
-
-    while retries > 0 {
-        match agent.get(path).call() {
-            Ok(resp) => {
-                thread::sleep(time::Duration::from_millis(900));
-                return Ok(resp.into_body().into_reader());
-            }
-            Err(ureq::Error::StatusCode(code)) if (400..=499).contains(&code) => {
-                thread::sleep(delay);
-                delay *= 2;
-                retries -= 1;
-            }
-            Err(e) => return Err(anyhow::anyhow!(e)),
-        }
-    }
-
-    Err(anyhow::anyhow!("max retries exceeded"))
-}
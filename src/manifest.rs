@@ -0,0 +1,118 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+    fallback::Provenance, ladder::ResultCounts, metadata::Contributor, numbering::SequenceReport,
+    provenance::Generator, state::Missing, stats::Length,
+};
+
+/// Machine-readable record of a book build, written with `--manifest`.
+#[derive(Serialize, Default)]
+pub struct Manifest {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub contributors: Vec<Contributor>,
+    pub source: String,
+    pub generator: Generator,
+    /// The genres the index page's JSON-LD gives.
+    pub subjects: Vec<String>,
+    /// When the JSON-LD says the book was first published, as it says it.
+    pub published: Option<String>,
+    /// The JSON-LD `Book` fields not otherwise used, such as `publisher` or
+    /// `aggregateRating`.
+    pub linked_data: BTreeMap<String, serde_json::Value>,
+    pub chapters: Vec<ManifestChapter>,
+    pub sequence: SequenceReport,
+    pub length: Option<Length>,
+    /// Chapters left out because the site no longer has them. Chapters that
+    /// failed over the network stop the build, so they never appear here.
+    pub missing: Vec<MissingChapter>,
+    /// Chapters left out because their pages couldn't be parsed.
+    pub failed: Vec<FailedChapter>,
+    /// How the chapters downloaded on this run came through, see
+    /// [`crate::ladder`].
+    pub results: ResultCounts,
+    /// Chapters whose text changed since the last `--check-revisions` run.
+    pub revised: Vec<RevisedChapter>,
+}
+
+#[derive(Serialize)]
+pub struct ManifestChapter {
+    pub index: usize,
+    pub title: String,
+    pub url: String,
+    /// The date the index gives the chapter, as YYYY-MM-DD.
+    pub published: Option<String>,
+    pub provenance: Provenance,
+    /// In the book's [`Length`] unit.
+    pub length: usize,
+    pub status: ChapterStatus,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ChapterStatus {
+    #[default]
+    Complete,
+    /// Behind a paywall or timer on the site, so the book has a placeholder
+    /// in its place; see `--locked-pattern`.
+    Locked,
+}
+
+#[derive(Serialize)]
+pub struct RevisedChapter {
+    pub index: usize,
+    pub title: String,
+    pub url: String,
+    /// Hashes of the normalized text before and after, see [`crate::revisions`].
+    pub previous_hash: String,
+    pub hash: String,
+}
+
+#[derive(Serialize)]
+pub struct MissingChapter {
+    pub index: usize,
+    pub title: String,
+    pub url: String,
+    pub status: u16,
+    /// When the chapter was first found missing.
+    pub since: String,
+}
+
+#[derive(Serialize)]
+pub struct FailedChapter {
+    pub index: usize,
+    pub title: String,
+    pub url: String,
+    pub error: String,
+    /// Where the page was saved, in the book's work directory.
+    pub saved: Option<PathBuf>,
+}
+
+impl MissingChapter {
+    pub fn new(index: usize, title: &str, missing: &Missing) -> Self {
+        MissingChapter {
+            index,
+            title: title.to_string(),
+            url: missing.url.clone(),
+            status: missing.status,
+            since: missing.since.clone(),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create manifest {}", path.display()))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)
+            .with_context(|| format!("Failed to write manifest {}", path.display()))
+    }
+}
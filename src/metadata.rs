@@ -0,0 +1,189 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use epub_builder::{EpubBuilder, MetadataOpfV3, ZipCommandOrLibrary};
+use serde::Serialize;
+
+use crate::xhtml;
+
+/// Characters sites use between co-authors, e.g. "甲/乙" or "甲、乙".
+const AUTHOR_SEPARATORS: &[char] = &['/', '／', '、', ',', '，', ';', '；', '&', '＆'];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Translator,
+    Illustrator,
+}
+
+impl Role {
+    /// MARC relator code, see https://id.loc.gov/vocabulary/relators.html
+    pub fn marc_code(self) -> &'static str {
+        match self {
+            Role::Translator => "trl",
+            Role::Illustrator => "ill",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Role::Translator => "Translator",
+            Role::Illustrator => "Illustrator",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Contributor {
+    pub name: String,
+    pub role: Role,
+}
+
+/// Splits scraped author strings on common separators, dropping empties and
+/// duplicates while keeping the original order.
+pub fn split_authors(raw: &[String]) -> Vec<String> {
+    let mut authors: Vec<String> = Vec::new();
+    for name in raw.iter().flat_map(|r| r.split(AUTHOR_SEPARATORS)) {
+        let name = name.trim();
+        if !name.is_empty() && !authors.iter().any(|a| a == name) {
+            authors.push(name.to_string());
+        }
+    }
+    authors
+}
+
+/// The form of a title or author that names files and is compared across
+/// mirrors: trimmed, with runs of whitespace, ideographic spaces included,
+/// collapsed to one space and, with `fold_width`, full-width ASCII mapped to
+/// half-width, so "書名（上）　" and "書名(上)" agree.
+pub fn normalize(text: &str, fold_width: bool) -> String {
+    let folded: String = text
+        .chars()
+        .map(|c| match c {
+            '\u{FF01}'..='\u{FF5E}' if fold_width => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            _ => c,
+        })
+        .collect();
+    folded.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Reads a schema.org `datePublished`: a full timestamp, a date, or just a
+/// year or month, taken as its first day.
+pub fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    let date = date.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(date) {
+        return Some(time.with_timezone(&Utc));
+    }
+    let day = match date.len() {
+        4 => format!("{date}-01-01"),
+        7 => format!("{date}-01"),
+        // A local time without an offset keeps only its date.
+        _ => date.get(..10).unwrap_or(date).to_string(),
+    };
+    let day = NaiveDate::parse_from_str(&day, "%Y-%m-%d").ok()?;
+    Some(day.and_hms_opt(0, 0, 0)?.and_utc())
+}
+
+/// Emits `dcterms:contributor` entries refined with their MARC role, since
+/// epub-builder only knows about authors.
+pub fn add_contributors(book: &mut EpubBuilder<ZipCommandOrLibrary>, contributors: &[Contributor]) {
+    for (i, c) in contributors.iter().enumerate() {
+        let id = format!("epub-contributor-{i}");
+
+        let mut name =
+            MetadataOpfV3::new("dcterms:contributor".to_string(), xhtml::escape(&c.name));
+        name.add_id(id.clone());
+        book.add_metadata_opf(Box::new(name));
+
+        let mut role = MetadataOpfV3::new("role".to_string(), c.role.marc_code().to_string());
+        role.refines = Some(format!("#{id}"));
+        role.add_scheme("marc:relators".to_string());
+        book.add_metadata_opf(Box::new(role));
+    }
+}
+
+/// The table of contents' title for a book in `language`, a BCP 47 tag;
+/// English for languages without one here.
+pub fn toc_title(language: &str) -> &'static str {
+    match label_language(language) {
+        ("zh", true) => "目錄",
+        ("zh", false) => "目录",
+        ("ja", _) => "目次",
+        ("ko", _) => "목차",
+        ("fr", _) => "Table des matières",
+        ("de", _) => "Inhaltsverzeichnis",
+        ("es", _) => "Índice",
+        _ => "Table of Contents",
+    }
+}
+
+/// The title of the page `--author-page` adds, like [`toc_title`].
+pub fn about_author_title(language: &str) -> &'static str {
+    match label_language(language) {
+        ("zh", true) => "關於作者",
+        ("zh", false) => "关于作者",
+        ("ja", _) => "著者について",
+        ("ko", _) => "작가 소개",
+        ("fr", _) => "À propos de l'auteur",
+        ("de", _) => "Über den Autor",
+        ("es", _) => "Sobre el autor",
+        _ => "About the Author",
+    }
+}
+
+/// The primary subtag labels for `language` are picked by, "en" for those
+/// without labels, and whether it's written in traditional characters.
+fn label_language(language: &str) -> (&'static str, bool) {
+    let mut subtags = language.split(['-', '_']).map(str::to_ascii_lowercase);
+    let primary = subtags.next().unwrap_or_default();
+    let primary = ["zh", "ja", "ko", "fr", "de", "es"]
+        .into_iter()
+        .find(|known| *known == primary)
+        .unwrap_or("en");
+    let traditional =
+        primary == "zh" && subtags.any(|tag| matches!(tag.as_str(), "hant" | "tw" | "hk" | "mo"));
+    (primary, traditional)
+}
+
+/// Adds the schema.org accessibility properties for a reflowable text book.
+/// With images the book is also visual; only when `all_described` gives
+/// every one of them alt text does it claim to stay fully readable as text.
+pub fn add_accessibility(
+    book: &mut EpubBuilder<ZipCommandOrLibrary>,
+    has_images: bool,
+    all_described: bool,
+) {
+    let readable_as_text = !has_images || all_described;
+    let mut meta = vec![
+        ("schema:accessMode", "textual"),
+        ("schema:accessibilityFeature", "tableOfContents"),
+        ("schema:accessibilityFeature", "readingOrder"),
+        ("schema:accessibilityHazard", "none"),
+    ];
+    if readable_as_text {
+        meta.push(("schema:accessModeSufficient", "textual"));
+    }
+    if has_images {
+        meta.push(("schema:accessMode", "visual"));
+        meta.push(("schema:accessModeSufficient", "textual,visual"));
+    }
+    if has_images && all_described {
+        meta.push(("schema:accessibilityFeature", "alternativeText"));
+    }
+
+    let summary = match (has_images, all_described) {
+        (false, _) => "Reflowable text with a navigable table of contents and no images.",
+        (true, true) => {
+            "Reflowable text with a navigable table of contents; images carry alternative text."
+        }
+        (true, false) => {
+            "Reflowable text with a navigable table of contents; not every image carries alternative text."
+        }
+    };
+    meta.push(("schema:accessibilitySummary", summary));
+
+    for (property, content) in meta {
+        book.add_metadata_opf(Box::new(MetadataOpfV3::new(
+            property.to_string(),
+            content.to_string(),
+        )));
+    }
+}
@@ -0,0 +1,74 @@
+//! The NCX epub-builder writes next to the navigation document, which
+//! readers that only know EPUB 2 navigate by.
+//!
+//! epub-builder gives every NCX a depth of 1 and no identifier, so some of
+//! those readers flatten a nested table of contents or drop it altogether,
+//! and numbers the entries one by one even where two point at the same
+//! place. [`fix`] rewrites it: `dtb:depth` becomes the nesting of its
+//! entries, `dtb:uid` the package's identifier, and `playOrder` follows
+//! reading order, shared by entries with the same target.
+
+use std::{collections::HashMap, sync::LazyLock};
+
+use regex::Regex;
+
+static PLAY_ORDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"playOrder="\d+""#).expect("valid playOrder regex"));
+static SRC: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"<content src="([^"]*)""#).expect("valid content regex"));
+static NAV_POINT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<(/?)navPoint\b").expect("valid navPoint regex"));
+
+/// `ncx` with its depth, identifier and play order fixed.
+pub fn fix(ncx: &str, uid: Option<&str>) -> String {
+    let mut depth = 0usize;
+    let mut deepest = 1;
+    for tag in NAV_POINT.captures_iter(ncx) {
+        if tag[1].is_empty() {
+            depth += 1;
+            deepest = deepest.max(depth);
+        } else {
+            depth = depth.saturating_sub(1);
+        }
+    }
+
+    let srcs: Vec<&str> = SRC
+        .captures_iter(ncx)
+        .map(|c| c.get(1).map_or("", |m| m.as_str()))
+        .collect();
+    let mut orders = play_orders(srcs).into_iter();
+    let mut fixed = PLAY_ORDER
+        .replace_all(ncx, |found: &regex::Captures| match orders.next() {
+            Some(order) => format!(r#"playOrder="{order}""#),
+            None => found[0].to_string(),
+        })
+        .into_owned();
+
+    fixed = fixed.replace(
+        r#"<meta name="dtb:depth" content="1" />"#,
+        &format!(r#"<meta name="dtb:depth" content="{deepest}" />"#),
+    );
+    if let Some(uid) = uid {
+        fixed = fixed.replacen(
+            "<head>",
+            &format!(
+                "<head>\n    <meta name=\"dtb:uid\" content=\"{}\" />",
+                crate::xhtml::escape(uid)
+            ),
+            1,
+        );
+    }
+    fixed
+}
+
+/// The play order of entries pointing at `srcs`, in document order: one
+/// more than the last for a new target, the same for one seen before.
+pub fn play_orders<'a>(srcs: impl IntoIterator<Item = &'a str>) -> Vec<usize> {
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    srcs.into_iter()
+        .map(|src| {
+            let next = seen.len() + 1;
+            *seen.entry(src).or_insert(next)
+        })
+        .collect()
+}
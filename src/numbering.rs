@@ -0,0 +1,217 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::fetch::ChapterLink;
+
+static LEADING_NUMBER: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)^\s*(?:第\s*([0-9０-９零〇一二两三四五六七八九十百千万]+)\s*[章节回话卷]|(?:chapter|ch\.?)\s*([0-9０-９]+)|([0-9０-９]+)(?:[.、:：\s]|$))",
+    )
+    .expect("valid chapter number regex")
+});
+
+/// Extracts the leading chapter number from a title such as "第137章",
+/// "第一百三十七章", "Chapter 137" or "137. Title".
+pub fn chapter_number(title: &str) -> Option<u64> {
+    let caps = LEADING_NUMBER.captures(title)?;
+    let m = caps
+        .get(1)
+        .or_else(|| caps.get(2))
+        .or_else(|| caps.get(3))?;
+    parse_number(m.as_str())
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    let digits: String = s
+        .chars()
+        .map(|c| match c {
+            '０'..='９' => char::from_u32(c as u32 - '０' as u32 + '0' as u32).unwrap_or(c),
+            _ => c,
+        })
+        .collect();
+
+    if digits.chars().all(|c| c.is_ascii_digit()) {
+        return digits.parse().ok();
+    }
+
+    parse_chinese_number(&digits)
+}
+
+fn parse_chinese_number(s: &str) -> Option<u64> {
+    let mut total = 0u64;
+    let mut section = 0u64;
+    let mut num = 0u64;
+
+    for c in s.chars() {
+        match c {
+            '零' | '〇' => num = 0,
+            '一' => num = 1,
+            '二' | '两' => num = 2,
+            '三' => num = 3,
+            '四' => num = 4,
+            '五' => num = 5,
+            '六' => num = 6,
+            '七' => num = 7,
+            '八' => num = 8,
+            '九' => num = 9,
+            '十' | '百' | '千' => {
+                let unit = match c {
+                    '十' => 10,
+                    '百' => 100,
+                    _ => 1000,
+                };
+                // "十二" means 12, so a bare unit counts as one of it.
+                section += if num == 0 { 1 } else { num } * unit;
+                num = 0;
+            }
+            '万' => {
+                total += (section + num) * 10_000;
+                section = 0;
+                num = 0;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(total + section + num)
+}
+
+/// Sorts the numbered links by their title number, leaving un-numbered links
+/// in their original positions. The sort is stable, so ties keep document order.
+pub fn sort_by_number(links: &mut Vec<ChapterLink>) {
+    let mut numbered = Vec::new();
+    let mut slots = Vec::with_capacity(links.len());
+
+    for link in links.drain(..) {
+        match chapter_number(&link.title) {
+            Some(n) => {
+                numbered.push((n, link));
+                slots.push(None);
+            }
+            None => slots.push(Some(link)),
+        }
+    }
+
+    numbered.sort_by_key(|(n, _)| *n);
+    let mut numbered = numbered.into_iter().map(|(_, link)| link);
+
+    links.extend(
+        slots
+            .into_iter()
+            .filter_map(|slot| slot.or_else(|| numbered.next())),
+    );
+}
+
+/// Returns the numbers missing between the lowest and highest chapter number,
+/// collapsed into inclusive ranges.
+pub fn gaps(numbers: &[u64]) -> Vec<(u64, u64)> {
+    let mut unique = numbers.to_vec();
+    unique.sort_unstable();
+    unique.dedup();
+
+    unique
+        .windows(2)
+        .filter(|w| w[1] > w[0] + 1)
+        .map(|w| (w[0] + 1, w[1] - 1))
+        .collect()
+}
+
+pub fn format_ranges(ranges: &[(u64, u64)]) -> String {
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{start}-{end}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Serialize, Default)]
+pub struct SequenceReport {
+    /// Missing chapter numbers as inclusive `[start, end]` ranges.
+    pub missing: Vec<(u64, u64)>,
+    pub duplicates: Vec<u64>,
+    pub out_of_order: Vec<OutOfOrder>,
+}
+
+#[derive(Serialize)]
+pub struct OutOfOrder {
+    /// Position of the entry in the chapter list.
+    pub index: usize,
+    pub number: u64,
+    pub title: String,
+}
+
+impl SequenceReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.duplicates.is_empty() && self.out_of_order.is_empty()
+    }
+
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if !self.missing.is_empty() {
+            problems.push(format!(
+                "missing chapter numbers: {}",
+                format_ranges(&self.missing)
+            ));
+        }
+        if !self.duplicates.is_empty() {
+            let dups: Vec<String> = self.duplicates.iter().map(u64::to_string).collect();
+            problems.push(format!("duplicated chapter numbers: {}", dups.join(", ")));
+        }
+        for o in &self.out_of_order {
+            problems.push(format!(
+                "chapter {} \"{}\" at position {} is out of order",
+                o.number,
+                o.title,
+                o.index + 1
+            ));
+        }
+
+        problems
+    }
+}
+
+/// Analyzes the chapter numbers of `titles` in their current order.
+pub fn analyze<'a>(titles: impl IntoIterator<Item = &'a str>) -> SequenceReport {
+    let titles: Vec<&str> = titles.into_iter().collect();
+    let numbered: Vec<(usize, u64)> = titles
+        .iter()
+        .enumerate()
+        .filter_map(|(i, t)| chapter_number(t).map(|n| (i, n)))
+        .collect();
+
+    let mut report = SequenceReport::default();
+    let numbers: Vec<u64> = numbered.iter().map(|&(_, n)| n).collect();
+
+    report.missing = gaps(&numbers);
+
+    let mut sorted = numbers.clone();
+    sorted.sort_unstable();
+    for w in sorted.windows(2) {
+        if w[0] == w[1] && report.duplicates.last() != Some(&w[0]) {
+            report.duplicates.push(w[0]);
+        }
+    }
+
+    let mut highest = None;
+    for &(i, n) in &numbered {
+        match highest {
+            Some(h) if n < h => report.out_of_order.push(OutOfOrder {
+                index: i,
+                number: n,
+                title: titles[i].to_string(),
+            }),
+            _ => highest = Some(n),
+        }
+    }
+
+    report
+}
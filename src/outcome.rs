@@ -0,0 +1,109 @@
+//! How a whole run went, as `--result-json` prints it: one JSON object
+//! however many books the run built.
+
+use std::{path::PathBuf, time::Duration};
+
+use serde::Serialize;
+
+use crate::{Error, Summary, exit_code, timing::Timings};
+
+#[derive(Serialize, Debug, Default)]
+pub struct RunResult {
+    /// "built", "skipped-chapters", "up-to-date", "partial" or "failed",
+    /// following [`RunResult::exit_code`].
+    pub status: &'static str,
+    /// One of [`exit_code`]'s.
+    pub exit_code: i32,
+    /// The first file written, then every one, e.g. each part.
+    pub output: Option<PathBuf>,
+    pub files: Vec<PathBuf>,
+    /// The files' size, in bytes.
+    pub written: u64,
+    pub chapters: ChapterCounts,
+    /// Response bytes, decompressed, of the books that were built.
+    pub downloaded: usize,
+    pub duration_secs: f64,
+    /// Seconds spent in each phase of the books that were built.
+    pub phase_secs: Timings,
+    pub errors: Vec<String>,
+    /// Whether a budget or deadline stop left a partial book.
+    #[serde(skip)]
+    partial: bool,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ChapterCounts {
+    /// Every chapter the runs planned, however it went.
+    pub total: usize,
+    pub succeeded: usize,
+    /// Left out because the site no longer has them or their pages
+    /// couldn't be parsed.
+    pub failed: usize,
+    /// Left out by `--exclude-title`, `--since` and the like.
+    pub skipped: usize,
+}
+
+impl RunResult {
+    /// Adds how one book's build went.
+    pub fn record(&mut self, result: &Result<Summary, Error>) {
+        match result {
+            Ok(summary) => {
+                let failed =
+                    summary.missing.len() + summary.failed.len() + summary.placeholders.len();
+                self.chapters.succeeded += summary.chapters;
+                self.chapters.failed += failed;
+                self.chapters.skipped += summary.excluded.len();
+                self.chapters.total += summary.chapters + failed + summary.excluded.len();
+                self.downloaded += summary.downloaded;
+                self.phase_secs.merge(&summary.timings);
+                self.files.extend(summary.files.iter().cloned());
+                self.written += summary.written;
+            }
+            Err(e) => {
+                match e {
+                    Error::Budget {
+                        chapters,
+                        of,
+                        partial,
+                        ..
+                    }
+                    | Error::Deadline {
+                        chapters,
+                        of,
+                        partial,
+                    } => {
+                        self.chapters.succeeded += chapters;
+                        self.chapters.total += of;
+                        self.files.push(partial.clone());
+                        self.partial = true;
+                    }
+                    Error::NothingToBuild { excluded } => {
+                        self.chapters.skipped += excluded;
+                        self.chapters.total += excluded;
+                    }
+                    _ => {}
+                }
+                self.error(format!("{e:#}"));
+            }
+        }
+    }
+
+    /// Notes a failure that never got as far as a build, e.g. a bad URL.
+    pub fn error(&mut self, message: impl Into<String>) {
+        self.errors.push(message.into());
+    }
+
+    /// Settles the status once the run's exit code is known.
+    pub fn finish(&mut self, exit_code: i32, duration: Duration) {
+        self.exit_code = exit_code;
+        self.status = match exit_code {
+            exit_code::SUCCESS => "built",
+            exit_code::SKIPPED => "skipped-chapters",
+            exit_code::UP_TO_DATE => "up-to-date",
+            _ if self.partial => "partial",
+            _ => "failed",
+        };
+        self.output = self.files.first().cloned();
+        self.duration_secs = duration.as_secs_f64();
+    }
+}
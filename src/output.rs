@@ -0,0 +1,221 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Result;
+
+pub const DEFAULT_TEMPLATE: &str = "{title}.epub";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Format {
+    #[default]
+    Epub,
+    /// An epub marked up for Kobo readers, with a span around every sentence.
+    Kepub,
+    Txt,
+    Md,
+    /// One self-contained HTML page.
+    Html,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Epub => "epub",
+            Format::Kepub => "kepub.epub",
+            Format::Txt => "txt",
+            Format::Md => "md",
+            Format::Html => "html",
+        }
+    }
+
+    /// Whether the format is built as an epub, rather than written as text.
+    pub fn is_epub(self) -> bool {
+        matches!(self, Format::Epub | Format::Kepub)
+    }
+
+    /// `path` with `insert` before this format's extension, e.g.
+    /// `Title.partial.kepub.epub` for `Title.kepub.epub`; a path that doesn't
+    /// end in the extension gets `insert` at the end.
+    pub fn insert_before_extension(self, path: &Path, insert: &str) -> PathBuf {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let dotted = format!(".{}", self.extension());
+        match name.strip_suffix(&dotted) {
+            Some(stem) => path.with_file_name(format!("{stem}{insert}{dotted}")),
+            None => path.with_file_name(format!("{name}{insert}")),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "epub" => Ok(Format::Epub),
+            "kepub" => Ok(Format::Kepub),
+            "txt" => Ok(Format::Txt),
+            "md" => Ok(Format::Md),
+            "html" => Ok(Format::Html),
+            _ => anyhow::bail!("Invalid --format: {s} (expected epub, kepub, txt, md or html)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Title,
+    Author,
+    Date,
+    Host,
+    LastChapter,
+    LastChapterTitle,
+}
+
+/// A parsed `--output-template`, e.g. `{author}/{title}.epub`.
+///
+/// Placeholders are validated when parsing, so a typo fails before any
+/// download; `{{` and `}}` stand for literal braces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputTemplate {
+    segments: Vec<Segment>,
+}
+
+pub struct OutputFields<'a> {
+    pub title: &'a str,
+    pub author: &'a str,
+    pub date: &'a str,
+    pub host: &'a str,
+    /// The last chapter's number, zero-padded, e.g. "0945".
+    pub last_chapter: &'a str,
+    pub last_chapter_title: &'a str,
+}
+
+impl Default for OutputTemplate {
+    fn default() -> Self {
+        DEFAULT_TEMPLATE
+            .parse()
+            .expect("valid default output template")
+    }
+}
+
+impl OutputTemplate {
+    pub fn for_format(format: Format) -> Self {
+        format!("{{title}}.{}", format.extension())
+            .parse()
+            .expect("valid default output template")
+    }
+}
+
+impl FromStr for OutputTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(template: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => anyhow::bail!("Unterminated placeholder in {template:?}"),
+                        }
+                    }
+                    let segment = match name.as_str() {
+                        "title" => Segment::Title,
+                        "author" => Segment::Author,
+                        "date" => Segment::Date,
+                        "host" => Segment::Host,
+                        "last_chapter" => Segment::LastChapter,
+                        "last_chapter_title" => Segment::LastChapterTitle,
+                        _ => anyhow::bail!(
+                            "Unknown placeholder {{{name}}} in {template:?} (expected title, author, date, host, last_chapter or last_chapter_title)"
+                        ),
+                    };
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(segment);
+                }
+                '}' => anyhow::bail!("Unmatched '}}' in {template:?} (use '}}}}' for a literal)"),
+                _ => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(OutputTemplate { segments })
+    }
+}
+
+impl OutputTemplate {
+    pub fn render(&self, fields: &OutputFields) -> PathBuf {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => out.push_str(s),
+                Segment::Title => out.push_str(&sanitize_component(fields.title)),
+                Segment::Author => out.push_str(&sanitize_component(fields.author)),
+                Segment::Date => out.push_str(&sanitize_component(fields.date)),
+                Segment::Host => out.push_str(&sanitize_component(fields.host)),
+                Segment::LastChapter => out.push_str(&sanitize_component(fields.last_chapter)),
+                Segment::LastChapterTitle => {
+                    out.push_str(&sanitize_component(fields.last_chapter_title))
+                }
+            }
+        }
+        PathBuf::from(out)
+    }
+}
+
+/// Names Windows keeps for devices, with or without an extension.
+const DEVICES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "COM¹", "COM²", "COM³", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8",
+    "LPT9", "LPT¹", "LPT²", "LPT³",
+];
+
+/// Makes a scraped value safe to use as a single path component, on
+/// Windows too: the characters it reserves are replaced, trailing dots and
+/// spaces, which it drops, are trimmed, and a device name gets an `_`.
+/// Full-width forms such as `：` and `？` are ordinary characters and kept.
+pub fn sanitize_component(value: &str) -> String {
+    let cleaned: String = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    let cleaned = cleaned.trim().trim_end_matches(['.', ' ']);
+    let stem = cleaned.split('.').next().unwrap_or_default();
+    if cleaned.is_empty() {
+        "_".to_string()
+    } else if DEVICES
+        .iter()
+        .any(|device| stem.trim_end().eq_ignore_ascii_case(device))
+    {
+        format!("{stem}_{}", &cleaned[stem.len()..])
+    } else {
+        cleaned.to_string()
+    }
+}
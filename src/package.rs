@@ -0,0 +1,236 @@
+//! What epub-builder can't be told, fixed in the finished archive: the
+//! [`ncx`], the spine `linear` attribute of pages outside the reading
+//! flow, and the language of every XHTML document.
+//!
+//! Back matter, the author's bio, the colophon and the inline table of
+//! contents epub-builder puts after them, is listed with
+//! `linear="no"`, so turning the page past the last chapter ends the book;
+//! the pages stay in the table of contents and the landmarks, which is how
+//! readers that hide non-linear pages still reach them.
+//!
+//! An `<html>` element without a language gets the book's, as `lang` and
+//! `xml:lang` (only `xml:lang` in EPUB 2), so readers hyphenate and pick
+//! fonts by it: chapter pages, chapter templates and epub-builder's own
+//! navigation documents alike.
+//!
+//! The `epub-dude:` metadata the build records, its version, source, length
+//! and last chapter, is declared as a prefix on the EPUB 3 `<package>`, so
+//! the properties resolve as the spec requires.
+//!
+//! Entries are deflated at the `--compression-level`, except the
+//! `mimetype`, which the spec requires stored, and images and fonts, which
+//! are compressed already and gain nothing from another pass. Entries
+//! already deflated at the level asked for are copied as they were.
+
+use std::io::{self, Read, Seek, Write};
+
+use anyhow::{Context, Result};
+use roxmltree::Document;
+use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+use crate::ncx;
+
+/// Where epub-builder puts the NCX and the package document.
+const NCX: &str = "OEBPS/toc.ncx";
+const OPF: &str = "OEBPS/content.opf";
+
+/// The prefix of the tool's own metadata properties, and the vocabulary it
+/// stands for.
+pub const PREFIX: (&str, &str) = ("epub-dude", "https://github.com/tommady/epub-dude#");
+
+/// The level epub-builder's zip command deflates every entry at, and the
+/// default. Its zip library deflates at the zip crate's default.
+pub const DEFAULT_LEVEL: u8 = 9;
+
+/// Extensions of entries that are stored rather than deflated.
+const COMPRESSED: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "woff", "woff2"];
+
+/// Generated pages that are back matter, by file name.
+pub const BACK_MATTER: &[&str] = &["about-author.xhtml", "colophon.xhtml", "toc.xhtml"];
+
+/// Writes `epub` to `to` with its NCX fixed, its [`PREFIX`] declared, the
+/// pages named in
+/// `nonlinear` taken out of the reading flow and its documents in
+/// `language`, one entry at a time, deflating at `level` (0 stores
+/// everything). `deflated_at` is the level `epub` was deflated at, if
+/// known.
+pub fn finish(
+    epub: impl Read + Seek,
+    to: impl Write + Seek,
+    nonlinear: &[&str],
+    language: &str,
+    level: u8,
+    deflated_at: Option<u8>,
+) -> Result<()> {
+    let mut zip = ZipArchive::new(epub).context("not a zip archive")?;
+    let mut out = ZipWriter::new(to);
+    let (ncx, opf, epub2) = match entry(&mut zip, OPF)? {
+        Some(opf) => {
+            let uid = identifier(&opf);
+            let epub2 = Document::parse(&opf)
+                .ok()
+                .and_then(|doc| {
+                    doc.root_element()
+                        .attribute("version")
+                        .map(|v| v.starts_with('2'))
+                })
+                .unwrap_or(false);
+            let ncx = entry(&mut zip, NCX)?.map(|ncx| ncx::fix(&ncx, uid.as_deref()));
+            let opf = unlink(&opf, nonlinear);
+            let opf = if epub2 { opf } else { declare_prefix(&opf) };
+            (ncx, Some(opf), epub2)
+        }
+        None => (None, None, false),
+    };
+
+    for i in 0..zip.len() {
+        let (name, method) = {
+            let file = zip.by_index_raw(i)?;
+            (file.name().to_string(), file.compression())
+        };
+        let level = match name.rsplit_once('.') {
+            _ if name == "mimetype" => 0,
+            Some((_, extension))
+                if COMPRESSED.contains(&extension.to_ascii_lowercase().as_str()) =>
+            {
+                0
+            }
+            _ => level,
+        };
+        let options = match level {
+            0 => SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+            level => SimpleFileOptions::default()
+                .compression_method(CompressionMethod::Deflated)
+                .compression_level(Some(level.into())),
+        };
+        let rewritten = match name.as_str() {
+            NCX => ncx.clone(),
+            OPF => opf.clone(),
+            _ if opf.is_some() && name.ends_with(".xhtml") => {
+                entry(&mut zip, &name)?.and_then(|page| in_language(&page, language, epub2))
+            }
+            _ => None,
+        };
+        match rewritten {
+            Some(text) => {
+                out.start_file(name, options)?;
+                out.write_all(text.as_bytes())?;
+            }
+            None if as_written(method, level, deflated_at) => {
+                out.raw_copy_file(zip.by_index_raw(i)?)?
+            }
+            None => {
+                out.start_file(name, options)?;
+                io::copy(&mut zip.by_index(i)?, &mut out)?;
+            }
+        }
+    }
+    out.finish()?.flush()?;
+    Ok(())
+}
+
+/// Whether an entry compressed with `method`, deflating at `deflated_at`,
+/// is already as it would be at `level`.
+fn as_written(method: CompressionMethod, level: u8, deflated_at: Option<u8>) -> bool {
+    match method {
+        CompressionMethod::Stored => level == 0,
+        CompressionMethod::Deflated => deflated_at == Some(level),
+        _ => false,
+    }
+}
+
+/// `page` with `language` on its `<html>` element, if it had none.
+fn in_language(page: &str, language: &str, epub2: bool) -> Option<String> {
+    let start = page.find("<html")?;
+    let end = start + page[start..].find('>')?;
+    let tag = &page[start..end];
+    if tag.contains("lang=") {
+        return None;
+    }
+    let lang = crate::xhtml::escape(language);
+    let at = start + "<html".len();
+    Some(format!(
+        "{}{}{}",
+        &page[..at],
+        crate::scripts::attributes(&lang, epub2),
+        &page[at..]
+    ))
+}
+
+fn entry(zip: &mut ZipArchive<impl Read + Seek>, name: &str) -> Result<Option<String>> {
+    let Ok(mut file) = zip.by_name(name) else {
+        return Ok(None);
+    };
+    let mut text = String::new();
+    file.read_to_string(&mut text)
+        .with_context(|| format!("Failed to read {name}"))?;
+    Ok(Some(text))
+}
+
+/// The value of the identifier `opf`'s package names as its unique one.
+pub fn identifier(opf: &str) -> Option<String> {
+    let doc = Document::parse(opf).ok()?;
+    let unique = doc.root_element().attribute("unique-identifier")?;
+    doc.descendants()
+        .find(|n| n.has_tag_name("identifier") && n.attribute("id") == Some(unique))
+        .and_then(|n| n.text())
+        .map(|text| text.trim().to_string())
+}
+
+/// `opf` with [`PREFIX`] added to the `prefix` attribute of its `<package>`
+/// when any property uses it.
+fn declare_prefix(opf: &str) -> String {
+    let (prefix, uri) = PREFIX;
+    let Ok(doc) = Document::parse(opf) else {
+        return opf.to_string();
+    };
+    let package = doc.root_element();
+    let used = doc.descendants().any(|n| {
+        n.attribute("property")
+            .is_some_and(|p| p.starts_with(&format!("{prefix}:")))
+    });
+    let declared = package.attribute("prefix").is_some_and(|p| {
+        p.split_whitespace()
+            .any(|word| word == format!("{prefix}:"))
+    });
+    if !used || declared {
+        return opf.to_string();
+    }
+
+    let declaration = format!("{prefix}: {uri}");
+    let mut fixed = opf.to_string();
+    match package.attribute_node("prefix") {
+        Some(attribute) => {
+            let end = attribute.range_value().end;
+            fixed.insert_str(end, &format!(" {declaration}"));
+        }
+        None => {
+            let start = package.range().start + "<package".len();
+            fixed.insert_str(start, &format!(r#" prefix="{declaration}""#));
+        }
+    }
+    fixed
+}
+
+/// `opf` with the spine entries of the manifest items at `files` marked
+/// `linear="no"`.
+fn unlink(opf: &str, files: &[&str]) -> String {
+    let Ok(doc) = Document::parse(opf) else {
+        return opf.to_string();
+    };
+    let ids: Vec<&str> = doc
+        .descendants()
+        .filter(|n| {
+            n.has_tag_name("item") && n.attribute("href").is_some_and(|h| files.contains(&h))
+        })
+        .filter_map(|n| n.attribute("id"))
+        .collect();
+    let mut fixed = opf.to_string();
+    for id in ids {
+        fixed = fixed.replace(
+            &format!(r#"<itemref idref="{id}"/>"#),
+            &format!(r#"<itemref idref="{id}" linear="no"/>"#),
+        );
+    }
+    fixed
+}
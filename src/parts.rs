@@ -0,0 +1,66 @@
+//! `--split-every`: one long book written as several numbered epubs.
+//!
+//! Every part carries the book's metadata and title page and its own table
+//! of contents, while chapter numbering, file names and footnote ids carry
+//! on from the part before. Calibre series metadata keeps the parts in
+//! order, and their identifiers differ only in the part number.
+
+use std::path::{Path, PathBuf};
+
+use epub_builder::{EpubBuilder, MetadataOpf, MetadataOpfV3, ZipCommandOrLibrary};
+use uuid::Uuid;
+
+use crate::{output::Format, workdir, xhtml};
+
+/// Where part `n`, from 1, of the book bound for `path` goes:
+/// `Title - Part 1.epub` for `Title.epub`.
+pub fn path(path: &Path, format: Format, n: usize) -> PathBuf {
+    format.insert_before_extension(path, &format!(" - Part {n}"))
+}
+
+/// The title of part `n`.
+pub fn title(title: &str, n: usize) -> String {
+    format!("{title} - Part {n}")
+}
+
+/// The identifier of part `n` of the book identified by `identity`, a URL;
+/// part 0 stands for the whole book.
+pub fn identifier(identity: &str, n: usize) -> Uuid {
+    Uuid::from_u64_pair(workdir::fnv1a(identity.as_bytes()), n as u64)
+}
+
+/// Marks the book as part `n` of the series `series` identified by
+/// `identity`.
+pub fn add_to(
+    book: &mut EpubBuilder<ZipCommandOrLibrary>,
+    series: &str,
+    identity: &str,
+    n: usize,
+    epub2: bool,
+) {
+    book.set_uuid(identifier(identity, n));
+    book.add_metadata_opf(Box::new(MetadataOpf {
+        name: "calibre:series".to_string(),
+        content: series.to_string(),
+    }));
+    book.add_metadata_opf(Box::new(MetadataOpf {
+        name: "calibre:series_index".to_string(),
+        content: n.to_string(),
+    }));
+    if epub2 {
+        return;
+    }
+
+    let mut collection =
+        MetadataOpfV3::new("belongs-to-collection".to_string(), xhtml::escape(series));
+    collection.add_id("series".to_string());
+    book.add_metadata_opf(Box::new(collection));
+    for (property, content) in [
+        ("collection-type", "series".to_string()),
+        ("group-position", n.to_string()),
+    ] {
+        let mut meta = MetadataOpfV3::new(property.to_string(), content);
+        meta.refines = Some("#series".to_string());
+        book.add_metadata_opf(Box::new(meta));
+    }
+}
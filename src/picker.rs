@@ -0,0 +1,156 @@
+//! The `--interactive` chapter picker: a paged checklist of the index that
+//! narrows as titles are searched.
+
+use std::io::{self, IsTerminal};
+
+use anyhow::{Result, bail};
+use console::{Key, Term, truncate_str};
+use epub_dude::ChapterLink;
+
+const HELP: &str =
+    "↑/↓ move, space toggles, tab toggles all shown, type to search, enter builds, esc cancels";
+
+/// Whether there's someone to ask: without a terminal on both ends the
+/// prompt would wait for keys that never come.
+pub fn available() -> bool {
+    io::stdin().is_terminal() && Term::stderr().is_term()
+}
+
+/// Shows `links` for checking on stderr and returns the positions of the
+/// checked ones, in index order.
+pub fn pick(title: &str, links: &[ChapterLink]) -> Result<Vec<usize>> {
+    let term = Term::stderr();
+    let mut picker = Picker {
+        links,
+        checked: vec![false; links.len()],
+        query: String::new(),
+        shown: (0..links.len()).collect(),
+        cursor: 0,
+        top: 0,
+    };
+    term.hide_cursor()?;
+    let picked = picker.run(&term, title);
+    term.show_cursor()?;
+    picked
+}
+
+struct Picker<'a> {
+    links: &'a [ChapterLink],
+    checked: Vec<bool>,
+    query: String,
+    /// Positions in `links` whose titles match `query`.
+    shown: Vec<usize>,
+    /// The highlighted entry, as an index into `shown`.
+    cursor: usize,
+    /// The first entry of `shown` on screen.
+    top: usize,
+}
+
+impl Picker<'_> {
+    fn run(&mut self, term: &Term, title: &str) -> Result<Vec<usize>> {
+        let mut drawn = 0;
+        loop {
+            let (height, width) = term.size();
+            let rows = usize::from(height).saturating_sub(4).clamp(3, 20);
+            self.scroll(rows);
+            term.clear_last_lines(drawn)?;
+            drawn = self.draw(term, title, rows, usize::from(width))?;
+
+            let last = self.shown.len().saturating_sub(1);
+            match term.read_key()? {
+                Key::ArrowUp => self.cursor = self.cursor.saturating_sub(1),
+                Key::ArrowDown => self.cursor = (self.cursor + 1).min(last),
+                Key::PageUp => self.cursor = self.cursor.saturating_sub(rows),
+                Key::PageDown => self.cursor = (self.cursor + rows).min(last),
+                Key::Home => self.cursor = 0,
+                Key::End => self.cursor = last,
+                Key::Char(' ') => {
+                    if let Some(&i) = self.shown.get(self.cursor) {
+                        self.checked[i] = !self.checked[i];
+                    }
+                }
+                Key::Tab => {
+                    let check = self.shown.iter().any(|&i| !self.checked[i]);
+                    for &i in &self.shown {
+                        self.checked[i] = check;
+                    }
+                }
+                Key::Enter => {
+                    term.clear_last_lines(drawn)?;
+                    let picked: Vec<usize> =
+                        (0..self.links.len()).filter(|&i| self.checked[i]).collect();
+                    if picked.is_empty() {
+                        bail!("No chapters picked");
+                    }
+                    return Ok(picked);
+                }
+                Key::Escape if !self.query.is_empty() => {
+                    self.query.clear();
+                    self.search();
+                }
+                Key::Escape | Key::CtrlC => {
+                    term.clear_last_lines(drawn)?;
+                    bail!("Chapter selection cancelled");
+                }
+                Key::Backspace => {
+                    self.query.pop();
+                    self.search();
+                }
+                Key::Char(c) if !c.is_control() => {
+                    self.query.push(c);
+                    self.search();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Case-insensitive substring match on the titles.
+    fn search(&mut self) {
+        let query = self.query.to_lowercase();
+        self.shown = (0..self.links.len())
+            .filter(|&i| self.links[i].title.to_lowercase().contains(&query))
+            .collect();
+        self.cursor = 0;
+        self.top = 0;
+    }
+
+    /// Keeps the cursor within the `rows` on screen.
+    fn scroll(&mut self, rows: usize) {
+        if self.cursor < self.top {
+            self.top = self.cursor;
+        } else if self.cursor >= self.top + rows {
+            self.top = self.cursor + 1 - rows;
+        }
+    }
+
+    /// Draws the page, returning how many lines it took.
+    fn draw(&self, term: &Term, title: &str, rows: usize, width: usize) -> Result<usize> {
+        let fit = |line: &str| truncate_str(line, width.saturating_sub(1), "…").into_owned();
+        let picked = self.checked.iter().filter(|&&c| c).count();
+        let mut lines = vec![
+            fit(&format!(
+                "{title}: {picked} of {} chapters picked",
+                self.links.len()
+            )),
+            fit(HELP),
+            fit(&format!("Search: {}", self.query)),
+        ];
+        if self.shown.is_empty() {
+            lines.push("  (no titles match)".to_string());
+        }
+        for (row, &i) in self.shown.iter().enumerate().skip(self.top).take(rows) {
+            let pointer = if row == self.cursor { '>' } else { ' ' };
+            let check = if self.checked[i] { 'x' } else { ' ' };
+            lines.push(fit(&format!(
+                "{pointer} [{check}] {:>4} {}",
+                i + 1,
+                self.links[i].title
+            )));
+        }
+        for line in &lines {
+            term.write_line(line)?;
+        }
+        Ok(lines.len())
+    }
+}
@@ -0,0 +1,130 @@
+use std::{fs::File, io::Write, path::Path};
+
+use anyhow::{Context, Result};
+
+use crate::{output::Format, xhtml};
+
+/// Converts generated chapter markup into plain lines of text.
+///
+/// Only the markup this tool emits needs handling: `<br />` breaks lines,
+/// ruby readings become "漢字（かんじ）" with the source's own `<rp>`
+/// parentheses dropped, and every other tag is removed.
+pub fn to_lines(markup: &str) -> Vec<String> {
+    let mut lines = vec![String::new()];
+    let mut in_rp = false;
+    let mut rest = markup;
+
+    while let Some(start) = rest.find('<') {
+        if !in_rp {
+            push_text(&mut lines, &rest[..start]);
+        }
+        let Some(end) = rest[start..].find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = &rest[start + 1..start + end];
+        rest = &rest[start + end + 1..];
+
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        let closing = tag.starts_with('/');
+
+        match (name, closing) {
+            ("br", _) => lines.push(String::new()),
+            ("rp", false) => in_rp = true,
+            ("rp", true) => in_rp = false,
+            ("rt", false) => push_text(&mut lines, "（"),
+            ("rt", true) => push_text(&mut lines, "）"),
+            ("td" | "th", true) => push_text(&mut lines, "\t"),
+            ("p" | "tr" | "caption", true) | ("blockquote" | "pre" | "table" | "h2", _) => {
+                lines.push(String::new())
+            }
+            _ => {}
+        }
+    }
+    if !in_rp {
+        push_text(&mut lines, rest);
+    }
+
+    lines
+        .into_iter()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+/// Appends text to the current line; newlines (kept in preformatted blocks)
+/// start new ones.
+fn push_text(lines: &mut Vec<String>, escaped: &str) {
+    for (i, part) in xhtml::unescape(escaped).split('\n').enumerate() {
+        if i > 0 {
+            lines.push(String::new());
+        }
+        if let Some(line) = lines.last_mut() {
+            line.push_str(part);
+        }
+    }
+}
+
+fn escape_markdown(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    for c in line.chars() {
+        if matches!(c, '\\' | '*' | '_' | '`' | '[' | ']' | '#' | '<' | '>') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+pub struct PlainChapter {
+    pub title: String,
+    pub markup: String,
+}
+
+/// Writes the whole book as a single `.txt` or `.md` file.
+pub fn write(
+    path: &Path,
+    format: Format,
+    title: &str,
+    authors: &[String],
+    chapters: &[PlainChapter],
+) -> Result<()> {
+    let mut out = String::new();
+    let md = format == Format::Md;
+
+    if md {
+        out.push_str(&format!("# {}\n\n", escape_markdown(title)));
+        if !authors.is_empty() {
+            out.push_str(&format!("*{}*\n\n", escape_markdown(&authors.join(", "))));
+        }
+    } else {
+        out.push_str(&format!("{title}\n"));
+        if !authors.is_empty() {
+            out.push_str(&format!("{}\n", authors.join(", ")));
+        }
+        out.push('\n');
+    }
+
+    for chapter in chapters {
+        let lines = to_lines(&chapter.markup);
+        if md {
+            out.push_str(&format!("## {}\n\n", escape_markdown(&chapter.title)));
+            for line in lines {
+                out.push_str(&format!("{}\n\n", escape_markdown(&line)));
+            }
+        } else {
+            out.push_str(&format!("\n{}\n\n", chapter.title));
+            for line in lines {
+                out.push_str(&format!("{line}\n"));
+            }
+        }
+    }
+
+    File::create(path)
+        .and_then(|mut f| f.write_all(out.as_bytes()))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
@@ -0,0 +1,171 @@
+//! How fast each host may be fetched from, learned across runs.
+//!
+//! `hosts.json` in the config directory, `$XDG_CONFIG_HOME/epub-dude` or
+//! `~/.config/epub-dude`, keeps a profile per host: the delay after each
+//! request, a cap on requests in flight and how often the host answered
+//! 429. A 429 makes the delay longer, and when more than one request was
+//! in flight, lowers the cap; a long run of clean responses eases both back.
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::checkpoint;
+
+/// Bumped whenever the stored format changes; profiles written with another
+/// version are discarded.
+pub const VERSION: u32 = 1;
+const FILE: &str = "hosts.json";
+/// Clean responses in a row before a host's pacing is eased.
+const CLEAN_STREAK: u64 = 100;
+/// The longest delay a host is slowed down to.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Where the profiles are kept.
+pub fn default_path() -> PathBuf {
+    let config = env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    match config {
+        Some(config) => config.join("epub-dude").join(FILE),
+        None => PathBuf::from(".epub-dude").join(FILE),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Profiles {
+    version: u32,
+    /// By host name, e.g. "czbooks.net".
+    pub hosts: BTreeMap<String, Profile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    /// Waited after every request, in milliseconds.
+    pub delay_ms: u64,
+    /// The most requests in flight at once; unset leaves it to `--jobs`.
+    pub jobs: Option<usize>,
+    pub requests: u64,
+    /// Requests answered 429.
+    pub throttled: u64,
+    /// When the last 429 came, in RFC 3339.
+    pub last_throttled: Option<String>,
+    /// Responses since the last 429 or easing.
+    pub clean_streak: u64,
+}
+
+impl Profile {
+    /// The share of requests answered 429.
+    pub fn throttle_rate(&self) -> f64 {
+        if self.requests == 0 {
+            0.0
+        } else {
+            self.throttled as f64 / self.requests as f64
+        }
+    }
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        Profiles {
+            version: VERSION,
+            hosts: BTreeMap::new(),
+        }
+    }
+}
+
+impl Profiles {
+    pub fn load(path: &Path) -> Self {
+        let Ok(json) = fs::read_to_string(path) else {
+            return Profiles::default();
+        };
+        match serde_json::from_str::<Profiles>(&json) {
+            Ok(profiles) if profiles.version == VERSION => profiles,
+            Ok(profiles) => {
+                log::warn!(
+                    "discarding {}: written by format version {}, expected {VERSION}",
+                    path.display(),
+                    profiles.version
+                );
+                Profiles::default()
+            }
+            Err(e) => {
+                log::warn!("discarding {}: {e}", path.display());
+                Profiles::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        checkpoint::write_atomic(path, &serde_json::to_vec_pretty(self)?)
+    }
+
+    /// The delay after each request to `host`: its stored one, but never
+    /// less than `base`.
+    pub fn delay(&self, host: &str, base: Duration) -> Duration {
+        self.hosts
+            .get(host)
+            .map_or(base, |p| base.max(Duration::from_millis(p.delay_ms)))
+    }
+
+    /// How many of `wanted` requests to `host` may be in flight at once.
+    pub fn jobs(&self, host: &str, wanted: usize) -> usize {
+        self.hosts
+            .get(host)
+            .and_then(|p| p.jobs)
+            .map_or(wanted, |cap| wanted.min(cap.max(1)))
+    }
+
+    /// Learns from one response from `host`, `throttled` if it was a 429,
+    /// while `in_flight` requests were running. Returns whether the host's
+    /// pacing changed.
+    pub fn learn(&mut self, host: &str, base: Duration, throttled: bool, in_flight: usize) -> bool {
+        let profile = self.hosts.entry(host.to_string()).or_default();
+        profile.requests += 1;
+        let delay = base.max(Duration::from_millis(profile.delay_ms));
+        if throttled {
+            profile.throttled += 1;
+            profile.last_throttled = Some(Local::now().to_rfc3339());
+            profile.clean_streak = 0;
+            profile.delay_ms = (delay * 3 / 2)
+                .max(delay + Duration::from_millis(500))
+                .min(MAX_DELAY)
+                .as_millis() as u64;
+            if in_flight > 1 {
+                profile.jobs = Some(profile.jobs.unwrap_or(in_flight).min(in_flight) - 1);
+            }
+            log::info!(
+                "{host}: HTTP 429, slowing down to {}ms between requests",
+                profile.delay_ms
+            );
+            return true;
+        }
+        profile.clean_streak += 1;
+        if profile.clean_streak < CLEAN_STREAK {
+            return false;
+        }
+        profile.clean_streak = 0;
+        if delay > base {
+            profile.delay_ms = base.max(delay * 9 / 10).as_millis() as u64;
+            log::debug!("{host}: easing to {}ms between requests", profile.delay_ms);
+        } else if let Some(jobs) = profile.jobs {
+            profile.jobs = Some(jobs + 1);
+            log::debug!("{host}: allowing {} requests at once", jobs + 1);
+        } else {
+            return false;
+        }
+        true
+    }
+}
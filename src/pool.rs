@@ -0,0 +1,98 @@
+//! Connection reuse. The agent keeps connections alive between requests,
+//! so chapters fetched one after another go over one connection instead of
+//! a TLS handshake each, which some hosts' connection-rate limits count;
+//! `--no-keepalive` closes each one after its request.
+//!
+//! Every connection [`resolve::agent`](crate::resolve::agent) opens is
+//! numbered, so the debug log can say which requests opened one and which
+//! reused one.
+
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use ureq::unversioned::transport::{Buffers, ConnectionDetails, Connector, NextTimeout, Transport};
+
+/// How many idle connections are kept per host, one per `--jobs` worker
+/// for all but the busiest runs.
+pub const IDLE_PER_HOST: usize = 8;
+/// How long an idle connection is kept, longer than the politeness delay
+/// between two chapters ever gets.
+pub const IDLE_AGE: Duration = Duration::from_secs(60);
+
+static OPENED: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The connection this thread last sent a request over, and how many
+    /// requests that connection has carried.
+    static LAST: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+/// The connection the last request on this thread went over and how many
+/// requests it has carried, one for a new connection; taken, so the next
+/// call is about the next request. `None` for agents that don't number
+/// their connections.
+pub fn last_connection() -> Option<(usize, usize)> {
+    LAST.take()
+}
+
+/// Numbers each connection the connectors before it open.
+#[derive(Debug)]
+pub struct Numbering;
+
+impl Connector<Box<dyn Transport>> for Numbering {
+    type Out = Numbered;
+
+    fn connect(
+        &self,
+        _details: &ConnectionDetails,
+        chained: Option<Box<dyn Transport>>,
+    ) -> Result<Option<Self::Out>, ureq::Error> {
+        Ok(chained.map(|inner| Numbered {
+            inner,
+            id: OPENED.fetch_add(1, Ordering::Relaxed) + 1,
+            requests: 0,
+            sending: false,
+        }))
+    }
+}
+
+#[derive(Debug)]
+pub struct Numbered {
+    inner: Box<dyn Transport>,
+    id: usize,
+    requests: usize,
+    /// Whether a request is being sent, so its body doesn't count as
+    /// another one.
+    sending: bool,
+}
+
+impl Transport for Numbered {
+    fn buffers(&mut self) -> &mut dyn Buffers {
+        self.inner.buffers()
+    }
+
+    fn transmit_output(&mut self, amount: usize, timeout: NextTimeout) -> Result<(), ureq::Error> {
+        if !self.sending {
+            self.sending = true;
+            self.requests += 1;
+            LAST.set(Some((self.id, self.requests)));
+        }
+        self.inner.transmit_output(amount, timeout)
+    }
+
+    fn await_input(&mut self, timeout: NextTimeout) -> Result<bool, ureq::Error> {
+        self.sending = false;
+        self.inner.await_input(timeout)
+    }
+
+    fn is_open(&mut self) -> bool {
+        self.inner.is_open()
+    }
+
+    fn is_tls(&self) -> bool {
+        self.inner.is_tls()
+    }
+}
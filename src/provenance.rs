@@ -0,0 +1,136 @@
+use chrono::Utc;
+use epub_builder::{EpubBuilder, MetadataOpfV3, ZipCommandOrLibrary};
+use serde::Serialize;
+
+use crate::{BuildOptions, SortOrder, workdir, xhtml};
+
+/// Describes which build of the tool produced a book, and from what.
+#[derive(Serialize, Default)]
+pub struct Generator {
+    pub version: String,
+    pub generated_at: String,
+    pub source: Option<String>,
+    pub config_hash: String,
+}
+
+impl Generator {
+    pub fn new(source: Option<String>, config: &str) -> Self {
+        Generator {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            generated_at: Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            source,
+            config_hash: format!("{:016x}", workdir::fnv1a(config.as_bytes())),
+        }
+    }
+
+    pub fn add_to(&self, book: &mut EpubBuilder<ZipCommandOrLibrary>) {
+        book.set_generator(format!("epub-dude {}", self.version));
+
+        let mut meta = vec![
+            ("epub-dude:version", &self.version),
+            ("epub-dude:generated", &self.generated_at),
+            ("epub-dude:config-hash", &self.config_hash),
+        ];
+        if let Some(source) = &self.source {
+            meta.push(("epub-dude:source", source));
+        }
+
+        for (property, content) in meta {
+            book.add_metadata_opf(Box::new(MetadataOpfV3::new(
+                property.to_string(),
+                xhtml::escape(content),
+            )));
+        }
+    }
+}
+
+/// The options that change what a built book contains, one `name=value`
+/// line each, for [`Generator::new`] to hash. Options that only change how
+/// the book is fetched, such as `--jobs` or the work directory, are left
+/// out, so they don't change the hash.
+pub fn config(options: &BuildOptions) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    let images = &options.images;
+    let typography = &options.typography;
+    let contributors: Vec<String> = options
+        .contributors
+        .iter()
+        .map(|c| format!("{}:{}", c.role.marc_code(), c.name))
+        .collect();
+    let fields = [
+        ("format", options.format.extension().to_string()),
+        ("epub2", options.epub2.to_string()),
+        ("language", options.language.clone()),
+        ("title", optional(options.title.clone())),
+        ("authors", options.authors.join("\u{1f}")),
+        ("contributors", contributors.join("\u{1f}")),
+        (
+            "sort",
+            match options.sort {
+                SortOrder::Document => "document",
+                SortOrder::TitleNumber => "title-number",
+            }
+            .to_string(),
+        ),
+        ("chapter-footer", optional(options.chapter_footer.clone())),
+        ("chapter-nav", options.chapter_nav.to_string()),
+        ("dedupe-toc-titles", options.dedupe_toc_titles.to_string()),
+        ("no-title-page", options.no_title_page.to_string()),
+        ("colophon", options.colophon.to_string()),
+        ("theme", options.theme.as_str().to_string()),
+        ("writing-mode", options.writing_mode.as_str().to_string()),
+        ("justify", typography.justify.to_string()),
+        (
+            "font-size",
+            optional(typography.font_size.map(|v| v.to_string())),
+        ),
+        (
+            "line-height",
+            optional(typography.line_height.map(|v| v.to_string())),
+        ),
+        (
+            "paragraph-spacing",
+            optional(typography.paragraph_spacing.map(|v| v.to_string())),
+        ),
+        (
+            "headings",
+            options
+                .headings
+                .patterns()
+                .collect::<Vec<_>>()
+                .join("\u{1f}"),
+        ),
+        ("max-subheadings", options.headings.max.to_string()),
+        ("max-chapter-size", options.max_chapter_size.to_string()),
+        (
+            "split-every",
+            optional(options.split_every.map(|v| v.to_string())),
+        ),
+        ("compression-level", options.compression_level.to_string()),
+        ("description-limit", options.description_limit.to_string()),
+        ("toc-title", optional(options.toc_title.clone())),
+        ("toc-description", options.toc_description.to_string()),
+        (
+            "reading-speed",
+            optional(options.reading_speed.map(|v| v.to_string())),
+        ),
+        ("length-meta", options.length_meta.to_string()),
+        ("merge-softwrap", options.merge_softwrap.to_string()),
+        ("per-paragraph-lang", options.per_paragraph_lang.to_string()),
+        ("embed-images", images.embed.to_string()),
+        ("alt-template", images.alt_template.clone()),
+        ("decorative-images", images.decorative.to_string()),
+        (
+            "max-image-dimension",
+            optional(images.max_dimension.map(|v| v.to_string())),
+        ),
+        (
+            "image-quality",
+            optional(images.quality.map(|v| v.to_string())),
+        ),
+    ];
+    fields
+        .iter()
+        .map(|(name, value)| format!("{name}={value}\n"))
+        .collect()
+}
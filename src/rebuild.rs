@@ -0,0 +1,151 @@
+//! `--rebuild`: a book assembled again from what its last complete build
+//! kept in the work directory, e.g. to try another stylesheet or chapter
+//! template, without a single request.
+//!
+//! Every complete build keeps the index page as parsed, title, authors,
+//! page metadata and chapter list, in `book.json`, and the chapters it has
+//! under `chapters/`. A rebuild reads both instead of the site; nothing
+//! else is downloaded either, so covers and chapter images are left out.
+
+use std::collections::BTreeMap;
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    checkpoint::Cache,
+    fallback::Planned,
+    fetch::{BookInfo, ChapterLink, jsonld::LinkedBook, og::PageMeta},
+};
+
+/// The index page as a complete build parsed it, with `--prefer-og` and
+/// the like already applied.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct StoredIndex {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub author_pages: Vec<String>,
+    pub description: Option<String>,
+    pub canonical: Option<String>,
+    pub image: Option<String>,
+    pub linked_data: Option<StoredLinkedBook>,
+    pub links: Vec<StoredLink>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct StoredLinkedBook {
+    pub name: Option<String>,
+    pub authors: Vec<String>,
+    pub description: Option<String>,
+    pub genres: Vec<String>,
+    pub published: Option<String>,
+    pub image: Option<String>,
+    pub extra: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredLink {
+    pub url: String,
+    pub title: String,
+    /// `YYYY-MM-DD`.
+    pub date: Option<String>,
+}
+
+impl From<&BookInfo> for StoredIndex {
+    fn from(info: &BookInfo) -> Self {
+        let string = |uri: &http::Uri| uri.to_string();
+        StoredIndex {
+            title: info.title.clone(),
+            authors: info.authors.clone(),
+            author_pages: info.author_pages.iter().map(string).collect(),
+            description: info.description.clone(),
+            canonical: info.page.canonical.as_ref().map(string),
+            image: info.page.image.as_ref().map(string),
+            linked_data: info.page.linked_data.as_ref().map(|book| StoredLinkedBook {
+                name: book.name.clone(),
+                authors: book.authors.clone(),
+                description: book.description.clone(),
+                genres: book.genres.clone(),
+                published: book.published.clone(),
+                image: book.image.as_ref().map(string),
+                extra: book.extra.clone(),
+            }),
+            links: info
+                .links
+                .iter()
+                .map(|link| StoredLink {
+                    url: link.uri.to_string(),
+                    title: link.title.clone(),
+                    date: link.date.map(|date| date.to_string()),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl StoredIndex {
+    /// The index as the build found it; URLs that no longer parse are
+    /// dropped.
+    pub fn info(&self) -> BookInfo {
+        let uri = |url: &String| url.parse().ok();
+        let linked_data = self.linked_data.as_ref().map(|book| LinkedBook {
+            name: book.name.clone(),
+            authors: book.authors.clone(),
+            description: book.description.clone(),
+            genres: book.genres.clone(),
+            published: book.published.clone(),
+            image: book.image.as_ref().and_then(uri),
+            extra: book.extra.clone(),
+        });
+        BookInfo {
+            authors: self.authors.clone(),
+            author_pages: self.author_pages.iter().filter_map(uri).collect(),
+            title: self.title.clone(),
+            description: self.description.clone(),
+            links: self
+                .links
+                .iter()
+                .filter_map(|link| {
+                    Some(ChapterLink {
+                        uri: uri(&link.url)?,
+                        title: link.title.clone(),
+                        date: link
+                            .date
+                            .as_deref()
+                            .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()),
+                    })
+                })
+                .collect(),
+            page: PageMeta {
+                canonical: self.canonical.as_ref().and_then(uri),
+                title: None,
+                description: None,
+                image: self.image.as_ref().and_then(uri),
+                linked_data,
+                document_title: None,
+                meta_description: None,
+            },
+        }
+    }
+}
+
+/// The chapters of `plan` that `cached` has nothing for, as "chapter 3
+/// \"Title\" (url)", leaving out those `skipped` says the book goes
+/// without anyway.
+pub fn uncached(plan: &[Planned], cached: &Cache, skipped: impl Fn(&str) -> bool) -> Vec<String> {
+    plan.iter()
+        .enumerate()
+        .filter(|(_, item)| {
+            let link = item.link.uri.to_string();
+            !cached.contains(&link) && !skipped(&link)
+        })
+        .map(|(i, item)| {
+            format!(
+                "chapter {} \"{}\" ({})",
+                i + 1,
+                item.link.title,
+                item.link.uri
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,125 @@
+//! Name resolution for `--ipv4-only`, `--ipv6-only` and `--resolve`.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use http::Uri;
+use ureq::{
+    Agent,
+    config::{Config, IpFamily},
+    tls::TlsConfig,
+    unversioned::{
+        resolver::{DefaultResolver, ResolvedSocketAddrs, Resolver},
+        transport::{Connector, DefaultConnector, NextTimeout},
+    },
+};
+
+use crate::pool;
+
+/// A `--resolve host:port:addr` pin, as curl takes it; IPv6 addresses may
+/// be bracketed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pin {
+    pub host: String,
+    pub port: u16,
+    pub addr: IpAddr,
+}
+
+impl FromStr for Pin {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut fields = s.splitn(3, ':');
+        let (Some(host), Some(port), Some(addr)) = (fields.next(), fields.next(), fields.next())
+        else {
+            anyhow::bail!("Invalid --resolve: {s} (expected host:port:addr)");
+        };
+        if host.is_empty() {
+            anyhow::bail!("Invalid --resolve: {s} (no host)");
+        }
+        let port = port
+            .parse()
+            .with_context(|| format!("Invalid --resolve: {s} (bad port {port})"))?;
+        let addr = addr
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .with_context(|| format!("Invalid --resolve: {s} (bad address {addr})"))?;
+        Ok(Pin {
+            host: host.to_ascii_lowercase(),
+            port,
+            addr,
+        })
+    }
+}
+
+/// An agent that connects over `family` only, to pinned hosts at their
+/// pinned address and with `tls`, see [`crate::tls::config`], keeping its
+/// connections alive between requests unless `keepalive` is off, see
+/// [`crate::pool`].
+pub fn agent(family: IpFamily, pins: Vec<Pin>, tls: TlsConfig, keepalive: bool) -> Agent {
+    let idle = if keepalive { pool::IDLE_PER_HOST } else { 0 };
+    let config = Agent::config_builder()
+        .ip_family(family)
+        .tls_config(tls)
+        .max_idle_connections(idle * 4)
+        .max_idle_connections_per_host(idle)
+        .max_idle_age(pool::IDLE_AGE)
+        .build();
+    Agent::with_parts(
+        config,
+        DefaultConnector::default().chain(pool::Numbering),
+        Pinning {
+            pins,
+            system: DefaultResolver::default(),
+        },
+    )
+}
+
+/// Answers for pinned hosts itself and asks the system about the rest,
+/// logging which addresses each host got.
+#[derive(Debug)]
+struct Pinning {
+    pins: Vec<Pin>,
+    system: DefaultResolver,
+}
+
+impl Resolver for Pinning {
+    fn resolve(
+        &self,
+        uri: &Uri,
+        config: &Config,
+        timeout: NextTimeout,
+    ) -> Result<ResolvedSocketAddrs, ureq::Error> {
+        let host = uri.host().unwrap_or_default();
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("http") => 80,
+            _ => 443,
+        });
+        if let Some(pin) = self
+            .pins
+            .iter()
+            .find(|pin| pin.port == port && pin.host.eq_ignore_ascii_case(host))
+        {
+            log::debug!("resolved {host}:{port} to {} (--resolve)", pin.addr);
+            let mut addrs = self.empty();
+            addrs.push(SocketAddr::new(pin.addr, port));
+            return Ok(addrs);
+        }
+        let addrs = self.system.resolve(uri, config, timeout)?;
+        let listed: Vec<String> = addrs.iter().map(|addr| addr.ip().to_string()).collect();
+        log::debug!(
+            "resolved {host}:{port} to {} ({})",
+            listed.join(", "),
+            match config.ip_family() {
+                IpFamily::Ipv4Only => "IPv4 only",
+                IpFamily::Ipv6Only => "IPv6 only",
+                IpFamily::Any => "any family",
+            }
+        );
+        Ok(addrs)
+    }
+}
@@ -0,0 +1,136 @@
+//! `--check-revisions`: noticing when a site rewrites chapters an earlier
+//! run already downloaded.
+//!
+//! Each run with the option keeps the text of every chapter it built in the
+//! book's work directory; the next one compares what it fetches against
+//! that. Text is normalized first, so markup or whitespace shuffles don't
+//! count as revisions. The first run only records.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+
+use crate::{checkpoint, workdir, xhtml};
+
+/// Bumped whenever the stored format changes; texts written with another
+/// version are discarded.
+pub const VERSION: u32 = 1;
+const FILE: &str = "texts.json";
+
+static BREAK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)<br\s*/?>|</?(?:p|div|h[1-6]|li|blockquote|tr)\b[^>]*>")
+        .expect("valid line break regex")
+});
+static TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").expect("valid tag regex"));
+
+/// The chapter texts an earlier run recorded, by chapter URL.
+#[derive(Serialize, Deserialize)]
+pub struct Texts {
+    version: u32,
+    chapters: BTreeMap<String, Text>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Text {
+    /// FNV-1a of `text`.
+    pub hash: String,
+    /// The normalized text, one paragraph per line.
+    pub text: String,
+}
+
+impl Text {
+    pub fn new(markup: &str) -> Self {
+        let text = normalize(markup);
+        Text {
+            hash: format!("{:016x}", workdir::fnv1a(text.as_bytes())),
+            text,
+        }
+    }
+}
+
+impl Default for Texts {
+    fn default() -> Self {
+        Texts {
+            version: VERSION,
+            chapters: BTreeMap::new(),
+        }
+    }
+}
+
+impl Texts {
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(FILE);
+        let Ok(json) = fs::read_to_string(&path) else {
+            return Texts::default();
+        };
+        match serde_json::from_str::<Texts>(&json) {
+            Ok(texts) if texts.version == VERSION => texts,
+            Ok(texts) => {
+                log::warn!(
+                    "discarding {}: written by format version {}, expected {VERSION}",
+                    path.display(),
+                    texts.version
+                );
+                Texts::default()
+            }
+            Err(e) => {
+                log::warn!("discarding {}: {e}", path.display());
+                Texts::default()
+            }
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        checkpoint::write_atomic(&dir.join(FILE), &serde_json::to_vec(self)?)
+    }
+
+    /// Records the chapter at `url` as `markup` now reads, returning the
+    /// text it replaces if that differs.
+    pub fn update(&mut self, url: &str, markup: &str) -> Option<(Text, Text)> {
+        let current = Text::new(markup);
+        match self.chapters.insert(url.to_string(), current.clone()) {
+            Some(previous) if previous.hash != current.hash => Some((previous, current)),
+            _ => None,
+        }
+    }
+}
+
+/// Chapter markup as plain text: tags dropped, entities decoded, runs of
+/// whitespace collapsed and blank lines removed.
+pub fn normalize(markup: &str) -> String {
+    let text = BREAK.replace_all(markup, "\n");
+    let text = xhtml::unescape(&TAG.replace_all(&text, ""));
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Writes a unified diff of chapter `index`'s revision to `dir`, returning
+/// where.
+pub fn write_diff(
+    dir: &Path,
+    index: usize,
+    url: &str,
+    previous: &Text,
+    current: &Text,
+) -> Result<PathBuf> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let path = dir.join(format!("chapter-{:04}.diff", index + 1));
+    let (previous, current) = (previous.text.clone() + "\n", current.text.clone() + "\n");
+    let diff = TextDiff::from_lines(&previous, &current)
+        .unified_diff()
+        .header(&format!("{url} (previous)"), &format!("{url} (current)"))
+        .to_string();
+    fs::write(&path, diff).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
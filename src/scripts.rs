@@ -0,0 +1,168 @@
+//! `--per-paragraph-lang`: paragraphs written mostly in another script
+//! than the book's, an English letter in a Chinese novel say, get a `lang`
+//! of their own, so readers hyphenate them and pick their fonts by it.
+//!
+//! A paragraph is a `<p>` element, or a line of line-broken chapter text
+//! outside any element. Its script is the one most of its letters are in,
+//! with at least [`MIN_LETTERS`] letters and [`MIN_SHARE`] of them, so a
+//! name or a word in passing doesn't count. Scripts stand for a language:
+//! Latin for English, Cyrillic for Russian, Hangul for Korean, Han for
+//! Chinese and Han with kana for Japanese. A paragraph in the book's own
+//! script is left alone, whatever language it is in.
+
+use std::{borrow::Cow, sync::LazyLock};
+
+use regex::{Captures, Regex};
+
+use crate::headings::nesting_after;
+
+const BREAK: &str = "<br />";
+pub const MIN_LETTERS: usize = 12;
+/// Of a paragraph's letters, how many are in its script, over 10.
+pub const MIN_SHARE: usize = 7;
+
+static PARAGRAPH: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<p\b([^>]*)>(.*?)</p>").expect("valid paragraph regex"));
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Script {
+    Latin,
+    Cyrillic,
+    Hangul,
+    Han,
+    /// Han with kana.
+    Japanese,
+}
+
+impl Script {
+    fn of_language(language: &str) -> Script {
+        let primary = language.split(['-', '_']).next().unwrap_or_default();
+        match primary.to_ascii_lowercase().as_str() {
+            "zh" | "yue" | "wuu" => Script::Han,
+            "ja" => Script::Japanese,
+            "ko" => Script::Hangul,
+            "ru" | "uk" | "be" | "bg" | "mk" | "sr" | "kk" | "mn" => Script::Cyrillic,
+            _ => Script::Latin,
+        }
+    }
+
+    fn language(self) -> &'static str {
+        match self {
+            Script::Latin => "en",
+            Script::Cyrillic => "ru",
+            Script::Hangul => "ko",
+            Script::Han => "zh",
+            Script::Japanese => "ja",
+        }
+    }
+}
+
+/// `body`, chapter markup, with the paragraphs in another script than
+/// `language`'s tagged with the language of theirs; with `epub2`, as
+/// `xml:lang` only, which is all XHTML 1.1 has.
+pub fn tag_paragraphs<'a>(body: &'a str, language: &str, epub2: bool) -> Cow<'a, str> {
+    let book = Script::of_language(language);
+    let foreign = |text: &str| script(text).filter(|&s| !same(s, book));
+
+    let tagged = PARAGRAPH.replace_all(body, |p: &Captures| match foreign(&p[2]) {
+        Some(s) if !p[1].contains("lang=") => {
+            format!(
+                "<p{}{}>{}</p>",
+                &p[1],
+                attributes(s.language(), epub2),
+                &p[2]
+            )
+        }
+        _ => p[0].to_string(),
+    });
+
+    let lines: Vec<&str> = tagged.split(BREAK).collect();
+    let mut out = String::with_capacity(tagged.len());
+    let mut depth = 0usize;
+    let mut changed = matches!(tagged, Cow::Owned(_));
+    for (n, line) in lines.iter().enumerate() {
+        if n > 0 {
+            out.push_str(BREAK);
+        }
+        let before = depth;
+        depth = nesting_after(depth, line);
+        let text = line.trim();
+        match foreign(text) {
+            Some(s) if before == 0 && depth == 0 && !text.contains("<p") => {
+                let start = line.len() - line.trim_start().len();
+                let end = start + text.len();
+                out.push_str(&line[..start]);
+                out.push_str(&format!(
+                    "<span{}>{text}</span>",
+                    attributes(s.language(), epub2)
+                ));
+                out.push_str(&line[end..]);
+                changed = true;
+            }
+            _ => out.push_str(line),
+        }
+    }
+    if changed {
+        Cow::Owned(out)
+    } else {
+        Cow::Borrowed(body)
+    }
+}
+
+/// The attributes that say an element is in `lang`, with a leading space.
+pub fn attributes(lang: &str, epub2: bool) -> String {
+    if epub2 {
+        format!(r#" xml:lang="{lang}""#)
+    } else {
+        format!(r#" lang="{lang}" xml:lang="{lang}""#)
+    }
+}
+
+/// Whether text in `found` reads as the book's `book` script: Han in a
+/// Japanese book is Japanese.
+fn same(found: Script, book: Script) -> bool {
+    found == book || (found == Script::Han && book == Script::Japanese)
+}
+
+/// The script most letters of `markup` outside its tags and entities are
+/// in, if there are enough of them.
+fn script(markup: &str) -> Option<Script> {
+    let mut counts = [0usize; 5];
+    let (mut tag, mut entity) = (false, false);
+    let mut kana = 0;
+    for c in markup.chars() {
+        match c {
+            '<' => tag = true,
+            '>' if tag => tag = false,
+            '&' if !tag => entity = true,
+            ';' if entity => entity = false,
+            _ if tag || entity => {}
+            'A'..='Z' | 'a'..='z' | '\u{c0}'..='\u{24f}' if c.is_alphabetic() => counts[0] += 1,
+            '\u{400}'..='\u{4ff}' => counts[1] += 1,
+            '\u{1100}'..='\u{11ff}' | '\u{3130}'..='\u{318f}' | '\u{ac00}'..='\u{d7af}' => {
+                counts[2] += 1
+            }
+            '\u{3400}'..='\u{4dbf}' | '\u{4e00}'..='\u{9fff}' | '\u{f900}'..='\u{faff}' => {
+                counts[3] += 1
+            }
+            '\u{3041}'..='\u{309f}' | '\u{30a0}'..='\u{30ff}' | '\u{31f0}'..='\u{31ff}' => {
+                // Kana makes Han Japanese.
+                counts[3] += 1;
+                kana += 1;
+            }
+            _ => {}
+        }
+    }
+    let letters: usize = counts.iter().sum();
+    let (most, &count) = counts.iter().enumerate().max_by_key(|&(_, n)| *n)?;
+    if letters < MIN_LETTERS || count * 10 < letters * MIN_SHARE {
+        return None;
+    }
+    Some(match most {
+        0 => Script::Latin,
+        1 => Script::Cyrillic,
+        2 => Script::Hangul,
+        _ if kana * 10 >= count => Script::Japanese,
+        _ => Script::Han,
+    })
+}
@@ -0,0 +1,181 @@
+use std::{io::Write, str::FromStr};
+
+use anyhow::{Context, Result};
+use http::Uri;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{ChapterLink, fetch::BookInfo, metadata};
+
+/// How `list` prints a book's chapters.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ListFormat {
+    /// `#`-prefixed metadata lines, then index, title and URL per line.
+    #[default]
+    Tsv,
+    Json,
+}
+
+impl FromStr for ListFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tsv" => Ok(ListFormat::Tsv),
+            "json" => Ok(ListFormat::Json),
+            _ => anyhow::bail!("Invalid --format: {s} (expected tsv or json)"),
+        }
+    }
+}
+
+/// A book's index as `list` prints it and `--chapters-file` reads it back.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChapterListing {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub source: String,
+    pub chapters: Vec<ListedChapter>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListedChapter {
+    /// Position on the index page, from 1.
+    pub index: usize,
+    pub title: String,
+    pub url: String,
+}
+
+impl ChapterListing {
+    pub fn new(source: &Uri, info: &BookInfo) -> Self {
+        ChapterListing {
+            title: info.title.trim().to_string(),
+            authors: metadata::split_authors(&info.authors),
+            source: source.to_string(),
+            chapters: info
+                .links
+                .iter()
+                .enumerate()
+                .map(|(i, link)| ListedChapter {
+                    index: i + 1,
+                    title: link.title.clone(),
+                    url: link.uri.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn write(&self, format: ListFormat, out: &mut impl Write) -> Result<()> {
+        match format {
+            ListFormat::Json => {
+                serde_json::to_writer_pretty(&mut *out, self)?;
+                writeln!(out)?;
+            }
+            ListFormat::Tsv => {
+                writeln!(out, "# title\t{}", tsv_field(&self.title))?;
+                writeln!(out, "# authors\t{}", tsv_field(&self.authors.join(", ")))?;
+                writeln!(out, "# source\t{}", self.source)?;
+                for chapter in &self.chapters {
+                    writeln!(
+                        out,
+                        "{}\t{}\t{}",
+                        chapter.index,
+                        tsv_field(&chapter.title),
+                        chapter.url
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tabs and line breaks would split the record, so they become spaces.
+fn tsv_field(s: &str) -> String {
+    s.replace(['\t', '\n', '\r'], " ")
+}
+
+/// The chapter URLs picked in a `--chapters-file`, in the file's order: a
+/// `list` output in either format, cut down by hand or by other tools. TSV
+/// lines need only keep their URL column; blank and `#` lines are skipped.
+pub fn read_selection(text: &str) -> Result<Vec<Uri>> {
+    let urls: Vec<String> = if text.trim_start().starts_with('{') {
+        let listing: ChapterListing =
+            serde_json::from_str(text).context("Not a chapter list in JSON")?;
+        listing.chapters.into_iter().map(|c| c.url).collect()
+    } else {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.rsplit('\t').next().unwrap_or(line).trim().to_string())
+            .collect()
+    };
+    urls.iter()
+        .enumerate()
+        .map(|(i, url)| {
+            url.parse()
+                .with_context(|| format!("Entry {}: invalid chapter URL {url:?}", i + 1))
+        })
+        .collect()
+}
+
+/// `--include-title` and `--exclude-title` patterns, matched against the
+/// chapter titles on the index page.
+///
+/// A title matching an include pattern is kept even if an exclude pattern
+/// matches it too. Otherwise a title matching an exclude pattern is dropped,
+/// and once any include pattern is given, so is a title matching none.
+#[derive(Debug, Default)]
+pub struct TitleFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl TitleFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        let compile = |flag: &str, patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|p| Regex::new(p).with_context(|| format!("Invalid --{flag}: {p}")))
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(TitleFilter {
+            include: compile("include-title", include)?,
+            exclude: compile("exclude-title", exclude)?,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.include.is_empty() || !self.exclude.is_empty()
+    }
+
+    pub fn keeps(&self, title: &str) -> bool {
+        if self.include.iter().any(|r| r.is_match(title)) {
+            return true;
+        }
+        self.include.is_empty() && !self.exclude.iter().any(|r| r.is_match(title))
+    }
+}
+
+/// Asks which of a book's chapters to build, given its title and index in
+/// order, and answers with the positions of those picked, in order.
+pub type Picker = fn(&str, &[ChapterLink]) -> Result<Vec<usize>>;
+
+/// Restricts `links` to the `selected` URLs, in their order; a selected
+/// URL the index doesn't list is an error, as the file is likely stale.
+pub fn apply(links: Vec<ChapterLink>, selected: &[Uri]) -> Result<Vec<ChapterLink>> {
+    let mut links: Vec<Option<ChapterLink>> = links.into_iter().map(Some).collect();
+    let mut picked = Vec::with_capacity(selected.len());
+    for uri in selected {
+        let Some(i) = links
+            .iter()
+            .position(|l| l.as_ref().is_some_and(|l| &l.uri == uri))
+        else {
+            if picked.iter().any(|l: &ChapterLink| &l.uri == uri) {
+                continue;
+            }
+            anyhow::bail!("{uri} from --chapters-file is not on the index page");
+        };
+        picked.push(links[i].take().expect("just found"));
+    }
+    Ok(picked)
+}
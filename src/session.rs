@@ -0,0 +1,160 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Error, checkpoint,
+    fetcher::{self, Delays, Fetcher, Raw, Response},
+};
+
+/// Bumped whenever the recording format changes.
+pub const VERSION: u32 = 1;
+
+/// Every HTTP exchange of a run, in order, as written by `--record`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Session {
+    version: u32,
+    pub exchanges: Vec<Exchange>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Exchange {
+    pub url: String,
+    /// The headers set on the request besides the agent's defaults.
+    pub request_headers: Vec<(String, String)>,
+    /// Absent when the request failed without a response.
+    pub status: Option<u16>,
+    pub headers: Vec<(String, String)>,
+    /// The raw bytes as received, base64 in the file.
+    #[serde(with = "base64_body")]
+    pub body: Vec<u8>,
+    /// Why the request failed, when it got no response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Session {
+    pub fn new(exchanges: Vec<Exchange>) -> Self {
+        Session {
+            version: VERSION,
+            exchanges,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let session: Session = serde_json::from_slice(&json)
+            .with_context(|| format!("{} is not a recorded session", path.display()))?;
+        if session.version != VERSION {
+            anyhow::bail!(
+                "{} was recorded by format version {}, expected {VERSION}",
+                path.display(),
+                session.version
+            );
+        }
+        Ok(session)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        checkpoint::write_atomic(path, &serde_json::to_vec_pretty(self)?)
+    }
+}
+
+/// Serves a recorded session instead of the network. Each URL's responses
+/// are played back in the order they were recorded, so retries replay as
+/// they happened; a URL the recording doesn't have, or has run out of, is
+/// an error.
+pub struct Replay {
+    exchanges: Mutex<HashMap<String, VecDeque<Exchange>>>,
+    misses: Mutex<Vec<String>>,
+}
+
+impl Replay {
+    pub fn new(session: Session) -> Self {
+        let mut exchanges: HashMap<String, VecDeque<Exchange>> = HashMap::new();
+        for exchange in session.exchanges {
+            exchanges
+                .entry(exchange.url.clone())
+                .or_default()
+                .push_back(exchange);
+        }
+        Replay {
+            exchanges: Mutex::new(exchanges),
+            misses: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The URLs requested that the recording couldn't answer, in order.
+    pub fn misses(&self) -> Vec<String> {
+        self.misses.lock().unwrap().clone()
+    }
+
+    fn next(&self, url: &str) -> crate::Result<Raw> {
+        let fetch_error = |reason: String| Error::Fetch {
+            url: url.to_string(),
+            status: None,
+            reason,
+        };
+        let exchange = self
+            .exchanges
+            .lock()
+            .unwrap()
+            .get_mut(url)
+            .and_then(VecDeque::pop_front);
+        let Some(exchange) = exchange else {
+            log::error!("{url} is not in the recording");
+            self.misses.lock().unwrap().push(url.to_string());
+            return Err(fetch_error("not in the recording".to_string()));
+        };
+        match (exchange.status, exchange.error) {
+            (Some(status), _) => {
+                let header = |wanted: &str| {
+                    exchange
+                        .headers
+                        .iter()
+                        .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+                        .map(|(_, value)| value.clone())
+                };
+                let encoding = header("content-encoding");
+                Ok(Raw {
+                    status,
+                    content_type: header("content-type"),
+                    wire_bytes: exchange.body.len(),
+                    body: fetcher::decode(url, encoding.as_deref(), exchange.body)?,
+                })
+            }
+            (None, error) => Err(fetch_error(error.unwrap_or_default())),
+        }
+    }
+}
+
+impl Fetcher for Replay {
+    fn get(&self, url: &str) -> crate::Result<Response> {
+        let delays = Delays {
+            after_request: Duration::ZERO,
+            backoff: Duration::ZERO,
+        };
+        fetcher::retrying(url, delays, None, || self.next(url), || {})
+    }
+}
+
+mod base64_body {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(body: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(body))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        STANDARD.decode(text).map_err(serde::de::Error::custom)
+    }
+}
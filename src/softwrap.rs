@@ -0,0 +1,110 @@
+//! `--merge-softwrap`: joins prose a site hard-wrapped at a fixed width,
+//! a line break every 40 characters or so, back into paragraphs.
+//!
+//! A chapter counts as hard-wrapped when no line is longer than
+//! [`MAX_WRAP`] characters and at least half of its lines come close to
+//! the longest. A line that comes close is joined to the next unless it
+//! ends a sentence, or the next line opens a paragraph of its own: with an
+//! ideographic or non-breaking space indent, a dialogue dash or an opening
+//! quote. Chapters with paragraphs of their own are left as they are.
+
+use std::borrow::Cow;
+
+use crate::headings::nesting_after;
+
+const BREAK: &str = "<br />";
+/// Sites wrap well short of a paragraph; a line any longer means the
+/// chapter has paragraphs of its own.
+const MAX_WRAP: usize = 120;
+
+/// What may end a sentence, closing quotes and brackets included.
+const CJK_FINAL: &[char] = &[
+    '。', '！', '？', '…', '‥', '」', '』', '”', '’', '）', '》', '!', '?', '.', '~', '～', '"',
+];
+const LATIN_FINAL: &[char] = &['.', '!', '?', ':', ';', '…', '"', '”', '’', ')'];
+/// What may open a paragraph of its own.
+const OPENERS: &[char] = &[
+    '\u{3000}', '\u{a0}', '—', '―', '–', '-', '「', '『', '“', '"', '‘',
+];
+
+/// Joins the hard-wrapped lines of `body`, chapter markup as the providers
+/// write it, for a book in `language`.
+pub fn merge<'a>(body: &'a str, language: &str) -> Cow<'a, str> {
+    let lines: Vec<&str> = body.split(BREAK).collect();
+    let Some(width) = wrap_width(&lines) else {
+        return Cow::Borrowed(body);
+    };
+    let primary = language.split(['-', '_']).next().unwrap_or_default();
+    let cjk = matches!(primary.to_ascii_lowercase().as_str(), "zh" | "ja");
+    let (finals, joiner) = if cjk {
+        (CJK_FINAL, "")
+    } else {
+        (LATIN_FINAL, " ")
+    };
+
+    let mut out = String::with_capacity(body.len());
+    let mut depth = 0usize;
+    // Whether the line goes on from the one before, so its leading
+    // whitespace is the source's formatting rather than an indent.
+    let mut continued = false;
+    for (n, line) in lines.iter().enumerate() {
+        depth = nesting_after(depth, line);
+        let line = if continued {
+            line.trim_ascii_start()
+        } else {
+            line
+        };
+        let Some(next) = lines.get(n + 1) else {
+            out.push_str(line);
+            break;
+        };
+        let text = line.trim_ascii();
+        continued = depth == 0
+            && length(text) * 4 >= width * 3
+            && !text.ends_with(finals)
+            && !next.trim_ascii().is_empty()
+            && !next.trim_ascii_start().starts_with(OPENERS);
+        if continued {
+            out.push_str(line.trim_ascii_end());
+            out.push_str(joiner);
+        } else {
+            out.push_str(line);
+            out.push_str(BREAK);
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// The width a hard-wrapped chapter is wrapped at: its longest line, if no
+/// line is longer than [`MAX_WRAP`] and at least half of them come close.
+fn wrap_width(lines: &[&str]) -> Option<usize> {
+    let lengths: Vec<usize> = lines
+        .iter()
+        .map(|line| length(line.trim_ascii()))
+        .filter(|&n| n > 0)
+        .collect();
+    let width = lengths.iter().copied().max()?;
+    if lengths.len() < 4 || width > MAX_WRAP {
+        return None;
+    }
+    let close = lengths.iter().filter(|&&n| n * 4 >= width * 3).count();
+    (close * 2 >= lengths.len()).then_some(width)
+}
+
+/// The characters of `line` outside its tags.
+fn length(line: &str) -> usize {
+    let mut inside = false;
+    line.chars()
+        .filter(|&c| match c {
+            '<' => {
+                inside = true;
+                false
+            }
+            '>' => {
+                inside = false;
+                false
+            }
+            _ => !inside,
+        })
+        .count()
+}
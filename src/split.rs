@@ -0,0 +1,139 @@
+use std::borrow::Cow;
+
+/// Some readers (ADE, older Kobos) struggle with content documents over
+/// about 300 KB.
+pub const DEFAULT_MAX_CHAPTER_SIZE: usize = 300 * 1024;
+
+/// Elements whose end tag finishes a paragraph-level block.
+const BLOCKS: &[&str] = &["aside", "blockquote", "div", "h2", "p", "pre", "table"];
+
+/// Splits chapter body markup into parts of at most `limit` bytes.
+///
+/// Cuts only happen between top-level paragraphs: after a `<br />` or the
+/// end tag of a block, outside any open element, so every part stays
+/// well-formed. A single paragraph longer than `limit` becomes its own,
+/// oversized part.
+pub fn split(body: &str, limit: usize) -> Vec<&str> {
+    if body.len() <= limit {
+        return vec![body];
+    }
+
+    let mut parts = Vec::new();
+    let mut part_start = 0;
+    let mut last_boundary = None;
+    let mut depth = 0usize;
+    let mut pos = 0;
+
+    while let Some(offset) = body[pos..].find('<') {
+        let start = pos + offset;
+        let Some(len) = body[start..].find('>') else {
+            break;
+        };
+        let end = start + len + 1;
+        let tag = &body[start..end];
+        pos = end;
+
+        if tag.ends_with("/>") {
+            // Void elements only end a paragraph when they are a line break.
+            if depth > 0 || !tag.starts_with("<br") {
+                continue;
+            }
+        } else if let Some(name) = tag.strip_prefix("</") {
+            depth = depth.saturating_sub(1);
+            if depth > 0 || !BLOCKS.contains(&name.trim_end_matches('>').trim()) {
+                continue;
+            }
+        } else {
+            depth += 1;
+            continue;
+        }
+
+        if end - part_start > limit
+            && let Some(boundary) = last_boundary.filter(|b| *b > part_start)
+        {
+            parts.push(&body[part_start..boundary]);
+            part_start = boundary;
+        }
+        last_boundary = Some(end);
+        if end - part_start > limit {
+            parts.push(&body[part_start..end]);
+            part_start = end;
+        }
+    }
+
+    if body.len() - part_start > limit
+        && let Some(boundary) = last_boundary.filter(|b| *b > part_start && *b < body.len())
+    {
+        parts.push(&body[part_start..boundary]);
+        part_start = boundary;
+    }
+    if part_start < body.len() {
+        let rest = &body[part_start..];
+        match parts.last_mut() {
+            // Don't leave a trailing part of nothing but whitespace.
+            Some(last) if rest.trim().is_empty() => {
+                let last_start = part_start - last.len();
+                *last = &body[last_start..];
+            }
+            _ => parts.push(rest),
+        }
+    }
+    parts
+}
+
+/// File names for the parts of chapter `index`: `3.xhtml` when it isn't
+/// split, otherwise `3-a.xhtml`, `3-b.xhtml`, ...
+pub fn part_names(index: usize, parts: usize) -> Vec<String> {
+    if parts <= 1 {
+        return vec![format!("{index}.xhtml")];
+    }
+    (0..parts)
+        .map(|p| format!("{index}-{}.xhtml", suffix(p)))
+        .collect()
+}
+
+/// a, b, ..., z, aa, ab, ...
+fn suffix(mut n: usize) -> String {
+    let mut out = Vec::new();
+    loop {
+        out.push(b'a' + (n % 26) as u8);
+        if n < 26 {
+            break;
+        }
+        n = n / 26 - 1;
+    }
+    out.reverse();
+    String::from_utf8(out).expect("ascii letters")
+}
+
+/// Rewrites in-page `href="#id"` links whose target ended up in another part
+/// (footnotes, usually) to point at that part's file.
+pub fn relink<'a>(parts: &[&'a str], names: &[String]) -> Vec<Cow<'a, str>> {
+    let owner = |id: &str| {
+        let needle = format!(r#"id="{id}""#);
+        parts.iter().position(|p| p.contains(&needle))
+    };
+
+    parts
+        .iter()
+        .enumerate()
+        .map(|(i, part)| {
+            if !part.contains(r##"href="#"##) {
+                return Cow::Borrowed(*part);
+            }
+            let mut out = String::with_capacity(part.len());
+            let mut rest = *part;
+            while let Some(start) = rest.find(r##"href="#"##) {
+                let value = start + r#"href=""#.len();
+                out.push_str(&rest[..value]);
+                rest = &rest[value..];
+                let id = rest[1..].split('"').next().unwrap_or_default();
+                if let Some(target) = owner(id).filter(|t| *t != i) {
+                    out.push_str(&names[target]);
+                }
+            }
+            out.push_str(rest);
+            Cow::Owned(out)
+        })
+        .collect()
+}
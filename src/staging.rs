@@ -0,0 +1,142 @@
+//! Where an epub build stages its intermediate files: the pages
+//! epub-builder's zip command zips up, and the archive it makes before
+//! [`package`](crate::package) rewrites it into the book. Both are removed
+//! once the book is written, or the build has failed.
+//!
+//! They go to the system's temp directory, often a small tmpfs, unless
+//! `--temp-dir` picks another. A rebuild checks there is room for about as
+//! much as the last build of the book staged before starting on it.
+//!
+//! Without a zip command, on Windows or wherever `--zip-command` can't be
+//! run, epub-builder's zip library is used instead. It holds the pages in
+//! memory rather than staging them.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use epub_builder::{ZipCommand, ZipCommandOrLibrary, ZipLibrary};
+use zip::ZipArchive;
+
+use crate::{BuildOptions, package, workdir};
+
+/// Whether each zip command tried so far could be run.
+static RUNS: Mutex<Option<HashMap<String, bool>>> = Mutex::new(None);
+
+/// The zip command epubs are built with unless told otherwise; none on
+/// Windows, which doesn't come with one.
+pub fn default_command() -> Option<String> {
+    (!cfg!(windows)).then(|| "zip".to_string())
+}
+
+/// What epubs are zipped up with: `options.zip_command` if it can be run,
+/// staging in [`dir`], the zip library otherwise.
+pub fn zip(options: &BuildOptions) -> epub_builder::Result<ZipCommandOrLibrary> {
+    match command(options) {
+        Some(command) => {
+            let mut zip = ZipCommand::new_in(dir(options))?;
+            zip.command(command);
+            Ok(ZipCommandOrLibrary::Command(zip))
+        }
+        None => Ok(ZipCommandOrLibrary::Library(ZipLibrary::new()?)),
+    }
+}
+
+/// The level [`zip`] deflates entries at, if it is known.
+pub fn deflated_at(options: &BuildOptions) -> Option<u8> {
+    command(options).map(|_| package::DEFAULT_LEVEL)
+}
+
+/// `options.zip_command`, if it can be run; tried once per command.
+fn command(options: &BuildOptions) -> Option<&str> {
+    let command = options.zip_command.as_deref()?;
+    let mut runs = RUNS.lock().unwrap_or_else(|e| e.into_inner());
+    let usable = *runs
+        .get_or_insert_with(HashMap::new)
+        .entry(command.to_string())
+        .or_insert_with(|| {
+            let tried = ZipCommand::new_in(dir(options)).and_then(|mut zip| {
+                zip.command(command);
+                zip.test()
+            });
+            if let Err(e) = &tried {
+                log::info!("{command} can't be run ({e}), using the built-in zip library");
+            }
+            tried.is_ok()
+        });
+    usable.then_some(command)
+}
+
+/// The directory intermediate files are staged in.
+pub fn dir(options: &BuildOptions) -> PathBuf {
+    options.temp_dir.clone().unwrap_or_else(std::env::temp_dir)
+}
+
+/// Where the archive of the epub going to `path` is staged, named after
+/// the process and the path so builds running at once keep apart.
+pub fn archive(options: &BuildOptions, path: &Path) -> PathBuf {
+    dir(options).join(format!(
+        "epub-dude-{}-{:016x}.unpackaged",
+        process::id(),
+        workdir::fnv1a(path.as_os_str().as_encoded_bytes())
+    ))
+}
+
+/// Fails unless files can be written to `dir` and, when the epub at
+/// `previous` was built before, it has room for as much as that build
+/// staged.
+pub fn check(dir: &Path, previous: &Path) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let probe = dir.join(format!("epub-dude-{}.probe", process::id()));
+    fs::write(&probe, b"")
+        .with_context(|| format!("Can't write to the temp directory {}", dir.display()))?;
+    let _ = fs::remove_file(&probe);
+
+    if let (Some(needed), Some(free)) = (staged(previous), free(dir)) {
+        log::debug!(
+            "staging about {} KiB in {}, {} KiB free",
+            needed / 1024,
+            dir.display(),
+            free / 1024
+        );
+        if free < needed {
+            anyhow::bail!(
+                "{} has {} MiB free, but the book stages about {} MiB; pick another directory with --temp-dir",
+                dir.display(),
+                free / (1024 * 1024),
+                needed.div_ceil(1024 * 1024)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// What building the epub at `path` stages: every entry uncompressed, and
+/// the archive itself.
+fn staged(path: &Path) -> Option<u64> {
+    let file = fs::File::open(path).ok()?;
+    let archived = file.metadata().ok()?.len();
+    let mut zip = ZipArchive::new(file).ok()?;
+    let mut entries = 0;
+    for i in 0..zip.len() {
+        entries += zip.by_index_raw(i).ok()?.size();
+    }
+    Some(entries + archived)
+}
+
+/// The bytes free for this user on the file system holding `dir`.
+#[cfg(unix)]
+fn free(dir: &Path) -> Option<u64> {
+    let stats = rustix::fs::statvfs(dir).ok()?;
+    Some(stats.f_bavail.saturating_mul(stats.f_frsize))
+}
+
+#[cfg(not(unix))]
+fn free(_dir: &Path) -> Option<u64> {
+    None
+}
@@ -0,0 +1,121 @@
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::checkpoint;
+
+/// Bumped whenever the stored format changes; state written with another
+/// version is discarded.
+pub const VERSION: u32 = 1;
+const FILE: &str = "state.json";
+
+/// What a book's earlier runs learned that later ones should know, kept in
+/// its work directory.
+#[derive(Serialize, Deserialize)]
+pub struct BookState {
+    version: u32,
+    /// Chapters the site answered 404 or 410 for.
+    pub missing: Vec<Missing>,
+    /// Chapters whose text matched `--locked-pattern`, to be filled in once
+    /// the site unlocks them.
+    #[serde(default)]
+    pub locked: Vec<Locked>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Missing {
+    pub url: String,
+    pub status: u16,
+    /// When the chapter was first found missing, as "2024-05-01".
+    pub since: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Locked {
+    pub url: String,
+    /// When the chapter was first found locked, as "2024-05-01".
+    pub since: String,
+}
+
+impl Default for BookState {
+    fn default() -> Self {
+        BookState {
+            version: VERSION,
+            missing: Vec::new(),
+            locked: Vec::new(),
+        }
+    }
+}
+
+impl BookState {
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(FILE);
+        let Ok(json) = fs::read_to_string(&path) else {
+            return BookState::default();
+        };
+        match serde_json::from_str::<BookState>(&json) {
+            Ok(state) if state.version == VERSION => state,
+            Ok(state) => {
+                log::warn!(
+                    "discarding {}: written by format version {}, expected {VERSION}",
+                    path.display(),
+                    state.version
+                );
+                BookState::default()
+            }
+            Err(e) => {
+                log::warn!("discarding {}: {e}", path.display());
+                BookState::default()
+            }
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        checkpoint::write_atomic(&dir.join(FILE), &serde_json::to_vec_pretty(self)?)
+    }
+
+    pub fn missing(&self, url: &str) -> Option<&Missing> {
+        self.missing.iter().find(|m| m.url == url)
+    }
+
+    /// Records `url` as missing, keeping the date it was first seen.
+    pub fn set_missing(&mut self, missing: Missing) {
+        if self.missing(&missing.url).is_none() {
+            self.missing.push(missing);
+        }
+    }
+
+    /// Forgets a chapter that turned out to exist after all. Returns whether
+    /// it was recorded.
+    pub fn found(&mut self, url: &str) -> bool {
+        let before = self.missing.len();
+        self.missing.retain(|m| m.url != url);
+        self.missing.len() != before
+    }
+
+    pub fn locked(&self, url: &str) -> Option<&Locked> {
+        self.locked.iter().find(|l| l.url == url)
+    }
+
+    /// Records `url` as locked, keeping the date it was first seen. Returns
+    /// whether it is newly recorded.
+    pub fn set_locked(&mut self, url: &str, since: String) -> bool {
+        if self.locked(url).is_some() {
+            return false;
+        }
+        self.locked.push(Locked {
+            url: url.to_string(),
+            since,
+        });
+        true
+    }
+
+    /// Forgets a chapter that is no longer locked. Returns whether it was
+    /// recorded.
+    pub fn unlocked(&mut self, url: &str) -> bool {
+        let before = self.locked.len();
+        self.locked.retain(|l| l.url != url);
+        self.locked.len() != before
+    }
+}
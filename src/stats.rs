@@ -0,0 +1,118 @@
+use epub_builder::{EpubBuilder, MetadataOpfV3, ZipCommandOrLibrary};
+use serde::Serialize;
+
+use std::borrow::Cow;
+
+use crate::{
+    fetch::{NOTE_MARKER_END, NOTE_MARKER_START},
+    plain,
+};
+
+/// What book length is measured in. Chinese and Japanese aren't written
+/// with spaces between words, so they count characters instead.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    #[default]
+    Characters,
+    Words,
+}
+
+impl Unit {
+    pub fn for_language(language: &str) -> Self {
+        let primary = language.split(['-', '_']).next().unwrap_or_default();
+        match primary.to_ascii_lowercase().as_str() {
+            "zh" | "ja" => Unit::Characters,
+            _ => Unit::Words,
+        }
+    }
+
+    /// Typical adult reading speed, per minute.
+    pub fn default_rate(self) -> usize {
+        match self {
+            Unit::Characters => 400,
+            Unit::Words => 250,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Unit::Characters => "characters",
+            Unit::Words => "words",
+        }
+    }
+}
+
+/// Measures generated chapter markup: tags, whitespace and placeholder
+/// markers don't count.
+pub fn count(markup: &str, unit: Unit) -> usize {
+    let lines = plain::to_lines(&without_note_markers(markup));
+    match unit {
+        Unit::Characters => lines
+            .iter()
+            .flat_map(|l| l.chars())
+            .filter(|c| !c.is_whitespace() && !('\u{E000}'..='\u{F8FF}').contains(c))
+            .count(),
+        Unit::Words => lines.iter().map(|l| l.split_whitespace().count()).sum(),
+    }
+}
+
+/// `markup` without footnote markers, the note numbers between them
+/// included.
+fn without_note_markers(markup: &str) -> Cow<'_, str> {
+    if !markup.contains(NOTE_MARKER_START) {
+        return Cow::Borrowed(markup);
+    }
+    let mut out = String::with_capacity(markup.len());
+    let mut rest = markup;
+    while let Some(start) = rest.find(NOTE_MARKER_START) {
+        out.push_str(&rest[..start]);
+        let tail = &rest[start + NOTE_MARKER_START.len_utf8()..];
+        rest = match tail.find(NOTE_MARKER_END) {
+            Some(end) => &tail[end + NOTE_MARKER_END.len_utf8()..],
+            None => tail,
+        };
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Length {
+    pub unit: Unit,
+    pub total: usize,
+    pub per_minute: usize,
+    pub reading_minutes: usize,
+}
+
+impl Length {
+    pub fn new(unit: Unit, total: usize, per_minute: usize) -> Self {
+        Length {
+            unit,
+            total,
+            per_minute,
+            reading_minutes: total.div_ceil(per_minute.max(1)),
+        }
+    }
+
+    /// "3h 20m" or "45m".
+    pub fn reading_time(&self) -> String {
+        match (self.reading_minutes / 60, self.reading_minutes % 60) {
+            (0, m) => format!("{m}m"),
+            (h, m) => format!("{h}h {m}m"),
+        }
+    }
+
+    pub fn add_to(&self, book: &mut EpubBuilder<ZipCommandOrLibrary>) {
+        let meta = [
+            (format!("epub-dude:{}", self.unit.as_str()), self.total),
+            (
+                "epub-dude:reading-minutes".to_string(),
+                self.reading_minutes,
+            ),
+        ];
+        for (property, content) in meta {
+            book.add_metadata_opf(Box::new(MetadataOpfV3::new(property, content.to_string())));
+        }
+    }
+}
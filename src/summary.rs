@@ -0,0 +1,218 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Local, NaiveDate};
+
+use crate::{exit_code, ladder::ResultCounts, stats::Length, timing::Timings};
+
+/// Collects what happened during one book build so it can be reported once
+/// the progress bar is done, and rendered into the colophon.
+#[derive(Default, Debug)]
+pub struct Summary {
+    pub source: String,
+    /// The book's title and authors as written into it.
+    pub title: String,
+    pub authors: Vec<String>,
+    /// Every file written: the book, or each of its parts.
+    pub files: Vec<PathBuf>,
+    /// Their size, in bytes.
+    pub written: u64,
+    pub chapters: usize,
+    pub first_fetch: Option<DateTime<Local>>,
+    pub last_fetch: Option<DateTime<Local>>,
+    /// The earliest and latest chapter dates the index gave.
+    pub published: Option<(NaiveDate, NaiveDate)>,
+    /// Titles of chapters whose content could not be fetched.
+    pub placeholders: Vec<String>,
+    /// Chapters that were locked on earlier runs and were filled in on this
+    /// one.
+    pub filled: usize,
+    /// Total size of embedded images as downloaded and as stored.
+    pub image_bytes_before: usize,
+    pub image_bytes_after: usize,
+    /// Book length in characters or words, see [`Length`].
+    pub length: usize,
+    /// Set once every chapter is in.
+    pub estimate: Option<Length>,
+    /// Chapters left out because the site answered 404 or 410.
+    pub missing: Vec<String>,
+    /// Chapters left out because their pages couldn't be parsed.
+    pub failed: Vec<String>,
+    /// How the chapters downloaded on this run came through, see
+    /// [`crate::ladder`].
+    pub results: ResultCounts,
+    /// Titles of chapters skipped by `--exclude-title`, `--include-title` or
+    /// `--since`.
+    pub excluded: Vec<String>,
+    /// Chapters whose text changed since the last `--check-revisions` run.
+    pub revised: Vec<String>,
+    /// The files of a book split with `--split-every`, in order.
+    pub parts: Vec<PathBuf>,
+    /// Response bytes of every request, pages and images alike, once
+    /// decompressed.
+    pub downloaded: usize,
+    /// The same responses' bytes as transferred.
+    pub transferred: usize,
+    /// Where the build's time went, see [`crate::timing`].
+    pub timings: Timings,
+    pub warnings: Vec<String>,
+}
+
+impl Summary {
+    pub fn new(source: impl Into<String>) -> Self {
+        Summary {
+            source: source.into(),
+            ..Default::default()
+        }
+    }
+
+    /// [`exit_code::SKIPPED`] when chapters had to be left out, otherwise
+    /// [`exit_code::SUCCESS`].
+    pub fn exit_code(&self) -> i32 {
+        if self.missing.is_empty() && self.failed.is_empty() && self.placeholders.is_empty() {
+            exit_code::SUCCESS
+        } else {
+            exit_code::SKIPPED
+        }
+    }
+
+    pub fn warn(&mut self, msg: impl Into<String>) {
+        self.warnings.push(msg.into());
+    }
+
+    pub fn chapter_fetched(&mut self) {
+        let now = Local::now();
+        self.first_fetch.get_or_insert(now);
+        self.last_fetch = Some(now);
+        self.chapters += 1;
+    }
+
+    /// The fetch dates as "2024-05-01" or "2024-05-01 – 2024-05-03".
+    pub fn chapter_dated(&mut self, date: NaiveDate) {
+        self.published = Some(match self.published {
+            Some((first, last)) => (first.min(date), last.max(date)),
+            None => (date, date),
+        });
+    }
+
+    /// When the chapters were published, by the dates on the index.
+    pub fn published_range(&self) -> Option<String> {
+        let (first, last) = self.published?;
+        if first == last {
+            Some(first.to_string())
+        } else {
+            Some(format!("{first} – {last}"))
+        }
+    }
+
+    pub fn fetch_range(&self) -> Option<String> {
+        let first = self.first_fetch?.format("%Y-%m-%d").to_string();
+        let last = self.last_fetch?.format("%Y-%m-%d").to_string();
+        if first == last {
+            Some(first)
+        } else {
+            Some(format!("{first} – {last}"))
+        }
+    }
+
+    pub fn print(&self) {
+        eprintln!("Fetched {} chapters from {}", self.chapters, self.source);
+        if let Some(length) = &self.estimate {
+            eprintln!(
+                "Length: {} {}, about {} at {}/min",
+                length.total,
+                length.unit.as_str(),
+                length.reading_time(),
+                length.per_minute
+            );
+        }
+        if self.transferred < self.downloaded {
+            eprintln!(
+                "Downloaded: {} KiB ({} KiB transferred)",
+                self.downloaded / 1024,
+                self.transferred / 1024
+            );
+        } else {
+            eprintln!("Downloaded: {} KiB", self.downloaded / 1024);
+        }
+        if self.timings.total() > std::time::Duration::ZERO {
+            eprintln!("Time: {}", self.timings);
+        }
+        if self.written > 0 {
+            eprintln!(
+                "Written: {} KiB, {:.2}s generating and compressing",
+                self.written / 1024,
+                self.timings.generate.as_secs_f64()
+            );
+        }
+        if self.image_bytes_before > 0 {
+            eprintln!(
+                "Images: {} KiB downloaded, {} KiB embedded",
+                self.image_bytes_before / 1024,
+                self.image_bytes_after / 1024
+            );
+        }
+
+        if !self.parts.is_empty() {
+            eprintln!("Split into {} parts:", self.parts.len());
+            for p in &self.parts {
+                eprintln!("  - {}", p.display());
+            }
+        }
+
+        if self.filled > 0 {
+            eprintln!("Filled in {} chapters that were locked before", self.filled);
+        }
+
+        if !self.placeholders.is_empty() {
+            eprintln!("Placeholder chapters:");
+            for p in &self.placeholders {
+                eprintln!("  - {p}");
+            }
+        }
+
+        if !self.missing.is_empty() {
+            eprintln!("Permanently missing chapters (use --retry-permanent to try again):");
+            for m in &self.missing {
+                eprintln!("  - {m}");
+            }
+        }
+
+        if !self.failed.is_empty() {
+            eprintln!("Chapters left out as their pages couldn't be parsed:");
+            for f in &self.failed {
+                eprintln!("  - {f}");
+            }
+        }
+
+        if self.results.troubled() {
+            let r = &self.results;
+            eprintln!(
+                "Downloads: {} fine, {} failed over HTTP, {} without text, {} unparsable",
+                r.fetched_ok, r.http_failed, r.parsed_empty, r.parse_error
+            );
+        }
+
+        if !self.revised.is_empty() {
+            eprintln!("Revised since the last check:");
+            for r in &self.revised {
+                eprintln!("  - {r}");
+            }
+        }
+
+        if !self.excluded.is_empty() {
+            eprintln!("Excluded chapters:");
+            for e in &self.excluded {
+                eprintln!("  - {e}");
+            }
+        }
+
+        if self.warnings.is_empty() {
+            return;
+        }
+
+        eprintln!("Warnings:");
+        for w in &self.warnings {
+            eprintln!("  - {w}");
+        }
+    }
+}
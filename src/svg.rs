@@ -0,0 +1,114 @@
+//! SVG illustrations made safe to embed.
+//!
+//! An SVG is a document, not just a picture: it can run scripts and load
+//! other files when it's opened. [`sanitize`] writes the drawing out again
+//! with only what draws it: elements and attributes in the SVG namespace
+//! and `xlink:href`, without scripts, `foreignObject`, event handlers, or
+//! references to anything outside the image but `data:` images.
+
+use std::sync::LazyLock;
+
+use regex::Regex;
+use roxmltree::{Document, Node, NodeType, ParsingOptions};
+
+use crate::xhtml;
+
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// Elements left out with everything in them.
+const DROPPED: &[&str] = &["script", "foreignObject", "iframe", "handler", "listener"];
+
+/// A `url(...)` pointing anywhere but into the image itself, or a CSS
+/// import.
+static EXTERNAL_URL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)url\(\s*['"]?\s*[^#'"\s)]|@import"#).expect("valid url regex")
+});
+
+/// Whether `bytes` look like an SVG document.
+pub fn is_svg(bytes: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(4096)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    head.starts_with('<') && head.contains("<svg")
+}
+
+/// The SVG in `bytes` with only what draws it, or `None` if it isn't a
+/// well-formed SVG document.
+pub fn sanitize(bytes: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(bytes)
+        .ok()?
+        .trim_start_matches('\u{feff}');
+    let options = ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    };
+    let doc = Document::parse_with_options(text, options).ok()?;
+    let root = doc.root_element();
+    if !root.has_tag_name((SVG_NS, "svg")) {
+        return None;
+    }
+    let mut out = String::with_capacity(text.len());
+    write(&mut out, root, true);
+    Some(out)
+}
+
+fn write(out: &mut String, node: Node, root: bool) {
+    let name = node.tag_name().name();
+    out.push('<');
+    out.push_str(name);
+    if root {
+        out.push_str(&format!(r#" xmlns="{SVG_NS}" xmlns:xlink="{XLINK_NS}""#));
+    }
+    for attribute in node.attributes() {
+        let prefix = match attribute.namespace() {
+            None => "",
+            Some(XLINK_NS) => "xlink:",
+            Some(XML_NS) => "xml:",
+            Some(_) => continue,
+        };
+        let value = attribute.value();
+        let local = attribute.name();
+        if local.to_ascii_lowercase().starts_with("on")
+            || (local == "href" && !internal(value))
+            || EXTERNAL_URL.is_match(value)
+        {
+            continue;
+        }
+        out.push_str(&format!(r#" {prefix}{local}="{}""#, xhtml::escape(value)));
+    }
+    out.push('>');
+    for child in node.children() {
+        match child.node_type() {
+            NodeType::Element
+                if child.tag_name().namespace() == Some(SVG_NS)
+                    && !DROPPED.contains(&child.tag_name().name()) =>
+            {
+                write(out, child, false);
+            }
+            NodeType::Text => {
+                let text = child.text().unwrap_or_default();
+                if name != "style" || !EXTERNAL_URL.is_match(text) {
+                    out.push_str(&xhtml::escape(text));
+                }
+            }
+            _ => {}
+        }
+    }
+    out.push_str(&format!("</{name}>"));
+}
+
+/// Whether a link stays inside the image: a fragment, or an image of its
+/// own as a `data:` URI.
+fn internal(href: &str) -> bool {
+    let href = href.trim();
+    href.starts_with('#')
+        || [
+            "data:image/png",
+            "data:image/jpeg",
+            "data:image/gif",
+            "data:image/webp",
+        ]
+        .iter()
+        .any(|prefix| href.starts_with(prefix))
+}
@@ -0,0 +1,116 @@
+//! `--chapter-template`: chapter pages laid out by the user instead of the
+//! built-in skeleton.
+//!
+//! Templates use Jinja-style syntax, which covers what Tera templates for a
+//! page like this usually need: `{{ title }}`, `{% if next_href %}` ...
+//! `{% endif %}` and the like. The variables are `title`, `body`, `index`
+//! (from 1), `total`, `prev_href`, `next_href` (empty at either end of the
+//! book) and `lang`. Everything but `body` is already escaped for XHTML;
+//! `body` is the chapter's markup, footer included.
+
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use roxmltree::{Document, ParsingOptions};
+
+use crate::xhtml;
+
+const NAME: &str = "chapter";
+const XHTML_NS: &str = "http://www.w3.org/1999/xhtml";
+
+pub struct ChapterTemplate {
+    engine: upon::Engine<'static>,
+    /// A hash of the source, which stands for it in debug output.
+    hash: u64,
+}
+
+impl std::fmt::Debug for ChapterTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ChapterTemplate({:016x})", self.hash)
+    }
+}
+
+/// What one chapter page is rendered from.
+pub struct ChapterFields<'a> {
+    pub title: &'a str,
+    pub body: &'a str,
+    /// From 1.
+    pub index: usize,
+    pub total: usize,
+    pub prev_href: Option<&'a str>,
+    pub next_href: Option<&'a str>,
+    pub lang: &'a str,
+}
+
+impl ChapterTemplate {
+    /// Reads the template at `path` and checks it, see [`ChapterTemplate::new`].
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        ChapterTemplate::new(source).with_context(|| format!("Invalid template {}", path.display()))
+    }
+
+    /// Compiles `source` and renders it with sample chapters, failing unless
+    /// each comes out as well-formed XHTML, so a broken template is caught
+    /// before anything is downloaded.
+    pub fn new(source: String) -> Result<Self> {
+        let hash = crate::workdir::fnv1a(source.as_bytes());
+        let mut engine = upon::Engine::new();
+        engine
+            .add_template(NAME, source)
+            .map_err(|e| anyhow::anyhow!("{e:#}"))?;
+        let template = ChapterTemplate { engine, hash };
+
+        let sample = |index, prev_href, next_href| ChapterFields {
+            title: "Chapter <2> & \"more\"",
+            body: "<p>Sample text.</p>\n<p class=\"chapter-footer\">Footer</p>",
+            index,
+            total: 3,
+            prev_href,
+            next_href,
+            lang: "en",
+        };
+        for fields in [
+            sample(1, None, Some("1.xhtml")),
+            sample(2, Some("0.xhtml"), Some("2-a.xhtml")),
+            sample(3, Some("1.xhtml"), None),
+        ] {
+            let page = template.render(&fields)?;
+            check_xhtml(&page).with_context(|| {
+                format!("chapter {} of the sample renders to:\n{page}", fields.index)
+            })?;
+        }
+        Ok(template)
+    }
+
+    pub fn render(&self, fields: &ChapterFields) -> Result<String> {
+        let href = |href: Option<&str>| href.map(xhtml::escape).unwrap_or_default();
+        self.engine
+            .template(NAME)
+            .render(upon::value! {
+                title: xhtml::escape(fields.title),
+                body: fields.body,
+                index: fields.index,
+                total: fields.total,
+                prev_href: href(fields.prev_href),
+                next_href: href(fields.next_href),
+                lang: xhtml::escape(fields.lang),
+            })
+            .to_string()
+            .map_err(|e| anyhow::anyhow!("{e:#}"))
+    }
+}
+
+/// Fails unless `page` parses as XML with an XHTML `<html>` root.
+fn check_xhtml(page: &str) -> Result<()> {
+    let options = ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    };
+    let doc = Document::parse_with_options(page, options).context("not well-formed XML")?;
+    let root = doc.root_element().tag_name();
+    if root.name() != "html" || root.namespace() != Some(XHTML_NS) {
+        anyhow::bail!("the root element isn't <html xmlns=\"{XHTML_NS}\">");
+    }
+    Ok(())
+}
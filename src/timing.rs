@@ -0,0 +1,144 @@
+//! How long a build spent in each phase, summed over its chapters, so a
+//! slow build can be told waiting on the network from parsing or writing
+//! the zip. The summary prints the breakdown, `--result-json` keeps it and
+//! each chapter's share is logged at trace level.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use serde::{Serialize, Serializer};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Fetch,
+    Decode,
+    Extract,
+    Xhtml,
+    Add,
+    Generate,
+}
+
+/// Time per [`Phase`]; serialized in seconds.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct Timings {
+    /// Waiting for pages and images, retries and politeness delays
+    /// included.
+    #[serde(serialize_with = "seconds")]
+    pub fetch: Duration,
+    /// Turning downloaded pages into text.
+    #[serde(serialize_with = "seconds")]
+    pub decode: Duration,
+    /// Tokenizing pages and extracting the index and chapter text.
+    #[serde(serialize_with = "seconds")]
+    pub extract: Duration,
+    /// Rendering chapter pages, images and footnotes included.
+    #[serde(serialize_with = "seconds")]
+    pub xhtml: Duration,
+    /// Adding the pages to the book.
+    #[serde(serialize_with = "seconds")]
+    pub add: Duration,
+    /// Generating and writing out the finished files.
+    #[serde(serialize_with = "seconds")]
+    pub generate: Duration,
+}
+
+impl Timings {
+    /// Runs `f`, counting the time it takes towards `phase`.
+    pub fn time<T>(&mut self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.add(phase, started.elapsed());
+        result
+    }
+
+    pub fn add(&mut self, phase: Phase, took: Duration) {
+        *self.of(phase) += took;
+    }
+
+    /// Adds every phase of `other`.
+    pub fn merge(&mut self, other: &Timings) {
+        for (phase, took) in other.phases() {
+            self.add(phase, took);
+        }
+    }
+
+    /// The time spent since these timings were `before`.
+    pub fn since(&self, before: &Timings) -> Timings {
+        let mut since = Timings::default();
+        for ((phase, now), (_, then)) in self.phases().into_iter().zip(before.phases()) {
+            since.add(phase, now.saturating_sub(then));
+        }
+        since
+    }
+
+    pub fn total(&self) -> Duration {
+        self.phases().into_iter().map(|(_, took)| took).sum()
+    }
+
+    fn phases(&self) -> [(Phase, Duration); 6] {
+        [
+            (Phase::Fetch, self.fetch),
+            (Phase::Decode, self.decode),
+            (Phase::Extract, self.extract),
+            (Phase::Xhtml, self.xhtml),
+            (Phase::Add, self.add),
+            (Phase::Generate, self.generate),
+        ]
+    }
+
+    fn of(&mut self, phase: Phase) -> &mut Duration {
+        match phase {
+            Phase::Fetch => &mut self.fetch,
+            Phase::Decode => &mut self.decode,
+            Phase::Extract => &mut self.extract,
+            Phase::Xhtml => &mut self.xhtml,
+            Phase::Add => &mut self.add,
+            Phase::Generate => &mut self.generate,
+        }
+    }
+}
+
+impl Phase {
+    /// What a chapter is going through in this phase, for error messages:
+    /// "chapter 3 ..., rendering".
+    pub fn doing(self) -> &'static str {
+        match self {
+            Phase::Fetch => "fetching",
+            Phase::Decode => "decoding",
+            Phase::Extract => "extracting",
+            Phase::Xhtml => "rendering",
+            Phase::Add => "adding to the book",
+            Phase::Generate => "writing the book",
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Phase::Fetch => "fetch",
+            Phase::Decode => "decode",
+            Phase::Extract => "extract",
+            Phase::Xhtml => "xhtml",
+            Phase::Add => "add",
+            Phase::Generate => "generate",
+        }
+    }
+}
+
+/// "fetch 12.41s, decode 0.02s, ..."
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (n, (phase, took)) in self.phases().into_iter().enumerate() {
+            if n > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{} {:.2}s", phase.as_str(), took.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+fn seconds<S: Serializer>(took: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(took.as_secs_f64())
+}
@@ -0,0 +1,77 @@
+//! Certificate checks for `--cacert` and `--insecure`, and what to say when
+//! one fails.
+
+use std::{error::Error as _, fs, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use rustls::CertificateError;
+use ureq::tls::{PemItem, RootCerts, TlsConfig};
+
+/// Trusts the certificates in the PEM file `cacert` in place of the
+/// built-in roots, as curl's `--cacert` does, or with `insecure` nothing at
+/// all.
+pub fn config(cacert: Option<&Path>, insecure: bool) -> Result<TlsConfig> {
+    let mut tls = TlsConfig::builder().disable_verification(insecure);
+    if let Some(path) = cacert {
+        let pem = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut certs = Vec::new();
+        for item in ureq::tls::parse_pem(&pem) {
+            match item.with_context(|| format!("{} is not a PEM file", path.display()))? {
+                PemItem::Certificate(cert) => certs.push(cert),
+                _ => log::debug!(
+                    "{}: skipping a PEM item that isn't a certificate",
+                    path.display()
+                ),
+            }
+        }
+        if certs.is_empty() {
+            anyhow::bail!("{} holds no certificates", path.display());
+        }
+        log::debug!(
+            "trusting {} certificates from {}",
+            certs.len(),
+            path.display()
+        );
+        tls = tls.root_certs(RootCerts::Specific(Arc::new(certs)));
+    }
+    Ok(tls.build())
+}
+
+/// Why `host`'s certificate was rejected, if that's what `e` is.
+pub(crate) fn rejection(host: &str, e: &ureq::Error) -> Option<String> {
+    let tls = match e {
+        ureq::Error::Rustls(e) => e,
+        ureq::Error::Io(e) => e.get_ref()?.downcast_ref::<rustls::Error>()?,
+        _ => {
+            let mut source = e.source();
+            loop {
+                let cause = source?;
+                if let Some(tls) = cause.downcast_ref::<rustls::Error>() {
+                    break tls;
+                }
+                source = cause.source();
+            }
+        }
+    };
+    let rustls::Error::InvalidCertificate(problem) = tls else {
+        return Some(format!("TLS with {host} failed: {tls}"));
+    };
+    let why = match problem {
+        CertificateError::Expired | CertificateError::ExpiredContext { .. } => {
+            "it has expired".to_string()
+        }
+        CertificateError::NotValidYet | CertificateError::NotValidYetContext { .. } => {
+            "it isn't valid yet".to_string()
+        }
+        CertificateError::UnknownIssuer => {
+            "its issuer isn't trusted (an incomplete chain, or a private CA for --cacert)"
+                .to_string()
+        }
+        CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. } => {
+            format!("it isn't valid for {host}")
+        }
+        CertificateError::Revoked => "it has been revoked".to_string(),
+        other => format!("{other:?}"),
+    };
+    Some(format!("certificate for {host} rejected: {why}"))
+}
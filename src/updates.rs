@@ -0,0 +1,38 @@
+//! `check`: whether a book's index lists chapters its last complete build
+//! didn't have, at the cost of one request.
+
+use std::{collections::HashSet, path::Path};
+
+use crate::{BookSource, ChapterLink, Fetcher, Result, workdir};
+
+pub struct Updates {
+    pub title: String,
+    /// False when the book was never built in full, so every chapter counts
+    /// as new.
+    pub built: bool,
+    /// Chapters on the index the last build didn't have, in index order.
+    pub new: Vec<ChapterLink>,
+}
+
+/// Fetches `source`'s index page and compares it with the record in the
+/// work directories under `root`.
+pub fn check(source: &BookSource, fetcher: &impl Fetcher, root: &Path) -> Result<Updates> {
+    let dir = workdir::book_dir(root, &source.uri);
+    let record = workdir::BookRecord::load(&dir);
+    let known: HashSet<&str> = record
+        .iter()
+        .flat_map(|r| r.chapters.iter().map(String::as_str))
+        .collect();
+    let info = source.info(fetcher)?;
+    let new = info
+        .links
+        .into_iter()
+        .filter(|link| !known.contains(link.uri.to_string().as_str()))
+        .collect();
+    let built = !known.is_empty();
+    let title = match record {
+        Some(record) if !record.title.is_empty() => record.title,
+        _ => info.title.trim().to_string(),
+    };
+    Ok(Updates { title, built, new })
+}
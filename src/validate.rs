@@ -0,0 +1,393 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::File,
+    io::Read,
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use roxmltree::{Document, ParsingOptions};
+use zip::{CompressionMethod, ZipArchive};
+
+/// One thing wrong with a generated epub.
+#[derive(Debug)]
+pub struct Problem {
+    pub file: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.file, self.message)
+    }
+}
+
+/// Re-opens a finished epub and runs the structural checks epubcheck would
+/// fail on first: the mimetype entry, the container, manifest and spine
+/// consistency, TOC links, non-linear pages no TOC reaches, the NCX
+/// against the navigation document and XHTML well-formedness.
+pub fn validate(path: &Path) -> Result<Vec<Problem>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut zip = ZipArchive::new(file)
+        .with_context(|| format!("{} is not a zip archive", path.display()))?;
+    let mut check = Checker {
+        names: zip.file_names().map(String::from).collect(),
+        ids: HashMap::new(),
+        problems: Vec::new(),
+    };
+
+    check.mimetype(&mut zip)?;
+
+    let Some(container) = check.read(&mut zip, "META-INF/container.xml")? else {
+        return Ok(check.problems);
+    };
+    let Some(opf_path) = check
+        .parse("META-INF/container.xml", &container, |doc| {
+            doc.descendants()
+                .find(|n| n.has_tag_name("rootfile"))
+                .and_then(|n| n.attribute("full-path"))
+                .map(String::from)
+        })
+        .flatten()
+    else {
+        check.problem("META-INF/container.xml", "no rootfile full-path");
+        return Ok(check.problems);
+    };
+
+    let Some(opf) = check.read(&mut zip, &opf_path)? else {
+        return Ok(check.problems);
+    };
+    let Some(items) = check.parse(&opf_path, &opf, |doc| {
+        let items: Vec<Item> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("item"))
+            .map(|n| Item {
+                id: n.attribute("id").unwrap_or_default().to_string(),
+                href: join(&opf_path, n.attribute("href").unwrap_or_default()),
+                media_type: n.attribute("media-type").unwrap_or_default().to_string(),
+                properties: n.attribute("properties").unwrap_or_default().to_string(),
+            })
+            .collect();
+        let spine: Vec<String> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("itemref"))
+            .map(|n| n.attribute("idref").unwrap_or_default().to_string())
+            .collect();
+        let nonlinear: Vec<String> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("itemref") && n.attribute("linear") == Some("no"))
+            .map(|n| n.attribute("idref").unwrap_or_default().to_string())
+            .collect();
+        (items, spine, nonlinear)
+    }) else {
+        return Ok(check.problems);
+    };
+    let (items, spine, nonlinear) = items;
+
+    for item in &items {
+        if !check.names.contains(&item.href) {
+            check.problem(
+                &opf_path,
+                format!(
+                    "manifest item {:?} points at missing {}",
+                    item.id, item.href
+                ),
+            );
+        }
+    }
+    for idref in &spine {
+        if !items.iter().any(|i| &i.id == idref) {
+            check.problem(
+                &opf_path,
+                format!("spine itemref {idref:?} is not in the manifest"),
+            );
+        }
+    }
+
+    // Parse every document first so TOC fragments can be checked against it.
+    let mut tocs = Vec::new();
+    let mut ncx = None;
+    let mut nav_toc = None;
+    for item in &items {
+        let is_ncx = item.media_type == "application/x-dtbncx+xml";
+        if (item.media_type != "application/xhtml+xml" && !is_ncx)
+            || !check.names.contains(&item.href)
+        {
+            continue;
+        }
+        let Some(source) = check.read(&mut zip, &item.href)? else {
+            continue;
+        };
+        let is_nav = item.properties.split_whitespace().any(|p| p == "nav");
+        if is_ncx {
+            ncx = Some((item.href.clone(), source.clone()));
+        }
+        let parsed = check.parse(&item.href, &source, |doc| {
+            let ids: HashSet<String> = doc
+                .descendants()
+                .filter_map(|n| n.attribute("id"))
+                .map(String::from)
+                .collect();
+            let links: Vec<String> = doc
+                .descendants()
+                .filter_map(|n| match n.tag_name().name() {
+                    "a" if is_nav => n.attribute("href"),
+                    "content" if is_ncx => n.attribute("src"),
+                    _ => None,
+                })
+                .map(String::from)
+                .collect();
+            let toc = is_nav.then(|| toc_targets(doc, &item.href));
+            (ids, links, toc)
+        });
+        if let Some((ids, links, toc)) = parsed {
+            if toc.is_some() {
+                nav_toc = toc;
+            }
+            if !is_ncx {
+                check.ids.insert(item.href.clone(), ids);
+            }
+            if is_nav || is_ncx {
+                tocs.push((item.href.clone(), links));
+            }
+        }
+    }
+
+    let mut listed = HashSet::new();
+    for (toc, links) in tocs {
+        for link in links {
+            let file = link.split('#').next().unwrap_or_default();
+            listed.insert(join(&toc, file));
+            check.link(&toc, &link);
+        }
+    }
+    // Readers may leave non-linear pages out of the page turns altogether.
+    for idref in &nonlinear {
+        if let Some(item) = items.iter().find(|i| &i.id == idref)
+            && !listed.contains(&item.href)
+        {
+            check.problem(
+                &opf_path,
+                format!(
+                    "non-linear {} is in no table of contents, so readers may not reach it",
+                    item.href
+                ),
+            );
+        }
+    }
+
+    if let Some((href, source)) = ncx {
+        let uid = crate::package::identifier(&opf);
+        check.ncx(&href, &source, uid.as_deref(), nav_toc.as_deref());
+    }
+
+    Ok(check.problems)
+}
+
+/// Where the links of `doc`'s `epub:type="toc"` nav point, resolved against
+/// `href`.
+fn toc_targets(doc: &Document, href: &str) -> Vec<String> {
+    doc.descendants()
+        .filter(|n| {
+            n.has_tag_name("nav")
+                && n.attributes()
+                    .any(|a| a.name() == "type" && a.value().split_whitespace().any(|t| t == "toc"))
+        })
+        .flat_map(|nav| nav.descendants())
+        .filter(|n| n.has_tag_name("a"))
+        .filter_map(|n| n.attribute("href"))
+        .map(|link| join(href, link))
+        .collect()
+}
+
+struct Item {
+    id: String,
+    /// Path inside the archive.
+    href: String,
+    media_type: String,
+    properties: String,
+}
+
+struct Checker {
+    names: HashSet<String>,
+    /// Element ids of every parsed XHTML document.
+    ids: HashMap<String, HashSet<String>>,
+    problems: Vec<Problem>,
+}
+
+impl Checker {
+    fn problem(&mut self, file: &str, message: impl Into<String>) {
+        self.problems.push(Problem {
+            file: file.to_string(),
+            message: message.into(),
+        });
+    }
+
+    fn mimetype(&mut self, zip: &mut ZipArchive<File>) -> Result<()> {
+        let mut first = zip.by_index(0).context("Empty epub archive")?;
+        if first.name() != "mimetype" {
+            let name = first.name().to_string();
+            drop(first);
+            self.problem(&name, "the first entry must be mimetype");
+            return Ok(());
+        }
+        let stored = first.compression() == CompressionMethod::Stored;
+        let mut content = String::new();
+        let read = first.read_to_string(&mut content);
+        drop(first);
+
+        if !stored {
+            self.problem("mimetype", "must be stored without compression");
+        }
+        if read.is_err() || content != "application/epub+zip" {
+            self.problem("mimetype", format!("unexpected content {content:?}"));
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, zip: &mut ZipArchive<File>, name: &str) -> Result<Option<String>> {
+        let Ok(mut file) = zip.by_name(name) else {
+            self.problem(name, "missing from the archive");
+            return Ok(None);
+        };
+        let mut content = String::new();
+        if let Err(e) = file.read_to_string(&mut content) {
+            drop(file);
+            self.problem(name, format!("unreadable: {e}"));
+            return Ok(None);
+        }
+        Ok(Some(content))
+    }
+
+    fn parse<T>(&mut self, name: &str, source: &str, f: impl FnOnce(&Document) -> T) -> Option<T> {
+        let options = ParsingOptions {
+            allow_dtd: true,
+            ..Default::default()
+        };
+        match Document::parse_with_options(source, options) {
+            Ok(doc) => Some(f(&doc)),
+            Err(e) => {
+                self.problem(name, format!("not well-formed XML: {e}"));
+                None
+            }
+        }
+    }
+
+    /// Checks that a TOC link found in `toc` points at an existing document
+    /// and, if it has a fragment, at an element in it.
+    fn link(&mut self, toc: &str, href: &str) {
+        let (file, fragment) = match href.split_once('#') {
+            Some((file, fragment)) => (file, Some(fragment)),
+            None => (href, None),
+        };
+        let target = if file.is_empty() {
+            toc.to_string()
+        } else {
+            join(toc, file)
+        };
+
+        match (self.ids.get(&target), fragment) {
+            (None, _) if !self.names.contains(&target) => {
+                self.problem(toc, format!("link {href:?} points at missing {target}"));
+            }
+            (Some(ids), Some(fragment)) if !ids.contains(fragment) => {
+                self.problem(
+                    toc,
+                    format!("link {href:?} points at no element in {target}"),
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Checker {
+    /// Checks what EPUB 2 readers navigate by in the NCX at `href`: that it
+    /// names the package's identifier, that its depth is that of its
+    /// entries, that entries with the same target share a play order and
+    /// the others follow reading order, and that it lists what the
+    /// navigation document does.
+    fn ncx(&mut self, href: &str, source: &str, uid: Option<&str>, nav: Option<&[String]>) {
+        let Some((meta, depth, points)) = self.parse(href, source, |doc| {
+            let meta = |name: &str| {
+                doc.descendants()
+                    .find(|n| n.has_tag_name("meta") && n.attribute("name") == Some(name))
+                    .and_then(|n| n.attribute("content"))
+                    .map(String::from)
+            };
+            let depth = doc
+                .descendants()
+                .filter(|n| n.has_tag_name("navPoint"))
+                .map(|n| n.ancestors().filter(|a| a.has_tag_name("navPoint")).count())
+                .max()
+                .unwrap_or(0);
+            let points: Vec<(String, Option<usize>)> = doc
+                .descendants()
+                .filter(|n| n.has_tag_name("navPoint"))
+                .map(|n| {
+                    let src = n
+                        .children()
+                        .find(|c| c.has_tag_name("content"))
+                        .and_then(|c| c.attribute("src"))
+                        .unwrap_or_default();
+                    let order = n.attribute("playOrder").and_then(|o| o.parse().ok());
+                    (join(href, src), order)
+                })
+                .collect();
+            ((meta("dtb:uid"), meta("dtb:depth")), depth, points)
+        }) else {
+            return;
+        };
+        let (found_uid, found_depth) = meta;
+
+        if found_uid.as_deref() != uid {
+            self.problem(
+                href,
+                format!("dtb:uid {found_uid:?} is not the package's identifier {uid:?}"),
+            );
+        }
+        if found_depth.as_deref() != Some(depth.max(1).to_string().as_str()) {
+            self.problem(
+                href,
+                format!("dtb:depth {found_depth:?}, but its entries nest {depth} deep"),
+            );
+        }
+
+        let srcs: Vec<&str> = points.iter().map(|(src, _)| src.as_str()).collect();
+        let expected = crate::ncx::play_orders(srcs.iter().copied());
+        for ((src, order), expected) in points.iter().zip(expected) {
+            if *order != Some(expected) {
+                self.problem(
+                    href,
+                    format!("navPoint for {src} has playOrder {order:?}, expected {expected}"),
+                );
+            }
+        }
+
+        if let Some(nav) = nav
+            && nav != srcs
+        {
+            self.problem(
+                href,
+                "lists other entries than the navigation document's table of contents",
+            );
+        }
+    }
+}
+
+/// Resolves `href` relative to the archive path of the document containing it.
+pub(crate) fn join(document: &str, href: &str) -> String {
+    let mut parts: Vec<&str> = document.split('/').collect();
+    parts.pop();
+    for segment in href.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(segment),
+        }
+    }
+    parts.join("/")
+}
@@ -0,0 +1,206 @@
+//! Where what a run learns about a book is kept for the next one.
+//!
+//! Every book gets a directory under the root, named after a hash of its
+//! index page URL as [`key`] writes it:
+//!
+//! ```text
+//! <root>/<url-hash>/
+//!     book.json        the URL, title and time of the last run, and the
+//!                      index page as the last complete build parsed it
+//!     chapters.json    the chapters of complete builds, for --rebuild,
+//!                      each kept in chapters/
+//!     state.json       chapters found permanently missing
+//!     checkpoint.json  chapters parsed so far, with --checkpoint-every
+//!     texts.json       chapter texts, with --check-revisions
+//!     generated.json   the XHTML pages of the last epub build, kept in
+//!                      pages/ and reused for chapters that haven't changed
+//!     index.html       the last index page missing chapters, title or
+//!                      author, for a look at what the site sent
+//!     failed/          chapter pages that couldn't be parsed, by chapter
+//!                      number
+//!     lock             held by the run building the book
+//! ```
+//!
+//! The root is `$XDG_DATA_HOME/epub-dude`, or `~/.local/share/epub-dude`
+//! when that isn't set; `--work-dir` picks another.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use http::Uri;
+use serde::{Deserialize, Serialize};
+
+use crate::{checkpoint, lock, rebuild::StoredIndex};
+
+const FILE: &str = "book.json";
+
+/// The root used without `--work-dir`.
+pub fn default_root() -> PathBuf {
+    let data = env::var_os("XDG_DATA_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")));
+    match data {
+        Some(data) => data.join("epub-dude"),
+        None => PathBuf::from(".epub-dude"),
+    }
+}
+
+/// The per-book directory under `root` for the index page at `uri`.
+pub fn book_dir(root: &Path, uri: &Uri) -> PathBuf {
+    root.join(format!("{:016x}", fnv1a(key(uri).as_bytes())))
+}
+
+/// `uri` as written for every spelling of the same index page: https, a
+/// lowercase host and no trailing slash.
+pub fn key(uri: &Uri) -> String {
+    let host = uri
+        .authority()
+        .map(|a| a.as_str().to_ascii_lowercase())
+        .unwrap_or_default();
+    let path = uri.path().trim_end_matches('/');
+    match uri.query() {
+        Some(query) => format!("https://{host}{path}?{query}"),
+        None => format!("https://{host}{path}"),
+    }
+}
+
+/// FNV-1a, which unlike std's hasher is stable across Rust releases.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// What `book.json` records about a book.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BookRecord {
+    pub url: String,
+    pub title: String,
+    /// RFC 3339.
+    pub last_run: String,
+    /// The chapter URLs the index listed when the book was last built in
+    /// full, which `check` compares against; empty until then.
+    #[serde(default)]
+    pub chapters: Vec<String>,
+    /// The index page as the last complete build parsed it, which
+    /// `--rebuild` builds from.
+    #[serde(default)]
+    pub index: Option<StoredIndex>,
+}
+
+impl BookRecord {
+    /// A record of a run starting now, keeping what `dir` has from the
+    /// last complete build.
+    pub fn new(dir: &Path, url: &Uri, title: &str) -> Self {
+        let (chapters, index) = BookRecord::load(dir)
+            .filter(|record| record.is_for(url))
+            .map(|record| (record.chapters, record.index))
+            .unwrap_or_default();
+        BookRecord {
+            url: url.to_string(),
+            title: title.to_string(),
+            last_run: Local::now().to_rfc3339(),
+            chapters,
+            index,
+        }
+    }
+
+    /// Whether the record is of the index page at `url`, however spelled.
+    pub fn is_for(&self, url: &Uri) -> bool {
+        self.url
+            .parse()
+            .is_ok_and(|recorded| key(&recorded) == key(url))
+    }
+
+    pub fn load(dir: &Path) -> Option<Self> {
+        let json = fs::read(dir.join(FILE)).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        checkpoint::write_atomic(&dir.join(FILE), &serde_json::to_vec_pretty(self)?)
+    }
+}
+
+/// A book directory found under the root.
+#[derive(Debug)]
+pub struct KnownBook {
+    pub dir: PathBuf,
+    /// `None` for directories from before `book.json` was written.
+    pub record: Option<BookRecord>,
+    /// Bytes on disk.
+    pub size: u64,
+}
+
+impl KnownBook {
+    /// When the book was last built, or failing that its directory touched.
+    pub fn last_run(&self) -> Option<DateTime<Local>> {
+        let recorded = self.record.as_ref().and_then(|record| {
+            DateTime::parse_from_rfc3339(&record.last_run)
+                .ok()
+                .map(|t| t.with_timezone(&Local))
+        });
+        match recorded {
+            Some(last_run) => Some(last_run),
+            None => fs::metadata(&self.dir)
+                .and_then(|m| m.modified())
+                .ok()
+                .map(DateTime::from),
+        }
+    }
+}
+
+/// Every book directory under `root`, most recently run first.
+pub fn list(root: &Path) -> Result<Vec<KnownBook>> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", root.display())),
+    };
+    let mut books = Vec::new();
+    for entry in entries {
+        let dir = entry?.path();
+        if dir.is_dir() {
+            books.push(KnownBook {
+                record: BookRecord::load(&dir),
+                size: size(&dir),
+                dir,
+            });
+        }
+    }
+    books.sort_by_key(|b| std::cmp::Reverse(b.last_run()));
+    Ok(books)
+}
+
+fn size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return fs::metadata(path).map_or(0, |m| m.len());
+    };
+    entries.flatten().map(|entry| size(&entry.path())).sum()
+}
+
+/// Deletes a book's directory, unless a run is building it right now.
+pub fn remove(dir: &Path) -> crate::Result<()> {
+    let lock = lock::BookLock::acquire(dir, Duration::ZERO)?;
+    for entry in fs::read_dir(dir).map_err(|e| crate::Error::output(dir, e))? {
+        let path = entry.map_err(|e| crate::Error::output(dir, e))?.path();
+        if path.file_name() == Some(lock::FILE.as_ref()) {
+            continue;
+        }
+        let removed = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        removed.map_err(|e| crate::Error::output(&path, e))?;
+    }
+    // Releasing the lock removes the now empty directory.
+    drop(lock);
+    Ok(())
+}
@@ -0,0 +1,502 @@
+use std::{cmp::Ordering, str::FromStr};
+
+use crate::{metadata::Contributor, summary::Summary};
+
+/// Base stylesheet, written for horizontal text; see [`stylesheet`].
+const STYLESHEET: &str = r#"section.title-page {
+  margin-top: 20%;
+  text-align: center;
+}
+section.title-page h1 {
+  font-size: 1.8em;
+  margin-bottom: 1em;
+}
+section.title-page p.description {
+  margin-top: 2em;
+  text-align: left;
+  font-size: 0.9em;
+}
+section.title-page p.source {
+  margin-top: 2em;
+  font-size: 0.75em;
+}
+section.cover {
+  text-align: center;
+}
+section.cover img {
+  max-width: 100%;
+  max-height: 100%;
+}
+section.colophon {
+  font-size: 0.85em;
+}
+h2.section {
+  font-size: 1.2em;
+  margin-top: 1.5em;
+}
+blockquote {
+  margin: 1em 0 1em 1em;
+  padding-left: 0.75em;
+  border-left: 3px solid #ccc;
+}
+pre {
+  font-family: monospace;
+  white-space: pre-wrap;
+}
+table {
+  border-collapse: collapse;
+  margin: 1em 0;
+}
+th, td {
+  border: 1px solid #999;
+  padding: 0.2em 0.5em;
+}
+aside.footnote, div.footnotes {
+  margin-top: 2em;
+  font-size: 0.85em;
+}
+p.chapter-footer {
+  margin-top: 2em;
+  font-size: 0.75em;
+  text-align: center;
+  color: gray;
+}
+p.chapter-nav {
+  margin-top: 1em;
+  font-size: 0.75em;
+  text-align: center;
+}
+"#;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum WritingMode {
+    #[default]
+    HorizontalTb,
+    VerticalRl,
+}
+
+impl WritingMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WritingMode::HorizontalTb => "horizontal-tb",
+            WritingMode::VerticalRl => "vertical-rl",
+        }
+    }
+}
+
+const CLASSIC: &str = r#"body {
+  font-family: serif;
+}
+p {
+  margin-top: 0;
+  margin-bottom: 0;
+  text-indent: 2em;
+}
+section.title-page p, p.chapter-footer, p.chapter-nav {
+  text-indent: 0;
+}
+"#;
+
+const MODERN: &str = r#"body {
+  font-family: sans-serif;
+}
+p {
+  margin-top: 0;
+  margin-bottom: 0.8em;
+  text-indent: 0;
+}
+"#;
+
+/// A built-in look for the book's text, chosen with `--theme`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Theme {
+    /// The reader's own typography.
+    #[default]
+    Plain,
+    /// Serif, indented paragraphs without space between them.
+    Classic,
+    /// Sans-serif, spaced paragraphs without an indent.
+    Modern,
+    /// No colors at all, so a reader's dark mode shows everything.
+    NightSafe,
+}
+
+impl Theme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Theme::Plain => "plain",
+            Theme::Classic => "classic",
+            Theme::Modern => "modern",
+            Theme::NightSafe => "night-safe",
+        }
+    }
+
+    /// The rules the theme adds to, or changes in, [`STYLESHEET`].
+    fn apply(self, css: &str) -> String {
+        match self {
+            Theme::Plain => css.to_string(),
+            Theme::Classic => format!("{css}{CLASSIC}"),
+            Theme::Modern => format!("{css}{MODERN}"),
+            // Borders take the text's color, and the footer is faded
+            // instead of grayed.
+            Theme::NightSafe => css
+                .replace(" solid #ccc;", " solid;")
+                .replace(" solid #999;", " solid;")
+                .replace("  color: gray;\n", "  opacity: 0.7;\n"),
+        }
+    }
+}
+
+impl FromStr for Theme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "plain" => Ok(Theme::Plain),
+            "classic" => Ok(Theme::Classic),
+            "modern" => Ok(Theme::Modern),
+            "night-safe" => Ok(Theme::NightSafe),
+            _ => anyhow::bail!(
+                "Invalid --theme: {s} (expected plain, classic, modern or night-safe)"
+            ),
+        }
+    }
+}
+
+/// The typography knobs of `--justify`, `--font-size`, `--line-height`
+/// and `--paragraph-spacing`, over whatever the theme says.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Typography {
+    pub justify: bool,
+    /// Percent of the reader's font size.
+    pub font_size: Option<u32>,
+    pub line_height: Option<f32>,
+    /// Space after each paragraph, in em.
+    pub paragraph_spacing: Option<f32>,
+}
+
+impl Typography {
+    /// The rules for the knobs that are set, to follow the theme's so they
+    /// win over them.
+    fn css(&self) -> String {
+        let mut body = String::new();
+        if self.justify {
+            body.push_str("  text-align: justify;\n");
+        }
+        if let Some(percent) = self.font_size {
+            body.push_str(&format!("  font-size: {percent}%;\n"));
+        }
+        if let Some(height) = self.line_height {
+            body.push_str(&format!("  line-height: {height};\n"));
+        }
+        let mut css = String::new();
+        if !body.is_empty() {
+            css.push_str(&format!("body {{\n{body}}}\n"));
+        }
+        if let Some(em) = self.paragraph_spacing {
+            css.push_str(&format!(
+                "p {{\n  margin-top: 0;\n  margin-bottom: {em}em;\n}}\n"
+            ));
+        }
+        css
+    }
+}
+
+/// The stylesheet embedded in every book as `stylesheet.css`, in `theme`
+/// with `typography` over it.
+///
+/// In vertical-rl the block direction runs right to left, so block-start and
+/// block-end margins move from top/bottom to right/left.
+pub fn stylesheet(mode: WritingMode, theme: Theme, typography: &Typography) -> String {
+    let css = theme.apply(STYLESHEET) + &typography.css();
+    match mode {
+        WritingMode::HorizontalTb => css,
+        WritingMode::VerticalRl => {
+            let css = css
+                .replace("margin-top", "margin-right")
+                .replace("margin-bottom", "margin-left");
+            format!(
+                "html {{\n  writing-mode: vertical-rl;\n  -epub-writing-mode: vertical-rl;\n  -webkit-writing-mode: vertical-rl;\n}}\n{css}"
+            )
+        }
+    }
+}
+
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    escape_into(&mut out, s);
+    out
+}
+
+/// [`escape`]s `s` onto the end of `out`, copying it in runs between the
+/// characters that need escaping.
+pub fn escape_into(out: &mut String, s: &str) {
+    let mut run = 0;
+    for (i, &b) in s.as_bytes().iter().enumerate() {
+        if let Some(entity) = entity(b) {
+            out.push_str(&s[run..i]);
+            out.push_str(entity);
+            run = i + 1;
+        }
+    }
+    out.push_str(&s[run..]);
+}
+
+/// What [`escape`] writes for the ASCII character `b`, if it isn't `b`.
+pub fn entity(b: u8) -> Option<&'static str> {
+    match b {
+        b'&' => Some("&amp;"),
+        b'<' => Some("&lt;"),
+        b'>' => Some("&gt;"),
+        b'"' => Some("&quot;"),
+        b'\'' => Some("&#39;"),
+        _ => None,
+    }
+}
+
+/// Reverses [`escape`].
+pub fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+const DOCUMENT_HEAD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+        <head>
+        <title>"#;
+const DOCUMENT_BODY: &str = r#"</title>
+        <link rel="stylesheet" type="text/css" href="stylesheet.css" />
+        </head>
+        <body>
+        "#;
+const DOCUMENT_TAIL: &str = r#"
+        </body>
+        </html>"#;
+
+/// Wraps body markup in the XHTML skeleton shared by every generated document.
+pub fn document(title: &str, body: &str) -> String {
+    let mut out = String::new();
+    document_into(&mut out, title, &[body]);
+    out
+}
+
+/// Appends the document for `body`, given in pieces, to `out` with a single
+/// allocation at most.
+fn document_into(out: &mut String, title: &str, body: &[&str]) {
+    let title = escape(title);
+    out.reserve(
+        DOCUMENT_HEAD.len()
+            + title.len()
+            + DOCUMENT_BODY.len()
+            + body.iter().map(|b| b.len()).sum::<usize>()
+            + DOCUMENT_TAIL.len(),
+    );
+    out.push_str(DOCUMENT_HEAD);
+    out.push_str(&title);
+    out.push_str(DOCUMENT_BODY);
+    for piece in body {
+        out.push_str(piece);
+    }
+    out.push_str(DOCUMENT_TAIL);
+}
+
+/// Builds a chapter document around already-sanitized body markup.
+pub fn chapter(title: &str, body: &str, footer: Option<&str>) -> String {
+    let mut out = String::new();
+    chapter_into(&mut out, title, body, footer);
+    out
+}
+
+/// Like [`chapter`], replacing the contents of `out` so its buffer can be
+/// reused from one chapter to the next.
+pub fn chapter_into(out: &mut String, title: &str, body: &str, footer: Option<&str>) {
+    out.clear();
+    match footer {
+        Some(footer) => document_into(out, title, &[body, "\n", footer]),
+        None => document_into(out, title, &[body]),
+    }
+}
+
+/// The page showing the cover image at `href`, ahead of the title page.
+pub fn cover_page(title: &str, href: &str) -> String {
+    document(
+        title,
+        &format!(
+            r#"<section class="cover" epub:type="cover"><img src="{}" alt="{}" /></section>"#,
+            escape(href),
+            escape(title)
+        ),
+    )
+}
+
+pub struct TitlePage<'a> {
+    pub title: &'a str,
+    pub authors: &'a [String],
+    pub contributors: &'a [Contributor],
+    pub source: Option<&'a str>,
+    pub description: Option<&'a str>,
+    pub description_limit: usize,
+}
+
+pub fn title_page(page: &TitlePage) -> String {
+    let mut body = String::from(r#"<section class="title-page" epub:type="titlepage">"#);
+    body.push_str(&format!("<h1>{}</h1>", escape(page.title)));
+
+    if !page.authors.is_empty() {
+        body.push_str(&format!(
+            r#"<p class="author">{}</p>"#,
+            escape(&page.authors.join(", "))
+        ));
+    }
+    for c in page.contributors {
+        body.push_str(&format!(
+            r#"<p class="contributor">{}: {}</p>"#,
+            c.role.label(),
+            escape(&c.name)
+        ));
+    }
+    if let Some(description) = page.description {
+        body.push_str(&description_paragraphs(description, page.description_limit));
+    }
+    if let Some(source) = page.source {
+        let source = escape(source);
+        body.push_str(&format!(
+            r#"<p class="source"><a href="{source}">{source}</a></p>"#
+        ));
+    }
+
+    body.push_str("</section>");
+    document(page.title, &body)
+}
+
+/// Back-matter page recording how and when the book was generated.
+pub fn colophon(summary: &Summary, version: &str, source: Option<&str>) -> String {
+    let mut body = String::from(r#"<section class="colophon" epub:type="colophon">"#);
+    body.push_str("<h2>Colophon</h2><dl>");
+
+    let mut entry = |term: &str, value: String| {
+        body.push_str(&format!("<dt>{term}</dt><dd>{value}</dd>"));
+    };
+    if let Some(source) = source {
+        let source = escape(source);
+        entry("Source", format!(r#"<a href="{source}">{source}</a>"#));
+    }
+    if let Some(range) = summary.published_range() {
+        entry("Published", escape(&range));
+    }
+    if let Some(range) = summary.fetch_range() {
+        entry("Fetched", escape(&range));
+    }
+    entry("Chapters", summary.chapters.to_string());
+    entry("Generated by", format!("epub-dude {}", escape(version)));
+
+    body.push_str("</dl>");
+    if !summary.placeholders.is_empty() {
+        body.push_str("<p>Placeholder chapters:</p><ul>");
+        for p in &summary.placeholders {
+            body.push_str(&format!("<li>{}</li>", escape(p)));
+        }
+        body.push_str("</ul>");
+    }
+    body.push_str("</section>");
+
+    document("Colophon", &body)
+}
+
+/// Cuts `text` to at most `limit` characters, marking the cut with an ellipsis.
+/// `description` as escaped `<p class="description">` paragraphs, one per
+/// non-blank line, cut short with "…" after `limit` characters in all.
+pub fn description_paragraphs(description: &str, limit: usize) -> String {
+    let lines: Vec<&str> = description
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect();
+    let mut out = String::new();
+    let mut left = limit;
+    for (n, line) in lines.iter().enumerate() {
+        let length = line.chars().count();
+        let more = n + 1 < lines.len();
+        let paragraph = match length.cmp(&left) {
+            Ordering::Less => line.to_string(),
+            Ordering::Equal if !more => line.to_string(),
+            Ordering::Equal => format!("{line}…"),
+            Ordering::Greater => truncate(line, left),
+        };
+        out.push_str(&format!(
+            r#"<p class="description">{}</p>"#,
+            escape(&paragraph)
+        ));
+        if length >= left {
+            break;
+        }
+        left -= length;
+    }
+    out
+}
+
+pub fn truncate(text: &str, limit: usize) -> String {
+    match text.char_indices().nth(limit) {
+        Some((end, _)) => format!("{}…", text[..end].trim_end()),
+        None => text.to_string(),
+    }
+}
+
+pub struct FooterFields<'a> {
+    pub url: &'a str,
+    pub date: &'a str,
+    pub index: usize,
+}
+
+/// The inline table of contents epub-builder adds to every book.
+pub const TOC_FILE: &str = "toc.xhtml";
+
+/// The `--chapter-nav` links at the end of a chapter: to the chapter before
+/// it, the table of contents and the chapter after it, the first and last
+/// left out where there is none.
+pub fn chapter_nav(prev: Option<&str>, next: Option<&str>) -> String {
+    let mut links = Vec::with_capacity(3);
+    if let Some(prev) = prev {
+        links.push(format!(r#"<a href="{}">← Previous</a>"#, escape(prev)));
+    }
+    links.push(format!(r#"<a href="{TOC_FILE}">Contents</a>"#));
+    if let Some(next) = next {
+        links.push(format!(r#"<a href="{}">Next →</a>"#, escape(next)));
+    }
+    format!(r#"<p class="chapter-nav">{}</p>"#, links.join(" | "))
+}
+
+/// Renders a `--chapter-footer` template, substituting `{url}`, `{date}` and
+/// `{index}`. The whole block is a single `p.chapter-footer` so later passes
+/// can find and strip it.
+pub fn footer(template: &str, fields: &FooterFields) -> String {
+    let mut out = String::from(r#"<p class="chapter-footer">"#);
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        out.push_str(&escape(&rest[..start]));
+        let tail = &rest[start..];
+        let Some(end) = tail.find('}') else {
+            rest = tail;
+            break;
+        };
+
+        match &tail[1..end] {
+            "url" => {
+                let url = escape(fields.url);
+                out.push_str(&format!(r#"<a href="{url}">{url}</a>"#));
+            }
+            "date" => out.push_str(&escape(fields.date)),
+            "index" => out.push_str(&fields.index.to_string()),
+            _ => out.push_str(&escape(&tail[..=end])),
+        }
+        rest = &tail[end + 1..];
+    }
+
+    out.push_str(&escape(rest));
+    out.push_str("</p>");
+    out
+}
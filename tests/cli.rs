@@ -0,0 +1,166 @@
+//! Runs the built binary against recorded sessions, for what only the
+//! command line decides.
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use epub_dude::{
+    exit_code,
+    session::{Exchange, Session},
+};
+
+fn index(book: &str, title: &str) -> String {
+    format!(
+        r#"<html><body>
+<span class="title">{title}</span>
+<span class="author"><a href="/a/1">作者甲</a></span>
+<ul id="chapter-list">
+  <li><a href="//czbooks.net/n/{book}/1">第一章 開始</a></li>
+</ul>
+</body></html>"#
+    )
+}
+
+fn chapter(text: &str) -> String {
+    format!(
+        r#"<html><body><div class="name">第一章 開始</div><div class="content"><p>{text}</p></div></body></html>"#
+    )
+}
+
+/// A per-test directory, so tests can run in parallel.
+fn dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("epub-dude-cli-{}-{name}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Records a session serving `pages`, by URL, in `dir`.
+fn session(dir: &Path, pages: &[(&str, String)]) -> PathBuf {
+    let exchanges = pages
+        .iter()
+        .map(|(url, body)| Exchange {
+            url: url.to_string(),
+            request_headers: Vec::new(),
+            status: Some(200),
+            headers: Vec::new(),
+            body: body.clone().into_bytes(),
+            error: None,
+        })
+        .collect();
+    let path = dir.join("session.json");
+    Session::new(exchanges).save(&path).unwrap();
+    path
+}
+
+/// Runs `epub-dude` with `args` in `dir`, keeping its data and config
+/// there too.
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_epub-dude"))
+        .args(args)
+        .current_dir(dir)
+        .env("XDG_DATA_HOME", dir.join("data"))
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn merge_builds_one_book_of_every_url() {
+    let dir = dir("merge");
+    let session = session(
+        &dir,
+        &[
+            ("https://czbooks.net/n/one", index("one", "第一本")),
+            ("https://czbooks.net/n/one/1", chapter("很久很久以前。")),
+            ("https://czbooks.net/n/two", index("two", "第二本")),
+            ("https://czbooks.net/n/two/1", chapter("從此以後。")),
+        ],
+    );
+    let session = session.to_str().unwrap();
+
+    let merged = run(
+        &dir,
+        &[
+            "fetch",
+            "--merge",
+            "--replay",
+            session,
+            "-o",
+            "merged.epub",
+            "https://czbooks.net/n/one",
+            "https://czbooks.net/n/two",
+        ],
+    );
+
+    assert!(
+        merged.status.success(),
+        "{}",
+        String::from_utf8_lossy(&merged.stderr)
+    );
+    let book = std::fs::read(dir.join("merged.epub")).unwrap();
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(book)).unwrap();
+    let mut nav = String::new();
+    std::io::Read::read_to_string(&mut zip.by_name("OEBPS/nav.xhtml").unwrap(), &mut nav).unwrap();
+    assert!(nav.contains("第一本") && nav.contains("第二本"), "{nav}");
+
+    let mirrored = run(
+        &dir,
+        &[
+            "fetch",
+            "--merge",
+            "--fallback-url",
+            "https://czbooks.net/n/mirror",
+            "--replay",
+            session,
+            "https://czbooks.net/n/one",
+            "https://czbooks.net/n/two",
+        ],
+    );
+
+    assert_eq!(mirrored.status.code(), Some(exit_code::USAGE));
+    let stderr = String::from_utf8_lossy(&mirrored.stderr);
+    assert!(
+        stderr.contains("--fallback-url can't be combined with --merge"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn images_are_only_fetched_for_formats_that_embed_them() {
+    let dir = dir("text-images");
+    let session = session(
+        &dir,
+        &[
+            ("https://czbooks.net/n/one", index("one", "第一本")),
+            (
+                "https://czbooks.net/n/one/1",
+                chapter(r#"很久以前。<img src="https://czbooks.net/img/1.png" alt="圖">"#),
+            ),
+        ],
+    );
+
+    for format in ["txt", "md"] {
+        let built = run(
+            &dir,
+            &[
+                "fetch",
+                "--format",
+                format,
+                "--images",
+                "--replay",
+                session.to_str().unwrap(),
+                "https://czbooks.net/n/one",
+            ],
+        );
+
+        // The image isn't in the recording, so fetching it would fail the replay.
+        assert!(
+            built.status.success(),
+            "{format}: {}",
+            String::from_utf8_lossy(&built.stderr)
+        );
+        assert!(dir.join(format!("第一本.{format}")).exists(), "{format}");
+    }
+}
@@ -0,0 +1,1075 @@
+//! Runs the pipeline over real HTTP against a scripted local server.
+
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use epub_dude::{
+    BookSource, BuildOptions, Delays, Error, Fetcher, HttpFetcher, Summary, TimeLimits, build_epub,
+    exit_code,
+    fetch::{Site, czbooksnet::CzBooksProvider},
+    outcome::{ChapterCounts, RunResult},
+    politeness::Profiles,
+    resolve,
+    selection::TitleFilter,
+    session::{Exchange, Replay, Session},
+    tls,
+};
+use tiny_http::Header;
+use ureq::config::IpFamily;
+use zip::ZipArchive;
+
+struct Reply {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn ok(body: impl Into<Vec<u8>>) -> Self {
+        Reply {
+            status: 200,
+            headers: vec![("Content-Type", "text/html; charset=utf-8".to_string())],
+            body: body.into(),
+        }
+    }
+
+    fn status(status: u16) -> Self {
+        Reply {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn redirect(to: &str) -> Self {
+        Reply::status(302).header("Location", to)
+    }
+
+    fn header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+struct Request<'a> {
+    path: &'a str,
+    headers: &'a [(String, String)],
+    /// How many earlier requests hit the same path.
+    hit: usize,
+}
+
+impl Request<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+struct Server {
+    base: String,
+    paths: Arc<Mutex<Vec<String>>>,
+}
+
+impl Server {
+    /// Serves `handler`'s replies on a free port until the test process exits.
+    fn start(handler: impl Fn(&Request) -> Reply + Send + 'static) -> Self {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let port = server.server_addr().to_ip().unwrap().port();
+        let paths = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&paths);
+
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let path = request.url().to_string();
+                let headers: Vec<(String, String)> = request
+                    .headers()
+                    .iter()
+                    .map(|h| (h.field.to_string(), h.value.to_string()))
+                    .collect();
+                let hit = {
+                    let mut seen = seen.lock().unwrap();
+                    let hit = seen.iter().filter(|p| **p == path).count();
+                    seen.push(path.clone());
+                    hit
+                };
+                let reply = handler(&Request {
+                    path: &path,
+                    headers: &headers,
+                    hit,
+                });
+                let mut response =
+                    tiny_http::Response::from_data(reply.body).with_status_code(reply.status);
+                for (name, value) in reply.headers {
+                    response.add_header(Header::from_bytes(name, value).unwrap());
+                }
+                let _ = request.respond(response);
+            }
+        });
+
+        Server {
+            base: format!("http://127.0.0.1:{port}"),
+            paths,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base)
+    }
+
+    fn hits(&self, path: &str) -> usize {
+        self.paths
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|p| *p == path)
+            .count()
+    }
+}
+
+const INDEX: &str = r#"<html><body>
+<span class="title">本地之書</span>
+<span class="author"><a href="/a/1">作者乙</a></span>
+<ul id="chapter-list">
+  <li><a href="/n/1">第一章 來</a></li>
+  <li><a href="/n/2">第二章 去</a></li>
+</ul>
+</body></html>"#;
+
+fn chapter(n: usize) -> String {
+    format!(
+        r#"<html><body><div class="name">第{n}章</div><div class="content"><p>第{n}章的內容。</p></div></body></html>"#
+    )
+}
+
+/// The index at `/book` and its chapters, everything else 404.
+fn book(request: &Request) -> Reply {
+    match request.path {
+        "/book" => Reply::ok(INDEX),
+        "/n/1" => Reply::ok(chapter(1)),
+        "/n/2" => Reply::ok(chapter(2)),
+        _ => Reply::status(404),
+    }
+}
+
+/// Collects the crate's log messages from every test; each test's server
+/// URL tells its own apart.
+struct Capture(Mutex<Vec<String>>);
+
+impl log::Log for Capture {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target().starts_with("epub_dude")
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            let message = format!("{} {}", record.level(), record.args());
+            self.0.lock().unwrap().push(message);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn logs(needle: &str) -> Vec<String> {
+    static LOGGER: OnceLock<&'static Capture> = OnceLock::new();
+    let logger = LOGGER.get_or_init(|| {
+        let logger = Box::leak(Box::new(Capture(Mutex::new(Vec::new()))));
+        log::set_logger(logger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+        logger
+    });
+    logger
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|m| m.contains(needle))
+        .cloned()
+        .collect()
+}
+
+/// The warnings among [`logs`].
+fn warnings(needle: &str) -> Vec<String> {
+    logs(needle)
+        .into_iter()
+        .filter(|m| m.starts_with("WARN "))
+        .collect()
+}
+
+fn run(server: &Server, name: &str) -> (Result<Summary, Error>, PathBuf) {
+    run_with(server, name, |fetcher| fetcher)
+}
+
+/// Like [`run`], with `configure` applied to the fetcher.
+fn run_with(
+    server: &Server,
+    name: &str,
+    configure: impl FnOnce(HttpFetcher) -> HttpFetcher,
+) -> (Result<Summary, Error>, PathBuf) {
+    logs("");
+    let fetcher = configure(
+        HttpFetcher::new(ureq::Agent::new_with_defaults()).delays(Delays {
+            after_request: Duration::ZERO,
+            backoff: Duration::from_millis(10),
+        }),
+    );
+    run_through(server, name, &fetcher)
+}
+
+/// Builds `server`'s book through any `fetcher`, e.g. a replay.
+fn run_through(
+    server: &Server,
+    name: &str,
+    fetcher: &impl Fetcher,
+) -> (Result<Summary, Error>, PathBuf) {
+    run_options(server, name, fetcher, BuildOptions::default())
+}
+
+/// Like [`run_through`], starting from `options`.
+fn run_options(
+    server: &Server,
+    name: &str,
+    fetcher: &impl Fetcher,
+    options: BuildOptions,
+) -> (Result<Summary, Error>, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("epub-dude-http-{}-{name}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("book.epub");
+    let options = BuildOptions {
+        output: path.to_str().unwrap().parse().unwrap(),
+        work_dir: dir.join("work"),
+        ..options
+    };
+    let source = BookSource::with_site(
+        server.url("/book").parse().unwrap(),
+        Site::of::<CzBooksProvider>(),
+    );
+    (build_epub(&source, fetcher, &options, &()), path)
+}
+
+fn epub_text(path: &Path) -> String {
+    let mut zip = ZipArchive::new(File::open(path).unwrap()).unwrap();
+    let mut all = String::new();
+    for i in 0..zip.len() {
+        let _ = zip.by_index(i).unwrap().read_to_string(&mut all);
+    }
+    all
+}
+
+#[test]
+fn retries_after_429() {
+    let server = Server::start(|request| match (request.path, request.hit) {
+        ("/n/1", 0) => Reply::status(429),
+        _ => book(request),
+    });
+
+    let (result, path) = run(&server, "429");
+
+    assert_eq!(result.unwrap().chapters, 2);
+    assert_eq!(server.hits("/n/1"), 2);
+    assert!(epub_text(&path).contains("第1章的內容。"));
+    let retries = warnings(&server.url("/n/1"));
+    assert_eq!(retries.len(), 1, "{retries:?}");
+    assert!(retries[0].contains("HTTP 429, retrying"), "{retries:?}");
+}
+
+#[test]
+fn gives_up_after_three_attempts() {
+    let server = Server::start(|request| match request.path {
+        "/n/2" => Reply::status(429),
+        _ => book(request),
+    });
+
+    let (result, path) = run(&server, "give-up");
+    let err = result.unwrap_err();
+
+    assert_eq!(err.exit_code(), exit_code::NETWORK);
+    assert!(format!("{err:#}").contains("chapter 2"), "{err:#}");
+    assert_eq!(server.hits("/n/2"), 3);
+    assert_eq!(warnings(&server.url("/n/2")).len(), 2);
+    assert!(!path.exists());
+}
+
+#[test]
+fn follows_redirect_chains() {
+    let server = Server::start(|request| match request.path {
+        "/book" => Reply::redirect("/moved"),
+        "/moved" => Reply::redirect("/index"),
+        "/index" => Reply::ok(INDEX),
+        "/n/1" => Reply::redirect("/n/1/"),
+        "/n/1/" => Reply::ok(chapter(1)),
+        _ => book(request),
+    });
+
+    let (result, path) = run(&server, "redirect");
+
+    assert_eq!(result.unwrap().chapters, 2);
+    assert_eq!(server.hits("/index"), 1);
+    assert_eq!(server.hits("/n/1/"), 1);
+    assert!(epub_text(&path).contains("本地之書"));
+}
+
+#[test]
+fn keeps_cookies_between_requests() {
+    let server = Server::start(|request| match request.path {
+        "/book" => Reply::ok(INDEX).header("Set-Cookie", "session=abc; Path=/"),
+        _ if !request
+            .header("Cookie")
+            .is_some_and(|c| c.contains("session=abc")) =>
+        {
+            Reply::status(403)
+        }
+        _ => book(request),
+    });
+
+    let (result, _) = run(&server, "cookies");
+
+    assert_eq!(result.unwrap().chapters, 2);
+    assert_eq!(server.hits("/n/1"), 1);
+}
+
+#[test]
+fn gbk_page_is_left_out_as_a_parse_error() {
+    // "第二章" and "你好" in GBK.
+    const GBK: &[u8] = b"<div class=\"name\">\xb5\xda\xb6\xfe\xd5\xc2</div><div class=\"content\">\xc4\xe3\xba\xc3</div>";
+    let server = Server::start(|request| match request.path {
+        "/n/2" => Reply::ok(GBK).header("Content-Type", "text/html; charset=gbk"),
+        _ => book(request),
+    });
+
+    let (result, path) = run(&server, "gbk");
+    let summary = result.unwrap();
+
+    assert_eq!(summary.exit_code(), exit_code::SKIPPED);
+    assert_eq!(summary.chapters, 1);
+    assert_eq!(summary.results.parse_error, 1);
+    assert!(summary.failed[0].contains("UTF-8"), "{:?}", summary.failed);
+    // In the book's work directory, named after its index URL.
+    let saved = std::fs::read_dir(path.with_file_name("work"))
+        .unwrap()
+        .map(|dir| dir.unwrap().path().join("failed").join("2.html"))
+        .find(|saved| saved.exists())
+        .expect("saved page");
+    assert!(summary.failed[0].contains(&saved.display().to_string()));
+    assert_eq!(std::fs::read(saved).unwrap(), GBK);
+    // Only its first download; a page that can't be parsed isn't retried.
+    assert_eq!(server.hits("/n/2"), 1);
+}
+
+#[test]
+fn dumps_raw_exchanges() {
+    // "你好" in GBK, served as the second chapter's text.
+    const GBK: &[u8] = b"<div class=\"content\">\xc4\xe3\xba\xc3</div>";
+    let server = Server::start(|request| match (request.path, request.hit) {
+        ("/n/1", 0) => Reply::status(429).header("Retry-After", "1"),
+        ("/n/2", _) => Reply::ok(GBK).header("Content-Type", "text/html; charset=gbk"),
+        _ => book(request),
+    });
+    let dump = std::env::temp_dir().join(format!("epub-dude-dump-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dump);
+
+    let (result, _) = run_with(&server, "dump", |f| f.dump_http(dump.clone()));
+
+    assert_eq!(result.unwrap().exit_code(), exit_code::SKIPPED);
+    let read = |name: &str| std::fs::read(dump.join(name)).unwrap();
+    // The index, the refused chapter, its retry and the GBK chapter.
+    let request = String::from_utf8(read("0002-request.txt")).unwrap();
+    assert!(request.starts_with("GET /n/1 HTTP/1.1\r\n"), "{request}");
+    assert!(request.contains("Host: 127.0.0.1:"), "{request}");
+    let refused = String::from_utf8(read("0002-response.txt")).unwrap();
+    assert!(
+        refused.starts_with("HTTP/1.1 429 Too Many Requests\r\n"),
+        "{refused}"
+    );
+    assert!(refused.contains("retry-after: 1\r\n"), "{refused}");
+    let gbk = read("0004-response.txt");
+    assert!(gbk.ends_with(GBK));
+    assert!(String::from_utf8_lossy(&gbk).contains("content-type: text/html; charset=gbk"));
+    assert!(!dump.join("0005-request.txt").exists());
+    let mapping = logs(&server.url("/n/"));
+    assert!(
+        mapping
+            .iter()
+            .any(|m| m == &format!("INFO 0004: GET {}", server.url("/n/2"))),
+        "{mapping:?}"
+    );
+}
+
+#[test]
+fn ignores_a_wrong_declared_charset() {
+    let server = Server::start(|request| match request.path {
+        "/n/1" => Reply::ok(chapter(1)).header("Content-Type", "text/html; charset=gbk"),
+        _ => book(request),
+    });
+
+    let (result, path) = run(&server, "declared-gbk");
+
+    assert_eq!(result.unwrap().chapters, 2);
+    assert!(epub_text(&path).contains("第1章的內容。"));
+}
+
+#[test]
+fn rotates_user_agent_on_403() {
+    // Blocks the client's own user agent, as some hosts do after a while.
+    let server = Server::start(|request| {
+        let blocked = request
+            .header("User-Agent")
+            .is_none_or(|ua| ua.starts_with("ureq"));
+        match request.path {
+            "/n/1" | "/n/2" if blocked => Reply::status(403),
+            "/n/1" | "/n/2" if request.header("Referer").is_none() => Reply::status(500),
+            _ => book(request),
+        }
+    });
+    let pool = vec!["Browser/1".to_string(), "Browser/2".to_string()];
+
+    let (result, path) = run_with(&server, "rotate", |f| f.rotate_user_agents(pool));
+
+    assert_eq!(result.unwrap().chapters, 2);
+    // The new agent sticks, so only the first chapter was refused.
+    assert_eq!(server.hits("/n/1"), 2);
+    assert_eq!(server.hits("/n/2"), 1);
+    assert!(epub_text(&path).contains("第2章的內容。"));
+    let rotations: Vec<String> = logs(&server.url("/n/1"))
+        .into_iter()
+        .filter(|m| m.contains("switching user agent"))
+        .collect();
+    assert_eq!(rotations.len(), 1, "{rotations:?}");
+    assert!(rotations[0].contains("Browser/1"), "{rotations:?}");
+}
+
+#[test]
+fn keeps_user_agent_without_rotation() {
+    let server = Server::start(|request| match request.path {
+        "/n/1" => Reply::status(403),
+        _ => book(request),
+    });
+
+    let (result, _) = run(&server, "no-rotate");
+
+    assert_eq!(result.unwrap_err().exit_code(), exit_code::NETWORK);
+    assert_eq!(server.hits("/n/1"), 3);
+    assert!(
+        logs(&server.url("/n/1"))
+            .iter()
+            .all(|m| !m.contains("switching user agent"))
+    );
+}
+
+#[test]
+fn replays_a_recorded_session() {
+    let server = Server::start(|request| match (request.path, request.hit) {
+        ("/n/2", 0) => Reply::status(429),
+        _ => book(request),
+    });
+    let fetcher = HttpFetcher::new(ureq::Agent::new_with_defaults())
+        .delays(Delays {
+            after_request: Duration::ZERO,
+            backoff: Duration::from_millis(10),
+        })
+        .record();
+    let (result, _) = run_through(&server, "record", &fetcher);
+    result.unwrap();
+    let file = std::env::temp_dir().join(format!("epub-dude-session-{}.json", std::process::id()));
+    fetcher.session().unwrap().save(&file).unwrap();
+
+    let session = Session::load(&file).unwrap();
+    let statuses: Vec<_> = session.exchanges.iter().map(|e| e.status).collect();
+    assert_eq!(statuses, [Some(200), Some(200), Some(429), Some(200)]);
+
+    let replay = Replay::new(session);
+    let (result, path) = run_through(&server, "replay", &replay);
+
+    assert_eq!(result.unwrap().chapters, 2);
+    assert!(replay.misses().is_empty());
+    assert!(epub_text(&path).contains("第2章的內容。"));
+    // The 429 was replayed too, and nothing reached the server again.
+    assert_eq!(server.hits("/n/2"), 2);
+    assert_eq!(server.hits("/book"), 1);
+}
+
+#[test]
+fn replay_fails_on_unrecorded_urls() {
+    let server = Server::start(book);
+    let replay = Replay::new(Session::new(Vec::new()));
+
+    let (result, _) = run_through(&server, "unrecorded", &replay);
+
+    assert!(format!("{:#}", result.unwrap_err()).contains("not in the recording"));
+    assert_eq!(replay.misses(), [server.url("/book")]);
+    assert_eq!(server.hits("/book"), 0);
+}
+
+#[test]
+fn decompresses_gzip_and_sees_through_false_claims() {
+    fn gzip(text: &str) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+    let padded = INDEX.replace("</body>", &format!("<!--{}--></body>", "填充".repeat(5000)));
+    let server = Server::start(move |request| {
+        let accepts = request
+            .header("Accept-Encoding")
+            .is_some_and(|e| e.contains("gzip"));
+        match request.path {
+            "/book" if accepts => Reply::ok(gzip(&padded)).header("Content-Encoding", "gzip"),
+            // Claims gzip, sends plain text.
+            "/n/1" => Reply::ok(chapter(1)).header("Content-Encoding", "gzip"),
+            _ => book(request),
+        }
+    });
+
+    let (result, path) = run(&server, "gzip");
+
+    let summary = result.unwrap();
+    assert_eq!(summary.chapters, 2);
+    assert!(
+        summary.transferred * 4 < summary.downloaded,
+        "{} of {}",
+        summary.transferred,
+        summary.downloaded
+    );
+    let text = epub_text(&path);
+    assert!(text.contains("本地之書") && text.contains("第1章的內容。"));
+}
+
+/// Serves one reply per connection on a free port, each `(head, body,
+/// sent)`: the response head, then the first `sent` bytes of `body` before
+/// the connection is dropped. `head` is given the request's head.
+fn serve_raw(reply: impl Fn(&str) -> (String, Vec<u8>, usize) + Send + 'static) -> String {
+    use std::io::{BufRead, BufReader, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut head = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            while reader.read_line(&mut head).unwrap_or(0) > 2 {}
+            let (response, body, sent) = reply(&head);
+            let _ = stream.write_all(response.as_bytes());
+            let _ = stream.write_all(&body[..sent]);
+        }
+    });
+    base
+}
+
+#[test]
+fn a_cut_off_download_resumes_where_it_broke_off() {
+    let image: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let served = image.clone();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&requests);
+    let base = serve_raw(move |head| {
+        let path = head.split_whitespace().nth(1).unwrap().to_string();
+        let header = |name: &str| {
+            head.lines().find_map(|line| {
+                let (n, value) = line.split_once(':')?;
+                n.eq_ignore_ascii_case(name)
+                    .then(|| value.trim().to_string())
+            })
+        };
+        let hit = {
+            let mut seen = seen.lock().unwrap();
+            seen.push((path.clone(), header("Range"), header("If-Range")));
+            seen.iter().filter(|(p, ..)| *p == path).count() - 1
+        };
+        let ranges = !path.starts_with("/plain");
+        let reply = |etag: &str, cut: Option<usize>| {
+            let from: usize = header("Range")
+                .and_then(|r| r.strip_prefix("bytes=")?.strip_suffix('-')?.parse().ok())
+                .unwrap_or(0);
+            let mut response = if from > 0 {
+                format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {from}-{}/{}\r\n",
+                    served.len() - 1,
+                    served.len()
+                )
+            } else {
+                "HTTP/1.1 200 OK\r\n".to_string()
+            };
+            if ranges {
+                response.push_str("Accept-Ranges: bytes\r\n");
+            }
+            let body = served[from..].to_vec();
+            response.push_str(&format!(
+                "Content-Type: image/png\r\nETag: {etag}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            ));
+            let sent = cut.unwrap_or(body.len());
+            (response, body, sent)
+        };
+        match (path.as_str(), hit) {
+            // Breaks off twice, resumed both times.
+            ("/resumed.png", 0) => reply("\"v1\"", Some(50_000)),
+            ("/resumed.png", 1) => reply("\"v1\"", Some(70_000)),
+            ("/resumed.png", _) => reply("\"v1\"", None),
+            // Changed in between, so downloaded again.
+            ("/changed.png", 0) => reply("\"v1\"", Some(50_000)),
+            ("/changed.png", _) => reply("\"v2\"", None),
+            ("/plain.png", 0) => reply("\"v1\"", Some(50_000)),
+            _ => reply("\"v1\"", None),
+        }
+    });
+    let fetcher = HttpFetcher::new(ureq::Agent::new_with_defaults()).delays(Delays {
+        after_request: Duration::ZERO,
+        backoff: Duration::from_millis(10),
+    });
+
+    for path in ["/resumed.png", "/changed.png", "/plain.png"] {
+        let response = fetcher.get(&format!("{base}{path}")).unwrap();
+        assert!(response.body == image, "{path}");
+    }
+    let v1 = Some("\"v1\"".to_string());
+    let range = |from: usize| Some(format!("bytes={from}-"));
+    let requests = requests.lock().unwrap();
+    let of = |path: &str| {
+        requests
+            .iter()
+            .filter(|(p, ..)| p == path)
+            .map(|(_, range, validator)| (range.clone(), validator.clone()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        of("/resumed.png"),
+        [
+            (None, None),
+            (range(50_000), v1.clone()),
+            (range(120_000), v1.clone())
+        ]
+    );
+    assert_eq!(
+        of("/changed.png"),
+        [(None, None), (range(50_000), v1), (None, None)]
+    );
+    assert_eq!(of("/plain.png"), [(None, None), (None, None)]);
+}
+
+/// A fetcher whose backoff would sleep far past any limit in these tests.
+fn patient() -> HttpFetcher {
+    HttpFetcher::new(ureq::Agent::new_with_defaults()).delays(Delays {
+        after_request: Duration::ZERO,
+        backoff: Duration::from_secs(30),
+    })
+}
+
+#[test]
+fn the_deadline_stops_retries_and_keeps_the_book_so_far() {
+    let server = Server::start(|request| match request.path {
+        "/n/2" => Reply::status(429),
+        _ => book(request),
+    });
+    let deadline = Instant::now() + Duration::from_millis(500);
+    let fetcher = patient().time_limits(TimeLimits {
+        deadline: Some(deadline),
+        per_page: None,
+    });
+    let options = BuildOptions {
+        deadline: Some(deadline),
+        ..BuildOptions::default()
+    };
+
+    let started = Instant::now();
+    let (result, path) = run_options(&server, "deadline", &fetcher, options);
+
+    assert!(started.elapsed() < Duration::from_secs(10));
+    let e = result.unwrap_err();
+    assert_eq!(e.exit_code(), exit_code::DEADLINE);
+    let Error::Deadline {
+        chapters,
+        of,
+        partial,
+    } = e
+    else {
+        panic!("{e}");
+    };
+    assert_eq!((chapters, of), (1, 2));
+    assert_eq!(partial, path.with_file_name("book.partial.epub"));
+    assert!(epub_text(&partial).contains("第1章的內容。"));
+    assert!(!path.exists());
+}
+
+#[test]
+fn the_chapter_timeout_bounds_retries() {
+    let server = Server::start(|request| match request.path {
+        "/n/2" => Reply::status(429),
+        _ => book(request),
+    });
+    let fetcher = patient().time_limits(TimeLimits {
+        deadline: None,
+        per_page: Some(Duration::from_millis(300)),
+    });
+
+    let started = Instant::now();
+    let (result, _) = run_through(&server, "chapter-timeout", &fetcher);
+
+    assert!(started.elapsed() < Duration::from_secs(10));
+    let e = result.unwrap_err();
+    assert_eq!(e.exit_code(), exit_code::NETWORK);
+    assert!(format!("{e:#}").contains("gave up after 300ms"), "{e:#}");
+}
+
+#[test]
+fn resolve_pins_a_host_to_an_address() {
+    let server = Server::start(book);
+    let url = server.url("/book");
+    let port = url.rsplit(':').next().unwrap().split('/').next().unwrap();
+    let pin: resolve::Pin = format!("novel.test:{port}:127.0.0.1").parse().unwrap();
+    assert_eq!(pin.port.to_string(), port);
+    assert!("novel.test:80".parse::<resolve::Pin>().is_err());
+    assert!("novel.test:80:[::1]".parse::<resolve::Pin>().is_ok());
+
+    let fetcher = HttpFetcher::new(resolve::agent(
+        IpFamily::Ipv4Only,
+        vec![pin],
+        tls::config(None, false).unwrap(),
+        true,
+    ));
+    let source = BookSource::with_site(
+        url.replace("127.0.0.1", "novel.test").parse().unwrap(),
+        Site::of::<CzBooksProvider>(),
+    );
+    let info = source.info(&fetcher).unwrap();
+
+    assert_eq!(info.links.len(), 2);
+    assert!(info.links[0].uri.to_string().contains("novel.test"));
+    assert_eq!(server.hits("/book"), 1);
+}
+
+#[test]
+fn sequential_requests_reuse_one_connection() {
+    let fetch = |keepalive| {
+        let server = Server::start(book);
+        let fetcher = HttpFetcher::new(resolve::agent(
+            IpFamily::Any,
+            Vec::new(),
+            tls::config(None, false).unwrap(),
+            keepalive,
+        ))
+        .delays(Delays {
+            after_request: Duration::ZERO,
+            backoff: Duration::from_millis(10),
+        });
+        logs("");
+        for path in ["/book", "/n/1", "/n/2"] {
+            fetcher.get(&server.url(path)).unwrap();
+        }
+        let opened = logs(&server.base)
+            .iter()
+            .filter(|m| m.contains("over new connection"))
+            .count();
+        let reused = logs(&server.base)
+            .iter()
+            .filter(|m| m.contains("reusing connection"))
+            .count();
+        (opened, reused)
+    };
+
+    assert_eq!(fetch(true), (1, 2));
+    assert_eq!(fetch(false), (3, 0));
+}
+
+#[test]
+fn tls_failures_name_the_host() {
+    // Answers the handshake in plain text, as a misconfigured port would.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("https://{}/book", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let _ = std::io::Write::write_all(
+                &mut stream.unwrap(),
+                b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n",
+            );
+        }
+    });
+    let fetcher = HttpFetcher::new(ureq::Agent::new_with_defaults());
+
+    let e = fetcher.get(&url).err().unwrap();
+
+    assert!(e.to_string().contains("TLS with 127.0.0.1 failed"), "{e}");
+
+    let dir = std::env::temp_dir().join(format!("epub-dude-http-{}-cacert", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let empty = dir.join("empty.pem");
+    std::fs::write(&empty, "not a certificate\n").unwrap();
+    let e = tls::config(Some(&empty), false).unwrap_err();
+    assert!(e.to_string().contains("holds no certificates"), "{e:#}");
+    assert!(tls::config(Some(&dir.join("missing.pem")), false).is_err());
+}
+
+/// A recording of `server`'s book in which `path` answered `status` with
+/// `body`, for runs that go wrong in a known way.
+fn scripted(server: &Server, path: &str, status: u16, body: &str) -> Replay {
+    let exchanges = [
+        ("/book", INDEX.to_string()),
+        ("/n/1", chapter(1)),
+        ("/n/2", chapter(2)),
+    ]
+    .into_iter()
+    .map(|(page, text)| {
+        let (status, text) = if page == path {
+            (status, body.to_string())
+        } else {
+            (200, text)
+        };
+        Exchange {
+            url: server.url(page),
+            request_headers: Vec::new(),
+            status: Some(status),
+            headers: Vec::new(),
+            body: text.into_bytes(),
+            error: None,
+        }
+    })
+    .collect();
+    Replay::new(Session::new(exchanges))
+}
+
+#[test]
+fn each_outcome_has_its_exit_code() {
+    let server = Server::start(book);
+    let outcome = |name, replay: &Replay, options| {
+        let (result, _) = run_options(&server, name, replay, options);
+        let code = match &result {
+            Ok(summary) => summary.exit_code(),
+            Err(e) => e.exit_code(),
+        };
+        let mut outcome = RunResult::default();
+        outcome.record(&result);
+        outcome.finish(code, Duration::ZERO);
+        outcome
+    };
+
+    let built = outcome(
+        "code-built",
+        &scripted(&server, "/n/2", 200, &chapter(2)),
+        BuildOptions::default(),
+    );
+    assert_eq!(
+        (built.exit_code, built.status),
+        (exit_code::SUCCESS, "built")
+    );
+    assert_eq!(
+        built.chapters,
+        ChapterCounts {
+            total: 2,
+            succeeded: 2,
+            failed: 0,
+            skipped: 0
+        }
+    );
+    assert!(built.output.as_ref().unwrap().ends_with("book.epub"));
+
+    let skipped = outcome(
+        "code-skipped",
+        &scripted(&server, "/n/2", 404, ""),
+        BuildOptions::default(),
+    );
+    assert_eq!(
+        (skipped.exit_code, skipped.status),
+        (exit_code::SKIPPED, "skipped-chapters")
+    );
+    assert_eq!(
+        (skipped.chapters.succeeded, skipped.chapters.failed),
+        (1, 1)
+    );
+
+    let network = outcome(
+        "code-network",
+        &scripted(&server, "/n/2", 500, ""),
+        BuildOptions::default(),
+    );
+    assert_eq!(
+        (network.exit_code, network.status),
+        (exit_code::NETWORK, "failed")
+    );
+    assert!(
+        network.errors[0].contains("HTTP 500"),
+        "{:?}",
+        network.errors
+    );
+
+    let parse = outcome(
+        "code-parse",
+        &scripted(&server, "/book", 200, "<html><body>維護中</body></html>"),
+        BuildOptions::default(),
+    );
+    assert_eq!(parse.exit_code, exit_code::PARSE, "{:?}", parse.errors);
+
+    let up_to_date = outcome(
+        "code-up-to-date",
+        &scripted(&server, "/n/2", 200, &chapter(2)),
+        BuildOptions {
+            title_filter: TitleFilter::new(&[], &["章".to_string()]).unwrap(),
+            ..BuildOptions::default()
+        },
+    );
+    assert_eq!(
+        (up_to_date.exit_code, up_to_date.status),
+        (exit_code::UP_TO_DATE, "up-to-date")
+    );
+    assert_eq!(up_to_date.chapters.skipped, 2);
+
+    let json = serde_json::to_value(&built).unwrap();
+    for key in [
+        "status",
+        "exit_code",
+        "output",
+        "chapters",
+        "downloaded",
+        "duration_secs",
+    ] {
+        assert!(json.get(key).is_some(), "{key} missing from {json}");
+    }
+}
+
+#[test]
+fn progress_is_logged_every_hundred_chapters_and_each_url_at_debug() {
+    let server = Server::start(|request| match request.path {
+        "/book" => {
+            let items: String = (1..=150)
+                .map(|n| format!(r#"<li><a href="/n/{n}">第{n}章</a></li>"#))
+                .collect();
+            Reply::ok(format!(
+                r#"<span class="title">長篇</span><span class="author"><a>作者</a></span><ul id="chapter-list">{items}</ul>"#
+            ))
+        }
+        path => match path.strip_prefix("/n/").and_then(|n| n.parse().ok()) {
+            Some(n) => Reply::ok(chapter(n)),
+            None => Reply::status(404),
+        },
+    });
+
+    let (result, _) = run(&server, "progress-log");
+
+    assert_eq!(result.unwrap().chapters, 150);
+    let progress = logs("chapter 100/150, ");
+    assert_eq!(progress.len(), 1, "{progress:?}");
+    assert!(progress[0].starts_with("INFO "), "{progress:?}");
+    assert!(
+        progress[0].contains(" elapsed, about 00:00:"),
+        "{progress:?}"
+    );
+    assert!(logs("chapter 150/150, ").is_empty());
+    let url = logs(&format!("chapter 42/150: {}", server.url("/n/42")));
+    assert_eq!(url.len(), 1, "{url:?}");
+    assert!(url[0].starts_with("DEBUG "), "{url:?}");
+}
+
+#[test]
+fn time_is_broken_down_by_phase() {
+    let server = Server::start(|request| {
+        if request.path == "/n/2" {
+            thread::sleep(Duration::from_millis(200));
+        }
+        book(request)
+    });
+
+    let (result, _) = run(&server, "timings");
+
+    let timings = result.as_ref().unwrap().timings.clone();
+    assert!(timings.fetch >= Duration::from_millis(200), "{timings}");
+    assert!(timings.extract > Duration::ZERO, "{timings}");
+    assert!(timings.xhtml > Duration::ZERO, "{timings}");
+    assert!(timings.generate > Duration::ZERO, "{timings}");
+    assert!(timings.fetch > timings.total() / 2, "{timings}");
+    assert!(
+        !logs("chapter 2 took fetch 0.2").is_empty(),
+        "{:?}",
+        logs("took fetch")
+    );
+
+    let mut outcome = RunResult::default();
+    outcome.record(&result);
+    let json = serde_json::to_value(&outcome).unwrap();
+    assert!(
+        json["phase_secs"]["fetch"].as_f64().unwrap() >= 0.2,
+        "{json}"
+    );
+    for phase in ["decode", "extract", "xhtml", "add", "generate"] {
+        assert!(
+            json["phase_secs"][phase].is_f64(),
+            "{phase} missing from {json}"
+        );
+    }
+}
+
+#[test]
+fn a_429_is_remembered_for_the_host() {
+    let server = Server::start(|request| match (request.path, request.hit) {
+        ("/n/1", 0) => Reply::status(429),
+        _ => book(request),
+    });
+    let dir = std::env::temp_dir().join(format!("epub-dude-http-{}-hosts", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let hosts = dir.join("config").join("hosts.json");
+
+    let fetcher = HttpFetcher::new(ureq::Agent::new_with_defaults())
+        .delays(Delays {
+            after_request: Duration::ZERO,
+            backoff: Duration::from_millis(10),
+        })
+        .politeness(hosts.clone(), true);
+    let (result, _) = run_through(&server, "hosts", &fetcher);
+    result.unwrap();
+    fetcher.save_politeness().unwrap();
+
+    let profiles = Profiles::load(&hosts);
+    let profile = &profiles.hosts["127.0.0.1"];
+    assert_eq!((profile.requests, profile.throttled), (4, 1));
+    assert!(profile.last_throttled.is_some());
+    assert_eq!(profile.delay_ms, 500);
+    assert_eq!(
+        profiles.delay("127.0.0.1", Duration::ZERO),
+        Duration::from_millis(500)
+    );
+    assert_eq!(
+        profiles.delay("elsewhere.test", Duration::ZERO),
+        Duration::ZERO
+    );
+
+    // Without learning the stored profile is used but left alone.
+    let before = std::fs::read_to_string(&hosts).unwrap();
+    let fetcher =
+        HttpFetcher::new(ureq::Agent::new_with_defaults()).politeness(hosts.clone(), false);
+    assert!(fetcher.get(&server.url("/n/2")).is_ok());
+    fetcher.save_politeness().unwrap();
+    assert_eq!(std::fs::read_to_string(&hosts).unwrap(), before);
+}
+
+#[test]
+fn host_profiles_back_off_and_ease() {
+    let base = Duration::from_millis(900);
+    let mut profiles = Profiles::default();
+
+    assert!(profiles.learn("a.test", base, true, 4));
+    assert_eq!(profiles.hosts["a.test"].delay_ms, 1400);
+    assert_eq!(profiles.hosts["a.test"].jobs, Some(3));
+    assert_eq!(profiles.jobs("a.test", 8), 3);
+    assert_eq!(profiles.jobs("a.test", 2), 2);
+
+    let eased = (0..100)
+        .filter(|_| profiles.learn("a.test", base, false, 1))
+        .count();
+    assert_eq!(eased, 1);
+    assert_eq!(profiles.hosts["a.test"].delay_ms, 1260);
+    assert!((profiles.hosts["a.test"].throttle_rate() - 1.0 / 101.0).abs() < 1e-9);
+
+    // Once back at the base delay, the concurrency cap lifts instead.
+    for _ in 0..500 {
+        profiles.learn("a.test", base, false, 1);
+    }
+    assert_eq!(profiles.delay("a.test", base), base);
+    assert!(profiles.hosts["a.test"].jobs.unwrap() > 3);
+}
@@ -0,0 +1,119 @@
+//! How much a build holds at once, measured by counting allocations. In a
+//! binary of its own, as the allocator counts every thread's.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use epub_dude::{BookSource, BuildOptions, MemoryFetcher, build_epub};
+
+struct Counting;
+
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let now = IN_USE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(now, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const CHAPTERS: usize = 1000;
+
+/// A book of `CHAPTERS` chapters of `chapter_kb` of different text each,
+/// so the finished archive compresses about as well as a real one.
+fn book(index_url: &str, chapter_kb: usize) -> MemoryFetcher {
+    let items: String = (1..=CHAPTERS)
+        .map(|n| format!(r#"<li><a href="{index_url}/{n}">第{n}章</a></li>"#))
+        .collect();
+    let mut fetcher = MemoryFetcher::new().page(
+        index_url,
+        format!(
+            r#"<span class="title">長篇</span><span class="author"><a>作者</a></span><ul id="chapter-list">{items}</ul>"#
+        ),
+    );
+    let mut seed = 1u32;
+    for n in 1..=CHAPTERS {
+        let mut text = String::new();
+        while text.len() < chapter_kb * 1024 {
+            text.push_str("<p>");
+            for _ in 0..40 {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                text.push(char::from_u32(0x4E00 + (seed >> 16) % 2000).unwrap());
+            }
+            text.push_str("</p>\n");
+        }
+        fetcher = fetcher.page(
+            &format!("{index_url}/{n}"),
+            format!(
+                r#"<html><body><div class="name">第{n}章</div><div class="content">{text}</div></body></html>"#
+            ),
+        );
+    }
+    fetcher
+}
+
+/// The most a build of the book at `index_url` held at once, in bytes.
+fn held(index_url: &str, fetcher: &MemoryFetcher) -> usize {
+    let dir = std::env::temp_dir().join(format!("epub-dude-{}-memory", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let name = index_url.rsplit('/').next().unwrap();
+    let options = BuildOptions {
+        output: dir
+            .join(format!("{name}.epub"))
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap(),
+        work_dir: dir.join("work"),
+        ..BuildOptions::default()
+    };
+    let source = BookSource::new(index_url.parse().unwrap()).unwrap();
+
+    let before = IN_USE.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let summary = build_epub(&source, fetcher, &options, &()).unwrap();
+    assert_eq!(summary.chapters, CHAPTERS);
+    PEAK.load(Ordering::Relaxed) - before
+}
+
+#[test]
+fn a_build_holds_one_chapter_at_a_time() {
+    let short = "https://czbooks.net/n/short";
+    let long = "https://czbooks.net/n/long";
+    let (short_book, long_book) = (book(short, 1), book(long, 16));
+
+    // Sixteen times the text, 15 MB more of it, costs no more than a few
+    // chapters: what a build holds grows with the number of chapters, not
+    // with the book. Second builds read the pages the first ones stored
+    // back in.
+    for build in ["first", "second"] {
+        let baseline = held(short, &short_book);
+        let held = held(long, &long_book);
+        eprintln!(
+            "{build} build held at most {} KB, {} KB with chapters of 1 KB",
+            held / 1024,
+            baseline / 1024
+        );
+        assert!(
+            held < baseline + 16 * 16 * 1024,
+            "{build} build held {} KB at once, {} KB with chapters of 1 KB",
+            held / 1024,
+            baseline / 1024
+        );
+    }
+}
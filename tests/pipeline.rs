@@ -0,0 +1,4152 @@
+//! Drives the whole index → chapters → epub flow against canned pages.
+
+use std::{fs::File, io::Read, path::PathBuf};
+
+use epub_dude::{
+    BookSource, BuildOptions, Error, Fetcher, MemoryFetcher, SortOrder, build_anthology,
+    build_epub, exit_code,
+    fetch::{Limits, Site, czbooksnet::CzBooksProvider},
+    fetcher::Response,
+    numbering,
+    selection::{self, ChapterListing, ListFormat, TitleFilter},
+    template::ChapterTemplate,
+    workdir,
+};
+use html5ever::tendril::StrTendril;
+use zip::ZipArchive;
+
+const INDEX_URL: &str = "https://czbooks.net/n/test";
+
+const INDEX: &str = r#"<html><body>
+<span class="title">測試之書</span>
+<span class="author"><a href="/a/1">作者甲</a></span>
+<ul id="chapter-list">
+  <li><a href="//czbooks.net/n/test/1">第一章 開始</a></li>
+  <li><a href="//czbooks.net/n/test/2">第二章 結束</a></li>
+</ul>
+</body></html>"#;
+
+fn chapter(title: &str, text: &str) -> String {
+    format!(
+        r#"<html><body><div class="name">{title}</div><div class="content">{text}</div></body></html>"#
+    )
+}
+
+fn book() -> MemoryFetcher {
+    MemoryFetcher::new()
+        .page(INDEX_URL, INDEX)
+        .page(
+            "https://czbooks.net/n/test/1",
+            chapter("第一章 開始", "<p>很久很久以前。</p>"),
+        )
+        .page(
+            "https://czbooks.net/n/test/2",
+            chapter("第二章 結束", "<p>從此以後。</p>"),
+        )
+}
+
+/// A per-test output path, so tests can run in parallel.
+fn output(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("epub-dude-{}-{name}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join("book.epub")
+}
+
+fn options(path: &std::path::Path) -> BuildOptions {
+    BuildOptions {
+        output: path.to_str().unwrap().parse().unwrap(),
+        work_dir: path.with_file_name("work"),
+        ..BuildOptions::default()
+    }
+}
+
+/// Every text entry in the archive, by name.
+fn entries(path: &std::path::Path) -> Vec<(String, String)> {
+    let mut zip = ZipArchive::new(File::open(path).unwrap()).unwrap();
+    (0..zip.len())
+        .filter_map(|i| {
+            let mut file = zip.by_index(i).unwrap();
+            let mut content = String::new();
+            file.read_to_string(&mut content).ok()?;
+            Some((file.name().to_string(), content))
+        })
+        .collect()
+}
+
+/// The text of the archive entry whose name ends with `suffix`.
+fn entry(path: &std::path::Path, suffix: &str) -> String {
+    entries(path)
+        .into_iter()
+        .find(|(name, _)| name.ends_with(suffix))
+        .unwrap_or_else(|| panic!("no entry ending with {suffix}"))
+        .1
+}
+
+/// An index page listing links titled `titles`, to chapters 1, 2 and so on
+/// in that order.
+fn index_of(titles: &[&str]) -> String {
+    let items: String = titles
+        .iter()
+        .enumerate()
+        .map(|(i, title)| format!(r#"<li><a href="/n/test/{}">{title}</a></li>"#, i + 1))
+        .collect();
+    INDEX.replace(
+        &INDEX[INDEX.find("<li>").unwrap()..INDEX.rfind("</li>").unwrap() + "</li>".len()],
+        &items,
+    )
+}
+
+fn source() -> BookSource {
+    BookSource::new(INDEX_URL.parse().unwrap()).unwrap()
+}
+
+#[test]
+fn builds_an_epub_from_canned_pages() {
+    let path = output("canned");
+    let fetcher = book();
+
+    let summary = build_epub(&source(), &fetcher, &options(&path), &()).unwrap();
+
+    assert_eq!(summary.chapters, 2);
+    assert_eq!(
+        fetcher.requests(),
+        [
+            INDEX_URL,
+            "https://czbooks.net/n/test/1",
+            "https://czbooks.net/n/test/2"
+        ]
+    );
+
+    let entries = entries(&path);
+    let find = |needle: &str| entries.iter().any(|(_, content)| content.contains(needle));
+    assert!(find("很久很久以前。"));
+    assert!(find("從此以後。"));
+
+    let (_, opf) = entries
+        .iter()
+        .find(|(name, _)| name.ends_with(".opf"))
+        .expect("package document");
+    assert!(opf.contains("測試之書"));
+    assert!(opf.contains("作者甲"));
+
+    let (_, nav) = entries
+        .iter()
+        .find(|(name, _)| name.ends_with("nav.xhtml"))
+        .expect("navigation document");
+    assert!(nav.contains("第一章 開始"));
+    assert!(nav.find("第一章").unwrap() < nav.find("第二章").unwrap());
+}
+
+#[test]
+fn unreachable_chapter_is_a_network_error() {
+    let path = output("unreachable");
+    let fetcher = book().status("https://czbooks.net/n/test/2", 503);
+
+    let err = build_epub(&source(), &fetcher, &options(&path), &()).unwrap_err();
+
+    assert_eq!(err.exit_code(), exit_code::NETWORK);
+    assert!(err.to_string().contains("chapter 2"), "{err}");
+    assert!(!path.exists());
+}
+
+/// Fails the second chapter with an error that doesn't say which page it
+/// was about, as one from deep in a dependency wouldn't.
+struct FailingSecond(MemoryFetcher);
+
+impl Fetcher for FailingSecond {
+    fn get(&self, url: &str) -> epub_dude::Result<Response> {
+        if url == "https://czbooks.net/n/test/2" {
+            return Err(anyhow::anyhow!("unexpected end of input").into());
+        }
+        self.0.get(url)
+    }
+}
+
+#[test]
+fn a_failure_mid_book_names_the_chapter_and_what_it_was_doing() {
+    let path = output("mid-book");
+
+    let err = build_epub(&source(), &FailingSecond(book()), &options(&path), &()).unwrap_err();
+
+    let causes: Vec<String> =
+        std::iter::successors(std::error::Error::source(&err), |cause| cause.source())
+            .map(|cause| cause.to_string())
+            .collect();
+    assert_eq!(
+        err.to_string(),
+        r#"chapter 2 "第二章 結束" (https://czbooks.net/n/test/2), fetching"#
+    );
+    assert_eq!(causes, ["unexpected end of input"]);
+    assert_eq!(err.exit_code(), exit_code::FAILURE);
+}
+
+#[test]
+fn staged_files_are_removed_whether_the_build_succeeds_or_fails() {
+    let path = output("temp-dir");
+    let temp = path.with_file_name("temp");
+    let options = BuildOptions {
+        temp_dir: Some(temp.clone()),
+        ..options(&path)
+    };
+    let staged = || std::fs::read_dir(&temp).unwrap().count();
+
+    build_epub(&source(), &book(), &options, &()).unwrap();
+    assert!(path.exists());
+    assert_eq!(staged(), 0);
+
+    build_epub(&source(), &FailingSecond(book()), &options, &()).unwrap_err();
+    assert_eq!(staged(), 0);
+}
+
+#[test]
+fn a_failed_chapter_keeps_its_typed_cause_under_the_context() {
+    let path = output("typed-cause");
+    let fetcher = book().status("https://czbooks.net/n/test/2", 503);
+
+    let err = build_epub(&source(), &fetcher, &options(&path), &()).unwrap_err();
+
+    let Error::Other(chain) = &err else {
+        panic!("{err:?}");
+    };
+    let cause = chain
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<Error>())
+        .unwrap_or_else(|| panic!("{err:#}"));
+    assert!(
+        matches!(cause, Error::Fetch { url, status: Some(503), .. } if url == "https://czbooks.net/n/test/2"),
+        "{cause:?}"
+    );
+    assert_eq!(cause.exit_code(), err.exit_code());
+    assert_eq!(err.permanent_status(), None);
+    // One line for scripts, naming the chapter and what went wrong with it.
+    let line = format!("{err:#}");
+    assert!(!line.contains('\n'), "{line}");
+    assert!(
+        line.contains("chapter 2") && line.contains("https://czbooks.net/n/test/2"),
+        "{line}"
+    );
+
+    let gone = Error::Fetch {
+        url: "https://czbooks.net/n/test/2".to_string(),
+        status: Some(410),
+        reason: "HTTP 410".to_string(),
+    };
+    assert_eq!(gone.permanent_status(), Some(410));
+    assert_eq!(
+        Error::Other(anyhow::Error::new(gone).context("chapter 2")).permanent_status(),
+        Some(410)
+    );
+    assert_eq!(
+        Error::Usage("bad".to_string()).exit_code(),
+        exit_code::USAGE
+    );
+    assert_eq!(
+        Error::Other(anyhow::anyhow!("unexpected")).exit_code(),
+        exit_code::FAILURE
+    );
+}
+
+#[test]
+fn an_unusable_temp_dir_fails_before_any_chapter_is_fetched() {
+    let path = output("bad-temp-dir");
+    let file = path.with_file_name("not-a-dir");
+    std::fs::write(&file, "").unwrap();
+    let fetcher = book();
+    let options = BuildOptions {
+        temp_dir: Some(file.join("temp")),
+        ..options(&path)
+    };
+
+    let err = build_epub(&source(), &fetcher, &options, &()).unwrap_err();
+
+    assert_eq!(err.exit_code(), exit_code::OUTPUT);
+    assert!(format!("{err:#}").contains("not-a-dir"), "{err:#}");
+    assert_eq!(fetcher.requests(), [INDEX_URL]);
+    assert!(!path.exists());
+}
+
+#[test]
+fn index_without_chapters_is_a_parse_error() {
+    let path = output("empty");
+    let fetcher = MemoryFetcher::new().page(INDEX_URL, "<html><body></body></html>");
+
+    let err = build_epub(&source(), &fetcher, &options(&path), &()).unwrap_err();
+
+    assert_eq!(err.exit_code(), exit_code::PARSE);
+    let Error::EmptyIndex { missing, saved, .. } = &err else {
+        panic!("{err}");
+    };
+    assert_eq!(
+        missing,
+        &[
+            "chapter links (ul#chapter-list a)",
+            "title (.title)",
+            "author (.author a)"
+        ]
+    );
+    let saved = saved.as_ref().unwrap();
+    assert!(err.to_string().contains(&saved.display().to_string()));
+    assert_eq!(
+        std::fs::read_to_string(saved).unwrap(),
+        "<html><body></body></html>"
+    );
+}
+
+#[test]
+fn an_index_without_an_author_credits_unknown_unless_one_is_required() {
+    let path = output("no-author");
+    let index = INDEX.replace(
+        r#"<span class="author"><a href="/a/1">作者甲</a></span>"#,
+        "",
+    );
+    let fetcher = book().page(INDEX_URL, index);
+
+    let summary = build_epub(&source(), &fetcher, &options(&path), &()).unwrap();
+    assert!(
+        summary
+            .warnings
+            .iter()
+            .any(|w| w.contains("found no author (.author a)") && w.contains("--author")),
+        "{:?}",
+        summary.warnings
+    );
+    let opf = entries(&path)
+        .into_iter()
+        .find(|(name, _)| name.ends_with(".opf"))
+        .unwrap()
+        .1;
+    assert!(opf.contains(">Unknown</dc:creator>"), "{opf}");
+
+    let required = BuildOptions {
+        require_author: true,
+        ..options(&path)
+    };
+    std::fs::remove_file(&path).unwrap();
+    let err = build_epub(&source(), &fetcher, &required, &()).unwrap_err();
+    assert_eq!(err.exit_code(), exit_code::PARSE);
+    assert!(
+        err.to_string().contains(
+            "found no author (.author a) on https://czbooks.net/n/test (set with --author)"
+        ),
+        "{err}"
+    );
+    assert!(!path.exists());
+
+    let with_author = BuildOptions {
+        authors: vec!["作者丙".to_string()],
+        ..required
+    };
+    build_epub(&source(), &fetcher, &with_author, &()).unwrap();
+}
+
+#[test]
+fn title_and_author_overrides_replace_the_scraped_ones_everywhere() {
+    let path = output("overrides");
+    let index = INDEX.replace(
+        r#"<span class="author"><a href="/a/1">作者甲</a></span>"#,
+        "",
+    );
+    let fetcher = book().page(INDEX_URL, index);
+    let options = BuildOptions {
+        output: path
+            .with_file_name("{author} - {title}.epub")
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap(),
+        title: Some("真正的書名".to_string()),
+        authors: vec!["譯者乙".to_string(), "作者丙".to_string()],
+        ..options(&path)
+    };
+
+    let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    assert!(
+        !summary
+            .warnings
+            .iter()
+            .any(|w| w.contains("found no author")),
+        "{:?}",
+        summary.warnings
+    );
+    let named = path.with_file_name("譯者乙, 作者丙 - 真正的書名.epub");
+    let opf = entry(&named, ".opf");
+    assert!(opf.contains(">真正的書名</dc:title>"), "{opf}");
+    assert!(!opf.contains("測試之書"), "{opf}");
+    assert!(opf.contains(">譯者乙</dc:creator>"), "{opf}");
+    assert!(opf.contains(">作者丙</dc:creator>"), "{opf}");
+}
+
+#[test]
+fn every_co_author_in_the_author_span_is_credited() {
+    let path = output("co-authors");
+    let index = INDEX.replace(
+        r#"<span class="author"><a href="/a/1">作者甲</a></span>"#,
+        r#"<span class="author"><a href="/a/1">作者甲</a> / <a href="/a/2">作者乙、作者丙</a></span>"#,
+    );
+    let fetcher = book().page(INDEX_URL, index);
+
+    build_epub(&source(), &fetcher, &options(&path), &()).unwrap();
+
+    let opf = entries(&path)
+        .into_iter()
+        .find(|(name, _)| name.ends_with(".opf"))
+        .unwrap()
+        .1;
+    let creators: Vec<&str> = opf
+        .split("<dc:creator")
+        .skip(1)
+        .filter_map(|c| c.split_once('>')?.1.split_once("</dc:creator>"))
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(creators, ["作者甲", "作者乙", "作者丙"], "{opf}");
+}
+
+#[test]
+fn translators_and_illustrators_are_credited_with_their_roles() {
+    use epub_dude::metadata::{Contributor, Role};
+
+    let path = output("contributors");
+    let options = BuildOptions {
+        contributors: vec![
+            Contributor {
+                name: "譯者乙".to_string(),
+                role: Role::Translator,
+            },
+            Contributor {
+                name: "繪者丙".to_string(),
+                role: Role::Illustrator,
+            },
+        ],
+        ..options(&path)
+    };
+
+    build_epub(&source(), &book(), &options, &()).unwrap();
+
+    let opf = entry(&path, ".opf");
+    for (i, name, code) in [(0, "譯者乙", "trl"), (1, "繪者丙", "ill")] {
+        assert!(
+            opf.contains(&format!(
+                r#"<meta id="epub-contributor-{i}" property="dcterms:contributor">{name}</meta>"#
+            )),
+            "{opf}"
+        );
+        assert!(
+            opf.contains(&format!(
+                r##"<meta refines="#epub-contributor-{i}" scheme="marc:relators" property="role">{code}</meta>"##
+            )),
+            "{opf}"
+        );
+    }
+    let title_page = entry(&path, "title.xhtml");
+    assert!(
+        title_page.contains(r#"<p class="author">作者甲</p>"#),
+        "{title_page}"
+    );
+    assert!(
+        title_page.contains(r#"<p class="contributor">Translator: 譯者乙</p>"#),
+        "{title_page}"
+    );
+    assert!(
+        title_page.contains(r#"<p class="contributor">Illustrator: 繪者丙</p>"#),
+        "{title_page}"
+    );
+}
+
+#[test]
+fn labels_are_stripped_from_the_scraped_author_and_title() {
+    let path = output("labels");
+    let index = INDEX
+        .replace(
+            r#"<a href="/a/1">作者甲</a>"#,
+            r#"<a href="/a/1">作者：作者甲</a> / <a href="/a/2"> AUTHOR : Writer B</a>"#,
+        )
+        .replace("測試之書", "測試之書 最新章节");
+    let fetcher = book().page(INDEX_URL, index);
+
+    build_epub(&source(), &fetcher, &options(&path), &()).unwrap();
+
+    let opf = entries(&path)
+        .into_iter()
+        .find(|(name, _)| name.ends_with(".opf"))
+        .unwrap()
+        .1;
+    assert!(opf.contains(">作者甲</dc:creator>"), "{opf}");
+    assert!(opf.contains(">Writer B</dc:creator>"), "{opf}");
+    assert!(!opf.contains("作者：") && !opf.contains("AUTHOR"), "{opf}");
+    assert!(opf.contains(">測試之書</dc:title>"), "{opf}");
+}
+
+#[test]
+fn title_cleanup_strips_site_names_and_the_rules_given() {
+    use epub_dude::cleanup::{Cleanup, Field};
+
+    let defaults = Cleanup::defaults(Field::Title);
+    assert_eq!(
+        defaults.apply("诡秘之主最新章节_诡秘之主无弹窗_某某小说网"),
+        "诡秘之主"
+    );
+    assert_eq!(defaults.apply("诡秘之主 | 某某小说网"), "诡秘之主");
+    assert_eq!(defaults.apply("诡秘之主（全文阅读）"), "诡秘之主");
+    // A rule that would leave nothing is skipped.
+    assert_eq!(defaults.apply("_某某小说网"), "_某某小说网");
+
+    let extra = Cleanup::new(Field::Title, true, &["^【[^】]*】".to_string()]).unwrap();
+    assert_eq!(extra.apply("【完结】诡秘之主_某某小说网"), "诡秘之主");
+    let only = Cleanup::new(Field::Title, false, &["^【[^】]*】".to_string()]).unwrap();
+    assert_eq!(
+        only.apply("【完结】诡秘之主_某某小说网"),
+        "诡秘之主_某某小说网"
+    );
+
+    let err = Cleanup::new(Field::Title, true, &["(".to_string()]).unwrap_err();
+    assert!(
+        err.to_string()
+            .starts_with("Invalid --title-strip regex: ("),
+        "{err}"
+    );
+
+    let path = output("title-cleanup");
+    let fetcher = book().page(INDEX_URL, INDEX.replace("測試之書", "測試之書_某某小說網"));
+    let options = BuildOptions {
+        output: path
+            .with_file_name("{title}.epub")
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap(),
+        ..options(&path)
+    };
+    build_epub(&source(), &fetcher, &options, &()).unwrap();
+    let opf = entry(&path.with_file_name("測試之書.epub"), ".opf");
+    assert!(opf.contains(">測試之書</dc:title>"), "{opf}");
+}
+
+#[test]
+fn output_files_are_named_after_normalized_metadata() {
+    let path = output("normalized");
+    let fetcher = book().page(INDEX_URL, INDEX.replace("測試之書", "測試　之書（上）"));
+    let options = BuildOptions {
+        output: path
+            .with_file_name("{title}.epub")
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap(),
+        fold_width: true,
+        ..options(&path)
+    };
+
+    build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    let named = path.with_file_name("測試 之書(上).epub");
+    let opf = entries(&named)
+        .into_iter()
+        .find(|(name, _)| name.ends_with(".opf"))
+        .unwrap()
+        .1;
+    assert!(opf.contains(">測試　之書（上）</dc:title>"), "{opf}");
+}
+
+#[test]
+fn the_output_template_fills_in_metadata_and_makes_directories() {
+    let path = output("output-template");
+    let template = path.with_file_name("{author}/{{{host}}} {title}.epub");
+    let options = BuildOptions {
+        output: template.to_str().unwrap().parse().unwrap(),
+        ..options(&path)
+    };
+
+    let summary = build_epub(&source(), &book(), &options, &()).unwrap();
+
+    let named = path
+        .with_file_name("作者甲")
+        .join("{czbooks.net} 測試之書.epub");
+    assert!(named.is_file(), "{:?}", summary.written);
+
+    for (template, error) in [
+        ("{titel}.epub", "Unknown placeholder {titel}"),
+        ("{title.epub", "Unterminated placeholder"),
+        ("title}.epub", "Unmatched '}'"),
+    ] {
+        let err = template
+            .parse::<epub_dude::output::OutputTemplate>()
+            .unwrap_err();
+        assert!(err.to_string().starts_with(error), "{template}: {err}");
+    }
+}
+
+#[test]
+fn output_names_are_safe_on_windows() {
+    let template: epub_dude::output::OutputTemplate = "{author}/{title}.epub".parse().unwrap();
+    let render = |author: &str, title: &str| {
+        template.render(&epub_dude::output::OutputFields {
+            title,
+            author,
+            date: "2024-01-01",
+            host: "czbooks.net",
+            last_chapter: "0001",
+            last_chapter_title: "",
+        })
+    };
+
+    assert_eq!(
+        render("作者甲. ", "CON"),
+        PathBuf::from("作者甲").join("CON_.epub")
+    );
+    assert_eq!(
+        render("作者", "第一卷：開始？"),
+        PathBuf::from("作者").join("第一卷：開始？.epub")
+    );
+    for (value, sanitized) in [
+        ("a:b?", "a_b_"),
+        ("<a|b>*\"", "_a_b___"),
+        ("nul.txt", "nul_.txt"),
+        ("Lpt9 .tar.gz", "Lpt9 _.tar.gz"),
+        ("COM¹", "COM¹_"),
+        ("Console", "Console"),
+        ("COM10", "COM10"),
+        ("完結...", "完結"),
+        ("...", "_"),
+        ("  ", "_"),
+    ] {
+        assert_eq!(
+            epub_dude::output::sanitize_component(value),
+            sanitized,
+            "{value:?}"
+        );
+    }
+}
+
+#[test]
+fn validation_names_the_file_and_problem_of_a_broken_epub() {
+    use std::io::Write;
+    use zip::write::{SimpleFileOptions, ZipWriter};
+
+    let path = output("validate");
+    build_epub(&source(), &book(), &options(&path), &()).unwrap();
+    assert!(epub_dude::validate::validate(&path).unwrap().is_empty());
+    let good = entries(&path);
+
+    // Copies the book with `change` applied to its entries, in order.
+    type Entries = Vec<(String, String)>;
+    let broken = |name: &str, change: &dyn Fn(&mut Entries)| {
+        let mut files = good.clone();
+        change(&mut files);
+        let copy = path.with_file_name(format!("{name}.epub"));
+        let mut zip = ZipWriter::new(File::create(&copy).unwrap());
+        for (file, content) in &files {
+            let method = match file == "mimetype" && name != "mimetype-deflated" {
+                true => zip::CompressionMethod::Stored,
+                false => zip::CompressionMethod::Deflated,
+            };
+            zip.start_file(
+                file,
+                SimpleFileOptions::default().compression_method(method),
+            )
+            .unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        epub_dude::validate::validate(&copy)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+    };
+    let edit = |files: &mut Entries, name: &str, from: &str, to: &str| {
+        let (_, content) = files.iter_mut().find(|(f, _)| f == name).unwrap();
+        assert!(content.contains(from), "{from} in {content}");
+        *content = content.replacen(from, to, 1);
+    };
+
+    assert_eq!(
+        broken("mimetype-late", &|files| files.swap(0, 1)),
+        ["META-INF/container.xml: the first entry must be mimetype"]
+    );
+    assert_eq!(
+        broken("mimetype-deflated", &|_| {}),
+        ["mimetype: must be stored without compression"]
+    );
+    assert_eq!(
+        broken("no-opf", &|files| edit(
+            files,
+            "META-INF/container.xml",
+            "OEBPS/content.opf",
+            "OEBPS/book.opf"
+        )),
+        ["OEBPS/book.opf: missing from the archive"]
+    );
+    let missing = broken("missing-chapter", &|files| {
+        files.retain(|(f, _)| f != "OEBPS/1.xhtml")
+    });
+    assert!(
+        missing.contains(
+            &r#"OEBPS/content.opf: manifest item "id_1.xhtml" points at missing OEBPS/1.xhtml"#
+                .to_string()
+        ),
+        "{missing:?}"
+    );
+    assert!(
+        missing.contains(
+            &r#"OEBPS/nav.xhtml: link "1.xhtml" points at missing OEBPS/1.xhtml"#.to_string()
+        ),
+        "{missing:?}"
+    );
+    assert_eq!(
+        broken("unknown-idref", &|files| edit(
+            files,
+            "OEBPS/content.opf",
+            r#"<itemref idref="id_1.xhtml""#,
+            r#"<itemref idref="id_9.xhtml""#
+        )),
+        [r#"OEBPS/content.opf: spine itemref "id_9.xhtml" is not in the manifest"#]
+    );
+    let fragment = broken("bad-fragment", &|files| {
+        edit(
+            files,
+            "OEBPS/nav.xhtml",
+            r#"href="1.xhtml""#,
+            r#"href="1.xhtml#nowhere""#,
+        )
+    });
+    assert!(
+        fragment.contains(
+            &r##"OEBPS/nav.xhtml: link "1.xhtml#nowhere" points at no element in OEBPS/1.xhtml"##
+                .to_string()
+        ),
+        "{fragment:?}"
+    );
+    let malformed = broken("malformed", &|files| {
+        edit(
+            files,
+            "OEBPS/0.xhtml",
+            "很久很久以前。",
+            "很久<b>很久以前。",
+        )
+    });
+    assert_eq!(malformed.len(), 1, "{malformed:?}");
+    assert!(
+        malformed[0].starts_with("OEBPS/0.xhtml: not well-formed XML: "),
+        "{malformed:?}"
+    );
+
+    let malformed = path.with_file_name("malformed.epub");
+    let err = Error::Validation {
+        problems: epub_dude::validate::validate(&malformed).unwrap(),
+        path: malformed,
+    };
+    assert!(
+        err.to_string().starts_with("1 structural problems in "),
+        "{err}"
+    );
+    assert_eq!(err.exit_code(), exit_code::OUTPUT);
+}
+
+#[test]
+fn without_a_zip_command_the_library_builds_the_epub() {
+    for zip_command in [None, Some("epub-dude-no-such-zip".to_string())] {
+        let path = output(&format!("zip-library-{}", zip_command.is_some()));
+        let options = BuildOptions {
+            zip_command,
+            validate: true,
+            ..options(&path)
+        };
+
+        build_epub(&source(), &book(), &options, &()).unwrap();
+
+        let mut zip = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let mimetype = zip.by_index(0).unwrap();
+        assert_eq!(mimetype.name(), "mimetype");
+        assert_eq!(mimetype.compression(), zip::CompressionMethod::Stored);
+        drop(mimetype);
+        let entries = entries(&path);
+        assert!(
+            entries
+                .iter()
+                .any(|(_, content)| content.contains("從此以後。"))
+        );
+    }
+}
+
+#[test]
+fn a_chapter_count_short_of_the_claimed_one_is_a_summary_warning() {
+    let path = output("count-warning");
+    let fetcher = book().page(INDEX_URL, INDEX.replace("</ul>", "</ul><p>共 5 章</p>"));
+    let options = BuildOptions {
+        count_check: Some(epub_dude::check::CountCheck {
+            pattern: regex::Regex::new(r"共\s*(\d+)\s*章").unwrap(),
+            tolerance: 0,
+            strict: false,
+        }),
+        ..options(&path)
+    };
+
+    let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    assert_eq!(
+        summary.warnings,
+        ["index page claims 5 chapters but 2 links were found (tolerance 0)"]
+    );
+}
+
+#[test]
+fn the_claimed_count_is_checked_within_its_tolerance_and_strictly_on_request() {
+    let fetcher = book().page(INDEX_URL, INDEX.replace("</ul>", "</ul><p>共 1,003 章</p>"));
+    let check = |pattern: &str, tolerance: usize, strict: bool| {
+        let path = output(&format!("count-{tolerance}-{strict}-{}", pattern.len()));
+        let options = BuildOptions {
+            count_check: Some(epub_dude::check::CountCheck {
+                pattern: regex::Regex::new(pattern).unwrap(),
+                tolerance,
+                strict,
+            }),
+            ..options(&path)
+        };
+        build_epub(&source(), &fetcher, &options, &())
+    };
+
+    // Within the tolerance, neither a warning nor a failure.
+    let summary = check(r"共\s*([\d,]+)\s*章", 1001, true).unwrap();
+    assert!(summary.warnings.is_empty(), "{:?}", summary.warnings);
+
+    let err = check(r"共\s*([\d,]+)\s*章", 1000, true).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("claims 1003 chapters but 2 links were found (tolerance 1000)"),
+        "{err}"
+    );
+    assert!(matches!(err, Error::Inconsistent(_)), "{err:?}");
+    assert_eq!(err.exit_code(), exit_code::PARSE);
+    assert_eq!(
+        epub_dude::check::claimed_count("共 1,003 章", &regex::Regex::new(r"\d[\d,]*").unwrap()),
+        Some(1003)
+    );
+
+    let summary = check(r"全\s*(\d+)\s*話", 0, true).unwrap();
+    assert_eq!(
+        summary.warnings,
+        ["claimed chapter count pattern `全\\s*(\\d+)\\s*話` did not match the index page"]
+    );
+}
+
+#[test]
+fn an_index_without_a_title_always_fails() {
+    let path = output("no-title");
+    let fetcher = book().page(INDEX_URL, INDEX.replace("測試之書", " "));
+
+    let err = build_epub(&source(), &fetcher, &options(&path), &()).unwrap_err();
+
+    assert_eq!(err.exit_code(), exit_code::PARSE);
+    assert!(
+        err.to_string().contains("found no title (.title)") && err.to_string().contains("--title"),
+        "{err}"
+    );
+    assert!(!path.exists());
+
+    let titled = BuildOptions {
+        title: Some("測試之書".to_string()),
+        ..options(&path)
+    };
+    build_epub(&source(), &fetcher, &titled, &()).unwrap();
+}
+
+#[test]
+fn title_number_sort_puts_numbered_chapters_in_order_and_reports_gaps() {
+    let path = output("title-number");
+    let index = index_of(&[
+        "第5章 戊",
+        "第一章\n    <b>甲</b>",
+        "序章",
+        "Chapter 2",
+        "3. 丙",
+    ]);
+    let mut fetcher = book().page(INDEX_URL, index.as_str());
+    for n in 1..=5 {
+        fetcher = fetcher.page(
+            &format!("https://czbooks.net/n/test/{n}"),
+            chapter(&format!("頁{n}"), &format!("<p>第{n}頁。</p>")),
+        );
+    }
+    let options = BuildOptions {
+        sort: SortOrder::TitleNumber,
+        ..options(&path)
+    };
+
+    let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    // Numbered chapters take the numbered slots in order; the prologue
+    // keeps its place.
+    let nav = entry(&path, "nav.xhtml");
+    let order: Vec<usize> = ["頁2", "頁4", "頁3", "頁5", "頁1"]
+        .iter()
+        .map(|title| {
+            nav.find(title)
+                .unwrap_or_else(|| panic!("{title} in {nav}"))
+        })
+        .collect();
+    assert!(order.is_sorted(), "{nav}");
+    assert_eq!(summary.warnings, ["missing chapter numbers: 4"]);
+
+    // Whitespace from the markup is collapsed in link titles.
+    let info = Site::of::<CzBooksProvider>()
+        .index(
+            &INDEX_URL.parse().unwrap(),
+            &StrTendril::from(index),
+            &Limits::default(),
+        )
+        .unwrap();
+    assert_eq!(info.links[1].title, "第一章 甲");
+
+    for (title, number) in [
+        ("第137章 夜", Some(137)),
+        ("第一百三十七章", Some(137)),
+        ("第十二回", Some(12)),
+        ("第１３７章", Some(137)),
+        ("Chapter 137: Night", Some(137)),
+        ("ch. 7", Some(7)),
+        ("137. Night", Some(137)),
+        ("序章", None),
+        ("Night 137", None),
+    ] {
+        assert_eq!(numbering::chapter_number(title), number, "{title}");
+    }
+}
+
+#[test]
+fn sequence_problems_warn_and_go_in_the_manifest_or_fail_when_strict() {
+    let path = output("sequence");
+    let index = index_of(&["第1章 甲", "第3章 丙", "第3章 丙", "第2章 乙", "第6章 己"]);
+    let mut fetcher = book().page(INDEX_URL, index.as_str());
+    for n in 1..=5 {
+        fetcher = fetcher.page(
+            &format!("https://czbooks.net/n/test/{n}"),
+            chapter(&format!("頁{n}"), &format!("<p>第{n}頁。</p>")),
+        );
+    }
+    let manifest = path.with_file_name("manifest.json");
+    let warned = BuildOptions {
+        manifest: Some(manifest.clone()),
+        ..options(&path)
+    };
+
+    let summary = build_epub(&source(), &fetcher, &warned, &()).unwrap();
+
+    assert_eq!(
+        summary.warnings,
+        [
+            "missing chapter numbers: 4-5",
+            "duplicated chapter numbers: 3",
+            "chapter 2 \"第2章 乙\" at position 4 is out of order",
+        ]
+    );
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap();
+    assert_eq!(
+        manifest["sequence"],
+        serde_json::json!({
+            "missing": [[4, 5]],
+            "duplicates": [3],
+            "out_of_order": [{"index": 3, "number": 2, "title": "第2章 乙"}],
+        })
+    );
+
+    let strict = BuildOptions {
+        strict_sequence: true,
+        ..options(&output("sequence-strict"))
+    };
+    let err = build_epub(&source(), &fetcher, &strict, &()).unwrap_err();
+    assert!(
+        err.to_string()
+            .starts_with("chapter sequence check failed: missing chapter numbers: 4-5; "),
+        "{err}"
+    );
+    assert!(matches!(err, Error::Inconsistent(_)), "{err:?}");
+    assert_eq!(err.exit_code(), exit_code::PARSE);
+}
+
+#[test]
+fn a_fallback_mirror_fills_missing_and_empty_chapters() {
+    let path = output("fallback-mirror");
+    let manifest = path.with_file_name("manifest.json");
+    let mirror = "https://czbooks.net/n/mirror";
+    let options = BuildOptions {
+        fallback: Some(mirror.parse().unwrap()),
+        manifest: Some(manifest.clone()),
+        ..options(&path)
+    };
+    let fetcher = book()
+        .page(INDEX_URL, index_of(&["第1章 甲", "第3章 丙"]).as_str())
+        .page(
+            "https://czbooks.net/n/test/1",
+            chapter("第1章 甲", "<p>主站的甲。</p>"),
+        )
+        .page("https://czbooks.net/n/test/2", chapter("第3章 丙", ""))
+        .page(
+            mirror,
+            index_of(&["第一章 甲", "第二章 乙", "第三章 丙"]).replace("/n/test/", "/n/mirror/"),
+        )
+        .page(
+            "https://czbooks.net/n/mirror/2",
+            chapter("第二章 乙", "<p>鏡像的乙。</p>"),
+        )
+        .page(
+            "https://czbooks.net/n/mirror/3",
+            chapter("第三章 丙", "<p>鏡像的丙。</p>"),
+        );
+
+    let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    let text: String = entries(&path)
+        .into_iter()
+        .filter(|(name, _)| name.ends_with(".xhtml"))
+        .map(|(_, content)| content)
+        .collect();
+    let order: Vec<usize> = ["主站的甲", "鏡像的乙", "鏡像的丙"]
+        .iter()
+        .map(|text_of| {
+            text.find(text_of)
+                .unwrap_or_else(|| panic!("{text_of} in {text}"))
+        })
+        .collect();
+    assert!(order.is_sorted(), "{text}");
+    assert!(
+        summary
+            .warnings
+            .iter()
+            .any(|w| w.ends_with("taken from fallback https://czbooks.net/n/mirror/3")),
+        "{:?}",
+        summary.warnings
+    );
+
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap();
+    let chapters: Vec<_> = manifest["chapters"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| {
+            (
+                c["url"].as_str().unwrap(),
+                c["provenance"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        chapters,
+        [
+            ("https://czbooks.net/n/test/1", "primary"),
+            ("https://czbooks.net/n/mirror/2", "fallback"),
+            ("https://czbooks.net/n/mirror/3", "fallback"),
+        ]
+    );
+}
+
+#[test]
+fn lengths_count_characters_or_words_and_estimate_reading_time() {
+    use epub_dude::stats::{Length, Unit, count};
+
+    assert_eq!(Unit::for_language("zh-TW"), Unit::Characters);
+    assert_eq!(Unit::for_language("ja_JP"), Unit::Characters);
+    assert_eq!(Unit::for_language("en"), Unit::Words);
+    assert_eq!(
+        count("<p>你好，世界！</p>\n<p>Hello  世界</p>", Unit::Characters),
+        13
+    );
+    assert_eq!(
+        count(
+            "<p>A long <em>way</em> home.</p><br />Goodbye.",
+            Unit::Words
+        ),
+        5
+    );
+    // Placeholders for formatting the text output keeps aren't text.
+    assert_eq!(count("字\u{E000}字\u{F8FF}", Unit::Characters), 2);
+    assert_eq!(count("看\u{E002}12\u{E003}。", Unit::Characters), 2);
+    assert_eq!(count("See \u{E002}0\u{E003} here", Unit::Words), 2);
+
+    let short = Length::new(Unit::Characters, 801, 400);
+    assert_eq!(
+        (short.reading_minutes, short.reading_time()),
+        (3, "3m".into())
+    );
+    let long = Length::new(Unit::Words, 30_250, 250);
+    assert_eq!(
+        (long.reading_minutes, long.reading_time()),
+        (121, "2h 1m".into())
+    );
+    assert_eq!(Length::new(Unit::Words, 10, 0).reading_minutes, 10);
+
+    let path = output("lengths");
+    let manifest = path.with_file_name("manifest.json");
+    let fetcher = book().page(
+        "https://czbooks.net/n/test/1",
+        chapter(
+            "第一章 開始",
+            "<p>看這裡<sup class=\"ref\">1</sup>。</p><div class=\"notes\"><p>註解。</p></div>",
+        ),
+    );
+    let options = BuildOptions {
+        notes: Some(epub_dude::fetch::NoteSelectors {
+            marker: ".ref".parse().unwrap(),
+            container: ".notes".parse().unwrap(),
+        }),
+        chapter_footer: Some("來源：{url}".to_string()),
+        reading_speed: Some(4),
+        length_meta: true,
+        manifest: Some(manifest.clone()),
+        ..options(&path)
+    };
+
+    build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap();
+    // The note's text counts; its number, the backlink and the footer don't.
+    assert_eq!(manifest["chapters"][0]["length"], 4 + 3, "{manifest}");
+    assert_eq!(manifest["chapters"][1]["length"], 5, "{manifest}");
+    assert_eq!(
+        manifest["length"],
+        serde_json::json!({
+            "unit": "characters",
+            "total": 12,
+            "per_minute": 4,
+            "reading_minutes": 3,
+        })
+    );
+    let opf = entry(&path, ".opf");
+    assert!(
+        opf.contains(r#"<meta property="epub-dude:characters">12</meta>"#),
+        "{opf}"
+    );
+    assert!(
+        opf.contains(r#"<meta property="epub-dude:reading-minutes">3</meta>"#),
+        "{opf}"
+    );
+}
+
+#[test]
+fn chapter_footers_link_the_source_and_stay_out_of_the_length() {
+    let build = |name: &str, chapter_footer: Option<&str>| {
+        let path = output(name);
+        let manifest = path.with_file_name("manifest.json");
+        let options = BuildOptions {
+            chapter_footer: chapter_footer.map(str::to_string),
+            manifest: Some(manifest.clone()),
+            ..options(&path)
+        };
+        build_epub(&source(), &book(), &options, &()).unwrap();
+        let manifest: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap();
+        (path, manifest)
+    };
+
+    let (path, footed) = build(
+        "footer",
+        Some("Source: {url}, fetched {date} #{index} <{other}>"),
+    );
+    let today = chrono::Local::now().format("%Y-%m-%d");
+    assert!(
+        entry(&path, "/1.xhtml").contains(&format!(
+            r#"<p class="chapter-footer">Source: <a href="https://czbooks.net/n/test/2">https://czbooks.net/n/test/2</a>, fetched {today} #2 &lt;{{other}}&gt;</p>"#
+        )),
+        "{}",
+        entry(&path, "/1.xhtml")
+    );
+
+    let (_, plain) = build("no-footer", None);
+    assert_eq!(
+        footed["chapters"][1]["length"],
+        plain["chapters"][1]["length"]
+    );
+    assert_eq!(footed["length"], plain["length"]);
+}
+
+#[test]
+fn provenance_is_declared_and_hashes_what_shapes_the_book() {
+    let build = |name: &str, change: fn(&mut BuildOptions)| {
+        let path = output(name);
+        let mut options = options(&path);
+        change(&mut options);
+        build_epub(&source(), &book(), &options, &()).unwrap();
+        let opf = entry(&path, ".opf");
+        let hash = opf
+            .split(r#"<meta property="epub-dude:config-hash">"#)
+            .nth(1)
+            .and_then(|rest| rest.split('<').next())
+            .unwrap_or_else(|| panic!("no config hash in {opf}"))
+            .to_string();
+        (opf, hash)
+    };
+
+    let (opf, hash) = build("provenance", |_| {});
+    assert!(
+        opf.contains(r#"<package prefix="epub-dude: https://github.com/tommady/epub-dude#" "#),
+        "{opf}"
+    );
+    assert!(
+        opf.contains(r#"<meta property="epub-dude:source">https://czbooks.net/n/test</meta>"#),
+        "{opf}"
+    );
+    assert_eq!(hash.len(), 16);
+
+    let (_, fetched_differently) = build("provenance-jobs", |options| {
+        options.jobs = 4;
+        options.wait_lock = std::time::Duration::from_secs(1);
+    });
+    assert_eq!(fetched_differently, hash);
+    let (_, themed) = build("provenance-theme", |options| {
+        options.theme = "classic".parse().unwrap();
+    });
+    assert_ne!(themed, hash);
+
+    let (opf, _) = build("provenance-epub2", |options| options.epub2 = true);
+    assert!(!opf.contains("prefix="), "{opf}");
+    let (opf, _) = build("no-provenance", |options| options.no_provenance = true);
+    assert!(!opf.contains("epub-dude:source"), "{opf}");
+}
+
+#[test]
+fn parallel_jobs_keep_chapter_order() {
+    let path = output("jobs");
+    let fetcher = book();
+    let options = BuildOptions {
+        jobs: 4,
+        ..options(&path)
+    };
+
+    let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    assert_eq!(summary.chapters, 2);
+    let mut requests = fetcher.requests();
+    requests.sort();
+    assert_eq!(
+        requests,
+        [
+            INDEX_URL,
+            "https://czbooks.net/n/test/1",
+            "https://czbooks.net/n/test/2"
+        ]
+    );
+    let entries = entries(&path);
+    let (_, nav) = entries
+        .iter()
+        .find(|(name, _)| name.ends_with("nav.xhtml"))
+        .expect("navigation document");
+    assert!(nav.find("第一章").unwrap() < nav.find("第二章").unwrap());
+}
+
+/// Takes its time over the first chapter, noting when each page was asked
+/// for and when the first was sent.
+struct SlowFirst {
+    inner: MemoryFetcher,
+    log: std::sync::Mutex<Vec<(String, std::time::Instant)>>,
+}
+
+impl Fetcher for SlowFirst {
+    fn get(&self, url: &str) -> epub_dude::Result<Response> {
+        let now = std::time::Instant::now();
+        self.log.lock().unwrap().push((url.to_string(), now));
+        if url == "https://czbooks.net/n/test/1" {
+            std::thread::sleep(std::time::Duration::from_millis(300));
+            let sent = std::time::Instant::now();
+            self.log.lock().unwrap().push(("sent".to_string(), sent));
+        }
+        self.inner.get(url)
+    }
+}
+
+#[test]
+fn a_slow_chapter_holds_up_only_itself() {
+    let path = output("slow-chapter");
+    let titles: Vec<String> = (1..=6).map(|n| format!("第{n}章")).collect();
+    let titles: Vec<&str> = titles.iter().map(String::as_str).collect();
+    let mut inner = MemoryFetcher::new().page(INDEX_URL, index_of(&titles));
+    for (n, title) in titles.iter().enumerate() {
+        inner = inner.page(
+            &format!("https://czbooks.net/n/test/{}", n + 1),
+            chapter(title, "<p>內容。</p>"),
+        );
+    }
+    let fetcher = SlowFirst {
+        inner,
+        log: std::sync::Mutex::new(Vec::new()),
+    };
+    let options = BuildOptions {
+        jobs: 2,
+        ..options(&path)
+    };
+
+    let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    assert_eq!(summary.chapters, 6);
+    let log = fetcher.log.lock().unwrap();
+    let when = |url: &str| log.iter().find(|(u, _)| u == url).unwrap().1;
+    // The other worker went on through the book meanwhile.
+    for n in 2..=6 {
+        let url = format!("https://czbooks.net/n/test/{n}");
+        assert!(when(&url) < when("sent"), "{url}: {log:?}");
+    }
+    let (_, nav) = entries(&path)
+        .into_iter()
+        .find(|(name, _)| name.ends_with("nav.xhtml"))
+        .unwrap();
+    let places: Vec<usize> = titles.iter().map(|t| nav.find(t).unwrap()).collect();
+    assert!(places.is_sorted(), "{nav}");
+}
+
+#[test]
+fn resumes_from_a_checkpoint() {
+    let path = output("checkpoint");
+    let options = BuildOptions {
+        checkpoint_every: Some(1),
+        partial_epub: true,
+        ..options(&path)
+    };
+    let third = |fetcher: MemoryFetcher| {
+        fetcher.page(
+            "https://czbooks.net/n/test/3",
+            chapter("第三章 再見", "<p>完。</p>"),
+        )
+    };
+    let index = INDEX.replace(
+        "</ul>",
+        r#"<li><a href="//czbooks.net/n/test/3">第三章 再見</a></li></ul>"#,
+    );
+
+    // The third chapter is unreachable, so the first build fails after
+    // checkpointing two.
+    let failing = book()
+        .page(INDEX_URL, index.as_str())
+        .status("https://czbooks.net/n/test/3", 503);
+    build_epub(&source(), &failing, &options, &()).unwrap_err();
+    assert!(path.with_extension("partial.epub").exists());
+
+    let complete = third(book().page(INDEX_URL, index.as_str()));
+    let summary = build_epub(&source(), &complete, &options, &()).unwrap();
+
+    assert_eq!(summary.chapters, 3);
+    assert_eq!(
+        complete.requests(),
+        [INDEX_URL, "https://czbooks.net/n/test/3"]
+    );
+    let entries = entries(&path);
+    assert!(entries.iter().any(|(_, c)| c.contains("很久很久以前。")));
+    assert!(!path.with_extension("partial.epub").exists());
+}
+
+#[test]
+fn a_locked_book_is_refused_until_the_lock_goes_stale() {
+    let path = output("lock");
+    let options = options(&path);
+    let lock = workdir::book_dir(&options.work_dir, &source().uri).join("lock");
+    std::fs::create_dir_all(lock.parent().unwrap()).unwrap();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    std::fs::write(&lock, format!("{}\n{now}\n", std::process::id())).unwrap();
+    let err = build_epub(&source(), &book(), &options, &()).unwrap_err();
+    assert_eq!(err.exit_code(), exit_code::LOCKED, "{err}");
+
+    // Older than any lock a live run could hold.
+    std::fs::write(&lock, format!("{}\n0\n", std::process::id())).unwrap();
+    build_epub(&source(), &book(), &options, &()).unwrap();
+    assert!(!lock.exists());
+
+    // Just created by another run that hasn't written itself down yet.
+    std::fs::write(&lock, "").unwrap();
+    let err = build_epub(&source(), &book(), &options, &()).unwrap_err();
+    assert_eq!(err.exit_code(), exit_code::LOCKED, "{err}");
+    File::options()
+        .write(true)
+        .open(&lock)
+        .unwrap()
+        .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(60))
+        .unwrap();
+    build_epub(&source(), &book(), &options, &()).unwrap();
+    assert!(!lock.exists());
+
+    // Every spelling of the index URL shares the lock.
+    for spelling in [
+        "https://czbooks.net/n/test/",
+        "http://czbooks.net/n/test",
+        "https://CZBooks.net/n/test",
+    ] {
+        assert_eq!(
+            workdir::book_dir(&options.work_dir, &spelling.parse().unwrap()),
+            lock.parent().unwrap(),
+            "{spelling}"
+        );
+    }
+    assert_ne!(
+        workdir::book_dir(
+            &options.work_dir,
+            &"https://czbooks.net/n/other".parse().unwrap()
+        ),
+        lock.parent().unwrap()
+    );
+}
+
+#[test]
+fn known_books_can_be_listed_and_removed() {
+    let path = output("clean");
+    let options = options(&path);
+    build_epub(&source(), &book(), &options, &()).unwrap();
+
+    let books = workdir::list(&options.work_dir).unwrap();
+    assert_eq!(books.len(), 1);
+    let record = books[0].record.as_ref().unwrap();
+    assert_eq!(
+        (record.title.as_str(), record.url.as_str()),
+        ("測試之書", INDEX_URL)
+    );
+    assert!(books[0].size > 0);
+    assert!(books[0].last_run().is_some());
+
+    // Not while a run holds the book.
+    let dir = workdir::book_dir(&options.work_dir, &source().uri);
+    assert_eq!(books[0].dir, dir);
+    std::fs::write(
+        dir.join("lock"),
+        format!("{}\n9999999999\n", std::process::id()),
+    )
+    .unwrap();
+    let err = workdir::remove(&dir).unwrap_err();
+    assert_eq!(err.exit_code(), exit_code::LOCKED, "{err}");
+
+    std::fs::remove_file(dir.join("lock")).unwrap();
+    workdir::remove(&dir).unwrap();
+    assert!(!dir.exists());
+    assert!(workdir::list(&options.work_dir).unwrap().is_empty());
+}
+
+#[test]
+fn download_budget_stops_with_a_partial_book() {
+    let path = output("budget");
+    let options = BuildOptions {
+        max_total_bytes: Some(INDEX.len()),
+        ..options(&path)
+    };
+
+    let err = build_epub(&source(), &book(), &options, &()).unwrap_err();
+
+    assert!(
+        matches!(
+            err,
+            Error::Budget {
+                chapters: 1,
+                of: 2,
+                ..
+            }
+        ),
+        "{err}"
+    );
+    assert_eq!(err.exit_code(), exit_code::NETWORK);
+    assert!(!path.exists());
+    let entries = entries(&path.with_extension("partial.epub"));
+    assert!(entries.iter().any(|(_, c)| c.contains("很久很久以前。")));
+    assert!(!entries.iter().any(|(_, c)| c.contains("從此以後。")));
+}
+
+#[test]
+fn gone_chapters_are_skipped_on_later_runs() {
+    let path = output("gone");
+    let options = options(&path);
+    let gone = || book().status("https://czbooks.net/n/test/2", 410);
+
+    let first = gone();
+    let summary = build_epub(&source(), &first, &options, &()).unwrap();
+    assert_eq!(summary.chapters, 1);
+    assert_eq!(summary.missing.len(), 1, "{:?}", summary.missing);
+    assert!(
+        first
+            .requests()
+            .contains(&"https://czbooks.net/n/test/2".to_string())
+    );
+
+    let second = gone();
+    let summary = build_epub(&source(), &second, &options, &()).unwrap();
+    assert_eq!(summary.missing.len(), 1);
+    assert!(
+        summary.missing[0].contains("skipped"),
+        "{:?}",
+        summary.missing
+    );
+    assert!(
+        !second
+            .requests()
+            .contains(&"https://czbooks.net/n/test/2".to_string())
+    );
+
+    // The chapter is back; retrying finds it and forgets it was missing.
+    let retry = BuildOptions {
+        retry_permanent: true,
+        ..self::options(&path)
+    };
+    let summary = build_epub(&source(), &book(), &retry, &()).unwrap();
+    assert_eq!(summary.chapters, 2);
+    assert!(summary.missing.is_empty());
+    let summary = build_epub(&source(), &book(), &options, &()).unwrap();
+    assert_eq!(summary.chapters, 2);
+}
+
+#[test]
+fn a_chapter_listing_reads_back_in_either_format() {
+    let info = source().info(&book()).unwrap();
+    let listing = ChapterListing::new(&INDEX_URL.parse().unwrap(), &info);
+
+    for format in [ListFormat::Tsv, ListFormat::Json] {
+        let mut out = Vec::new();
+        listing.write(format, &mut out).unwrap();
+        let selected = selection::read_selection(&String::from_utf8(out).unwrap()).unwrap();
+        assert_eq!(
+            selected,
+            [
+                "https://czbooks.net/n/test/1".parse::<http::Uri>().unwrap(),
+                "https://czbooks.net/n/test/2".parse().unwrap(),
+            ],
+            "{format:?}"
+        );
+    }
+}
+
+#[test]
+fn a_chapters_file_picks_chapters_in_its_order() {
+    let path = output("chapters-file");
+    let fetcher = book();
+    let tsv = "# title\t測試之書\n2\t第二章 結束\thttps://czbooks.net/n/test/2\n\
+               1\t第一章 開始\thttps://czbooks.net/n/test/1\n";
+    let options = BuildOptions {
+        chapters: Some(selection::read_selection(tsv).unwrap()),
+        ..options(&path)
+    };
+
+    let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    assert_eq!(summary.chapters, 2);
+    let entries = entries(&path);
+    let (_, nav) = entries
+        .iter()
+        .find(|(name, _)| name.ends_with("nav.xhtml"))
+        .expect("navigation document");
+    assert!(nav.find("第二章").unwrap() < nav.find("第一章").unwrap());
+}
+
+#[test]
+fn a_chapters_file_entry_missing_from_the_index_is_a_usage_error() {
+    let path = output("chapters-file-stale");
+    let options = BuildOptions {
+        chapters: Some(vec!["https://czbooks.net/n/test/9".parse().unwrap()]),
+        ..options(&path)
+    };
+
+    let err = build_epub(&source(), &book(), &options, &()).unwrap_err();
+
+    assert!(matches!(err, Error::Usage(_)), "{err:?}");
+    assert!(err.to_string().contains("/n/test/9"));
+}
+
+#[test]
+fn a_picker_narrows_the_chapters_down() {
+    fn second_only(title: &str, links: &[epub_dude::ChapterLink]) -> anyhow::Result<Vec<usize>> {
+        assert_eq!(title, "測試之書");
+        assert_eq!(links.len(), 2);
+        Ok(vec![1])
+    }
+    let path = output("picker");
+    let fetcher = book();
+    let options = BuildOptions {
+        pick: Some(second_only),
+        ..options(&path)
+    };
+
+    let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    assert_eq!(summary.chapters, 1);
+    assert!(
+        !fetcher
+            .requests()
+            .contains(&"https://czbooks.net/n/test/1".to_string())
+    );
+}
+
+#[test]
+fn picking_nothing_is_a_usage_error() {
+    let path = output("picker-cancelled");
+    let options = BuildOptions {
+        pick: Some(|_, _| anyhow::bail!("Chapter selection cancelled")),
+        ..options(&path)
+    };
+
+    let err = build_epub(&source(), &book(), &options, &()).unwrap_err();
+
+    assert!(matches!(err, Error::Usage(_)), "{err:?}");
+}
+
+#[test]
+fn title_filters_skip_chapters_before_downloading() {
+    let path = output("title-filter");
+    let fetcher = book();
+    let options = BuildOptions {
+        title_filter: TitleFilter::new(&[], &["結束".to_string()]).unwrap(),
+        ..options(&path)
+    };
+
+    let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    assert_eq!(summary.chapters, 1);
+    assert_eq!(summary.excluded, ["第二章 結束"]);
+    assert_eq!(
+        fetcher.requests(),
+        [INDEX_URL, "https://czbooks.net/n/test/1"]
+    );
+}
+
+#[test]
+fn an_include_wins_over_an_exclude_only_when_both_match() {
+    let filter = TitleFilter::new(&["番外".to_string()], &["作者的话|感言".to_string()]).unwrap();
+
+    // Both match: the include wins.
+    assert!(filter.keeps("番外 作者的话"));
+    // Only the include matches.
+    assert!(filter.keeps("番外一"));
+    // Only the exclude matches.
+    assert!(!filter.keeps("完本感言"));
+    // Neither matches: with includes given, only included titles are kept.
+    assert!(!filter.keeps("第一章"));
+
+    let exclude_only = TitleFilter::new(&[], &["感言".to_string()]).unwrap();
+    assert!(exclude_only.keeps("第一章"));
+    assert!(!exclude_only.keeps("完本感言"));
+}
+
+#[test]
+fn split_every_writes_numbered_parts_as_a_series() {
+    let path = output("split");
+    let options = BuildOptions {
+        split_every: Some(1),
+        validate: true,
+        ..options(&path)
+    };
+
+    let summary = build_epub(&source(), &book(), &options, &()).unwrap();
+
+    let part = |n: usize| path.with_file_name(format!("book - Part {n}.epub"));
+    assert_eq!(summary.parts, [part(1), part(2)]);
+    assert!(!path.exists());
+
+    for (n, (text, other)) in [
+        ("很久很久以前。", "從此以後。"),
+        ("從此以後。", "很久很久以前。"),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let entries = entries(&part(n + 1));
+        let find = |needle: &str| entries.iter().any(|(_, content)| content.contains(needle));
+        assert!(find(text));
+        assert!(!find(other));
+        // Chapter files keep their place in the whole book.
+        assert!(
+            entries
+                .iter()
+                .any(|(name, _)| name.ends_with(&format!("/{n}.xhtml")))
+        );
+
+        let (_, opf) = entries
+            .iter()
+            .find(|(name, _)| name.ends_with(".opf"))
+            .expect("package document");
+        assert!(opf.contains(&format!("測試之書 - Part {}", n + 1)));
+        assert!(opf.contains(r#"<meta name="calibre:series" content="測試之書"/>"#));
+        assert!(opf.contains(&format!(
+            r#"<meta name="calibre:series_index" content="{}"/>"#,
+            n + 1
+        )));
+    }
+}
+
+#[test]
+fn merged_sources_nest_under_their_titles() {
+    let path = output("merge");
+    let second = "https://czbooks.net/n/sequel";
+    let fetcher = book()
+        .page(
+            second,
+            r#"<html><body>
+<span class="title">續集</span>
+<span class="author"><a href="/a/2">作者乙</a></span>
+<ul id="chapter-list"><li><a href="//czbooks.net/n/sequel/1">第一章 重逢</a></li></ul>
+</body></html>"#,
+        )
+        .page(
+            "https://czbooks.net/n/sequel/1",
+            chapter("第一章 重逢", "<p>多年以後。</p>"),
+        );
+    let sources = [source(), BookSource::new(second.parse().unwrap()).unwrap()];
+    let options = BuildOptions {
+        title: Some("合集".to_string()),
+        validate: true,
+        ..options(&path)
+    };
+
+    let summary = build_anthology(&sources, &fetcher, &options, &()).unwrap();
+
+    assert_eq!(summary.chapters, 3);
+    assert!(
+        summary
+            .warnings
+            .iter()
+            .any(|w| w.contains("作者乙") && w.contains("keeping 作者甲")),
+        "{:?}",
+        summary.warnings
+    );
+
+    let entries = entries(&path);
+    // File numbering runs across the sources.
+    assert!(entries.iter().any(|(name, _)| name.ends_with("/2.xhtml")));
+    let (_, opf) = entries
+        .iter()
+        .find(|(name, _)| name.ends_with(".opf"))
+        .expect("package document");
+    assert!(opf.contains("合集"));
+    assert!(!opf.contains("作者乙"));
+
+    let (_, nav) = entries
+        .iter()
+        .find(|(name, _)| name.ends_with("nav.xhtml"))
+        .expect("navigation document");
+    let at = |needle: &str| nav.find(needle).unwrap();
+    assert!(at("測試之書") < at("第一章 開始"));
+    assert!(at("第二章 結束") < at("續集"));
+    assert!(at("續集") < at("第一章 重逢"));
+    // Each source's chapters are a list inside its entry.
+    let first_arc = &nav[at("測試之書")..at("續集")];
+    assert!(first_arc.contains("<ol"), "{nav}");
+}
+
+#[test]
+fn the_ncx_follows_the_nesting_and_reading_order_of_the_contents() {
+    let path = output("ncx");
+    let second = "https://czbooks.net/n/sequel";
+    let fetcher = book()
+        .page(
+            second,
+            r#"<html><body>
+<span class="title">續集</span>
+<span class="author"><a href="/a/1">作者甲</a></span>
+<ul id="chapter-list"><li><a href="//czbooks.net/n/sequel/1">第一章 重逢</a></li></ul>
+</body></html>"#,
+        )
+        .page(
+            "https://czbooks.net/n/sequel/1",
+            chapter("第一章 重逢", "<p>多年以後。</p>"),
+        );
+    let sources = [source(), BookSource::new(second.parse().unwrap()).unwrap()];
+    let options = BuildOptions {
+        title: Some("合集".to_string()),
+        validate: true,
+        ..options(&path)
+    };
+
+    build_anthology(&sources, &fetcher, &options, &()).unwrap();
+
+    let entries = entries(&path);
+    let find = |suffix: &str| {
+        &entries
+            .iter()
+            .find(|(name, _)| name.ends_with(suffix))
+            .expect(suffix)
+            .1
+    };
+    let (opf, ncx) = (find(".opf"), find("toc.ncx"));
+    // Each source's chapters nest under its entry.
+    assert!(
+        ncx.contains(r#"<meta name="dtb:depth" content="2" />"#),
+        "{ncx}"
+    );
+    let uid = opf
+        .split("<dc:identifier id=\"epub-id-1\">")
+        .nth(1)
+        .and_then(|rest| rest.split('<').next())
+        .expect("package identifier");
+    assert!(
+        ncx.contains(&format!(r#"<meta name="dtb:uid" content="{uid}" />"#)),
+        "{ncx}"
+    );
+    let at = |needle: &str| ncx.find(needle).unwrap();
+    assert!(at("測試之書") < at("第一章 開始"));
+    assert!(at("第二章 結束") < at("續集"));
+    for order in 1..=7 {
+        assert!(ncx.contains(&format!(r#"playOrder="{order}""#)), "{ncx}");
+    }
+    assert!(!ncx.contains(r#"playOrder="8""#));
+}
+
+#[test]
+fn kepub_wraps_sentences_in_kobo_spans() {
+    let path = output("kepub").with_file_name("book.kepub.epub");
+    let fetcher = MemoryFetcher::new()
+        .page(INDEX_URL, INDEX)
+        .page(
+            "https://czbooks.net/n/test/1",
+            chapter(
+                "第一章 開始",
+                "<p>很久很久以前。「有人嗎？」他問。</p><p>It was 3.5 hours. <b>Late</b> &amp; dark!</p>",
+            ),
+        )
+        .page(
+            "https://czbooks.net/n/test/2",
+            chapter("第二章 結束", "<p>從此以後。</p>"),
+        );
+    let options = BuildOptions {
+        format: "kepub".parse().unwrap(),
+        validate: true,
+        ..options(&path)
+    };
+
+    build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    let entries = entries(&path);
+    let (_, first) = entries
+        .iter()
+        .find(|(_, content)| content.contains("很久很久以前"))
+        .expect("first chapter");
+    roxmltree::Document::parse(first).expect("well-formed XHTML");
+    assert!(first.contains(r#"<div id="book-columns"><div id="book-inner">"#));
+    for sentence in [
+        "很久很久以前。",
+        "「有人嗎？」",
+        "他問。",
+        "It was 3.5 hours.",
+        "Late &amp; dark!",
+    ] {
+        assert!(
+            first.contains(&format!("\">{sentence}</span>")),
+            "{sentence} in {first}"
+        );
+    }
+    assert!(first.contains(r#"<span class="koboSpan" id="kobo.1.1">It was"#));
+}
+
+#[test]
+fn the_summary_names_what_was_written() {
+    let path = output("summary-files");
+
+    let summary = build_epub(&source(), &book(), &options(&path), &()).unwrap();
+
+    assert_eq!(summary.files, [path]);
+    assert_eq!(summary.title, "測試之書");
+    assert_eq!(summary.authors, ["作者甲"]);
+}
+
+#[test]
+fn ruby_reads_inline_in_text_and_markdown() {
+    let fetcher = book().page(
+        "https://czbooks.net/n/test/1",
+        chapter(
+            "第一章 開始",
+            "<ruby>漢字<rp>(</rp><rt>かんじ</rt><rp>)</rp></ruby>を読む。<br><ruby>東京<rt>とうきょう</ruby>へ。",
+        ),
+    );
+    for format in ["txt", "md"] {
+        let path = output(&format!("ruby-{format}")).with_file_name(format!("book.{format}"));
+        let options = BuildOptions {
+            format: format.parse().unwrap(),
+            ..options(&path)
+        };
+        build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains("漢字（かんじ）を読む。"), "{text}");
+        assert!(text.contains("東京（とうきょう）へ。"), "{text}");
+        assert!(!text.contains("(") && !text.contains("<"), "{text}");
+    }
+}
+
+#[test]
+fn footnotes_become_pop_up_notes_at_the_end_of_their_chapter() {
+    let page = std::fs::read_to_string(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/czbooks/chapter-footnotes.html"
+    ))
+    .unwrap();
+    let fetcher = book()
+        .page("https://czbooks.net/n/test/1", page.as_str())
+        .page("https://czbooks.net/n/test/2", page.as_str());
+    let build = |name: &str, epub2: bool| {
+        let path = output(name);
+        let options = BuildOptions {
+            notes: Some(epub_dude::fetch::NoteSelectors {
+                marker: ".note-ref".parse().unwrap(),
+                container: ".notes".parse().unwrap(),
+            }),
+            epub2,
+            ..options(&path)
+        };
+        build_epub(&source(), &fetcher, &options, &()).unwrap();
+        (entry(&path, "/0.xhtml"), entry(&path, "/1.xhtml"))
+    };
+
+    let (first, second) = build("footnotes", false);
+    assert!(
+        second.contains(concat!(
+            r##"他說起了拉格朗日點<a epub:type="noteref" id="noteref-1-1" href="#note-1-1"><sup>1</sup></a>，"##,
+            r##"又提到了洛希極限<a epub:type="noteref" id="noteref-1-2" href="#note-1-2"><sup>2</sup></a>。"##,
+        )),
+        "{second}"
+    );
+    // A marker without a note is dropped, and the notes follow the text.
+    assert!(
+        second.contains(concat!(
+            r#"<br />  沒有註釋的標記會被拿掉。<br />  <br />"#,
+            r#"<aside epub:type="footnote" class="footnote" id="note-1-1"><p>1. 兩個天體引力平衡之處。</p></aside>"#,
+            r#"<aside epub:type="footnote" class="footnote" id="note-1-2"><p>2. 衛星被潮汐力撕裂的距離。</p></aside>"#,
+            "\n",
+        )),
+        "{second}"
+    );
+    // Numbered per chapter, with ids unique across the book.
+    assert!(
+        first.contains(
+            r##"<a epub:type="noteref" id="noteref-0-1" href="#note-0-1"><sup>1</sup></a>"##
+        ),
+        "{first}"
+    );
+    assert!(
+        !second.contains("[1]") && !second.contains("fn1"),
+        "{second}"
+    );
+
+    let (_, second) = build("footnotes-epub2", true);
+    assert!(
+        second.contains(r##"拉格朗日點<a id="noteref-1-1" href="#note-1-1"><sup>1</sup></a>"##),
+        "{second}"
+    );
+    assert!(
+        second.contains(concat!(
+            r#"<div class="footnotes">"#,
+            r##"<p class="footnote" id="note-1-1"><a href="#noteref-1-1">1</a>. 兩個天體引力平衡之處。</p>"##,
+            r##"<p class="footnote" id="note-1-2"><a href="#noteref-1-2">2</a>. 衛星被潮汐力撕裂的距離。</p>"##,
+            "</div>",
+        )),
+        "{second}"
+    );
+    assert!(!second.contains("epub:type=\"noteref\""), "{second}");
+}
+
+#[test]
+fn html_is_one_self_contained_page() {
+    let path = output("html").with_file_name("book.html");
+    let mut png = Vec::new();
+    image::RgbImage::new(1, 1)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    let fetcher = book()
+        .page(
+            "https://czbooks.net/n/test/1",
+            chapter(
+                "第一章 開始",
+                r#"<p>很久很久以前。</p><img src="/pic.png" alt="地圖">"#,
+            ),
+        )
+        .page("https://czbooks.net/pic.png", png);
+    let options = BuildOptions {
+        format: "html".parse().unwrap(),
+        images: epub_dude::images::ImageOptions {
+            embed: true,
+            ..Default::default()
+        },
+        ..options(&path)
+    };
+
+    let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    assert_eq!(summary.files, std::slice::from_ref(&path));
+    let page = std::fs::read_to_string(&path).unwrap();
+    assert!(page.starts_with("<!DOCTYPE html>"));
+    roxmltree::Document::parse_with_options(
+        &page,
+        roxmltree::ParsingOptions {
+            allow_dtd: true,
+            ..Default::default()
+        },
+    )
+    .expect("well-formed markup");
+    assert!(page.contains(r##"<a href="#chapter-2">第二章 結束</a>"##));
+    assert!(page.contains(r#"<section class="chapter" id="chapter-2">"#));
+    assert!(page.contains(r#"<img src="data:image/png;base64,"#));
+    assert!(page.contains("<style>"));
+    // Nothing to fetch when it's opened.
+    assert!(!page.contains("http://") && !page.contains("https://"));
+}
+
+#[test]
+fn an_image_shown_in_every_chapter_is_stored_once() {
+    let path = output("image-dedup");
+    let mut png = Vec::new();
+    image::RgbImage::new(1, 1)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    let banner = |n: usize| {
+        format!(
+            r#"<img src="/banner.png?v={n}" alt="橫幅"><img src="/watermark.png" alt="浮水印"><p>第{n}頁。</p>"#
+        )
+    };
+    let fetcher = book()
+        .page(
+            "https://czbooks.net/n/test/1",
+            chapter("第一章 開始", &banner(1)),
+        )
+        .page(
+            "https://czbooks.net/n/test/2",
+            chapter("第二章 結束", &banner(2)),
+        )
+        .page("https://czbooks.net/banner.png?v=1", png.clone())
+        .page("https://czbooks.net/banner.png?v=2", png);
+    let options = BuildOptions {
+        images: epub_dude::images::ImageOptions {
+            embed: true,
+            skip: Some(regex::Regex::new(r"/watermark\.").unwrap()),
+            ..Default::default()
+        },
+        validate: true,
+        ..options(&path)
+    };
+
+    build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    assert!(
+        !fetcher
+            .requests()
+            .iter()
+            .any(|url| url.contains("watermark"))
+    );
+    let zip = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+    let images: Vec<_> = zip
+        .file_names()
+        .filter(|name| name.contains("images/"))
+        .collect();
+    assert_eq!(images, ["OEBPS/images/0001.png"]);
+    let entries = entries(&path);
+    for chapter in ["0.xhtml", "1.xhtml"] {
+        let (_, page) = entries
+            .iter()
+            .find(|(name, _)| name.ends_with(&format!("/{chapter}")))
+            .expect(chapter);
+        assert!(
+            page.contains(r#"<img src="images/0001.png" alt="橫幅" />"#),
+            "{page}"
+        );
+        assert!(!page.contains("浮水印"), "{page}");
+    }
+}
+
+#[test]
+fn pages_are_deflated_at_the_level_asked_for_and_images_stored() {
+    let mut png = Vec::new();
+    image::RgbImage::new(1, 1)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    let fetcher = book()
+        .page(
+            "https://czbooks.net/n/test/1",
+            chapter(
+                "第一章 開始",
+                &r#"<img src="/map.png" alt="地圖"><p>很久很久以前。</p>"#.repeat(50),
+            ),
+        )
+        .page("https://czbooks.net/map.png", png);
+
+    let mut sizes = Vec::new();
+    for level in [9, 0] {
+        let path = output(&format!("compression-{level}"));
+        let options = BuildOptions {
+            images: epub_dude::images::ImageOptions {
+                embed: true,
+                ..Default::default()
+            },
+            compression_level: level,
+            validate: true,
+            ..options(&path)
+        };
+
+        let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+        let size = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(summary.written, size);
+        sizes.push(size);
+        let mut zip = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(zip.by_index(0).unwrap().name(), "mimetype");
+        assert!(zip.file_names().any(|name| name.ends_with(".png")));
+        for i in 0..zip.len() {
+            let file = zip.by_index(i).unwrap();
+            let stored = level == 0
+                || file.name() == "mimetype"
+                || file.name().ends_with(".png")
+                || file.size() == 0;
+            let expected = match stored {
+                true => zip::CompressionMethod::Stored,
+                false => zip::CompressionMethod::Deflated,
+            };
+            assert_eq!(file.compression(), expected, "{} at {level}", file.name());
+        }
+    }
+    assert!(sizes[1] > sizes[0], "{sizes:?}");
+}
+
+#[test]
+fn images_are_told_apart_by_their_bytes_and_made_safe_to_show() {
+    let path = output("image-formats");
+    let mut webp = Vec::new();
+    image::RgbImage::new(2, 2)
+        .write_to(
+            &mut std::io::Cursor::new(&mut webp),
+            image::ImageFormat::WebP,
+        )
+        .unwrap();
+    let svg = r#"<?xml version="1.0"?>
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" onload="alert(1)">
+<script>alert(2)</script>
+<image xlink:href="https://tracker.example/pixel.png" width="1" height="1"/>
+<circle cx="5" cy="5" r="4" fill="url(#shade)"/>
+</svg>"#;
+    let fetcher = book()
+        .page(
+            "https://czbooks.net/n/test/1",
+            chapter(
+                "第一章 開始",
+                r#"<img src="/map.png" alt="地圖"><img src="/photo.png" alt="照片"><img src="/broken.png" alt="壞圖"><p>很久很久以前。</p>"#,
+            ),
+        )
+        .page("https://czbooks.net/map.png", svg)
+        .page("https://czbooks.net/photo.png", webp)
+        .page("https://czbooks.net/broken.png", &b"\x89PNG not really"[..]);
+    let options = |formats: &str, name: &str| BuildOptions {
+        images: epub_dude::images::ImageOptions {
+            embed: true,
+            formats: Some(formats.split(',').map(|f| f.parse().unwrap()).collect()),
+            ..Default::default()
+        },
+        validate: true,
+        ..self::options(&output(name))
+    };
+
+    let summary = build_epub(
+        &source(),
+        &fetcher,
+        &options("jpeg,png,svg", "image-formats"),
+        &(),
+    )
+    .unwrap();
+
+    assert!(
+        summary
+            .warnings
+            .iter()
+            .any(|w| w.contains("broken.png") && w.contains("corrupt or unsupported png")),
+        "{:?}",
+        summary.warnings
+    );
+    let entries = entries(&path);
+    let find = |suffix: &str| {
+        &entries
+            .iter()
+            .find(|(name, _)| name.ends_with(suffix))
+            .expect(suffix)
+            .1
+    };
+    let (opf, page, drawing) = (find(".opf"), find("/0.xhtml"), find("images/0001.svg"));
+    assert!(opf.contains(r#"media-type="image/svg+xml""#), "{opf}");
+    assert!(opf.contains(r#"href="images/0002.jpg""#), "{opf}");
+    assert!(!opf.contains("webp"), "{opf}");
+    assert!(
+        page.contains(r#"<img src="images/0001.svg" alt="地圖" />"#),
+        "{page}"
+    );
+    assert!(
+        page.contains(r#"<img src="images/0002.jpg" alt="照片" />"#),
+        "{page}"
+    );
+    assert!(
+        page.contains("壞圖") && !page.contains("broken.png"),
+        "{page}"
+    );
+    assert!(
+        drawing.contains(r##"<circle cx="5" cy="5" r="4" fill="url(#shade)">"##),
+        "{drawing}"
+    );
+    for unsafe_part in ["alert", "tracker.example", "onload"] {
+        assert!(!drawing.contains(unsafe_part), "{drawing}");
+    }
+
+    let summary = build_epub(
+        &source(),
+        &fetcher,
+        &options("jpeg,png", "image-formats-raster"),
+        &(),
+    )
+    .unwrap();
+    assert!(
+        summary
+            .warnings
+            .iter()
+            .any(|w| w.contains("map.png") && w.contains("SVG")),
+        "{:?}",
+        summary.warnings
+    );
+}
+
+#[test]
+fn images_keep_their_alt_text_or_get_the_template_or_are_decorative() {
+    let mut png = Vec::new();
+    image::RgbImage::new(4, 4)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    let fetcher = book()
+        .page(
+            "https://czbooks.net/n/test/1",
+            chapter(
+                "第一章 開始",
+                r#"<img src="/a.png" alt="地圖 &quot;北方&quot;"><img src="/a.png"><p>很久很久以前。</p>"#,
+            ),
+        )
+        .page(
+            "https://czbooks.net/n/test/2",
+            chapter("第二章 結束", r#"<img src="/a.png"><p>從此以後。</p>"#),
+        )
+        .page("https://czbooks.net/a.png", png);
+    let build = |name: &str, images: epub_dude::images::ImageOptions| {
+        let path = output(name);
+        let options = BuildOptions {
+            images: epub_dude::images::ImageOptions {
+                embed: true,
+                ..images
+            },
+            ..options(&path)
+        };
+        build_epub(&source(), &fetcher, &options, &()).unwrap();
+        (entry(&path, "/0.xhtml"), entry(&path, "/1.xhtml"))
+    };
+
+    let (first, second) = build("alt-template", Default::default());
+    assert!(
+        first.contains(
+            r#"<img src="images/0001.png" alt="地圖 &quot;北方&quot;" /><img src="images/0001.png" alt="Illustration 2" />"#
+        ),
+        "{first}"
+    );
+    // Numbered across the book.
+    assert!(
+        second.contains(r#"<img src="images/0001.png" alt="Illustration 3" />"#),
+        "{second}"
+    );
+
+    let (first, _) = build(
+        "alt-custom",
+        epub_dude::images::ImageOptions {
+            alt_template: "插圖 {n}".to_string(),
+            ..Default::default()
+        },
+    );
+    assert!(first.contains(r#"alt="插圖 2""#), "{first}");
+
+    let (first, second) = build(
+        "alt-decorative",
+        epub_dude::images::ImageOptions {
+            decorative: true,
+            ..Default::default()
+        },
+    );
+    assert!(
+        first.contains(
+            r#"<img src="images/0001.png" alt="地圖 &quot;北方&quot;" /><img src="images/0001.png" alt="" role="presentation" />"#
+        ),
+        "{first}"
+    );
+    assert!(
+        second.contains(r#"<img src="images/0001.png" alt="" role="presentation" />"#),
+        "{second}"
+    );
+}
+
+#[test]
+fn accessibility_metadata_claims_alt_text_only_when_every_image_has_it() {
+    let mut png = Vec::new();
+    image::RgbImage::new(4, 4)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    let build = |name: &str, text: &str, decorative: bool| {
+        let path = output(name);
+        let fetcher = book()
+            .page("https://czbooks.net/n/test/1", chapter("第一章 開始", text))
+            .page("https://czbooks.net/a.png", png.clone());
+        let options = BuildOptions {
+            images: epub_dude::images::ImageOptions {
+                embed: true,
+                decorative,
+                ..Default::default()
+            },
+            ..options(&path)
+        };
+        build_epub(&source(), &fetcher, &options, &()).unwrap();
+        let opf = entry(&path, ".opf");
+        let meta = |property: &str| -> Vec<String> {
+            opf.split(&format!(r#"<meta property="schema:{property}">"#))
+                .skip(1)
+                .filter_map(|rest| rest.split('<').next())
+                .map(str::to_string)
+                .collect()
+        };
+        (
+            meta("accessMode"),
+            meta("accessModeSufficient"),
+            meta("accessibilityFeature").contains(&"alternativeText".to_string()),
+            meta("accessibilitySummary"),
+        )
+    };
+
+    let (modes, sufficient, alt_text, _) = build("a11y-text", "<p>文字。</p>", false);
+    assert_eq!(
+        (modes, sufficient, alt_text),
+        (
+            vec!["textual".to_string()],
+            vec!["textual".to_string()],
+            false
+        )
+    );
+
+    let (modes, sufficient, alt_text, _) = build(
+        "a11y-described",
+        r#"<img src="/a.png" alt="插圖"><p>文字。</p>"#,
+        false,
+    );
+    assert_eq!(modes, ["textual", "visual"]);
+    assert_eq!(sufficient, ["textual", "textual,visual"]);
+    assert!(alt_text);
+
+    let (modes, sufficient, alt_text, summary) = build(
+        "a11y-partly",
+        r#"<img src="/a.png" alt="插圖"><img src="/a.png"><p>文字。</p>"#,
+        true,
+    );
+    assert_eq!(modes, ["textual", "visual"]);
+    assert_eq!(sufficient, ["textual,visual"]);
+    assert!(!alt_text);
+    assert_eq!(
+        summary,
+        [
+            "Reflowable text with a navigable table of contents; not every image carries alternative text."
+        ]
+    );
+}
+
+#[test]
+fn only_oversized_images_or_an_explicit_quality_re_encode() {
+    let encode = |img: image::DynamicImage, format: image::ImageFormat| {
+        let mut bytes = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    };
+    let small = encode(
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(40, 30, |x, y| {
+            let noise = (x * 7919 + y * 104_729) % 251;
+            image::Rgb([
+                noise as u8,
+                (noise * 3 % 256) as u8,
+                (noise * 5 % 256) as u8,
+            ])
+        })),
+        image::ImageFormat::Png,
+    );
+    let big = encode(
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(400, 200, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        })),
+        image::ImageFormat::Png,
+    );
+    let translucent = encode(
+        image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            300,
+            300,
+            image::Rgba([0, 0, 255, 100]),
+        )),
+        image::ImageFormat::Png,
+    );
+    let fetcher = book()
+        .page(
+            "https://czbooks.net/n/test/1",
+            chapter(
+                "第一章 開始",
+                r#"<img src="/small.png" alt="小"><img src="/big.png" alt="大"><img src="/alpha.png" alt="透明"><p>很久很久以前。</p>"#,
+            ),
+        )
+        .page("https://czbooks.net/small.png", small.clone())
+        .page("https://czbooks.net/big.png", big)
+        .page("https://czbooks.net/alpha.png", translucent);
+    let build = |name: &str, quality: Option<u8>| {
+        let path = output(name);
+        let options = BuildOptions {
+            images: epub_dude::images::ImageOptions {
+                embed: true,
+                max_dimension: Some(100),
+                quality,
+                ..Default::default()
+            },
+            ..options(&path)
+        };
+        build_epub(&source(), &fetcher, &options, &()).unwrap();
+        let mut zip = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let mut images: Vec<(String, Vec<u8>)> = (0..zip.len())
+            .filter_map(|i| {
+                let mut file = zip.by_index(i).unwrap();
+                let name = file.name().strip_prefix("OEBPS/images/")?.to_string();
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes).unwrap();
+                Some((name, bytes))
+            })
+            .collect();
+        images.sort();
+        images
+    };
+
+    let images = build("reencode-oversized", None);
+    let names: Vec<&str> = images.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["0001.png", "0002.jpg", "0003.png"]);
+    // Within the limit and no quality asked for: untouched.
+    assert_eq!(images[0].1, small);
+    let size = |bytes: &[u8]| {
+        let img = image::load_from_memory(bytes).unwrap();
+        (img.width(), img.height(), img.color().has_alpha())
+    };
+    assert_eq!(size(&images[1].1), (100, 50, false));
+    assert_eq!(size(&images[2].1), (100, 100, true));
+
+    let images = build("reencode-quality", Some(40));
+    let names: Vec<&str> = images.iter().map(|(name, _)| name.as_str()).collect();
+    // Asked for a quality, the small one is recompressed too.
+    assert_eq!(names, ["0001.jpg", "0002.jpg", "0003.png"]);
+    assert_eq!(size(&images[0].1), (40, 30, false));
+}
+
+#[test]
+fn lazy_loaded_images_are_fetched_from_their_real_source() {
+    let png = |width: u32, height: u32| {
+        let mut png = Vec::new();
+        image::RgbImage::new(width, height)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        png
+    };
+    let fetcher = book()
+        .page(
+            "https://czbooks.net/n/test/1",
+            chapter(
+                "第一章 開始",
+                concat!(
+                    r#"<img src="data:image/gif;base64,R0lGODlhAQABAAAAACw=" data-src="/lazy.png" alt="一">"#,
+                    r#"<img src="/tiny.png" srcset="/s.png 100w, /l.png 800w, /m.png 300w" alt="二">"#,
+                    r#"<img srcset="data:image/gif;base64,R0lGODlhAQABAAAAACw= 3x, a.png 1x, b.png 2x" alt="三">"#,
+                    r#"<img src="/tiny.png" data-original="/original.png" alt="四">"#,
+                    "<p>很久很久以前。</p>",
+                ),
+            ),
+        )
+        .page("https://czbooks.net/lazy.png", png(10, 10))
+        .page("https://czbooks.net/tiny.png", png(1, 1))
+        .page("https://czbooks.net/s.png", png(100, 1))
+        .page("https://czbooks.net/m.png", png(300, 1))
+        .page("https://czbooks.net/l.png", png(800, 1))
+        .page("https://czbooks.net/n/test/a.png", png(5, 1))
+        .page("https://czbooks.net/n/test/b.png", png(20, 1))
+        .page("https://czbooks.net/original.png", png(30, 30));
+    let build = |name: &str, max_dimension: Option<u32>| {
+        let path = output(name);
+        let options = BuildOptions {
+            images: epub_dude::images::ImageOptions {
+                embed: true,
+                max_dimension,
+                ..Default::default()
+            },
+            ..options(&path)
+        };
+        let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+        assert!(summary.warnings.is_empty(), "{:?}", summary.warnings);
+        let mut zip = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let mut images: Vec<(String, u32)> = (0..zip.len())
+            .filter_map(|i| {
+                let mut file = zip.by_index(i).unwrap();
+                let name = file.name().strip_prefix("OEBPS/images/")?.to_string();
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes).unwrap();
+                Some((name, image::load_from_memory(&bytes).unwrap().width()))
+            })
+            .collect();
+        images.sort();
+        images
+            .into_iter()
+            .map(|(_, width)| width)
+            .collect::<Vec<_>>()
+    };
+
+    // The widest candidate, the densest one when they give densities.
+    assert_eq!(build("lazy-images", None), [10, 800, 20, 30]);
+    // The widest that fits the limit.
+    assert_eq!(build("lazy-images-limited", Some(500)), [10, 300, 20, 30]);
+    // All too wide: the narrowest, then downscaled.
+    assert_eq!(build("lazy-images-small", Some(50)), [10, 50, 20, 30]);
+}
+
+#[test]
+fn cover_from_content_takes_the_first_big_image_of_chapter_one() {
+    let png = |width: u32, height: u32| {
+        let mut png = Vec::new();
+        image::RgbImage::new(width, height)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        png
+    };
+    let fetcher = book()
+        .page(
+            "https://czbooks.net/n/test/1",
+            chapter(
+                "第一章 開始",
+                r#"<img src="/icon.png" alt="圖示"><img src="/title.png" alt="扉頁"><p>很久很久以前。</p>"#,
+            ),
+        )
+        .page("https://czbooks.net/icon.png", png(16, 16))
+        .page("https://czbooks.net/title.png", png(400, 300));
+    let build = |min: u32, name: &str| {
+        let path = output(name);
+        let options = BuildOptions {
+            images: epub_dude::images::ImageOptions {
+                embed: true,
+                cover_from_content: Some(min),
+                ..Default::default()
+            },
+            validate: true,
+            ..options(&path)
+        };
+        build_epub(&source(), &fetcher, &options, &()).unwrap();
+        entries(&path)
+    };
+
+    let entries = build(300, "cover-from-content");
+    let find = |suffix: &str| {
+        &entries
+            .iter()
+            .find(|(name, _)| name.ends_with(suffix))
+            .expect(suffix)
+            .1
+    };
+    let (opf, page) = (find(".opf"), find("/0.xhtml"));
+    assert!(
+        opf.contains(r#"properties="cover-image" id="cover-image" href="cover.png""#),
+        "{opf}"
+    );
+    // The image stays where it was in the chapter.
+    assert!(
+        page.contains(r#"<img src="images/0002.png" alt="扉頁" />"#),
+        "{page}"
+    );
+
+    let entries = build(500, "cover-too-small");
+    let (_, opf) = entries
+        .iter()
+        .find(|(name, _)| name.ends_with(".opf"))
+        .unwrap();
+    assert!(!opf.contains("cover-image"), "{opf}");
+}
+
+#[test]
+fn vertical_writing_turns_pages_right_to_left() {
+    use epub_dude::xhtml::WritingMode;
+
+    let build = |name: &str, writing_mode: WritingMode| {
+        let path = output(name);
+        let options = BuildOptions {
+            writing_mode,
+            ..options(&path)
+        };
+        build_epub(&source(), &book(), &options, &()).unwrap();
+        (entry(&path, ".opf"), entry(&path, "stylesheet.css"))
+    };
+
+    let (opf, css) = build("vertical-rl", WritingMode::VerticalRl);
+    assert!(
+        opf.contains(r#"<spine toc="ncx" page-progression-direction="rtl">"#),
+        "{opf}"
+    );
+    assert!(
+        opf.contains(r#"<meta name="primary-writing-mode" content="vertical-rl"/>"#),
+        "{opf}"
+    );
+    assert!(
+        css.starts_with("html {\n  writing-mode: vertical-rl;\n  -epub-writing-mode: vertical-rl;"),
+        "{css}"
+    );
+    // Margins turn with the text.
+    assert!(
+        !css.contains("margin-top") && css.contains("margin-right: 20%;"),
+        "{css}"
+    );
+
+    let (opf, css) = build("horizontal-tb", WritingMode::HorizontalTb);
+    assert!(opf.contains(r#"page-progression-direction="ltr""#), "{opf}");
+    assert!(!opf.contains("primary-writing-mode"), "{opf}");
+    assert!(
+        !css.contains("writing-mode") && css.contains("margin-top: 20%;"),
+        "{css}"
+    );
+}
+
+#[test]
+fn each_theme_has_its_stylesheet() {
+    use epub_dude::xhtml::{Theme, WritingMode, stylesheet};
+
+    for (name, theme) in [
+        ("plain", Theme::Plain),
+        ("classic", Theme::Classic),
+        ("modern", Theme::Modern),
+        ("night-safe", Theme::NightSafe),
+    ] {
+        assert_eq!(name.parse::<Theme>().unwrap(), theme);
+        insta::assert_snapshot!(
+            format!("stylesheet_{name}"),
+            stylesheet(WritingMode::HorizontalTb, theme, &Default::default())
+        );
+    }
+    let night = stylesheet(
+        WritingMode::VerticalRl,
+        Theme::NightSafe,
+        &Default::default(),
+    );
+    assert!(!night.contains('#') && !night.contains("color"), "{night}");
+
+    let path = output("theme");
+    let options = BuildOptions {
+        theme: Theme::Classic,
+        ..options(&path)
+    };
+    build_epub(&source(), &book(), &options, &()).unwrap();
+    let (_, css) = entries(&path)
+        .into_iter()
+        .find(|(name, _)| name.ends_with("stylesheet.css"))
+        .expect("stylesheet");
+    assert!(css.contains("text-indent: 2em;"), "{css}");
+}
+
+#[test]
+fn typography_knobs_override_the_theme() {
+    use epub_dude::xhtml::{Theme, Typography, WritingMode, stylesheet};
+
+    let typography = Typography {
+        justify: true,
+        font_size: Some(110),
+        line_height: Some(1.6),
+        paragraph_spacing: Some(0.5),
+    };
+    let css = stylesheet(WritingMode::HorizontalTb, Theme::Modern, &typography);
+    let at = |needle: &str| css.find(needle).expect(needle);
+    // Later rules win, so the knobs come after the theme's own.
+    assert!(
+        at("margin-bottom: 0.8em;") < at("margin-bottom: 0.5em;"),
+        "{css}"
+    );
+    for rule in [
+        "text-align: justify;",
+        "font-size: 110%;",
+        "line-height: 1.6;",
+    ] {
+        assert!(at("font-family: sans-serif;") < at(rule), "{css}");
+    }
+
+    let vertical = stylesheet(WritingMode::VerticalRl, Theme::Plain, &typography);
+    assert!(vertical.contains("margin-left: 0.5em;"), "{vertical}");
+    let untouched = stylesheet(
+        WritingMode::HorizontalTb,
+        Theme::Plain,
+        &Typography::default(),
+    );
+    assert!(!untouched.contains("justify") && !untouched.contains("line-height"));
+}
+
+#[test]
+fn every_document_is_in_the_book_language_and_foreign_paragraphs_in_theirs() {
+    let fetcher = book().page(
+        "https://czbooks.net/n/test/1",
+        chapter(
+            "第一章 開始",
+            "<p>很久很久以前。</p><p>Once upon a time, in a land far away.</p>",
+        ),
+    );
+    for (epub2, attributes) in [
+        (false, r#"<html lang="zh" xml:lang="zh""#),
+        (true, r#"<html xml:lang="zh""#),
+    ] {
+        let path = output(if epub2 { "lang-epub2" } else { "lang" });
+        let options = BuildOptions {
+            per_paragraph_lang: true,
+            epub2,
+            validate: true,
+            ..options(&path)
+        };
+        build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+        let entries = entries(&path);
+        let pages: Vec<_> = entries
+            .iter()
+            .filter(|(name, _)| name.ends_with(".xhtml"))
+            .collect();
+        assert!(pages.iter().any(|(name, _)| name.ends_with("toc.xhtml")));
+        for (name, page) in pages {
+            assert!(page.contains(attributes), "{name}: {page}");
+        }
+        let (_, first) = entries
+            .iter()
+            .find(|(name, _)| name.ends_with("/0.xhtml"))
+            .unwrap();
+        let span = if epub2 {
+            r#"<span xml:lang="en">Once upon a time"#
+        } else {
+            r#"<span lang="en" xml:lang="en">Once upon a time"#
+        };
+        assert!(first.contains(span), "{first}");
+        assert!(!first.contains(r#"lang="zh">很久"#), "{first}");
+    }
+}
+
+#[test]
+fn toc_description_shows_the_synopsis_above_the_html_contents() {
+    let path = output("toc-description").with_file_name("described.html");
+    let index = INDEX.replace(
+        "<span class=\"title\">",
+        "<meta property=\"og:description\" content=\"少年 &lt;出山&gt;。\n\n  一路向南，走過山海。\">\n<span class=\"title\">",
+    );
+    let fetcher = book().page(INDEX_URL, index.as_str());
+    let page = |options: BuildOptions| {
+        build_epub(&source(), &fetcher, &options, &()).unwrap();
+        std::fs::read_to_string(&path).unwrap()
+    };
+    let html = || BuildOptions {
+        format: "html".parse().unwrap(),
+        ..options(&path)
+    };
+
+    assert!(!page(html()).contains("class=\"description\""));
+
+    let described = page(BuildOptions {
+        toc_description: true,
+        description_limit: 12,
+        ..html()
+    });
+    let shown =
+        r#"<p class="description">少年 &lt;出山&gt;。</p><p class="description">一路向南…</p>"#;
+    let (before, after) = described.split_once(shown).expect(&described);
+    assert!(before.contains("<header>") && after.contains("<nav class=\"toc\">"));
+}
+
+#[test]
+fn a_catalog_lists_every_epub_with_its_cover() {
+    let path = output("catalog");
+    let dir = path.parent().unwrap().to_path_buf();
+    build_epub(&source(), &book(), &options(&path), &()).unwrap();
+
+    let mut png = Vec::new();
+    image::RgbImage::new(600, 900)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    let mut covered =
+        epub_builder::EpubBuilder::new(epub_builder::ZipLibrary::new().unwrap()).unwrap();
+    covered
+        .metadata("title", "有封面 & 書")
+        .unwrap()
+        .metadata("author", "作者乙")
+        .unwrap()
+        .add_cover_image("cover.png", png.as_slice(), "image/png")
+        .unwrap()
+        .add_content(epub_builder::EpubContent::new("a.xhtml", "<p/>".as_bytes()))
+        .unwrap();
+    std::fs::create_dir_all(dir.join("more books")).unwrap();
+    let covered_path = dir.join("more books/covered.epub");
+    covered
+        .generate(File::create(&covered_path).unwrap())
+        .unwrap();
+    std::fs::write(dir.join("broken.epub"), "not a zip").unwrap();
+    // A cover outside the package document's directory.
+    let mut outside = zip::ZipWriter::new(File::create(dir.join("outside.epub")).unwrap());
+    for (name, content) in [
+        (
+            "META-INF/container.xml",
+            r#"<container><rootfiles><rootfile full-path="OEBPS/content.opf"/></rootfiles></container>"#.as_bytes(),
+        ),
+        (
+            "OEBPS/content.opf",
+            r#"<package><metadata><title>封面在外</title></metadata><manifest><item id="c" href="../images/./cover.png" media-type="image/png" properties="cover-image"/></manifest></package>"#.as_bytes(),
+        ),
+        ("images/cover.png", png.as_slice()),
+    ] {
+        outside
+            .start_file(name, zip::write::SimpleFileOptions::default())
+            .unwrap();
+        std::io::Write::write_all(&mut outside, content).unwrap();
+    }
+    outside.finish().unwrap();
+
+    let books = epub_dude::catalog::scan(&dir).unwrap();
+    assert_eq!(books.len(), 3, "the broken file is skipped");
+    let outside = books.iter().find(|b| b.title == "封面在外").unwrap();
+    assert_eq!(outside.cover.as_ref().map(|(c, _)| c), Some(&png));
+    let feed = dir.join("catalog.xml");
+    epub_dude::catalog::write(&dir, &feed, "書架", &books).unwrap();
+
+    let xml = std::fs::read_to_string(&feed).unwrap();
+    let doc = roxmltree::Document::parse(&xml).expect("well-formed feed");
+    let entries: Vec<_> = doc
+        .descendants()
+        .filter(|n| n.has_tag_name("entry"))
+        .collect();
+    let child = |entry: &roxmltree::Node, name: &str| {
+        entry
+            .descendants()
+            .find(|n| n.has_tag_name(name))
+            .and_then(|n| n.text())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let link = |entry: &roxmltree::Node, rel: &str| {
+        entry
+            .children()
+            .find(|n| n.attribute("rel") == Some(rel))
+            .and_then(|n| n.attribute("href"))
+            .map(str::to_string)
+    };
+
+    let built = entries
+        .iter()
+        .find(|e| child(e, "title") == "測試之書")
+        .unwrap();
+    assert_eq!(child(built, "name"), "作者甲");
+    assert!(child(built, "id").starts_with("urn:uuid:"));
+    assert_eq!(
+        link(built, "http://opds-spec.org/acquisition").as_deref(),
+        Some("book.epub")
+    );
+    assert_eq!(link(built, "http://opds-spec.org/image"), None);
+
+    let covered = entries
+        .iter()
+        .find(|e| child(e, "title") == "有封面 & 書")
+        .unwrap();
+    assert_eq!(
+        link(covered, "http://opds-spec.org/acquisition").as_deref(),
+        Some("more%20books/covered.epub")
+    );
+    let thumbnail = link(covered, "http://opds-spec.org/image/thumbnail").unwrap();
+    let thumbnail = image::open(dir.join(thumbnail)).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (171, 256));
+    assert!(
+        dir.join(link(covered, "http://opds-spec.org/image").unwrap())
+            .exists()
+    );
+
+    // A feed elsewhere links back to the books.
+    let elsewhere = dir.join("feeds/opds.xml");
+    std::fs::create_dir_all(elsewhere.parent().unwrap()).unwrap();
+    epub_dude::catalog::write(&dir, &elsewhere, "書架", &books).unwrap();
+    let xml = std::fs::read_to_string(&elsewhere).unwrap();
+    assert!(xml.contains(r#"href="../book.epub""#), "{xml}");
+    assert!(
+        xml.contains(r#"href="../more%20books/covered.epub""#),
+        "{xml}"
+    );
+    let cover = xml
+        .split(r#"<link rel="http://opds-spec.org/image" href=""#)
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .unwrap();
+    assert!(cover.starts_with("../covers/"), "{xml}");
+    assert!(elsewhere.parent().unwrap().join(cover).exists(), "{cover}");
+
+    // Ids follow the books, not the run.
+    let again = epub_dude::catalog::scan(&dir).unwrap();
+    let ids = |books: &[epub_dude::catalog::Book]| books.iter().map(|b| b.id()).collect::<Vec<_>>();
+    assert_eq!(ids(&books), ids(&again));
+}
+
+const OG_INDEX: &str = r#"<html><head>
+<link rel="canonical" href="https://czbooks.net/n/test-canonical">
+<meta property="og:title" content="開放圖譜之書">
+<meta property="og:description" content="一個關於測試的故事。">
+<meta property="og:image" content="/cover.png">
+</head><body>
+<span class="title">測試之書</span>
+<span class="author"><a href="/a/1">作者甲</a></span>
+<ul id="chapter-list">
+  <li><a href="//czbooks.net/n/test/1">第一章 開始</a></li>
+  <li><a href="//czbooks.net/n/test/2">第二章 結束</a></li>
+</ul>
+</body></html>"#;
+
+fn og_book() -> MemoryFetcher {
+    let mut png = Vec::new();
+    image::RgbImage::new(2, 3)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    book()
+        .page(INDEX_URL, OG_INDEX)
+        .page("https://czbooks.net/cover.png", png)
+}
+
+#[test]
+fn the_title_page_opens_the_book_after_the_cover() {
+    let index = OG_INDEX.replace("一個關於測試的故事。", "一個關於測試的很長很長的故事。");
+    let fetcher = og_book().page(INDEX_URL, index);
+    let build = |name: &str, no_title_page: bool| {
+        let path = output(name);
+        let options = BuildOptions {
+            description_limit: 8,
+            no_title_page,
+            ..options(&path)
+        };
+        build_epub(&source(), &fetcher, &options, &()).unwrap();
+        path
+    };
+
+    let path = build("title-page", false);
+    let opf = entry(&path, ".opf");
+    let itemrefs: Vec<&str> = opf
+        .split(r#"<itemref idref=""#)
+        .skip(1)
+        .filter_map(|item| item.split('"').next())
+        .collect();
+    assert_eq!(
+        itemrefs[..3],
+        ["id_cover.xhtml", "id_title.xhtml", "id_0.xhtml"],
+        "{opf}"
+    );
+    assert!(
+        opf.contains(r#"<reference type="title-page" title="測試之書" href="title.xhtml"/>"#),
+        "{opf}"
+    );
+    assert!(entry(&path, "cover.xhtml").contains(r#"<img src="cover.png" alt="測試之書" />"#));
+    let page = entry(&path, "title.xhtml");
+    for expected in [
+        r#"<section class="title-page" epub:type="titlepage"><h1>測試之書</h1>"#,
+        r#"<p class="author">作者甲</p>"#,
+        r#"<p class="description">一個關於測試的很…</p>"#,
+        r#"<p class="source"><a href="https://czbooks.net/n/test">https://czbooks.net/n/test</a></p>"#,
+        "stylesheet.css",
+    ] {
+        assert!(page.contains(expected), "{expected} in {page}");
+    }
+
+    let path = build("no-title-page", true);
+    let opf = entry(&path, ".opf");
+    assert!(!opf.contains("title.xhtml"), "{opf}");
+    assert!(
+        opf.contains(r#"<itemref idref="id_cover.xhtml"/>"#),
+        "{opf}"
+    );
+}
+
+#[test]
+fn open_graph_tags_fill_in_what_scraping_missed() {
+    let path = output("og-fallback");
+    build_epub(&source(), &og_book(), &options(&path), &()).unwrap();
+
+    let entries = entries(&path);
+    let file = |name: &str| {
+        entries
+            .iter()
+            .find(|(n, _)| n.ends_with(name))
+            .map(|(_, c)| c.as_str())
+            .unwrap_or_else(|| panic!("no {name}"))
+    };
+    let opf = file("content.opf");
+    assert!(opf.contains("測試之書"), "the scraped title is kept");
+    assert!(!opf.contains("開放圖譜之書"));
+    assert!(file("title.xhtml").contains("一個關於測試的故事。"));
+    assert!(opf.contains(r#"properties="cover-image" id="cover-image" href="cover.png""#));
+    let identifier = epub_dude::parts::identifier(INDEX_URL, 0).to_string();
+    assert!(opf.contains(&identifier), "identified by the index URL");
+}
+
+#[test]
+fn prefer_og_puts_the_tags_first() {
+    let path = output("og-prefer");
+    let options = BuildOptions {
+        prefer_og: true,
+        ..options(&path)
+    };
+    build_epub(&source(), &og_book(), &options, &()).unwrap();
+
+    let (_, opf) = entries(&path)
+        .into_iter()
+        .find(|(n, _)| n.ends_with("content.opf"))
+        .unwrap();
+    assert!(opf.contains("<dc:title>開放圖譜之書</dc:title>"));
+    let identifier =
+        epub_dude::parts::identifier("https://czbooks.net/n/test-canonical", 0).to_string();
+    assert!(opf.contains(&identifier), "identified by the canonical URL");
+}
+
+#[test]
+fn the_page_title_and_description_are_the_last_resort() {
+    let index = r#"<html><head>
+<title>
+  無名之書 - 小說天地 &amp; 書城
+</title>
+<meta property="og:site_name" content="小說天地 &amp; 書城">
+<meta name="description" content="只有頁面描述的故事。">
+</head><body>
+<svg><title>圖示</title></svg>
+<ul id="chapter-list">
+  <li><a href="//czbooks.net/n/test/1">第一章 開始</a></li>
+  <li><a href="//czbooks.net/n/test/2">第二章 結束</a></li>
+</ul>
+</body></html>"#;
+    let path = output("document-meta");
+    build_epub(
+        &source(),
+        &book().page(INDEX_URL, index),
+        &options(&path),
+        &(),
+    )
+    .unwrap();
+
+    let entries = entries(&path);
+    let file = |name: &str| {
+        entries
+            .iter()
+            .find(|(n, _)| n.ends_with(name))
+            .map(|(_, c)| c.clone())
+            .unwrap()
+    };
+    assert!(file("content.opf").contains("<dc:title>無名之書</dc:title>"));
+    assert!(file("title.xhtml").contains("只有頁面描述的故事。"));
+
+    // Anything better comes first.
+    assert_eq!(
+        epub_dude::fetch::og::without_site_name("書 | 書城", Some("書城")),
+        "書"
+    );
+    assert_eq!(
+        epub_dude::fetch::og::without_site_name("書城", Some("書城")),
+        "書城"
+    );
+    let index = OG_INDEX.replace("</head>", "<title>別的</title></head>");
+    let info = source().info(&book().page(INDEX_URL, index)).unwrap();
+    assert_eq!(info.title, "測試之書");
+    assert_eq!(info.description.as_deref(), Some("一個關於測試的故事。"));
+}
+
+#[test]
+fn json_ld_fills_in_the_book_and_the_manifest() {
+    let index = r#"<html><head>
+<script type="application/ld+json">{ "@type": "Book", "name": </script>
+<script type="application/ld+json">
+{"@context": "https://schema.org", "@graph": [
+  {"@type": "WebSite", "name": "書站"},
+  {"@type": "Book", "name": "結構化之書",
+   "author": [{"@type": "Person", "name": "作者乙"}, "作者丙"],
+   "description": "1 < 2 的故事。",
+   "genre": ["奇幻", "冒險"],
+   "datePublished": "2019-03",
+   "publisher": {"@type": "Organization", "name": "出版社"},
+   "hasPart": [{"@type": "Chapter", "name": "第一章"}]}
+]}
+</script>
+<meta property="og:title" content="開放圖譜之書">
+</head><body>
+<ul id="chapter-list">
+  <li><a href="//czbooks.net/n/test/1">第一章 開始</a></li>
+  <li><a href="//czbooks.net/n/test/2">第二章 結束</a></li>
+</ul>
+</body></html>"#;
+    let path = output("json-ld");
+    let manifest = path.with_file_name("manifest.json");
+    let options = BuildOptions {
+        manifest: Some(manifest.clone()),
+        ..options(&path)
+    };
+    build_epub(&source(), &book().page(INDEX_URL, index), &options, &()).unwrap();
+
+    let entries = entries(&path);
+    let file = |name: &str| {
+        entries
+            .iter()
+            .find(|(n, _)| n.ends_with(name))
+            .map(|(_, c)| c.clone())
+            .unwrap()
+    };
+    let opf = file("content.opf");
+    assert!(
+        opf.contains("<dc:title>結構化之書</dc:title>"),
+        "JSON-LD before Open Graph"
+    );
+    assert!(opf.contains("作者乙") && opf.contains("作者丙"));
+    assert!(opf.contains("<dc:subject>奇幻</dc:subject>"));
+    assert!(opf.contains("<dc:subject>冒險</dc:subject>"));
+    assert!(opf.contains("2019-03-01"));
+    assert!(file("title.xhtml").contains("1 &lt; 2 的故事。"));
+
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap();
+    assert_eq!(manifest["subjects"], serde_json::json!(["奇幻", "冒險"]));
+    assert_eq!(manifest["published"], "2019-03");
+    assert_eq!(manifest["linked_data"]["publisher"]["name"], "出版社");
+    assert!(manifest["linked_data"].get("hasPart").is_none());
+    assert!(manifest["linked_data"].get("@type").is_none());
+}
+
+#[test]
+fn since_skips_older_chapters_and_dates_are_recorded() {
+    let index = r#"<html><body>
+<span class="title">測試之書</span>
+<span class="author"><a href="/a/1">作者甲</a></span>
+<ul id="chapter-list">
+  <li><a href="//czbooks.net/n/test/1">第一章 開始</a><span class="time">2023-12-31</span></li>
+  <li><a href="//czbooks.net/n/test/2">第二章 結束</a><span class="time">2024-01-02</span></li>
+  <li><a href="//czbooks.net/n/test/3">第三章 後記</a></li>
+</ul>
+</body></html>"#;
+    let fetcher = book().page(INDEX_URL, index).page(
+        "https://czbooks.net/n/test/3",
+        chapter("第三章 後記", "<p>完。</p>"),
+    );
+    let path = output("since");
+    let manifest = path.with_file_name("manifest.json");
+    let options = BuildOptions {
+        chapter_dates: Some("span.time".parse().unwrap()),
+        since: Some("2024-01-01".parse().unwrap()),
+        colophon: true,
+        manifest: Some(manifest.clone()),
+        ..options(&path)
+    };
+
+    let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    assert_eq!(summary.chapters, 2);
+    assert_eq!(summary.excluded, ["第一章 開始"]);
+    assert!(
+        !fetcher
+            .requests()
+            .contains(&"https://czbooks.net/n/test/1".to_string())
+    );
+    assert!(
+        summary
+            .warnings
+            .iter()
+            .any(|w| w.contains("1 chapters without a date"))
+    );
+
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap();
+    assert_eq!(manifest["chapters"][0]["published"], "2024-01-02");
+    assert!(manifest["chapters"][1]["published"].is_null());
+    let (_, colophon) = entries(&path)
+        .into_iter()
+        .find(|(n, _)| n.ends_with("colophon.xhtml"))
+        .unwrap();
+    assert!(colophon.contains("<dt>Published</dt><dd>2024-01-02</dd>"));
+}
+
+#[test]
+fn the_colophon_records_the_run_summary() {
+    let path = output("colophon");
+    let no_colophon = output("no-colophon");
+    let options = BuildOptions {
+        colophon: true,
+        locked: Some(regex::Regex::new("付費解鎖").unwrap()),
+        ..options(&path)
+    };
+    let fetcher = book().page(
+        "https://czbooks.net/n/test/2",
+        chapter("第二章 結束", "<p>本章需付費解鎖。</p>"),
+    );
+
+    let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    assert_eq!(summary.placeholders.len(), 1, "{:?}", summary.placeholders);
+    let page = entry(&path, "colophon.xhtml");
+    let today = chrono::Local::now().format("%Y-%m-%d").to_string();
+    for expected in [
+        r#"<section class="colophon" epub:type="colophon"><h2>Colophon</h2><dl>"#.to_string(),
+        r#"<dt>Source</dt><dd><a href="https://czbooks.net/n/test">https://czbooks.net/n/test</a></dd>"#
+            .to_string(),
+        format!("<dt>Fetched</dt><dd>{}</dd>", summary.fetch_range().unwrap()),
+        "<dt>Chapters</dt><dd>2</dd>".to_string(),
+        format!(
+            "<dt>Generated by</dt><dd>epub-dude {}</dd>",
+            env!("CARGO_PKG_VERSION")
+        ),
+        format!(
+            "<p>Placeholder chapters:</p><ul><li>{}</li></ul>",
+            summary.placeholders[0].replace('"', "&quot;")
+        ),
+    ] {
+        assert!(page.contains(&expected), "{expected} in {page}");
+    }
+    assert!(summary.fetch_range().unwrap().contains(&today));
+
+    let without = BuildOptions {
+        colophon: false,
+        output: no_colophon.to_str().unwrap().parse().unwrap(),
+        work_dir: no_colophon.with_file_name("work"),
+        ..options
+    };
+    build_epub(&source(), &fetcher, &without, &()).unwrap();
+    assert!(!entry(&no_colophon, ".opf").contains("colophon"));
+}
+
+#[test]
+fn back_matter_is_left_out_of_the_reading_flow() {
+    let path = output("back-matter");
+    let options = BuildOptions {
+        author_page: Some(".description".parse().unwrap()),
+        colophon: true,
+        validate: true,
+        ..options(&path)
+    };
+    let fetcher = book().page(
+        "https://czbooks.net/a/1",
+        r#"<html><body><div class="description"><p>生於台北。</p></div></body></html>"#,
+    );
+
+    build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    let entries = entries(&path);
+    let find = |suffix: &str| {
+        &entries
+            .iter()
+            .find(|(name, _)| name.ends_with(suffix))
+            .expect(suffix)
+            .1
+    };
+    let (opf, nav) = (find(".opf"), find("nav.xhtml"));
+    for chapter in ["title", "0", "1"] {
+        assert!(
+            opf.contains(&format!(r#"<itemref idref="id_{chapter}.xhtml"/>"#)),
+            "{opf}"
+        );
+    }
+    for page in ["about-author", "colophon", "toc"] {
+        assert!(
+            opf.contains(&format!(
+                r#"<itemref idref="id_{page}.xhtml" linear="no"/>"#
+            )),
+            "{opf}"
+        );
+    }
+    // Still reachable from the contents and the landmarks.
+    let landmarks = &nav[nav.find(r#"epub:type = "landmarks""#).unwrap()..];
+    assert!(
+        landmarks.contains(r#"<a epub:type="acknowledgements" href="about-author.xhtml">"#),
+        "{nav}"
+    );
+    assert!(
+        landmarks.contains(r#"<a epub:type="colophon" href="colophon.xhtml">"#),
+        "{nav}"
+    );
+    let contents = &nav[..nav.find(r#"epub:type = "landmarks""#).unwrap()];
+    assert!(contents.contains(r#"href="colophon.xhtml""#), "{nav}");
+}
+
+#[test]
+fn chapters_without_text_are_fetched_again_then_read_by_the_fallback() {
+    let path = output("readable");
+    let manifest = path.with_file_name("manifest.json");
+    let text = "從此以後，他們過著幸福快樂的日子。".repeat(8);
+    let fetcher = book().page(
+        "https://czbooks.net/n/test/2",
+        format!(
+            r#"<html><body><div class="name">第二章 結束</div>
+<div class="menu"><a href="/">首頁</a> <a href="/n/test">目錄</a></div>
+<div class="article"><p>{text}</p><p>全文完。</p></div>
+<div class="footer">本站提供免費閱讀服務。</div></body></html>"#
+        ),
+    );
+    let options = BuildOptions {
+        manifest: Some(manifest.clone()),
+        ..options(&path)
+    };
+
+    let summary = build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    let fetches = fetcher
+        .requests()
+        .iter()
+        .filter(|url| url.as_str() == "https://czbooks.net/n/test/2")
+        .count();
+    assert_eq!(fetches, 2);
+    assert_eq!(summary.results.fetched_ok, 1);
+    assert_eq!(summary.results.parsed_empty, 1);
+    assert!(
+        summary
+            .warnings
+            .iter()
+            .any(|w| w.contains("chapter 2") && w.contains("largest block")),
+        "{:?}",
+        summary.warnings
+    );
+    let (_, page) = entries(&path)
+        .into_iter()
+        .find(|(n, _)| n.ends_with("/1.xhtml"))
+        .unwrap();
+    assert!(page.contains(&text) && page.contains("全文完。"), "{page}");
+    assert!(!page.contains("首頁") && !page.contains("本站"), "{page}");
+
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap();
+    assert_eq!(manifest["results"]["fetched_ok"], 1);
+    assert_eq!(manifest["results"]["parsed_empty"], 1);
+    assert_eq!(manifest["results"]["parse_error"], 0);
+}
+
+#[test]
+fn revised_chapters_are_reported_with_a_diff() {
+    let path = output("revisions");
+    let diffs = path.with_file_name("diffs");
+    let manifest = path.with_file_name("manifest.json");
+    let options = BuildOptions {
+        check_revisions: true,
+        revision_diff: Some(diffs.clone()),
+        manifest: Some(manifest.clone()),
+        ..options(&path)
+    };
+
+    let first = build_epub(&source(), &book(), &options, &()).unwrap();
+    assert!(first.revised.is_empty(), "the first run only records");
+
+    let revised = book()
+        .page(
+            "https://czbooks.net/n/test/1",
+            chapter("第一章 開始", "<div><p>  很久很久以前。\n</p></div>"),
+        )
+        .page(
+            "https://czbooks.net/n/test/2",
+            chapter("第二章 結束", "<p>從此以後。</p><p>全文完。</p>"),
+        );
+    let second = build_epub(&source(), &revised, &options, &()).unwrap();
+
+    assert_eq!(second.revised.len(), 1, "{:?}", second.revised);
+    assert!(second.revised[0].contains("第二章 結束"));
+    let diff = std::fs::read_to_string(diffs.join("chapter-0002.diff")).unwrap();
+    assert!(diff.contains("+全文完。"), "{diff}");
+    assert!(!diffs.join("chapter-0001.diff").exists());
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap();
+    assert_eq!(manifest["revised"][0]["index"], 1);
+    assert_ne!(
+        manifest["revised"][0]["hash"],
+        manifest["revised"][0]["previous_hash"]
+    );
+
+    let third = build_epub(&source(), &revised, &options, &()).unwrap();
+    assert!(third.revised.is_empty());
+}
+
+#[test]
+fn check_compares_the_index_with_the_last_build() {
+    let path = output("check");
+    let options = options(&path);
+
+    let never = epub_dude::updates::check(&source(), &book(), &options.work_dir).unwrap();
+    assert!(!never.built);
+    assert_eq!(never.new.len(), 2);
+
+    build_epub(&source(), &book(), &options, &()).unwrap();
+    let fetcher = book();
+    let current = epub_dude::updates::check(&source(), &fetcher, &options.work_dir).unwrap();
+    assert!(current.built && current.new.is_empty());
+    assert_eq!(current.title, "測試之書");
+    assert_eq!(fetcher.requests(), [INDEX_URL], "only the index is fetched");
+
+    let grown = INDEX.replace(
+        "</ul>",
+        r#"<li><a href="//czbooks.net/n/test/3">第三章 續篇</a></li></ul>"#,
+    );
+    let updates =
+        epub_dude::updates::check(&source(), &book().page(INDEX_URL, grown), &options.work_dir)
+            .unwrap();
+    let titles: Vec<_> = updates.new.iter().map(|l| l.title.as_str()).collect();
+    assert_eq!(titles, ["第三章 續篇"]);
+}
+
+#[test]
+fn author_page_appends_the_bio_and_failures_only_warn() {
+    let path = output("author-page");
+    let with_bio = BuildOptions {
+        author_page: Some(".description".parse().unwrap()),
+        ..options(&path)
+    };
+    let fetcher = book().page(
+        "https://czbooks.net/a/1",
+        r#"<html><body><div class="description"><p>生於台北。</p>
+<div class="more"><p>著有多部&amp;小說。</p></div></div><p>不是介紹。</p></body></html>"#,
+    );
+
+    build_epub(&source(), &fetcher, &with_bio, &()).unwrap();
+
+    let files = entries(&path);
+    let (_, about) = files
+        .iter()
+        .find(|(name, _)| name.ends_with("about-author.xhtml"))
+        .expect("about the author page");
+    assert!(about.contains("<p>生於台北。</p>"), "{about}");
+    assert!(about.contains("<p>著有多部&amp;小說。</p>"), "{about}");
+    assert!(!about.contains("不是介紹"), "{about}");
+    // Titled in the book's language, in the page and the contents.
+    assert!(about.contains("<title>关于作者</title>"), "{about}");
+    let nav = entry(&path, "nav.xhtml");
+    assert!(
+        nav.contains(r#"<a href="about-author.xhtml">关于作者</a>"#),
+        "{nav}"
+    );
+    for (language, title) in [
+        ("zh-Hant-TW", "關於作者"),
+        ("ja", "著者について"),
+        ("en", "About the Author"),
+    ] {
+        let localized = BuildOptions {
+            language: language.to_string(),
+            author_page: Some(".description".parse().unwrap()),
+            ..options(&path)
+        };
+        build_epub(&source(), &fetcher, &localized, &()).unwrap();
+        let about = entry(&path, "about-author.xhtml");
+        assert!(
+            about.contains(&format!("<title>{title}</title>")),
+            "{language}: {about}"
+        );
+    }
+    let (_, opf) = files
+        .iter()
+        .find(|(name, _)| name.ends_with(".opf"))
+        .expect("package document");
+    assert!(opf.contains(r#"type="acknowledgements""#), "{opf}");
+
+    let summary = build_epub(&source(), &book(), &with_bio, &()).unwrap();
+    assert_eq!(summary.chapters, 2);
+    assert!(
+        summary
+            .warnings
+            .iter()
+            .any(|w| w.contains("author page https://czbooks.net/a/1")),
+        "{:?}",
+        summary.warnings
+    );
+    let entries = self::entries(&path);
+    assert!(
+        !entries
+            .iter()
+            .any(|(name, _)| name.ends_with("about-author.xhtml"))
+    );
+}
+
+#[test]
+fn snapshots_are_named_after_the_last_chapter_and_updates_need_new_ones() {
+    let dir = output("snapshots").with_file_name("");
+    let template = format!(
+        "{}/{{title}} (up to ch. {{last_chapter}}, {{last_chapter_title}}).epub",
+        dir.display()
+    );
+    let update = BuildOptions {
+        output: template.parse().unwrap(),
+        update: true,
+        ..options(&dir.join("book.epub"))
+    };
+
+    let summary = build_epub(&source(), &book(), &update, &()).unwrap();
+    let first = dir.join("測試之書 (up to ch. 0002, 第二章 結束).epub");
+    assert_eq!(summary.files, std::slice::from_ref(&first));
+    let (_, opf) = entries(&first)
+        .into_iter()
+        .find(|(name, _)| name.ends_with(".opf"))
+        .expect("package document");
+    assert!(
+        opf.contains(r#"property="epub-dude:last-chapter">0002<"#),
+        "{opf}"
+    );
+    assert!(opf.contains("第二章 結束"), "{opf}");
+
+    std::fs::remove_file(&first).unwrap();
+    let err = build_epub(&source(), &book(), &update, &()).unwrap_err();
+    assert!(matches!(err, Error::UpToDate { chapters: 2 }), "{err}");
+    assert_eq!(err.exit_code(), exit_code::UP_TO_DATE);
+    assert!(!first.exists());
+
+    let index = INDEX.replace(
+        "</ul>",
+        r#"<li><a href="//czbooks.net/n/test/3">第三章 再會</a></li></ul>"#,
+    );
+    let fetcher = book().page(INDEX_URL, index).page(
+        "https://czbooks.net/n/test/3",
+        chapter("第三章 再會", "<p>後來。</p>"),
+    );
+    let summary = build_epub(&source(), &fetcher, &update, &()).unwrap();
+    assert_eq!(
+        summary.files,
+        [dir.join("測試之書 (up to ch. 0003, 第三章 再會).epub")]
+    );
+}
+
+#[test]
+fn unchanged_chapters_reuse_their_pages_until_an_option_changes() {
+    let path = output("generated");
+    let base = options(&path);
+    let stored = workdir::book_dir(&base.work_dir, &source().uri).join("pages");
+    let text = |path: &std::path::Path| -> String {
+        entries(path)
+            .into_iter()
+            .map(|(_, content)| content)
+            .collect()
+    };
+    // Marks the stored pages, so a build that reuses them shows it.
+    let mark = || {
+        let mut marked = 0;
+        for key in std::fs::read_dir(&stored).unwrap() {
+            for page in std::fs::read_dir(key.unwrap().path()).unwrap() {
+                let page = page.unwrap().path();
+                let xhtml = std::fs::read_to_string(&page).unwrap();
+                if xhtml.contains("很久很久以前。") {
+                    std::fs::write(&page, xhtml.replace("很久很久以前。", "重用的頁面。")).unwrap();
+                    marked += 1;
+                }
+            }
+        }
+        assert_eq!(marked, 1);
+    };
+
+    build_epub(&source(), &book(), &base, &()).unwrap();
+    mark();
+    build_epub(&source(), &book(), &base, &()).unwrap();
+    assert!(text(&path).contains("重用的頁面。"));
+
+    // A revised chapter is rendered again; the reused pages were stored
+    // again as they were.
+    let fetcher = book().page(
+        "https://czbooks.net/n/test/1",
+        chapter("第一章 開始", "<p>很久很久以前，改過了。</p>"),
+    );
+    build_epub(&source(), &fetcher, &base, &()).unwrap();
+    assert!(!text(&path).contains("重用的頁面。"));
+    assert!(text(&path).contains("很久很久以前，改過了。"));
+
+    let changed = [
+        BuildOptions {
+            chapter_footer: Some("from {url}".to_string()),
+            ..options(&path)
+        },
+        BuildOptions {
+            max_chapter_size: 4096,
+            ..options(&path)
+        },
+        BuildOptions {
+            epub2: true,
+            ..options(&path)
+        },
+    ];
+    for changed in changed {
+        build_epub(&source(), &book(), &base, &()).unwrap();
+        mark();
+        build_epub(&source(), &book(), &changed, &()).unwrap();
+        let text = text(&path);
+        assert!(!text.contains("重用的頁面。"), "{changed:?}");
+        assert!(text.contains("很久很久以前。"), "{changed:?}");
+    }
+}
+
+#[test]
+fn locked_chapters_get_placeholders_until_an_update_fills_them() {
+    let path = output("locked");
+    let manifest = path.with_file_name("manifest.json");
+    let options = BuildOptions {
+        locked: Some(regex::Regex::new("付費解鎖").unwrap()),
+        update: true,
+        manifest: Some(manifest.clone()),
+        ..options(&path)
+    };
+    let locked = || {
+        book().page(
+            "https://czbooks.net/n/test/2",
+            chapter("第二章 結束", "<p>本章需付費解鎖。</p>"),
+        )
+    };
+    let text = || -> String {
+        entries(&path)
+            .into_iter()
+            .map(|(_, content)| content)
+            .collect()
+    };
+
+    let summary = build_epub(&source(), &locked(), &options, &()).unwrap();
+    assert_eq!(summary.placeholders.len(), 1, "{:?}", summary.placeholders);
+    assert_eq!(summary.exit_code(), exit_code::SKIPPED);
+    assert!(text().contains("locked on the site"));
+    assert!(!text().contains("付費解鎖"));
+    let json = std::fs::read_to_string(&manifest).unwrap();
+    assert!(json.contains(r#""status": "locked""#), "{json}");
+
+    // Still locked: only that chapter is downloaded again.
+    let still_locked = locked();
+    let summary = build_epub(&source(), &still_locked, &options, &()).unwrap();
+    assert_eq!(summary.placeholders.len(), 1);
+    assert_eq!(summary.filled, 0);
+    assert_eq!(
+        still_locked.requests(),
+        [INDEX_URL, "https://czbooks.net/n/test/2"]
+    );
+    assert!(text().contains("很久很久以前。"));
+
+    let unlocked = book();
+    let summary = build_epub(&source(), &unlocked, &options, &()).unwrap();
+    assert_eq!(summary.filled, 1);
+    assert!(summary.placeholders.is_empty());
+    assert_eq!(summary.exit_code(), exit_code::SUCCESS);
+    assert_eq!(
+        unlocked.requests(),
+        [INDEX_URL, "https://czbooks.net/n/test/2"]
+    );
+    assert!(text().contains("很久很久以前。"));
+    assert!(text().contains("從此以後。"));
+    let json = std::fs::read_to_string(&manifest).unwrap();
+    assert!(!json.contains(r#""status": "locked""#), "{json}");
+
+    let err = build_epub(&source(), &book(), &options, &()).unwrap_err();
+    assert!(matches!(err, Error::UpToDate { .. }), "{err}");
+}
+
+const NAV_TEMPLATE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" lang="{{ lang }}">
+<head><title>{{ title }}</title></head>
+<body class="novel">
+<header>{{ index }} / {{ total }}</header>
+{{ body }}
+<nav>{% if prev_href %}<a href="{{ prev_href }}">prev</a>{% endif %}{% if next_href %}<a href="{{ next_href }}">next</a>{% endif %}</nav>
+</body>
+</html>"#;
+
+#[test]
+fn a_chapter_template_lays_out_pages_with_links_between_them() {
+    let path = output("template");
+    let options = BuildOptions {
+        chapter_template: Some(ChapterTemplate::new(NAV_TEMPLATE.to_string()).unwrap()),
+        chapter_footer: Some("{index}".to_string()),
+        ..options(&path)
+    };
+
+    build_epub(&source(), &book(), &options, &()).unwrap();
+
+    let files = entries(&path);
+    let page = |name: &str| {
+        files
+            .iter()
+            .find(|(entry, _)| entry.ends_with(name))
+            .map(|(_, content)| content.clone())
+            .unwrap_or_else(|| panic!("no {name}"))
+    };
+    let first = page("/0.xhtml");
+    assert!(first.contains(r#"<body class="novel">"#), "{first}");
+    assert!(first.contains("<header>1 / 2</header>"), "{first}");
+    assert!(first.contains("很久很久以前。"), "{first}");
+    assert!(first.contains(r#"class="chapter-footer">1</p>"#), "{first}");
+    assert!(
+        first.contains(r#"<nav><a href="1.xhtml">next</a></nav>"#),
+        "{first}"
+    );
+    let second = page("/1.xhtml");
+    assert!(second.contains("<header>2 / 2</header>"), "{second}");
+    assert!(
+        second.contains(r#"<nav><a href="0.xhtml">prev</a></nav>"#),
+        "{second}"
+    );
+}
+
+#[test]
+fn section_headings_become_capped_sub_entries_in_the_nav_and_ncx() {
+    let path = output("section-headings");
+    let fetcher = book()
+        .page(
+            "https://czbooks.net/n/test/1",
+            chapter(
+                "第一章 開始",
+                "<p>（一）</p><p>開場。</p><blockquote><p>【信】</p></blockquote>\
+                 <p>中段。</p><p>第三節</p><p>【插曲】</p><p>結尾。</p>",
+            ),
+        )
+        .page(
+            "https://czbooks.net/n/test/2",
+            chapter("第二章 結束", "<p>（一）</p><p>又開場。</p>"),
+        );
+    let options = BuildOptions {
+        headings: epub_dude::headings::HeadingRules::new(true, &["^第.節$".to_string()], 2)
+            .unwrap(),
+        ..options(&path)
+    };
+
+    build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    let first = entry(&path, "/0.xhtml");
+    assert!(
+        first.contains(r#"<h2 class="section" id="section-0-1">（一）</h2>"#),
+        "{first}"
+    );
+    assert!(
+        first.contains(r#"<h2 class="section" id="section-0-2">第三節</h2>"#),
+        "{first}"
+    );
+    // Past the cap, and inside a quote, lines stay as they were.
+    assert!(!first.contains("section-0-3"), "{first}");
+    assert!(
+        first.contains("【插曲】") && first.contains("【信】"),
+        "{first}"
+    );
+    assert_eq!(first.matches(r#"class="section""#).count(), 2, "{first}");
+    let second = entry(&path, "/1.xhtml");
+    assert!(
+        second.contains(r#"<h2 class="section" id="section-1-1">（一）</h2>"#),
+        "{second}"
+    );
+
+    let nav = entry(&path, "nav.xhtml");
+    let contents = &nav[..nav.find("landmarks").unwrap()];
+    let chapter = &contents[contents.find(r#"<a href="0.xhtml">"#).unwrap()..];
+    let chapter = &chapter[..chapter.find("</li>\n      <li>").unwrap()];
+    assert_eq!(
+        chapter
+            .split("<a href=\"")
+            .skip(1)
+            .filter_map(|link| link.split('<').next())
+            .collect::<Vec<_>>(),
+        [
+            r#"0.xhtml">第一章 開始"#,
+            r#"0.xhtml#section-0-1">（一）"#,
+            r#"0.xhtml#section-0-2">第三節"#,
+        ],
+        "{nav}"
+    );
+    assert!(
+        contents.contains(r#"<a href="1.xhtml#section-1-1">（一）</a>"#),
+        "{nav}"
+    );
+
+    let ncx = entry(&path, "toc.ncx");
+    let doc = roxmltree::Document::parse(&ncx).unwrap();
+    let ns = "http://www.daisy.org/z3986/2005/ncx/";
+    let point = doc
+        .descendants()
+        .find(|n| {
+            n.has_tag_name((ns, "navPoint"))
+                && n.children()
+                    .find(|c| c.has_tag_name((ns, "content")))
+                    .and_then(|c| c.attribute("src"))
+                    == Some("0.xhtml")
+        })
+        .unwrap_or_else(|| panic!("no chapter in {ncx}"));
+    let children: Vec<&str> = point
+        .children()
+        .filter(|c| c.has_tag_name((ns, "navPoint")))
+        .filter_map(|c| c.children().find(|c| c.has_tag_name((ns, "content"))))
+        .filter_map(|c| c.attribute("src"))
+        .collect();
+    assert_eq!(
+        children,
+        ["0.xhtml#section-0-1", "0.xhtml#section-0-2"],
+        "{ncx}"
+    );
+    let ids: Vec<&str> = doc
+        .descendants()
+        .filter_map(|n| n.attribute("id"))
+        .collect();
+    let unique: std::collections::HashSet<_> = ids.iter().collect();
+    assert_eq!(unique.len(), ids.len(), "{ncx}");
+}
+
+#[test]
+fn oversized_chapters_split_only_between_top_level_blocks() {
+    use epub_dude::split::{part_names, relink, split};
+
+    let paragraph = |n: usize| format!("<p>第{n}段。{}</p>", "字".repeat(20));
+    let body = [
+        paragraph(1),
+        format!(
+            "<blockquote><p>信{}</p><blockquote>內層<br />{}</blockquote></blockquote>",
+            "甲".repeat(30),
+            "乙".repeat(30)
+        ),
+        paragraph(2),
+        format!(
+            "<table><tr><td>{}</td></tr><tr><td>{}</td></tr></table>",
+            "丙".repeat(30),
+            "丁".repeat(30)
+        ),
+        r#"<aside epub:type="footnote" id="note-0-1"><p>註<br />解。</p></aside>"#.to_string(),
+        "一行<br />又一行<br />".to_string(),
+        format!("<p>{}</p>", "長".repeat(200)),
+        paragraph(3),
+    ]
+    .concat();
+
+    for limit in [1, 80, 200, 400] {
+        let parts = split(&body, limit);
+        assert_eq!(parts.concat(), body, "limit {limit}");
+        for part in &parts {
+            // Each part is well-formed on its own: no element was cut.
+            roxmltree::Document::parse(&format!(
+                r#"<div xmlns:epub="http://www.idpf.org/2007/ops">{part}</div>"#
+            ))
+            .unwrap_or_else(|e| panic!("limit {limit}: {e} in {part}"));
+            let oversized = part.len() > limit;
+            let single_block = split(part, 1).len() == 1;
+            assert!(!oversized || single_block, "limit {limit}: {part}");
+        }
+    }
+    // A paragraph over the limit is a part of its own, whole.
+    let parts = split(&body, 400);
+    assert!(
+        parts.contains(&format!("<p>{}</p>", "長".repeat(200)).as_str()),
+        "{parts:?}"
+    );
+    assert_eq!(split(&body, body.len()), [body.as_str()]);
+
+    assert_eq!(part_names(3, 1), ["3.xhtml"]);
+    let names = part_names(3, 28);
+    assert_eq!(names[..2], ["3-a.xhtml", "3-b.xhtml"]);
+    assert_eq!(names[25..], ["3-z.xhtml", "3-aa.xhtml", "3-ab.xhtml"]);
+
+    let parts = [
+        r##"<p>看<a epub:type="noteref" id="noteref-0-1" href="#note-0-1">1</a>，<a href="#here">這</a></p><p id="here">此處</p>"##,
+        r##"<aside id="note-0-1"><p><a href="#noteref-0-1">1</a>. 註</p></aside><a href="#gone">無</a>"##,
+    ];
+    let names = part_names(0, 2);
+    assert_eq!(
+        relink(&parts, &names),
+        [
+            r##"<p>看<a epub:type="noteref" id="noteref-0-1" href="0-b.xhtml#note-0-1">1</a>，<a href="#here">這</a></p><p id="here">此處</p>"##,
+            r##"<aside id="note-0-1"><p><a href="0-a.xhtml#noteref-0-1">1</a>. 註</p></aside><a href="#gone">無</a>"##,
+        ]
+    );
+}
+
+#[test]
+fn split_chapters_have_one_contents_entry_and_links_into_their_parts() {
+    let path = output("split-chapter");
+    let long = "很久".repeat(300);
+    let fetcher = book().page(
+        "https://czbooks.net/n/test/1",
+        chapter(
+            "第一章 開始",
+            &format!(
+                "<p>看這裡<sup class=\"ref\">1</sup>。</p>\n<p>{long}</p>\n<p>{long}</p>\n<p>Part 2</p>\n<p>尾聲。</p>\n<div class=\"notes\"><p>註解。</p></div>"
+            ),
+        ),
+    );
+    let options = BuildOptions {
+        max_chapter_size: 2500,
+        headings: epub_dude::headings::HeadingRules::new(true, &[], 30).unwrap(),
+        notes: Some(epub_dude::fetch::NoteSelectors {
+            marker: ".ref".parse().unwrap(),
+            container: ".notes".parse().unwrap(),
+        }),
+        ..options(&path)
+    };
+
+    build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    let opf = entry(&path, ".opf");
+    let spine: Vec<&str> = opf
+        .split(r#"<itemref idref=""#)
+        .skip(1)
+        .filter_map(|item| item.split('"').next())
+        .collect();
+    let parts: Vec<&&str> = spine.iter().filter(|id| id.starts_with("id_0-")).collect();
+    assert!(parts.len() >= 2, "{opf}");
+    let first_part = spine.iter().position(|id| *id == "id_0-a.xhtml").unwrap();
+    assert_eq!(spine[first_part + 1], "id_0-b.xhtml", "{opf}");
+
+    let nav = entry(&path, "nav.xhtml");
+    let contents = &nav[..nav.find("landmarks").unwrap()];
+    assert_eq!(contents.matches(r#"href="0-a.xhtml""#).count(), 1, "{nav}");
+    assert!(!contents.contains(r#"href="0-b.xhtml""#), "{nav}");
+    // The section heading in a later part is linked in that part.
+    let section = contents
+        .split("<a href=\"")
+        .find(|link| link.contains(">Part 2<"))
+        .unwrap_or_else(|| panic!("no Part 2 in {nav}"));
+    let heading_part = section.split('#').next().unwrap();
+    assert_ne!(heading_part, "0-a.xhtml", "{nav}");
+    assert!(entry(&path, &format!("/{heading_part}")).contains(">Part 2<"));
+
+    // The note is in the last part, and the reference in the first links there.
+    let last = *parts.last().unwrap();
+    let last = last.trim_start_matches("id_");
+    assert!(
+        entry(&path, "/0-a.xhtml").contains(&format!(r##"href="{last}#note-0-1""##)),
+        "{}",
+        entry(&path, "/0-a.xhtml")
+    );
+    assert!(
+        entry(&path, &format!("/{last}")).contains(r#"id="note-0-1""#),
+        "{}",
+        entry(&path, &format!("/{last}"))
+    );
+}
+
+#[test]
+fn chapter_nav_links_the_final_file_names_of_neighbouring_chapters() {
+    let path = output("chapter-nav");
+    let long = "很久".repeat(200);
+    let fetcher = book().page(
+        "https://czbooks.net/n/test/2",
+        chapter("第二章 結束", &format!("<p>{long}</p>\n<p>{long}</p>")),
+    );
+    let options = BuildOptions {
+        chapter_nav: true,
+        max_chapter_size: 2200,
+        ..options(&path)
+    };
+
+    build_epub(&source(), &fetcher, &options, &()).unwrap();
+
+    let files = entries(&path);
+    let page = |name: &str| {
+        files
+            .iter()
+            .find(|(entry, _)| entry.ends_with(name))
+            .map(|(_, content)| content.clone())
+            .unwrap_or_else(|| panic!("no {name}"))
+    };
+    let first = page("/0.xhtml");
+    assert!(
+        first.contains(
+            r#"<p class="chapter-nav"><a href="toc.xhtml">Contents</a> | <a href="1-a.xhtml">Next →</a></p>"#
+        ),
+        "{first}"
+    );
+    // Only the last part of a split chapter ends with the links.
+    assert!(!page("/1-a.xhtml").contains("chapter-nav\""));
+    let last = page("/1-b.xhtml");
+    assert!(
+        last.contains(
+            r#"<p class="chapter-nav"><a href="0.xhtml">← Previous</a> | <a href="toc.xhtml">Contents</a></p>"#
+        ),
+        "{last}"
+    );
+    assert!(files.iter().any(|(entry, _)| entry.ends_with("/toc.xhtml")));
+}
+
+#[test]
+fn the_table_of_contents_is_titled_in_the_book_language() {
+    let path = output("toc-title");
+    let nav = |options: &BuildOptions| {
+        build_epub(&source(), &book(), options, &()).unwrap();
+        let files = entries(&path);
+        let page = |name: &str| {
+            files
+                .iter()
+                .find(|(entry, _)| entry.ends_with(name))
+                .map(|(_, content)| content.clone())
+                .unwrap_or_else(|| panic!("no {name}"))
+        };
+        (page("/nav.xhtml"), page("/toc.xhtml"))
+    };
+
+    let (doc, inline) = nav(&options(&path));
+    assert!(doc.contains(r#"<h1 id="toc-title">目录</h1>"#), "{doc}");
+    assert!(inline.contains("目录"), "{inline}");
+
+    let (doc, _) = nav(&BuildOptions {
+        language: "zh-Hant-TW".to_string(),
+        ..options(&path)
+    });
+    assert!(doc.contains("<title>目錄</title>"), "{doc}");
+
+    let (doc, _) = nav(&BuildOptions {
+        language: "ja".to_string(),
+        ..options(&path)
+    });
+    assert!(doc.contains("目次"), "{doc}");
+
+    let (doc, inline) = nav(&BuildOptions {
+        toc_title: Some("章節列表".to_string()),
+        ..options(&path)
+    });
+    assert!(doc.contains("章節列表") && !doc.contains("目录"), "{doc}");
+    assert!(inline.contains("章節列表"), "{inline}");
+}
+
+#[test]
+fn repeated_chapter_titles_are_numbered_in_the_toc_only() {
+    let path = output("dedupe-toc");
+    let fetcher = MemoryFetcher::new()
+        .page(INDEX_URL, INDEX)
+        .page(
+            "https://czbooks.net/n/test/1",
+            chapter("无题", "<p>很久很久以前。</p>"),
+        )
+        .page(
+            "https://czbooks.net/n/test/2",
+            chapter("无题", "<p>從此以後。</p>"),
+        );
+    let page = |name: &str| {
+        entries(&path)
+            .into_iter()
+            .find(|(entry, _)| entry.ends_with(name))
+            .map(|(_, content)| content)
+            .unwrap_or_else(|| panic!("no {name}"))
+    };
+
+    build_epub(&source(), &fetcher, &options(&path), &()).unwrap();
+    assert!(!page("/nav.xhtml").contains("无题 (2)"));
+
+    let options = BuildOptions {
+        dedupe_toc_titles: true,
+        ..options(&path)
+    };
+    build_epub(&source(), &fetcher, &options, &()).unwrap();
+    let nav = page("/nav.xhtml");
+    assert!(nav.contains(r#"<a href="0.xhtml">无题</a>"#), "{nav}");
+    assert!(nav.contains(r#"<a href="1.xhtml">无题 (2)</a>"#), "{nav}");
+    assert!(page("/toc.ncx").contains("无题 (2)"));
+    let second = page("/1.xhtml");
+    assert!(
+        second.contains("无题") && !second.contains("无题 (2)"),
+        "{second}"
+    );
+}
+
+#[test]
+fn a_broken_chapter_template_fails_before_anything_is_fetched() {
+    let unclosed = NAV_TEMPLATE.replace("</nav>", "");
+    let err = ChapterTemplate::new(unclosed).unwrap_err();
+    assert!(format!("{err:#}").contains("not well-formed"), "{err:#}");
+
+    let not_xhtml = "<html><body>{{ body }}</body></html>".to_string();
+    let err = ChapterTemplate::new(not_xhtml).unwrap_err();
+    assert!(format!("{err:#}").contains("root element"), "{err:#}");
+
+    let unknown = NAV_TEMPLATE.replace("{{ total }}", "{{ chapters }}");
+    assert!(ChapterTemplate::new(unknown).is_err());
+
+    let bad_syntax = NAV_TEMPLATE.replace("{% endif %}", "");
+    assert!(ChapterTemplate::new(bad_syntax).is_err());
+}
+
+#[test]
+fn merge_softwrap_joins_hard_wrapped_chapters_only() {
+    let path = output("merge-softwrap");
+    let wrapped = "\u{3000}\u{3000}火車在黃昏時分駛進了那座早已無人使用的舊站，月台<br>
+上的燈一盞接著一盞亮了起來，照著斑駁的站牌和長椅，<br>
+他提著行李走下車廂，才發現整座車站只剩下他一個人。<br>
+「有人嗎？」<br>";
+    let fetcher = book().page(
+        "https://czbooks.net/n/test/2",
+        chapter("第二章 舊站", wrapped),
+    );
+    let page = |options: &BuildOptions| {
+        build_epub(&source(), &fetcher, options, &()).unwrap();
+        entries(&path)
+            .into_iter()
+            .find(|(entry, _)| entry.ends_with("/1.xhtml"))
+            .map(|(_, content)| content)
+            .unwrap()
+    };
+
+    let joined = "月台上的燈一盞接著一盞亮了起來，照著斑駁的站牌和長椅，他提著行李";
+    let plain = page(&options(&path));
+    assert!(!plain.contains(joined), "{plain}");
+
+    let merged = page(&BuildOptions {
+        merge_softwrap: true,
+        ..options(&path)
+    });
+    assert!(merged.contains(joined), "{merged}");
+    assert!(merged.contains("一個人。<br />"), "{merged}");
+}
+
+#[test]
+fn rebuild_assembles_the_book_from_the_work_directory_alone() {
+    let path = output("rebuild");
+    let rebuild = BuildOptions {
+        rebuild: true,
+        chapter_nav: true,
+        ..options(&path)
+    };
+    let offline = MemoryFetcher::new();
+
+    let Err(Error::Usage(message)) = build_epub(&source(), &offline, &rebuild, &()) else {
+        panic!("a book never built can't be rebuilt");
+    };
+    assert!(message.contains("needs a complete build"), "{message}");
+
+    // A build of the first chapter only caches that one.
+    let first = "1\t第一章 開始\thttps://czbooks.net/n/test/1\n";
+    let narrowed = BuildOptions {
+        chapters: Some(selection::read_selection(first).unwrap()),
+        ..options(&path)
+    };
+    build_epub(&source(), &book(), &narrowed, &()).unwrap();
+    let Err(Error::Usage(message)) = build_epub(&source(), &offline, &rebuild, &()) else {
+        panic!("the second chapter isn't cached");
+    };
+    assert!(
+        message.contains("1 of 2 chapters aren't cached"),
+        "{message}"
+    );
+    assert!(
+        message.contains("chapter 2 \"第二章 結束\" (https://czbooks.net/n/test/2)"),
+        "{message}"
+    );
+
+    build_epub(&source(), &book(), &options(&path), &()).unwrap();
+    let summary = build_epub(&source(), &offline, &rebuild, &()).unwrap();
+
+    assert!(offline.requests().is_empty(), "{:?}", offline.requests());
+    assert_eq!(summary.chapters, 2);
+    let entries = entries(&path);
+    assert!(entries.iter().any(|(_, c)| c.contains("很久很久以前。")));
+    assert!(entries.iter().any(|(_, c)| c.contains("chapter-nav")));
+    let opf = &entries
+        .iter()
+        .find(|(n, _)| n.ends_with("content.opf"))
+        .unwrap()
+        .1;
+    assert!(opf.contains("測試之書") && opf.contains("作者甲"), "{opf}");
+}
+
+#[test]
+fn a_chapter_cache_of_the_old_format_is_moved_over() {
+    let path = output("old-cache");
+    build_epub(&source(), &book(), &options(&path), &()).unwrap();
+    let dir = workdir::book_dir(&options(&path).work_dir, &source().uri);
+    let saved = |n: usize, title: &str| {
+        format!(
+            r#"{{"link":"https://czbooks.net/n/test/{n}","url":"https://czbooks.net/n/test/{n}","provenance":"primary","chapter":{{"title":"{title}","text":"<p>舊的快取。</p>","images":[],"notes":[]}}}}"#
+        )
+    };
+    std::fs::write(
+        dir.join("chapters.json"),
+        format!(
+            r#"{{"version":1,"source":"{INDEX_URL}","chapters":[{},{}]}}"#,
+            saved(1, "第一章 開始"),
+            saved(2, "第二章 結束")
+        ),
+    )
+    .unwrap();
+
+    let rebuild = BuildOptions {
+        rebuild: true,
+        ..options(&path)
+    };
+    let summary = build_epub(&source(), &MemoryFetcher::new(), &rebuild, &()).unwrap();
+    assert_eq!(summary.chapters, 2);
+    assert!(entries(&path).iter().any(|(_, c)| c.contains("舊的快取。")));
+    assert_eq!(std::fs::read_dir(dir.join("chapters")).unwrap().count(), 2);
+    let list = std::fs::read_to_string(dir.join("chapters.json")).unwrap();
+    assert!(!list.contains("舊的快取。"), "{list}");
+}
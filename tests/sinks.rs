@@ -0,0 +1,635 @@
+//! Extraction from saved czbooks.net pages in `tests/fixtures/czbooks`.
+//!
+//! Chapter markup is snapshot-tested; after an intended change, review and
+//! accept the new output with `cargo insta review`.
+
+use std::{fs, time::Duration};
+
+use epub_dude::{
+    BookSource, ChapterLink, Error, MemoryFetcher, exit_code,
+    fetch::{Limits, Site},
+};
+use html5ever::tendril::StrTendril;
+use http::Uri;
+
+fn fixture(name: &str) -> StrTendril {
+    let path = format!(
+        "{}/tests/fixtures/czbooks/{name}",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{path}: {e}"))
+        .into()
+}
+
+fn site() -> (Uri, Site) {
+    let uri: Uri = "https://czbooks.net/n/abc123".parse().unwrap();
+    let site = Site::for_uri(&uri).unwrap();
+    (uri, site)
+}
+
+fn links(links: &[ChapterLink]) -> Vec<(String, &str)> {
+    links
+        .iter()
+        .map(|l| (l.uri.to_string(), l.title.as_str()))
+        .collect()
+}
+
+#[test]
+fn index_page() {
+    let (uri, site) = site();
+    let info = site
+        .index(&uri, &fixture("index.html"), &Limits::default())
+        .unwrap();
+
+    assert_eq!(info.title, "山海旅人");
+    assert_eq!(info.authors, ["青山"]);
+    assert_eq!(
+        links(&info.links),
+        [
+            ("https://czbooks.net/n/abc123/1".to_string(), "第一章 離家"),
+            ("https://czbooks.net/n/abc123/2".to_string(), "第二章 渡河"),
+            ("https://czbooks.net/n/abc123/3".to_string(), "第三章 夜宿"),
+        ]
+    );
+}
+
+#[test]
+fn paginated_index_ignores_page_links() {
+    let (uri, site) = site();
+    let info = site
+        .index(&uri, &fixture("index-paginated.html"), &Limits::default())
+        .unwrap();
+
+    assert_eq!(info.title, "長夜行");
+    assert_eq!(info.authors, ["墨"]);
+    assert_eq!(
+        links(&info.links),
+        [
+            (
+                "https://czbooks.net/n/def456/101".to_string(),
+                "第一百零一章 燈火"
+            ),
+            (
+                "https://czbooks.net/n/def456/102".to_string(),
+                "第一百零二章 風起"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn chapter_with_ads() {
+    let (uri, site) = site();
+    let chapter = site
+        .chapter(&uri, &fixture("chapter-ads.html"), None, &Limits::default())
+        .unwrap();
+
+    assert_eq!(chapter.title, "第一章 離家");
+    insta::assert_snapshot!(chapter.text);
+}
+
+#[test]
+fn chapter_with_nested_divs() {
+    let (uri, site) = site();
+    let chapter = site
+        .chapter(
+            &uri,
+            &fixture("chapter-nested.html"),
+            None,
+            &Limits::default(),
+        )
+        .unwrap();
+
+    assert_eq!(chapter.title, "第二章 渡河");
+    insta::assert_snapshot!(chapter.text);
+}
+
+#[test]
+fn chapter_with_entities() {
+    let (uri, site) = site();
+    let chapter = site
+        .chapter(
+            &uri,
+            &fixture("chapter-entities.html"),
+            None,
+            &Limits::default(),
+        )
+        .unwrap();
+
+    assert_eq!(chapter.title, "第三章 夜宿 & 篝火");
+    insta::assert_snapshot!(chapter.text);
+}
+
+/// Blockquotes, nested too, and preformatted text survive as they were,
+/// the `<pre>` with its blank lines and indentation.
+#[test]
+fn chapter_with_blocks() {
+    let (uri, site) = site();
+    let chapter = site
+        .chapter(
+            &uri,
+            &fixture("chapter-blocks.html"),
+            None,
+            &Limits::default(),
+        )
+        .unwrap();
+
+    assert_eq!(
+        chapter.text,
+        concat!(
+            "<br />  信上寫著：<br />  ",
+            "<blockquote>親愛的旅人：<br /><blockquote>山那邊<br />還有山。</blockquote>——你的朋友<br /></blockquote><br />  ",
+            "<pre>【系統】\n  姓名：旅人\n\n  等級：  3\n\t技能：夜行 &lt;被動&gt;</pre><br />  ",
+            "他收起了信。<br />",
+        )
+    );
+}
+
+/// Ruby is kept, with the end tags the source left out closed, and reads
+/// as "漢字（かんじ）" in plain text.
+#[test]
+fn chapter_with_ruby() {
+    let (uri, site) = site();
+    let chapter = site
+        .chapter(
+            &uri,
+            &fixture("chapter-ruby.html"),
+            None,
+            &Limits::default(),
+        )
+        .unwrap();
+
+    assert_eq!(chapter.title, "第四章 振り仮名");
+    let lines: Vec<&str> = chapter
+        .text
+        .split("<br />")
+        .map(str::trim_ascii)
+        .filter(|line| !line.is_empty())
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            "<ruby>漢字<rp>(</rp><rt>かんじ</rt><rp>)</rp></ruby>を読む。",
+            // Without <rp>.
+            "<ruby>東京<rt>とうきょう</rt></ruby>へ行く。",
+            // <rt> and <rb> closed by the next part.
+            "<ruby>明<rt>あ</rt><rb>日</rb><rt>した</rt></ruby>の朝。",
+            "<ruby><ruby>外<rt>そと</rt></ruby><rt>がい</rt></ruby>に出る。",
+            // Closed at the end of the content.
+            "<ruby>未完<rt>みかん</rt></ruby>",
+        ]
+    );
+
+    let plain: Vec<String> = epub_dude::plain::to_lines(&chapter.text)
+        .into_iter()
+        .filter(|line| !line.is_empty())
+        .collect();
+    assert_eq!(
+        plain,
+        [
+            "漢字（かんじ）を読む。",
+            "東京（とうきょう）へ行く。",
+            "明（あ）日（した）の朝。",
+            "外（そと）（がい）に出る。",
+            "未完（みかん）",
+        ]
+    );
+}
+
+/// Tables keep their rows and cells, with only valid spans; text between
+/// cells gets a cell of its own, and cells and sections outside a table
+/// degrade to lines of text.
+#[test]
+fn chapter_with_tables() {
+    let (uri, site) = site();
+    let chapter = site
+        .chapter(
+            &uri,
+            &fixture("chapter-tables.html"),
+            None,
+            &Limits::default(),
+        )
+        .unwrap();
+
+    assert_eq!(
+        chapter.text,
+        concat!(
+            "<br />  屬性面板：<br />  ",
+            r#"<table><tr><th colspan="2">旅人</th></tr><tr><td>力量</td><td>12</td></tr>"#,
+            "<tr><td>    多出來的字<br />    </td><td>敏捷</td><td>9</td></tr></table><br />  ",
+            "體力 7 <br /><br />  ",
+            "孤兒 格 <br />  ",
+            "完。<br />",
+        )
+    );
+    roxmltree::Document::parse(&format!("<div>{}</div>", chapter.text)).unwrap();
+}
+
+/// Pages are expected to be UTF-8; a GBK page is reported rather than
+/// decoded into mojibake.
+#[test]
+fn gbk_chapter_is_a_parse_error() {
+    let url = "https://czbooks.net/n/abc123/1";
+    let bytes = fs::read(format!(
+        "{}/tests/fixtures/czbooks/chapter-gbk.html",
+        env!("CARGO_MANIFEST_DIR")
+    ))
+    .unwrap();
+    let fetcher = MemoryFetcher::new().page(url, bytes);
+    let source = BookSource::new("https://czbooks.net/n/abc123".parse().unwrap()).unwrap();
+    let link = ChapterLink {
+        uri: url.parse().unwrap(),
+        title: String::new(),
+        date: None,
+    };
+
+    let err = source.chapter(&fetcher, &link).unwrap_err();
+
+    assert!(
+        matches!(&err, Error::Parse { what, .. } if what == "UTF-8 text"),
+        "{err}"
+    );
+}
+
+#[test]
+fn unterminated_attribute_times_out() {
+    let (uri, site) = site();
+    let page = format!(
+        r#"<div class="name">第一章</div><div class="content"><p title="{}"#,
+        "x".repeat(4 * 1024 * 1024)
+    );
+    let limits = Limits {
+        parse_time: Duration::ZERO,
+        ..Limits::default()
+    };
+
+    let err = site.chapter(&uri, &page.into(), None, &limits).unwrap_err();
+
+    assert!(matches!(err, Error::ParseTimeout { .. }), "{err}");
+    assert_eq!(err.exit_code(), exit_code::PARSE);
+}
+
+#[test]
+fn unterminated_attribute_parses_within_the_default_limit() {
+    let (uri, site) = site();
+    let page = format!(
+        r#"<div class="name">第一章</div><div class="content"><p>開頭</p><p title="{}"#,
+        "x".repeat(4 * 1024 * 1024)
+    );
+
+    let chapter = site
+        .chapter(&uri, &page.into(), None, &Limits::default())
+        .unwrap();
+
+    assert_eq!(chapter.text, "開頭<br />");
+}
+
+#[test]
+fn chapter_with_footnotes() {
+    let (uri, site) = site();
+    let notes = epub_dude::fetch::NoteSelectors {
+        marker: ".note-ref".parse().unwrap(),
+        container: ".notes".parse().unwrap(),
+    };
+    let chapter = site
+        .chapter(
+            &uri,
+            &fixture("chapter-footnotes.html"),
+            Some(&notes),
+            &Limits::default(),
+        )
+        .unwrap();
+
+    assert_eq!(
+        chapter.notes,
+        ["兩個天體引力平衡之處。", "衛星被潮汐力撕裂的距離。"]
+    );
+    // Each marker's own text is dropped for a numbered placeholder.
+    assert!(!chapter.text.contains("[1]"), "{}", chapter.text);
+    assert!(!chapter.text.contains("兩個天體"), "{}", chapter.text);
+    let markers: Vec<String> = (0..3).map(epub_dude::fetch::note_marker).collect();
+    let at: Vec<usize> = markers
+        .iter()
+        .map(|m| chapter.text.find(m.as_str()).expect("marker"))
+        .collect();
+    assert!(at.is_sorted(), "{}", chapter.text);
+}
+
+#[test]
+fn chapter_text_is_capped() {
+    let (uri, site) = site();
+    let page = format!(
+        r#"<div class="name">第一章</div><div class="content">{}</div>"#,
+        "<p>一段很長的文字。</p>".repeat(100_000)
+    );
+    let limits = Limits {
+        max_text: 64 * 1024,
+        ..Limits::default()
+    };
+
+    let chapter = site.chapter(&uri, &page.into(), None, &limits).unwrap();
+
+    // Text stops within a paragraph of the cap, and markup with it.
+    let kept = chapter.text.matches("一段很長的文字。").count() * "一段很長的文字。".len();
+    assert!(kept.abs_diff(limits.max_text) < 24, "{kept}");
+    assert!(
+        chapter.text.len() < 2 * limits.max_text,
+        "{}",
+        chapter.text.len()
+    );
+}
+
+#[test]
+fn chapter_lists_split_by_volume_are_read_in_order() {
+    let (uri, site) = site();
+    let info = site
+        .index(&uri, &fixture("index-volumes.html"), &Limits::default())
+        .unwrap();
+
+    let chapter = |n: &str, title| (format!("https://czbooks.net/n/ghi789/{n}"), title);
+    assert_eq!(
+        links(&info.links),
+        [
+            chapter("1", "第一章 啟程"),
+            chapter("2", "第二章 入城"),
+            chapter("3", "第三章 渡川"),
+            chapter("3-1", "番外 川邊"),
+            chapter("4", "第四章 問路"),
+            chapter("5", "第五章 登天"),
+        ]
+    );
+}
+
+#[test]
+fn classes_and_ids_match_as_tokens_whatever_the_case() {
+    let (uri, site) = site();
+    let index = r#"<span class=" Title js-title ">書</span>
+<span class="meta	author">作者: <a href="/a/1">甲</a></span>
+<ul id=" Chapter-List " class="nav"><li><a href="/n/abc123/1">第一章</a></li></ul>"#;
+
+    let info = site.index(&uri, &index.into(), &Limits::default()).unwrap();
+
+    assert_eq!(info.title, "書");
+    assert_eq!(info.authors, ["甲"]);
+    assert_eq!(
+        links(&info.links),
+        [("https://czbooks.net/n/abc123/1".to_string(), "第一章")]
+    );
+
+    let page = r#"<div class="name js-title">第一章</div>
+<div class="CONTENT  clearfix
+">正文</div><div class="content-footer">廣告</div>"#;
+    let chapter = site
+        .chapter(&uri, &page.into(), None, &Limits::default())
+        .unwrap();
+
+    assert_eq!(chapter.title, "第一章");
+    assert_eq!(chapter.text.trim(), "正文");
+
+    // Selectors from the command line match the same way.
+    let dated = r#"<ul id="chapter-list"><li><a href="/n/abc123/1">第一章</a>
+<span class="Time small" id=" d1">2024-01-05</span></li></ul>"#;
+    let info = site
+        .index_with(
+            &uri,
+            &dated.into(),
+            &Limits::default(),
+            Some(&"span.time#D1".parse().unwrap()),
+        )
+        .unwrap();
+    assert_eq!(info.links[0].date.unwrap().to_string(), "2024-01-05");
+}
+
+#[test]
+fn index_links_are_capped() {
+    let (uri, site) = site();
+    let items: String = (1..=500)
+        .map(|n| format!(r#"<li><a href="/n/abc123/{n}">第{n}章</a></li>"#))
+        .collect();
+    let page = format!(r#"<span class="title">書</span><ul id="chapter-list">{items}</ul>"#);
+    let limits = Limits {
+        max_links: 100,
+        ..Limits::default()
+    };
+
+    let info = site.index(&uri, &page.into(), &limits).unwrap();
+
+    assert_eq!(info.links.len(), 100);
+    assert_eq!(info.links[99].title, "第100章");
+}
+
+#[test]
+fn chapter_dates_are_read_next_to_their_links() {
+    let (uri, site) = site();
+    let page = r#"<span class="title">書</span><ul id="chapter-list">
+<li><a href="/n/abc123/1">第一章</a> <span class="time">2024-01-05</span></li>
+<li><a href="/n/abc123/2">第二章</a> <span class="time">無日期</span></li>
+<li><a href="/n/abc123/3">第三章</a> <span class="time">2024年2月<b>3</b>日</span></li>
+</ul><span class="time">2020-01-01</span>"#;
+    let dates = "span.time".parse().unwrap();
+
+    let info = site
+        .index_with(&uri, &page.into(), &Limits::default(), Some(&dates))
+        .unwrap();
+
+    let dates: Vec<_> = info
+        .links
+        .iter()
+        .map(|l| l.date.map(|d| d.to_string()))
+        .collect();
+    assert_eq!(
+        dates,
+        [
+            Some("2024-01-05".to_string()),
+            None,
+            Some("2024-02-03".to_string())
+        ]
+    );
+    assert_eq!(info.links[2].title, "第三章");
+
+    let undated = site.index(&uri, &page.into(), &Limits::default()).unwrap();
+    assert!(undated.links.iter().all(|l| l.date.is_none()));
+}
+
+#[test]
+fn dates_before_their_links_go_to_the_next_link() {
+    let (uri, site) = site();
+    let page = r#"<ul id="chapter-list">
+<li><i>2023/12/30</i><a href="/n/abc123/1">第一章</a></li>
+<li><i>2023.12.31</i><a href="/n/abc123/2">第二章</a></li>
+</ul>"#;
+
+    let info = site
+        .index_with(
+            &uri,
+            &page.into(),
+            &Limits::default(),
+            Some(&"i".parse().unwrap()),
+        )
+        .unwrap();
+
+    let dates: Vec<_> = info
+        .links
+        .iter()
+        .map(|l| l.date.unwrap().to_string())
+        .collect();
+    assert_eq!(dates, ["2023-12-30", "2023-12-31"]);
+}
+
+#[test]
+fn dates_read_in_common_formats() {
+    use chrono::{Local, NaiveDate, TimeZone};
+    use epub_dude::dates::parse;
+
+    let now = Local.with_ymd_and_hms(2024, 3, 10, 12, 0, 0).unwrap();
+    let day = |y, m, d| NaiveDate::from_ymd_opt(y, m, d);
+    for (text, expected) in [
+        ("2024-01-15", day(2024, 1, 15)),
+        ("更新於 2024/1/5 08:30", day(2024, 1, 5)),
+        ("2023年12月1日", day(2023, 12, 1)),
+        ("03-01", day(2024, 3, 1)),
+        ("12-25", day(2023, 12, 25)),
+        ("今天", day(2024, 3, 10)),
+        ("昨天 18:00", day(2024, 3, 9)),
+        ("前天", day(2024, 3, 8)),
+        ("3天前", day(2024, 3, 7)),
+        ("2 週前", day(2024, 2, 25)),
+        ("13小時前", day(2024, 3, 9)),
+        ("1 month ago", day(2024, 2, 10)),
+        ("yesterday", day(2024, 3, 9)),
+        ("第一章", None),
+    ] {
+        assert_eq!(parse(text, now), expected, "{text}");
+    }
+}
+
+#[test]
+fn metadata_normalizes_across_cjk_punctuation_variants() {
+    use epub_dude::metadata::normalize;
+
+    for (text, folded, kept) in [
+        ("書名（上）", "書名(上)", "書名（上）"),
+        ("　書名　　第二部　", "書名 第二部", "書名 第二部"),
+        ("作者\t甲\n", "作者 甲", "作者 甲"),
+        ("Ｒｅ：ＺＥＲＯ！", "Re:ZERO!", "Ｒｅ：ＺＥＲＯ！"),
+        ("第１２３章", "第123章", "第１２３章"),
+        ("【書名】～外傳～", "【書名】~外傳~", "【書名】～外傳～"),
+        // CJK punctuation with no half-width form stays as it is.
+        (
+            "書名、續集。「上」",
+            "書名、續集。「上」",
+            "書名、續集。「上」",
+        ),
+    ] {
+        assert_eq!(normalize(text, true), folded, "{text}");
+        assert_eq!(normalize(text, false), kept, "{text}");
+    }
+}
+
+#[test]
+fn hard_wrapped_prose_is_merged_back_into_paragraphs() {
+    use epub_dude::softwrap::merge;
+
+    let (uri, site) = site();
+    let wrapped = site
+        .chapter(
+            &uri,
+            &fixture("chapter-hardwrapped.html"),
+            None,
+            &Limits::default(),
+        )
+        .unwrap();
+
+    let merged = merge(&wrapped.text, "zh-Hant");
+    let lines: Vec<&str> = merged
+        .split("<br />")
+        .map(str::trim_ascii)
+        .filter(|line| !line.is_empty())
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            "\u{3000}\u{3000}火車在黃昏時分駛進了那座早已無人使用的舊站，月台上的燈一盞接著一盞亮了起來，照著斑駁的站牌和長椅，他提著行李走下車廂，才發現整座車站只剩下他一個人。",
+            "\u{3000}\u{3000}遠處的售票窗口裡似乎還坐著一個人影，他走近一看，卻只是一件掛在椅背上的舊外套，衣角隨著晚風輕輕擺動著，像是在向他招手，又像是在催他快些離開這個地方。",
+            "「有人嗎？」",
+            "——沒有人回答他，只有風聲從月台的另一頭慢慢吹過去，把地上的落葉捲起來，又輕輕地放回了原來的地方。",
+        ],
+        "{merged}"
+    );
+}
+
+#[test]
+fn paragraphed_prose_is_left_as_it_is() {
+    use epub_dude::softwrap::merge;
+
+    let (uri, site) = site();
+    for name in ["chapter-paragraphed.html", "chapter-ads.html"] {
+        let chapter = site
+            .chapter(&uri, &fixture(name), None, &Limits::default())
+            .unwrap();
+        assert_eq!(merge(&chapter.text, "zh-Hant"), chapter.text, "{name}");
+    }
+
+    let english =
+        "The train pulled in at dusk.<br />Nobody was waiting.<br />He sat down<br />and waited.";
+    assert_eq!(merge(english, "en"), english);
+    let wrapped = "The train pulled into the old station at<br />dusk, and one by one the lamps along the<br />platform came on over the faded benches.<br />Nobody was waiting for him on the platform.";
+    assert_eq!(
+        merge(wrapped, "en"),
+        "The train pulled into the old station at dusk, and one by one the lamps along the platform came on over the faded benches.<br />Nobody was waiting for him on the platform."
+    );
+}
+
+#[test]
+fn paragraphs_in_another_script_get_their_own_language() {
+    use epub_dude::scripts::tag_paragraphs;
+
+    let (uri, site) = site();
+    let chapter = |name: &str| {
+        site.chapter(&uri, &fixture(name), None, &Limits::default())
+            .unwrap()
+            .text
+    };
+
+    // Paragraphs come out of the extractor as lines.
+    let paragraphs = chapter("chapter-mixed.html");
+    let tagged = tag_paragraphs(&paragraphs, "zh-Hant", false);
+    assert!(
+        tagged.contains(r#"<span lang="en" xml:lang="en">Dear Chen,"#),
+        "{tagged}"
+    );
+    assert!(
+        tagged.contains(r#"<span lang="ja" xml:lang="ja">信的背面"#),
+        "{tagged}"
+    );
+    // A name in passing doesn't make a paragraph English.
+    assert_eq!(tagged.matches("<span").count(), 2, "{tagged}");
+    // Nor does it make one Chinese: no script has most of its letters.
+    let english = tag_paragraphs(&paragraphs, "en", false);
+    assert_eq!(
+        english.matches(r#"<span lang="zh""#).count(),
+        1,
+        "{english}"
+    );
+    assert!(!english.contains(r#"lang="en""#), "{english}");
+
+    let markup = "<p>他拆開信。</p><p class=\"letter\">Dear Chen, the station will be closed.</p>";
+    assert_eq!(
+        tag_paragraphs(markup, "zh", false),
+        "<p>他拆開信。</p><p class=\"letter\" lang=\"en\" xml:lang=\"en\">Dear Chen, the station will be closed.</p>"
+    );
+
+    let lines = chapter("chapter-mixed-lines.html");
+    let tagged = tag_paragraphs(&lines, "zh-Hant", true);
+    assert!(
+        tagged.contains(r#"<span xml:lang="en">Thank you for letting me know."#),
+        "{tagged}"
+    );
+    assert_eq!(tagged.matches("<span").count(), 1, "{tagged}");
+
+    assert_eq!(
+        tag_paragraphs(&chapter("chapter-paragraphed.html"), "zh-Hant", false),
+        chapter("chapter-paragraphed.html")
+    );
+}